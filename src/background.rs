@@ -0,0 +1,233 @@
+use crate::exporter::Exporter;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{debug, warn};
+
+/// Schedule of the background collections: a fixed interval, optionally
+/// smeared by a random fraction so exporters started together drift apart
+pub struct Schedule {
+    interval: Duration,
+    jitter: f64,
+    rng: StdRng,
+}
+
+impl Schedule {
+    /// `jitter` is the largest fraction of the interval a tick may move by, in [0, 1]
+    pub fn new(interval: Duration, jitter: f64) -> Self {
+        Self::with_rng(interval, jitter, StdRng::from_entropy())
+    }
+
+    pub fn with_rng(interval: Duration, jitter: f64, rng: StdRng) -> Self {
+        Self {
+            interval,
+            jitter: jitter.clamp(0.0, 1.0),
+            rng,
+        }
+    }
+
+    /// Delay until the next collection: the interval moved by up to `jitter`
+    /// of itself in either direction
+    pub fn next_delay(&mut self) -> Duration {
+        if self.jitter == 0.0 {
+            return self.interval;
+        }
+        let offset = self.rng.gen_range(-self.jitter..=self.jitter);
+        self.interval.mul_f64(1.0 + offset)
+    }
+}
+
+/// Collect on the schedule forever, so scrapes only read the latest results.
+///
+/// Collections hold `lock`, the one fresh scrapes take, so a forced collection
+/// never runs alongside a background one. A collection taking longer than
+/// `timeout` is recorded as timed out, and while it stays wedged holding the
+/// lock the following ticks are skipped instead of piling up behind it.
+pub async fn run(exporter: Exporter, mut schedule: Schedule, lock: Arc<tokio::sync::Mutex<()>>, timeout: Duration) {
+    // Start of the previous collection and the delay it was followed by
+    let mut previous: Option<(Instant, Duration)> = None;
+    loop {
+        let started = Instant::now();
+        if let Some((previous_start, delay)) = previous {
+            // The delay only starts once a collection finishes, so a slow one shows up as drift
+            exporter.set_collection_interval_drift(started.duration_since(previous_start), delay);
+        }
+
+        match lock.clone().try_lock_owned() {
+            Ok(guard) => {
+                let collector = exporter.clone();
+                // NVML calls block, so keep them off the async workers
+                let collection = tokio::task::spawn_blocking(move || {
+                    let _guard = guard;
+                    collector.refresh()
+                });
+                match tokio::time::timeout(timeout, collection).await {
+                    Ok(Ok(Ok(_))) => debug!("Background collection completed"),
+                    // The failure is already reported through the up metric
+                    Ok(Ok(Err(_))) => {}
+                    Ok(Err(e)) => warn!("Background collection task failed: {}", e),
+                    Err(_) => {
+                        warn!("Background collection timed out after {:?}", timeout);
+                        exporter.record_collection_timeout();
+                    }
+                }
+            }
+            Err(_) => debug!("Skipping background collection, another collection is still running"),
+        }
+        let delay = schedule.next_delay();
+        previous = Some((started, delay));
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// Sample the devices every `interval` forever, so the time spent in each throttle
+/// reason and the range of the graphics clock are accounted for between collections
+pub async fn sample_devices(exporter: Exporter, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        let sampler = exporter.clone();
+        if let Err(e) = tokio::task::spawn_blocking(move || sampler.sample_devices()).await {
+            warn!("Device sampling task failed: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exporter::ExporterConfig;
+    use crate::metrics::{DeviceSample, Metrics, MockMetricsCollector};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn test_next_delay_within_jitter() {
+        let interval = Duration::from_secs(10);
+        let mut schedule = Schedule::with_rng(interval, 0.2, StdRng::seed_from_u64(42));
+
+        let delays: Vec<Duration> = (0..1000).map(|_| schedule.next_delay()).collect();
+        for delay in &delays {
+            assert!(
+                (Duration::from_secs(8)..=Duration::from_secs(12)).contains(delay),
+                "{:?} outside of 10s +/- 20%",
+                delay
+            );
+        }
+        // The ticks are actually spread out
+        assert!(delays.iter().any(|d| *d < Duration::from_secs(9)));
+        assert!(delays.iter().any(|d| *d > Duration::from_secs(11)));
+    }
+
+    #[test]
+    fn test_next_delay_is_reproducible_with_seed() {
+        let interval = Duration::from_secs(10);
+        let mut a = Schedule::with_rng(interval, 0.5, StdRng::seed_from_u64(7));
+        let mut b = Schedule::with_rng(interval, 0.5, StdRng::seed_from_u64(7));
+
+        for _ in 0..10 {
+            assert_eq!(a.next_delay(), b.next_delay());
+        }
+    }
+
+    #[test]
+    fn test_next_delay_without_jitter() {
+        let mut schedule = Schedule::new(Duration::from_secs(15), 0.0);
+
+        assert_eq!(schedule.next_delay(), Duration::from_secs(15));
+    }
+
+    #[tokio::test]
+    async fn test_slow_collection_reports_drift() {
+        let mut collector = MockMetricsCollector::new();
+        collector.expect_collect().returning(|| {
+            std::thread::sleep(Duration::from_millis(50));
+            Ok(Metrics::default())
+        });
+        let exporter = Exporter::with_collector(Arc::new(collector), ExporterConfig::default());
+        let drift = |exporter: &Exporter| {
+            exporter
+                .families()
+                .into_iter()
+                .find(|mf| mf.get_name() == "nvidia_collection_interval_drift_seconds")
+                .map(|mf| mf.get_metric()[0].get_gauge().get_value())
+        };
+        assert_eq!(drift(&exporter), None);
+
+        let lock = Arc::new(tokio::sync::Mutex::new(()));
+        let schedule = Schedule::new(Duration::from_millis(10), 0.0);
+        let task = tokio::spawn(run(exporter.clone(), schedule, lock, Duration::from_secs(10)));
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        task.abort();
+
+        // Each collection takes 50ms on top of the 10ms interval
+        let drift = drift(&exporter).expect("drift should be reported after two collections");
+        assert!(drift >= 0.05, "drift {} should cover the slow collection", drift);
+    }
+
+    #[tokio::test]
+    async fn test_wedged_collection_times_out() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let mut collector = MockMetricsCollector::new();
+        let collections = calls.clone();
+        collector.expect_collect().returning(move || {
+            // The first collection wedges in the driver
+            if collections.fetch_add(1, Ordering::SeqCst) == 0 {
+                std::thread::sleep(Duration::from_millis(300));
+            }
+            Ok(Metrics::default())
+        });
+        let exporter = Exporter::with_collector(Arc::new(collector), ExporterConfig::default());
+        let up = |exporter: &Exporter| {
+            exporter
+                .families()
+                .into_iter()
+                .find(|mf| mf.get_name() == "nvidia_up")
+                .map(|mf| mf.get_metric()[0].get_gauge().get_value())
+        };
+
+        let lock = Arc::new(tokio::sync::Mutex::new(()));
+        let schedule = Schedule::new(Duration::from_millis(10), 0.0);
+        let task = tokio::spawn(run(exporter.clone(), schedule, lock, Duration::from_millis(50)));
+        tokio::time::sleep(Duration::from_millis(150)).await;
+
+        assert_eq!(up(&exporter), Some(0.0));
+        // The ticks while the wedged collection held the lock were skipped
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        // Collections resume once the wedged one returns
+        tokio::time::sleep(Duration::from_millis(300)).await;
+        task.abort();
+        assert!(calls.load(Ordering::SeqCst) > 1);
+        assert_eq!(up(&exporter), Some(1.0));
+    }
+
+    #[tokio::test]
+    async fn test_throttle_reasons_sampled_between_collections() {
+        let mut collector = MockMetricsCollector::new();
+        collector.expect_collect().returning(|| Ok(Metrics::default()));
+        collector.expect_sample().returning(|| {
+            Ok(vec![DeviceSample {
+                minor_number: "0".to_string(),
+                throttle_reasons: Some(0x4),
+                clock_graphics: None,
+            }])
+        });
+        let exporter = Exporter::with_collector(Arc::new(collector), ExporterConfig::default());
+
+        let task = tokio::spawn(sample_devices(exporter.clone(), Duration::from_millis(10)));
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        task.abort();
+
+        let mf = exporter
+            .families()
+            .into_iter()
+            .find(|mf| mf.get_name() == "nvidia_throttle_reason_seconds_total")
+            .expect("throttle reasons should be accumulated");
+        assert_eq!(mf.get_metric().len(), 1);
+        let seconds = mf.get_metric()[0].get_counter().get_value();
+        // Only the time between the first and the last sample is accounted for
+        assert!(seconds > 0.0 && seconds < 0.25, "{}s power capped out of 200ms", seconds);
+    }
+}
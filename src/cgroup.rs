@@ -0,0 +1,88 @@
+use crate::process::read_proc_file;
+use std::path::Path;
+
+/// Resolve the job a process belongs to from its cgroup membership under `proc_root`
+/// (normally `/proc`). Returns the SLURM job id when the process runs inside a SLURM
+/// job cgroup, otherwise the leaf of its cgroup path. Returns `None` if the procfs
+/// entry is missing or unreadable, e.g. because the process already exited.
+pub fn job_label(proc_root: &Path, pid: u32) -> Option<String> {
+    job_from_cgroup(&read_proc_file(proc_root, pid, "cgroup")?)
+}
+
+/// Extract a job label from the contents of a `/proc/<pid>/cgroup` file
+fn job_from_cgroup(contents: &str) -> Option<String> {
+    // Each line is "hierarchy-id:controllers:path"; cgroup v2 has a single "0::path" line
+    let paths: Vec<&str> = contents
+        .lines()
+        .filter_map(|line| line.splitn(3, ':').nth(2))
+        .collect();
+
+    // SLURM places job steps under ".../job_<id>/step_<n>/..." with both cgroup versions
+    let slurm_job = paths.iter().find_map(|path| {
+        path.split('/')
+            .find_map(|component| component.strip_prefix("job_"))
+            .filter(|id| !id.is_empty() && id.chars().all(|c| c.is_ascii_digit()))
+    });
+    if let Some(id) = slurm_job {
+        return Some(id.to_string());
+    }
+
+    // Fall back to the leaf of the first non-root cgroup path
+    paths
+        .iter()
+        .filter_map(|path| path.rsplit('/').find(|component| !component.is_empty()))
+        .next()
+        .map(str::to_string)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::process::fixture_proc;
+
+    #[test]
+    fn test_slurm_job_cgroup_v1() {
+        let proc_root = fixture_proc("cgroup", &[(
+            4242,
+            "12:memory:/slurm/uid_1000/job_123456/step_0/task_0\n\
+             11:devices:/slurm/uid_1000/job_123456/step_0\n\
+             1:name=systemd:/system.slice/slurmd.service\n",
+        )]);
+
+        assert_eq!(job_label(proc_root.path(), 4242), Some("123456".to_string()));
+    }
+
+    #[test]
+    fn test_slurm_job_cgroup_v2() {
+        let proc_root = fixture_proc("cgroup", &[(
+            4242,
+            "0::/system.slice/slurmstepd.scope/job_987/step_batch/user/task_0\n",
+        )]);
+
+        assert_eq!(job_label(proc_root.path(), 4242), Some("987".to_string()));
+    }
+
+    #[test]
+    fn test_generic_cgroup_leaf() {
+        let proc_root = fixture_proc("cgroup", &[(4242, "0::/system.slice/docker-0123abcd.scope\n")]);
+
+        assert_eq!(
+            job_label(proc_root.path(), 4242),
+            Some("docker-0123abcd.scope".to_string())
+        );
+    }
+
+    #[test]
+    fn test_root_cgroup_has_no_job() {
+        let proc_root = fixture_proc("cgroup", &[(4242, "0::/\n")]);
+
+        assert_eq!(job_label(proc_root.path(), 4242), None);
+    }
+
+    #[test]
+    fn test_missing_procfs_entry() {
+        let proc_root = fixture_proc("cgroup", &[]);
+
+        assert_eq!(job_label(proc_root.path(), 4242), None);
+    }
+}
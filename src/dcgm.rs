@@ -0,0 +1,188 @@
+use crate::metrics::Device;
+use crate::remote::{self, RemoteEndpoint};
+use anyhow::Result;
+use std::collections::HashMap;
+use std::time::Duration;
+use tracing::debug;
+
+/// Port of dcgm-exporter when the endpoint doesn't give one, its default
+pub const DEFAULT_PORT: u16 = 9400;
+
+/// How long connecting to, and each read from or write to, dcgm-exporter may take.
+/// It runs on the same node, so an answer is quick or not coming.
+const TIMEOUT: Duration = Duration::from_secs(1);
+
+/// DCGM profiling fields of a GPU, as ratios (0-1)
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct DcgmFields {
+    /// Fraction of time at least one warp was active on an SM, averaged over the SMs
+    pub sm_active: Option<f64>,
+    /// Fraction of cycles the tensor pipes were active
+    pub tensor_active: Option<f64>,
+    /// Fraction of cycles the device memory interface was sending or receiving data
+    pub dram_active: Option<f64>,
+}
+
+/// Parse a --dcgm-exporter-endpoint value, like a --remote-endpoint but on
+/// dcgm-exporter's port by default
+pub fn parse_endpoint(s: &str) -> Result<RemoteEndpoint, String> {
+    remote::parse_endpoint(s, DEFAULT_PORT)
+}
+
+/// Fill in the DCGM profiling fields of `devices` from dcgm-exporter, matching
+/// them by UUID. Leaves the devices as they are when dcgm-exporter can't be
+/// reached, as on nodes without DCGM.
+pub fn add_fields(endpoint: &RemoteEndpoint, devices: &mut [Device]) {
+    let fields = match fetch(endpoint) {
+        Ok(fields) => fields,
+        Err(e) => {
+            debug!("Failed to read DCGM fields from {}: {:#}", endpoint.host, e);
+            return;
+        }
+    };
+    for device in devices {
+        if let Some(fields) = fields.get(&device.uuid) {
+            device.sm_active = fields.sm_active;
+            device.tensor_active = fields.tensor_active;
+            device.dram_active = fields.dram_active;
+        }
+    }
+}
+
+fn fetch(endpoint: &RemoteEndpoint) -> Result<HashMap<String, DcgmFields>> {
+    let body = remote::http_get(endpoint, "", "text/plain", TIMEOUT)?;
+    Ok(parse(&String::from_utf8_lossy(&body)))
+}
+
+/// Extract the profiling fields of each GPU, by UUID, from dcgm-exporter's
+/// metrics. Series of MIG instances, which carry a `GPU_I_ID` label, are skipped
+/// so they don't stand in for the whole GPU.
+fn parse(text: &str) -> HashMap<String, DcgmFields> {
+    let mut fields: HashMap<String, DcgmFields> = HashMap::new();
+    for line in text.lines().filter(|line| !line.starts_with('#')) {
+        let Some((name, rest)) = line.split_once('{') else {
+            continue;
+        };
+        let Some((labels, value)) = rest.rsplit_once('}') else {
+            continue;
+        };
+        if label(labels, "GPU_I_ID").is_some_and(|id| !id.is_empty()) {
+            continue;
+        }
+        let (Some(uuid), Some(value)) = (
+            label(labels, "UUID"),
+            value.split_whitespace().next().and_then(|v| v.parse::<f64>().ok()),
+        ) else {
+            continue;
+        };
+        let entry = fields.entry(uuid.to_string()).or_default();
+        match name {
+            "DCGM_FI_PROF_SM_ACTIVE" => entry.sm_active = Some(value),
+            "DCGM_FI_PROF_PIPE_TENSOR_ACTIVE" => entry.tensor_active = Some(value),
+            "DCGM_FI_PROF_DRAM_ACTIVE" => entry.dram_active = Some(value),
+            _ => {}
+        }
+    }
+    fields
+}
+
+/// Value of the label `name` in the labels of a series, without unescaping
+fn label<'a>(labels: &'a str, name: &str) -> Option<&'a str> {
+    let pattern = format!("{}=\"", name);
+    let start = labels
+        .match_indices(&pattern)
+        .map(|(pos, _)| pos)
+        .find(|&pos| pos == 0 || labels[..pos].ends_with(','))?;
+    labels[start + pattern.len()..].split('"').next()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+
+    const METRICS: &str = "\
+# HELP DCGM_FI_PROF_SM_ACTIVE The ratio of cycles an SM has at least 1 warp assigned (in %).
+# TYPE DCGM_FI_PROF_SM_ACTIVE gauge
+DCGM_FI_PROF_SM_ACTIVE{gpu=\"0\",UUID=\"GPU-aaaa\",device=\"nvidia0\",modelName=\"NVIDIA A100-SXM4-80GB\",Hostname=\"node-1\"} 0.751234
+DCGM_FI_PROF_SM_ACTIVE{gpu=\"1\",UUID=\"GPU-bbbb\",device=\"nvidia1\",modelName=\"NVIDIA A100-SXM4-80GB\",Hostname=\"node-1\"} 0.000000
+DCGM_FI_PROF_PIPE_TENSOR_ACTIVE{gpu=\"0\",UUID=\"GPU-aaaa\",device=\"nvidia0\",modelName=\"NVIDIA A100-SXM4-80GB\",Hostname=\"node-1\"} 0.402000
+DCGM_FI_PROF_DRAM_ACTIVE{gpu=\"0\",UUID=\"GPU-aaaa\",device=\"nvidia0\",modelName=\"NVIDIA A100-SXM4-80GB\",Hostname=\"node-1\"} 0.250000
+DCGM_FI_PROF_DRAM_ACTIVE{gpu=\"1\",UUID=\"GPU-bbbb\",device=\"nvidia1\",modelName=\"NVIDIA A100-SXM4-80GB\",Hostname=\"node-1\",GPU_I_PROFILE=\"1g.10gb\",GPU_I_ID=\"7\"} 0.900000
+DCGM_FI_DEV_GPU_TEMP{gpu=\"0\",UUID=\"GPU-aaaa\",device=\"nvidia0\",modelName=\"NVIDIA A100-SXM4-80GB\",Hostname=\"node-1\"} 41
+";
+
+    fn device(uuid: &str) -> Device {
+        Device {
+            uuid: uuid.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        let fields = parse(METRICS);
+        assert_eq!(
+            fields["GPU-aaaa"],
+            DcgmFields {
+                sm_active: Some(0.751234),
+                tensor_active: Some(0.402),
+                dram_active: Some(0.25),
+            }
+        );
+        // The MIG instance's DRAM activity isn't the GPU's
+        assert_eq!(
+            fields["GPU-bbbb"],
+            DcgmFields {
+                sm_active: Some(0.0),
+                ..Default::default()
+            }
+        );
+        assert_eq!(fields.len(), 2);
+    }
+
+    #[test]
+    fn test_label() {
+        let labels = r#"gpu="0",XGPU_I_ID="3",UUID="GPU-aaaa",GPU_I_ID="""#;
+        assert_eq!(label(labels, "gpu"), Some("0"));
+        assert_eq!(label(labels, "UUID"), Some("GPU-aaaa"));
+        assert_eq!(label(labels, "GPU_I_ID"), Some(""));
+        assert_eq!(label(labels, "device"), None);
+    }
+
+    #[test]
+    fn test_add_fields() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let endpoint = parse_endpoint(&listener.local_addr().unwrap().to_string()).unwrap();
+        std::thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let mut request = [0; 1024];
+                let _ = stream.read(&mut request);
+                let _ = write!(stream, "HTTP/1.0 200 OK\r\nContent-Type: text/plain\r\n\r\n{}", METRICS);
+            }
+        });
+
+        let mut devices = vec![device("GPU-aaaa"), device("GPU-cccc")];
+        add_fields(&endpoint, &mut devices);
+        assert_eq!(devices[0].sm_active, Some(0.751234));
+        assert_eq!(devices[0].tensor_active, Some(0.402));
+        assert_eq!(devices[0].dram_active, Some(0.25));
+        // Not known to DCGM
+        assert_eq!(devices[1].sm_active, None);
+    }
+
+    #[test]
+    fn test_add_fields_without_dcgm() {
+        // Nothing listens on a port that was bound and released
+        let endpoint = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            parse_endpoint(&listener.local_addr().unwrap().to_string()).unwrap()
+        };
+        let mut devices = vec![device("GPU-aaaa")];
+        add_fields(&endpoint, &mut devices);
+        assert_eq!(devices[0].sm_active, None);
+
+        assert_eq!(parse_endpoint("localhost").unwrap().host, "localhost:9400");
+    }
+}
@@ -0,0 +1,246 @@
+use crate::exporter::{encode_text, Exporter};
+use crate::metrics::Device;
+use anyhow::{bail, Context, Result};
+use serde_json::Value;
+use std::io::Write;
+
+/// Output format of the dump subcommand
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum DumpFormat {
+    /// Prometheus exposition text
+    #[default]
+    Prometheus,
+    /// The collected data as JSON
+    Json,
+    /// One row per device, with a header row
+    Csv,
+}
+
+/// Columns of the CSV output when no fields are given, in order
+pub const CSV_COLUMNS: [&str; 19] = [
+    "index",
+    "minor_number",
+    "uuid",
+    "name",
+    "pci_bus_id",
+    "temperature",
+    "fan_speed",
+    "power_usage",
+    "power_limit",
+    "memory_total",
+    "memory_used",
+    "utilization_gpu",
+    "utilization_memory",
+    "clock_graphics",
+    "clock_sm",
+    "clock_memory",
+    "performance_state",
+    "ecc_errors_corrected",
+    "ecc_errors_uncorrected",
+];
+
+/// Perform a single collection and write it to `out` in `format`. The JSON
+/// devices only carry `fields`, or every field when it is empty; the CSV columns
+/// are `fields` in the given order, or `CSV_COLUMNS` when it is empty. Returns
+/// an error if the collection failed.
+pub fn run(exporter: &Exporter, format: DumpFormat, fields: &[String], out: &mut impl Write) -> Result<()> {
+    if format == DumpFormat::Prometheus && !fields.is_empty() {
+        bail!("Device fields can only be selected for the json and csv formats");
+    }
+    let metrics = exporter.refresh().context("Metrics collection failed")?;
+
+    match format {
+        DumpFormat::Prometheus => {
+            encode_text(&exporter.families(), out).context("Failed to encode metrics")?;
+        }
+        DumpFormat::Json => {
+            let mut value = serde_json::to_value(&metrics).context("Failed to encode metrics as JSON")?;
+            if !fields.is_empty() {
+                if let Some(Value::Array(devices)) = value.get_mut("devices") {
+                    for device in devices.iter_mut().filter_map(Value::as_object_mut) {
+                        device.retain(|name, _| fields.contains(name));
+                    }
+                }
+            }
+            serde_json::to_writer_pretty(&mut *out, &value).context("Failed to encode metrics as JSON")?;
+            writeln!(out)?;
+        }
+        DumpFormat::Csv => {
+            let columns: Vec<&str> = if fields.is_empty() {
+                CSV_COLUMNS.to_vec()
+            } else {
+                fields.iter().map(String::as_str).collect()
+            };
+            writeln!(out, "{}", columns.join(","))?;
+            for device in &metrics.devices {
+                let value = serde_json::to_value(device).context("Failed to encode device")?;
+                let row: Vec<String> = columns.iter().map(|column| csv_cell(&value[column])).collect();
+                writeln!(out, "{}", row.join(","))?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Format a device field as a CSV cell: empty when missing, lists and structs
+/// as JSON, quoted when needed
+fn csv_cell(value: &Value) -> String {
+    let cell = match value {
+        Value::Null => return String::new(),
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    };
+    if cell.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", cell.replace('"', "\"\""))
+    } else {
+        cell
+    }
+}
+
+/// Parse a --json-fields entry, which must name a serialized `Device` field
+pub fn parse_json_field(s: &str) -> Result<String, String> {
+    let device = serde_json::to_value(Device::default()).map_err(|e| e.to_string())?;
+    match device.as_object() {
+        Some(fields) if fields.contains_key(s) => Ok(s.to_string()),
+        _ => Err(format!("'{}' is not a device field", s)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exporter::ExporterConfig;
+    use crate::metrics::{Device, Metrics, MockMetricsCollector};
+    use std::sync::Arc;
+
+    fn mock_exporter() -> Exporter {
+        let mut collector = MockMetricsCollector::new();
+        collector.expect_collect().returning(|| {
+            Ok(Metrics {
+                version: "525.116.04".to_string(),
+                devices: vec![Device {
+                    index: "0".to_string(),
+                    minor_number: "0".to_string(),
+                    name: "NVIDIA GeForce RTX 3080".to_string(),
+                    uuid: "GPU-12345678-1234-1234-1234-123456789012".to_string(),
+                    temperature: 65.0,
+                    ..Default::default()
+                }],
+                ..Default::default()
+            })
+        });
+        Exporter::with_collector(Arc::new(collector), ExporterConfig::default())
+    }
+
+    #[test]
+    fn test_dump_text() {
+        let mut out = Vec::new();
+        run(&mock_exporter(), DumpFormat::Prometheus, &[], &mut out).unwrap();
+
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.contains("nvidia_up 1"));
+        assert!(text.contains("nvidia_temperatures{minor=\"0\"} 65"));
+    }
+
+    #[test]
+    fn test_dump_json() {
+        let mut out = Vec::new();
+        run(&mock_exporter(), DumpFormat::Json, &[], &mut out).unwrap();
+
+        let value: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(value["version"], "525.116.04");
+        assert_eq!(value["devices"][0]["name"], "NVIDIA GeForce RTX 3080");
+        assert_eq!(value["devices"][0]["temperature"], 65.0);
+        assert!(value["devices"][0]["clock_graphics"].is_null());
+    }
+
+    #[test]
+    fn test_dump_json_fields() {
+        let fields = ["index".to_string(), "temperature".to_string()];
+        let mut out = Vec::new();
+        run(&mock_exporter(), DumpFormat::Json, &fields, &mut out).unwrap();
+
+        let value: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(value["version"], "525.116.04");
+        assert_eq!(
+            value["devices"][0],
+            serde_json::json!({
+                "index": "0",
+                "temperature": 65.0,
+            })
+        );
+    }
+
+    #[test]
+    fn test_dump_csv() {
+        let mut out = Vec::new();
+        run(&mock_exporter(), DumpFormat::Csv, &[], &mut out).unwrap();
+
+        let text = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 2, "a header and one row per device");
+        assert_eq!(lines[0], CSV_COLUMNS.join(","));
+        let row: Vec<&str> = lines[1].split(',').collect();
+        assert_eq!(row.len(), CSV_COLUMNS.len());
+        assert_eq!(row[0], "0");
+        assert_eq!(row[2], "GPU-12345678-1234-1234-1234-123456789012");
+        assert_eq!(row[3], "NVIDIA GeForce RTX 3080");
+        assert_eq!(row[5], "65.0");
+        // Missing values are left empty
+        assert_eq!(row[13], "");
+    }
+
+    #[test]
+    fn test_dump_csv_fields() {
+        let fields = ["temperature".to_string(), "name".to_string(), "fan_speed_rpm".to_string()];
+        let mut out = Vec::new();
+        run(&mock_exporter(), DumpFormat::Csv, &fields, &mut out).unwrap();
+
+        let text = String::from_utf8(out).unwrap();
+        assert_eq!(text, "temperature,name,fan_speed_rpm\n65.0,NVIDIA GeForce RTX 3080,[]\n");
+    }
+
+    #[test]
+    fn test_csv_columns_are_device_fields() {
+        for column in CSV_COLUMNS {
+            assert!(parse_json_field(column).is_ok(), "{} is not a device field", column);
+        }
+    }
+
+    #[test]
+    fn test_csv_cell() {
+        assert_eq!(csv_cell(&Value::Null), "");
+        assert_eq!(csv_cell(&serde_json::json!(1.5)), "1.5");
+        assert_eq!(csv_cell(&serde_json::json!("A100, 80GB")), "\"A100, 80GB\"");
+        assert_eq!(csv_cell(&serde_json::json!("say \"hi\"")), "\"say \"\"hi\"\"\"");
+        assert_eq!(csv_cell(&serde_json::json!([[0, 1.0]])), "\"[[0,1.0]]\"");
+    }
+
+    #[test]
+    fn test_dump_prometheus_rejects_fields() {
+        let fields = ["temperature".to_string()];
+        let mut out = Vec::new();
+        assert!(run(&mock_exporter(), DumpFormat::Prometheus, &fields, &mut out).is_err());
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn test_parse_json_field() {
+        assert_eq!(parse_json_field("power_usage"), Ok("power_usage".to_string()));
+        assert!(parse_json_field("power").is_err());
+    }
+
+    #[test]
+    fn test_dump_collection_failure() {
+        let mut collector = MockMetricsCollector::new();
+        collector
+            .expect_collect()
+            .returning(|| Err(anyhow::anyhow!("NVML not available")));
+        let exporter = Exporter::with_collector(Arc::new(collector), ExporterConfig::default());
+
+        let mut out = Vec::new();
+        assert!(run(&exporter, DumpFormat::Prometheus, &[], &mut out).is_err());
+        assert!(out.is_empty());
+    }
+}
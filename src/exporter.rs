@@ -1,28 +1,399 @@
-use crate::metrics::collect_metrics;
+use crate::cgroup;
+use crate::dcgm;
+use crate::metrics::{nvml_error_code, CallResults, Device, DeviceSample, GpuProcess, Metrics, MetricsCollector, NvmlCollector};
+use crate::process;
+use crate::samples::SampleWindow;
+use crate::remote::{RemoteCollector, RemoteEndpoint};
+use crate::smi::SmiCollector;
+use crate::throttle::{self, ThrottleTimer};
+use crate::visibility;
+use anyhow::Result;
+use nvml_wrapper::error::NvmlError;
 use prometheus::{
     core::Collector,
-    proto::MetricFamily,
-    Gauge, GaugeVec, Opts,
+    proto::{LabelPair, Metric, MetricFamily, MetricType, Quantile, Summary},
+    Counter, CounterVec, Encoder, Gauge, GaugeVec, Histogram, HistogramOpts, Opts, TextEncoder, DEFAULT_BUCKETS,
 };
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 use tracing::{debug, warn};
 
-const NAMESPACE: &str = "nvidia";
+pub const NAMESPACE: &str = "nvidia";
+
+/// Unit used to report the memory metrics
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum MemoryUnit {
+    /// Bytes, reported without a name suffix for compatibility
+    #[default]
+    Bytes,
+    /// Mebibytes (1024^2 bytes)
+    Mib,
+    /// Gibibytes (1024^3 bytes)
+    Gib,
+}
+
+impl MemoryUnit {
+    /// Suffix appended to the memory metric names
+    fn suffix(self) -> &'static str {
+        match self {
+            MemoryUnit::Bytes => "",
+            MemoryUnit::Mib => "_mib",
+            MemoryUnit::Gib => "_gib",
+        }
+    }
+
+    /// Convert a value in bytes to this unit
+    fn convert_bytes(self, bytes: f64) -> f64 {
+        match self {
+            MemoryUnit::Bytes => bytes,
+            MemoryUnit::Mib => bytes / (1024.0 * 1024.0),
+            MemoryUnit::Gib => bytes / (1024.0 * 1024.0 * 1024.0),
+        }
+    }
+}
+
+/// Scale used to report the utilization metrics
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum UtilizationScale {
+    /// 0-100, reported without a name suffix for compatibility
+    #[default]
+    Percent,
+    /// 0-1
+    Ratio,
+}
+
+impl UtilizationScale {
+    /// Suffix appended to the utilization metric names
+    fn suffix(self) -> &'static str {
+        match self {
+            UtilizationScale::Percent => "",
+            UtilizationScale::Ratio => "_ratio",
+        }
+    }
+
+    /// Convert a percentage to this scale
+    fn convert_percent(self, percent: f64) -> f64 {
+        match self {
+            UtilizationScale::Percent => percent,
+            UtilizationScale::Ratio => percent / 100.0,
+        }
+    }
+}
+
+/// Consecutive failed collections after which NVML is re-initialized
+pub const DEFAULT_NVML_REINIT_THRESHOLD: u32 = 3;
+
+/// Order in which devices are assigned their `index` label
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum DeviceOrder {
+    /// NVML enumeration order
+    #[default]
+    Nvml,
+    /// Ascending PCI bus ID, matching CUDA_DEVICE_ORDER=PCI_BUS_ID
+    Pci,
+}
+
+/// Other exporter whose metric names `--compat` reproduces
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Compat {
+    /// The Go nvidia_gpu_exporter, which exports `nvidia-smi` query fields as nvidia_smi_*
+    GoNvidiaGpuExporter,
+}
+
+/// How a metric converts to the unit of its Go nvidia_gpu_exporter counterpart
+#[derive(Debug, Clone, Copy)]
+enum CompatUnit {
+    Same,
+    /// 0-100 to 0-1
+    Percent,
+    /// Configured utilization scale to 0-1
+    Utilization,
+    /// Configured memory unit to bytes
+    Memory,
+    /// Milliwatts to watts
+    Milli,
+    /// MHz to Hz
+    Mega,
+}
+
+/// Metrics renamed by `--compat go-nvidia-gpu-exporter`: our name without the
+/// namespace and configurable unit suffix, the Go exporter's name, and the
+/// conversion to its unit. Metrics not listed keep their names.
+const GO_EXPORTER_NAMES: [(&str, &str, CompatUnit); 23] = [
+    ("info", "nvidia_smi_gpu_info", CompatUnit::Same),
+    ("temperatures", "nvidia_smi_temperature_gpu", CompatUnit::Same),
+    ("fanspeed", "nvidia_smi_fan_speed_ratio", CompatUnit::Percent),
+    ("memory_total", "nvidia_smi_memory_total_bytes", CompatUnit::Memory),
+    ("memory_used", "nvidia_smi_memory_used_bytes", CompatUnit::Memory),
+    ("utilization_gpu", "nvidia_smi_utilization_gpu_ratio", CompatUnit::Utilization),
+    ("utilization_memory", "nvidia_smi_utilization_memory_ratio", CompatUnit::Utilization),
+    ("power_usage", "nvidia_smi_power_draw_watts", CompatUnit::Milli),
+    ("power_limit_milliwatts", "nvidia_smi_power_limit_watts", CompatUnit::Milli),
+    ("power_limit_default_milliwatts", "nvidia_smi_power_default_limit_watts", CompatUnit::Milli),
+    ("power_limit_enforced_milliwatts", "nvidia_smi_enforced_power_limit_watts", CompatUnit::Milli),
+    ("clock_graphics_mhz", "nvidia_smi_clocks_current_graphics_clock_hz", CompatUnit::Mega),
+    ("clock_sm_mhz", "nvidia_smi_clocks_current_sm_clock_hz", CompatUnit::Mega),
+    ("clock_memory_mhz", "nvidia_smi_clocks_current_memory_clock_hz", CompatUnit::Mega),
+    ("clock_graphics_max_mhz", "nvidia_smi_clocks_max_graphics_clock_hz", CompatUnit::Mega),
+    ("clock_sm_max_mhz", "nvidia_smi_clocks_max_sm_clock_hz", CompatUnit::Mega),
+    ("clock_memory_max_mhz", "nvidia_smi_clocks_max_memory_clock_hz", CompatUnit::Mega),
+    ("performance_state", "nvidia_smi_pstate", CompatUnit::Same),
+    ("pcie_link_generation", "nvidia_smi_pcie_link_gen_current", CompatUnit::Same),
+    ("pcie_link_width", "nvidia_smi_pcie_link_width_current", CompatUnit::Same),
+    ("pcie_max_link_generation", "nvidia_smi_pcie_link_gen_max", CompatUnit::Same),
+    ("pcie_max_link_width", "nvidia_smi_pcie_link_width_max", CompatUnit::Same),
+    ("encoder_sessions", "nvidia_smi_encoder_stats_session_count", CompatUnit::Same),
+];
+
+/// Quantiles reported by the power usage summary
+const POWER_SUMMARY_QUANTILES: [f64; 4] = [0.5, 0.9, 0.95, 0.99];
+
+/// Reasons reported by `active_clock_limit`, each with the NVML throttle reason
+/// bits it covers, from highest to lowest priority. Thermal and power limits come
+/// first since they cap clocks to protect the hardware, then the unattributed HW
+/// slowdown, sync boost, clocks held down by a setting, and finally idleness.
+/// NVML has no throttle reason bits for reliability or board limits, those only
+/// show up in the violation counters.
+const CLOCK_LIMIT_REASONS: [(&str, u64); 6] = [
+    ("thermal", throttle::HW_THERMAL_SLOWDOWN | throttle::SW_THERMAL_SLOWDOWN),
+    ("power", throttle::HW_POWER_BRAKE_SLOWDOWN | throttle::SW_POWER_CAP),
+    ("hw_slowdown", throttle::HW_SLOWDOWN),
+    ("sync_boost", throttle::SYNC_BOOST),
+    ("clock_setting", throttle::APPLICATIONS_CLOCKS_SETTING | throttle::DISPLAY_CLOCK_SETTING),
+    ("low_utilization", throttle::GPU_IDLE),
+];
+
+/// Reported by `active_clock_limit` when nothing limits the clocks
+const CLOCK_LIMIT_NONE: &str = "none";
+
+/// Actions reported by `recommended_recovery_action`, with their
+/// `nvmlDeviceGpuRecoveryAction_t` value
+const RECOVERY_ACTIONS: [(&str, u64); 5] = [
+    ("none", 0),
+    ("gpu_reset", 1),
+    ("node_reboot", 2),
+    ("drain_p2p", 3),
+    ("drain_and_reset", 4),
+];
+
+/// Encoder sessions exported per GPU before the rest are dropped
+pub const DEFAULT_MAX_SESSIONS_PER_GPU: usize = 16;
+
+/// Samples kept per GPU by the power usage summary, whatever its window
+pub const DEFAULT_SAMPLE_BUFFER_CAPACITY: usize = 10_000;
+
+/// CPUs exported per GPU by the CPU affinity metric, bounding its cardinality on large hosts
+const MAX_AFFINITY_CPUS_PER_GPU: usize = 256;
+
+/// Labels the exporter sets itself, which constant labels must not reuse
+pub const RESERVED_LABEL_NAMES: [&str; 30] = [
+    "action", "bucket", "call", "code", "codec", "cpu", "ecc_version", "fan", "field_id", "gi_id", "host", "image_version",
+    "index", "job", "minor", "minor_number", "name", "oem_version", "pci_bus_id", "pid", "power_version",
+    "process_name", "pstate", "quantile", "reason", "resolution", "result", "source", "uuid", "version",
+];
+
+/// Options controlling how collected metrics are exported
+#[derive(Debug, Clone)]
+pub struct ExporterConfig {
+    /// Skip series for unsupported optional metrics instead of reporting 0
+    pub drop_zero_value_optional_metrics: bool,
+    /// Unit used for the memory_total/memory_used metrics
+    pub memory_unit: MemoryUnit,
+    /// Scale used for the GPU, memory and video engine utilization metrics
+    pub utilization_scale: UtilizationScale,
+    /// Suppress the per-device info metric, which exposes GPU UUIDs
+    pub drop_device_info_metric: bool,
+    /// Consecutive failed collections after which NVML is re-initialized (0 disables)
+    pub nvml_reinit_threshold: u32,
+    /// Maximum number of encoder sessions exported per GPU, capping label cardinality
+    pub max_sessions_per_gpu: usize,
+    /// Add a `job` label resolved from each process's cgroup to the per-process metrics
+    pub enable_cgroup_labels: bool,
+    /// Add a `process_name` label read from each process's `comm` to the per-process metrics
+    pub enable_process_names: bool,
+    /// Also export the P-State as a one-hot set of series, one per state
+    pub performance_state_one_hot: bool,
+    /// Order in which devices are assigned their `index` label
+    pub device_order: DeviceOrder,
+    /// Window over which the power usage summary is computed (None disables the summary)
+    pub power_summary_window: Option<Duration>,
+    /// Most samples kept per GPU for the power usage summary; the oldest are evicted beyond it
+    pub sample_buffer_capacity: usize,
+    /// Names replacing the `minor` label value, keyed by minor number
+    pub minor_names: BTreeMap<String, String>,
+    /// Replace GPU UUIDs with a salted SHA-256 hash
+    pub redact_uuid: bool,
+    /// Salt mixed into the redacted UUIDs
+    pub uuid_salt: String,
+    /// Export the NUMA node and CPU affinity of each GPU
+    pub enable_topology_metrics: bool,
+    /// Round inherently whole-number metrics (temperatures, utilization, fan speed, P-State)
+    pub round_integers: bool,
+    /// Collect by running `nvidia-smi` when NVML fails to initialize
+    pub enable_smi_fallback: bool,
+    /// Add the device `index` label to every series with a `minor` label
+    pub enable_index_label: bool,
+    /// Constant labels added to every metric, e.g. the cluster or region
+    pub const_labels: HashMap<String, String>,
+    /// Collect and export the maximum graphics clock of every supported P-State
+    pub collect_pstate_clocks: bool,
+    /// How long failed collections keep serving the last successful one before
+    /// reporting up=0 (None reports every failure right away)
+    pub stale_tolerance: Option<Duration>,
+    /// Leave out per-device series whose value hasn't changed since the last scrape
+    pub delta_mode: bool,
+    /// Upper bounds of the collection duration histogram buckets in seconds, increasing
+    pub collection_duration_buckets: Vec<f64>,
+    /// Count the NVML calls of each query by result
+    pub enable_call_metrics: bool,
+    /// NVML field ids queried on every device and exported as is
+    pub extra_field_ids: Vec<u32>,
+    /// Collect from these exporters instead of the local GPUs, labelling their
+    /// devices with a `host` label
+    pub remote_endpoints: Vec<RemoteEndpoint>,
+    /// Rename metrics to match another exporter (None keeps the names)
+    pub compat: Option<Compat>,
+    /// Number of devices a collection should find (None skips the check)
+    pub expected_device_count: Option<usize>,
+    /// Collect each device on its own thread under this timeout, leaving out the
+    /// devices that take longer (None collects them one after the other)
+    pub per_device_timeout: Option<Duration>,
+    /// Keep the metric families without samples, so `encode_text` writes their
+    /// HELP and TYPE lines even on nodes without GPUs
+    pub always_emit_metric_types: bool,
+    /// Also export the total power and memory used, and the mean utilization,
+    /// across all devices
+    pub emit_node_aggregates: bool,
+    /// Read the DCGM profiling fields of the devices from this dcgm-exporter
+    /// (None leaves them out)
+    pub dcgm_endpoint: Option<RemoteEndpoint>,
+}
+
+impl Default for ExporterConfig {
+    fn default() -> Self {
+        Self {
+            drop_zero_value_optional_metrics: false,
+            memory_unit: MemoryUnit::default(),
+            utilization_scale: UtilizationScale::default(),
+            drop_device_info_metric: false,
+            nvml_reinit_threshold: DEFAULT_NVML_REINIT_THRESHOLD,
+            max_sessions_per_gpu: DEFAULT_MAX_SESSIONS_PER_GPU,
+            enable_cgroup_labels: false,
+            enable_process_names: false,
+            performance_state_one_hot: false,
+            device_order: DeviceOrder::default(),
+            power_summary_window: None,
+            sample_buffer_capacity: DEFAULT_SAMPLE_BUFFER_CAPACITY,
+            minor_names: BTreeMap::new(),
+            redact_uuid: false,
+            uuid_salt: String::new(),
+            enable_topology_metrics: false,
+            round_integers: false,
+            enable_smi_fallback: false,
+            enable_index_label: false,
+            const_labels: HashMap::new(),
+            collect_pstate_clocks: false,
+            stale_tolerance: None,
+            delta_mode: false,
+            collection_duration_buckets: DEFAULT_BUCKETS.to_vec(),
+            enable_call_metrics: false,
+            extra_field_ids: Vec::new(),
+            remote_endpoints: Vec::new(),
+            compat: None,
+            expected_device_count: None,
+            per_device_timeout: None,
+            always_emit_metric_types: false,
+            emit_node_aggregates: false,
+            dcgm_endpoint: None,
+        }
+    }
+}
+
+/// Description of an exported metric, independent of the devices present
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct CatalogEntry {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub metric_type: String,
+    pub help: String,
+    pub labels: Vec<String>,
+}
+
+/// Identifies a series: its family name and label pairs
+type SeriesKey = (String, Vec<(String, String)>);
 
 #[derive(Clone)]
 pub struct Exporter {
+    collector: Arc<dyn MetricsCollector + Send + Sync>,
+    config: ExporterConfig,
+    nvml_reinit_count: Arc<AtomicU64>,
+    smi_parse_error_count: Arc<AtomicU64>,
+    /// NVML calls of failed collections, which only the collector can report
+    failed_call_results: Arc<Mutex<CallResults>>,
     up: Gauge,
+    last_scrape_success: Gauge,
+    nvml_permission_denied: Gauge,
+    serving_stale: Gauge,
+    snapshot_age: GaugeVec,
+    collection_duration: Histogram,
+    last_collection_error_code: GaugeVec,
+    nvml_reinit: Counter,
+    smi_parse_errors: Counter,
+    sample_buffer_evictions: Counter,
+    duplicate_label_collisions: Counter,
+    nvml_calls_per_scrape: Gauge,
+    nvml_call_results: CounterVec,
+    nvml_session_uptime: GaugeVec,
+    /// When the current NVML session started, i.e. the first collection succeeded
+    /// or the first one after the watchdog re-initialized NVML, with the number of
+    /// re-initializations by then
+    nvml_session_start: Arc<Mutex<Option<(Instant, u64)>>>,
+    /// Unlabelled, so it is only exported once background collection has reported a drift
+    collection_interval_drift: GaugeVec,
+    remote_up: GaugeVec,
     info: GaugeVec,
     device_count: Gauge,
+    visible_devices_restricted: Gauge,
+    /// Unlabelled, so it is only exported when the host's GPUs can be counted
+    host_device_count: GaugeVec,
+    /// Unlabelled, so it is only exported with an expected device count
+    device_count_mismatch: GaugeVec,
+    device_collection_timed_out: GaugeVec,
+    /// Unlabelled, so they are only exported with node aggregates and devices
+    node_power_usage: GaugeVec,
+    node_memory_used: GaugeVec,
+    node_utilization_gpu_average: GaugeVec,
     temperatures: GaugeVec,
+    memory_temperature_margin: GaugeVec,
+    target_temperature: GaugeVec,
     device_info: GaugeVec,
     power_usage: GaugeVec,
     power_usage_average: GaugeVec,
+    power_efficiency: GaugeVec,
+    power_usage_peak: GaugeVec,
     fan_speed: GaugeVec,
+    fan_speed_percent: GaugeVec,
+    fan_speed_max_percent: GaugeVec,
+    fan_speed_rpm: GaugeVec,
     memory_total: GaugeVec,
     memory_used: GaugeVec,
+    memory_used_peak: GaugeVec,
+    memory_reserved: GaugeVec,
+    memory_bandwidth: GaugeVec,
     utilization_memory: GaugeVec,
     utilization_gpu: GaugeVec,
     utilization_gpu_average: GaugeVec,
+    sm_active: GaugeVec,
+    tensor_active: GaugeVec,
+    dram_active: GaugeVec,
+    gpu_num_cores: GaugeVec,
+    gpu_num_sm: GaugeVec,
     // Clock speeds
     clock_graphics: GaugeVec,
     clock_sm: GaugeVec,
@@ -30,25 +401,112 @@ pub struct Exporter {
     clock_graphics_max: GaugeVec,
     clock_sm_max: GaugeVec,
     clock_memory_max: GaugeVec,
+    clock_graphics_customer_boost_max: GaugeVec,
+    clock_user_locked: GaugeVec,
+    app_clock_shortfall: GaugeVec,
+    throttling_supported: GaugeVec,
+    active_clock_limit: GaugeVec,
+    throttle_reason_seconds: CounterVec,
+    memory_thermal_throttle_events: CounterVec,
+    /// Accumulates the time between throttle reason samples in background mode
+    throttle_timer: Arc<Mutex<ThrottleTimer>>,
+    clock_graphics_sampled_min: GaugeVec,
+    clock_graphics_sampled_max: GaugeVec,
+    /// Lowest and highest graphics clock sampled per minor number since the previous collection
+    clock_graphics_range: Arc<Mutex<BTreeMap<String, (f64, f64)>>>,
+    pstate_max_clock_graphics: GaugeVec,
     // Power limits
     power_limit: GaugeVec,
     power_limit_default: GaugeVec,
+    power_limit_enforced: GaugeVec,
+    // Energy
+    energy_consumption: CounterVec,
     // Performance state
     performance_state: GaugeVec,
+    performance_state_current: GaugeVec,
+    // GPU operation mode
+    gpu_operation_mode: GaugeVec,
+    gpu_operation_mode_pending: GaugeVec,
+    display_active: GaugeVec,
+    display_connected: GaugeVec,
     // PCIe
     pcie_link_gen: GaugeVec,
     pcie_link_width: GaugeVec,
+    pcie_link_gen_max: GaugeVec,
+    pcie_link_width_max: GaugeVec,
+    pcie_link_speed: GaugeVec,
     pcie_tx_throughput: GaugeVec,
     pcie_rx_throughput: GaugeVec,
     // Encoder/Decoder
     encoder_utilization: GaugeVec,
     decoder_utilization: GaugeVec,
+    jpeg_utilization: GaugeVec,
+    ofa_utilization: GaugeVec,
+    encoder_capacity: GaugeVec,
+    encoder_sessions: GaugeVec,
+    encoder_session_fps: GaugeVec,
+    encoder_session_latency: GaugeVec,
     // ECC errors
     ecc_errors_corrected: GaugeVec,
     ecc_errors_uncorrected: GaugeVec,
+    row_remap_availability: GaugeVec,
+    // Health
+    recommended_recovery_action: GaugeVec,
+    reset_required: GaugeVec,
+    retired_pages_pending: GaugeVec,
+    ecc_sram_threshold_exceeded: GaugeVec,
+    inforom_valid: GaugeVec,
+    inforom_info: GaugeVec,
+    device_lost: GaugeVec,
+    // Topology
+    numa_node: GaugeVec,
+    cpu_affinity: GaugeVec,
+    // Extra NVML fields
+    raw_field: GaugeVec,
     // Processes
     compute_processes: GaugeVec,
     graphics_processes: GaugeVec,
+    compute_processes_memory: GaugeVec,
+    graphics_processes_memory: GaugeVec,
+    process_memory_used: GaugeVec,
+    process_memory_used_mig: GaugeVec,
+    /// Root of the procfs used to resolve process cgroups and count the host's GPUs
+    proc_root: PathBuf,
+    /// Root of the sysfs used to read the devices cgroup
+    sys_root: PathBuf,
+    /// Power usage samples per minor number for the power usage summary
+    power_samples: Arc<Mutex<BTreeMap<String, SampleWindow>>>,
+    /// Minor numbers whose full power sample buffer was already warned about
+    full_sample_buffers: Arc<Mutex<BTreeSet<String>>>,
+    /// Data of the last successful collection, `None` after a failed one
+    last_metrics: Arc<Mutex<Option<Metrics>>>,
+    /// When the last successful collection finished, to tell how stale it is
+    last_success: Arc<Mutex<Option<Instant>>>,
+    /// Bumped by every timeout, so a collection that outlives its timeout can tell
+    /// and leave the recorded timeout alone
+    collection_generation: Arc<AtomicU64>,
+    /// Whether a collection has finished, failed or timed out yet
+    collected: Arc<AtomicBool>,
+    /// When the last successful collection finished, which the served samples are as of
+    last_collection: Arc<Mutex<Option<SystemTime>>>,
+    /// Every series of the last scrape in delta mode
+    emitted: Arc<Mutex<HashMap<SeriesKey, Metric>>>,
+    /// Index of each device of the last collection, keyed by minor number, for the `index` label
+    device_indices: Arc<Mutex<BTreeMap<String, String>>>,
+    /// Minor numbers of the devices the last collection found lost, whose series
+    /// are left out but for `device_lost`
+    lost_minors: Arc<Mutex<BTreeSet<String>>>,
+    /// UUIDs given as minor number by the last collection to devices whose minor
+    /// number collided, so each device is counted once while its collision lasts
+    colliding_minors: Arc<Mutex<BTreeSet<String>>>,
+    /// Host and local minor number of each remote device seen so far, keyed by its
+    /// "host/minor" minor number, for the `host` label
+    device_hosts: Arc<Mutex<BTreeMap<String, (String, String)>>>,
+    /// UUID of each device seen so far, keyed by minor number, for the `uuid` label
+    /// of the metrics renamed by `--compat`
+    device_uuids: Arc<Mutex<BTreeMap<String, String>>>,
+    /// Highest used memory per GPU UUID since the exporter started, in bytes
+    memory_peaks: Arc<Mutex<BTreeMap<String, f64>>>,
 }
 
 impl Default for Exporter {
@@ -59,34 +517,261 @@ impl Default for Exporter {
 
 impl Exporter {
     pub fn new() -> Self {
+        Self::with_config(ExporterConfig::default())
+    }
+
+    pub fn with_config(config: ExporterConfig) -> Self {
+        if !config.remote_endpoints.is_empty() {
+            let collector = RemoteCollector::new(config.remote_endpoints.clone());
+            return Self::with_collector(Arc::new(collector), config);
+        }
+        let mut collector = NvmlCollector::new(config.nvml_reinit_threshold);
+        let nvml_reinit_count = collector.reinit_count();
+        let failed_call_results = collector.failed_call_results();
+        let smi = SmiCollector::new("nvidia-smi");
+        let smi_parse_error_count = smi.parse_error_count();
+        if config.enable_smi_fallback {
+            collector = collector.with_fallback(smi);
+        }
+        if config.collect_pstate_clocks {
+            collector = collector.with_pstate_clocks();
+        }
+        if !config.extra_field_ids.is_empty() {
+            collector = collector.with_extra_field_ids(config.extra_field_ids.clone());
+        }
+        if let Some(timeout) = config.per_device_timeout {
+            collector = collector.with_per_device_timeout(timeout);
+        }
+        Self {
+            nvml_reinit_count,
+            smi_parse_error_count,
+            failed_call_results,
+            ..Self::with_collector(Arc::new(collector), config)
+        }
+    }
+
+    pub fn with_collector(
+        collector: Arc<dyn MetricsCollector + Send + Sync>,
+        config: ExporterConfig,
+    ) -> Self {
+        let memory_suffix = config.memory_unit.suffix();
+        let utilization_suffix = config.utilization_scale.suffix();
+        let mut process_labels = vec!["minor", "pid"];
+        if config.enable_cgroup_labels {
+            process_labels.push("job");
+        }
+        if config.enable_process_names {
+            process_labels.push("process_name");
+        }
+        let mig_process_labels = [process_labels.as_slice(), &["gi_id"]].concat();
+        let const_labels = config.const_labels.clone();
+        let collection_duration_buckets = config.collection_duration_buckets.clone();
         Self {
-            up: Gauge::with_opts(Opts::new("up", "NVML Metric Collection Operational")
-                .namespace(NAMESPACE))
-                .expect("Failed to create up metric"),
+            collector,
+            config,
+            nvml_reinit_count: Arc::new(AtomicU64::new(0)),
+            smi_parse_error_count: Arc::new(AtomicU64::new(0)),
+            failed_call_results: Arc::new(Mutex::new(BTreeMap::new())),
+            up: Gauge::with_opts(
+                Opts::new("up", "NVML Metric Collection Operational")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
+            )
+            .expect("Failed to create up metric"),
+            last_scrape_success: Gauge::with_opts(
+                Opts::new("last_scrape_success", "Whether the last metrics collection completed successfully")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
+            )
+            .expect("Failed to create last_scrape_success metric"),
+            nvml_permission_denied: Gauge::with_opts(
+                Opts::new("nvml_permission_denied", "Whether the last collection failed because NVML lacked permission to access the devices")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
+            )
+            .expect("Failed to create nvml_permission_denied metric"),
+            serving_stale: Gauge::with_opts(
+                Opts::new("serving_stale", "Whether the metrics are from the last successful collection, served while collections fail within the stale tolerance")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
+            )
+            .expect("Failed to create serving_stale metric"),
+            snapshot_age: GaugeVec::new(
+                Opts::new("snapshot_age_seconds", "Seconds since the last successful collection")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
+                &[],
+            )
+            .expect("Failed to create snapshot_age metric"),
+            collection_duration: Histogram::with_opts(
+                HistogramOpts::new("collection_duration_seconds", "Time taken by each collection in seconds")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone())
+                    .buckets(collection_duration_buckets),
+            )
+            .expect("Failed to create collection_duration metric"),
+            last_collection_error_code: GaugeVec::new(
+                Opts::new("last_collection_error_code", "Error that failed the last collection, as a code label (1 for the current code, absent after a successful collection)")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
+                &["code"],
+            )
+            .expect("Failed to create last_collection_error_code metric"),
+            duplicate_label_collisions: Counter::with_opts(
+                Opts::new("duplicate_label_collisions_total", "Number of devices exported under their UUID as minor number because their minor number collided with another device's")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
+            )
+            .expect("Failed to create duplicate_label_collisions metric"),
+            nvml_reinit: Counter::with_opts(
+                Opts::new("nvml_reinit_total", "Number of times NVML was re-initialized after repeated collection failures")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
+            )
+            .expect("Failed to create nvml_reinit metric"),
+            smi_parse_errors: Counter::with_opts(
+                Opts::new("smi_parse_errors_total", "Number of nvidia-smi output rows skipped by the fallback collector because they couldn't be parsed")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
+            )
+            .expect("Failed to create smi_parse_errors metric"),
+            sample_buffer_evictions: Counter::with_opts(
+                Opts::new("sample_buffer_evictions_total", "Number of power usage samples evicted before leaving the summary window because a GPU's sample buffer was full")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
+            )
+            .expect("Failed to create sample_buffer_evictions metric"),
+            nvml_calls_per_scrape: Gauge::with_opts(
+                Opts::new("nvml_calls_per_scrape", "Number of NVML calls made by the last successful collection")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
+            )
+            .expect("Failed to create nvml_calls_per_scrape metric"),
+            nvml_call_results: CounterVec::new(
+                Opts::new("nvml_call_result_total", "Number of NVML calls made by collections, by query and result")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
+                &["call", "result"],
+            )
+            .expect("Failed to create nvml_call_results metric"),
+            nvml_session_uptime: GaugeVec::new(
+                Opts::new("nvml_session_uptime_seconds", "Seconds since the exporter initialized NVML, first or after a re-initialization")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
+                &["source"],
+            )
+            .expect("Failed to create nvml_session_uptime metric"),
+            nvml_session_start: Arc::new(Mutex::new(None)),
+            remote_up: GaugeVec::new(
+                Opts::new("remote_up", "Whether the last collection from the remote exporter succeeded (1 = up)")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
+                &["host"],
+            )
+            .expect("Failed to create remote_up metric"),
+            collection_interval_drift: GaugeVec::new(
+                Opts::new("collection_interval_drift_seconds", "Time between the last two background collections minus the configured interval")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
+                &[],
+            )
+            .expect("Failed to create collection_interval_drift metric"),
             info: GaugeVec::new(
-                Opts::new("driver_info", "NVML Info").namespace(NAMESPACE),
+                Opts::new("driver_info", "NVML Info")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
                 &["version"],
             )
             .expect("Failed to create driver_info metric"),
             device_count: Gauge::with_opts(
                 Opts::new("device_count", "Count of found nvidia devices")
-                    .namespace(NAMESPACE),
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
             )
             .expect("Failed to create device_count metric"),
+            visible_devices_restricted: Gauge::with_opts(
+                Opts::new("visible_devices_restricted", "Whether the GPUs visible to the exporter are limited by NVIDIA_VISIBLE_DEVICES or the devices cgroup (1 = restricted)")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
+            )
+            .expect("Failed to create visible_devices_restricted metric"),
+            host_device_count: GaugeVec::new(
+                Opts::new("host_device_count", "Count of nvidia devices on the host, visible to the exporter or not")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
+                &[],
+            )
+            .expect("Failed to create host_device_count metric"),
+            device_count_mismatch: GaugeVec::new(
+                Opts::new("device_count_mismatch", "Whether the count of found nvidia devices differs from --expected-gpu-count")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
+                &[],
+            )
+            .expect("Failed to create device_count_mismatch metric"),
+            device_collection_timed_out: GaugeVec::new(
+                Opts::new("device_collection_timed_out", "Whether the device was left out of the last collection for exceeding --per-device-timeout")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
+                &["index"],
+            )
+            .expect("Failed to create device_collection_timed_out metric"),
+            node_power_usage: GaugeVec::new(
+                Opts::new("node_power_usage_milliwatts", "Power usage summed over all devices in milliwatts")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
+                &[],
+            )
+            .expect("Failed to create node_power_usage metric"),
+            node_memory_used: GaugeVec::new(
+                Opts::new("node_memory_used_bytes", "Used memory summed over all devices in bytes")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
+                &[],
+            )
+            .expect("Failed to create node_memory_used metric"),
+            node_utilization_gpu_average: GaugeVec::new(
+                Opts::new(
+                    format!("node_utilization_gpu_average{}", utilization_suffix),
+                    "GPU utilization averaged over 10s, averaged over all devices",
+                )
+                .namespace(NAMESPACE)
+                .const_labels(const_labels.clone()),
+                &[],
+            )
+            .expect("Failed to create node_utilization_gpu_average metric"),
             device_info: GaugeVec::new(
-                Opts::new("info", "Info as reported by the device").namespace(NAMESPACE),
-                &["index", "minor", "uuid", "name"],
+                Opts::new("info", "Info as reported by the device")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
+                &["index", "minor", "uuid", "name", "pci_bus_id"],
             )
             .expect("Failed to create info metric"),
             temperatures: GaugeVec::new(
                 Opts::new("temperatures", "Temperature as reported by the device")
-                    .namespace(NAMESPACE),
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
                 &["minor"],
             )
             .expect("Failed to create temperatures metric"),
+            memory_temperature_margin: GaugeVec::new(
+                Opts::new("memory_temperature_margin_celsius", "Degrees Celsius the memory temperature is below its slowdown threshold")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
+                &["minor"],
+            )
+            .expect("Failed to create memory_temperature_margin metric"),
+            target_temperature: GaugeVec::new(
+                Opts::new("gpu_target_temperature_celsius", "Temperature the fans are set to hold the GPU at in Celsius")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
+                &["minor"],
+            )
+            .expect("Failed to create gpu_target_temperature metric"),
             power_usage: GaugeVec::new(
                 Opts::new("power_usage", "Power usage as reported by the device")
-                    .namespace(NAMESPACE),
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
                 &["minor"],
             )
             .expect("Failed to create power_usage metric"),
@@ -95,604 +780,2320 @@ impl Exporter {
                     "power_usage_average",
                     "Power usage as reported by the device averaged over 10s",
                 )
-                .namespace(NAMESPACE),
+                .namespace(NAMESPACE)
+                .const_labels(const_labels.clone()),
                 &["minor"],
             )
             .expect("Failed to create power_usage_average metric"),
+            power_usage_peak: GaugeVec::new(
+                Opts::new("power_usage_peak_milliwatts", "Highest power usage sampled by the driver since the previous collection in milliwatts")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
+                &["minor"],
+            )
+            .expect("Failed to create power_usage_peak metric"),
+            power_efficiency: GaugeVec::new(
+                Opts::new("power_efficiency", "GPU utilization percent per watt of power used")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
+                &["minor"],
+            )
+            .expect("Failed to create power_efficiency metric"),
             fan_speed: GaugeVec::new(
                 Opts::new("fanspeed", "Fan speed as reported by the device")
-                    .namespace(NAMESPACE),
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
                 &["minor"],
             )
             .expect("Failed to create fanspeed metric"),
+            fan_speed_percent: GaugeVec::new(
+                Opts::new("fan_speed_percent", "Speed of each fan in percent as reported by the device")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
+                &["minor", "fan"],
+            )
+            .expect("Failed to create fan_speed_percent metric"),
+            fan_speed_max_percent: GaugeVec::new(
+                Opts::new("fan_speed_max_percent", "Speed of the fastest fan in percent")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
+                &["minor"],
+            )
+            .expect("Failed to create fan_speed_max_percent metric"),
+            fan_speed_rpm: GaugeVec::new(
+                Opts::new("fan_speed_rpm", "Fan speed in RPM as reported by the device")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
+                &["minor", "fan"],
+            )
+            .expect("Failed to create fan_speed_rpm metric"),
             memory_total: GaugeVec::new(
-                Opts::new("memory_total", "Total memory as reported by the device")
-                    .namespace(NAMESPACE),
+                Opts::new(format!("memory_total{}", memory_suffix), "Total memory as reported by the device")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
                 &["minor"],
             )
             .expect("Failed to create memory_total metric"),
             memory_used: GaugeVec::new(
-                Opts::new("memory_used", "Used memory as reported by the device")
-                    .namespace(NAMESPACE),
+                Opts::new(format!("memory_used{}", memory_suffix), "Used memory as reported by the device")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
                 &["minor"],
             )
             .expect("Failed to create memory_used metric"),
+            memory_used_peak: GaugeVec::new(
+                Opts::new("memory_used_peak_bytes", "Highest used memory seen since the exporter started in bytes")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
+                &["minor"],
+            )
+            .expect("Failed to create memory_used_peak metric"),
+            memory_reserved: GaugeVec::new(
+                Opts::new("memory_reserved_bytes", "Memory reserved by the driver and firmware in bytes, which is neither used nor free")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
+                &["minor"],
+            )
+            .expect("Failed to create memory_reserved metric"),
+            memory_bandwidth: GaugeVec::new(
+                Opts::new(
+                    "memory_bandwidth_bytes_per_second",
+                    "Theoretical memory bandwidth at the current memory clock in bytes/s \
+                     (memory clock x 2 transfers per clock x bus width / 8). NVML reports \
+                     memory clocks such that the double data rate applies to both GDDR and HBM",
+                )
+                .namespace(NAMESPACE)
+                .const_labels(const_labels.clone()),
+                &["minor"],
+            )
+            .expect("Failed to create memory_bandwidth metric"),
             utilization_memory: GaugeVec::new(
-                Opts::new("utilization_memory", "Memory Utilization as reported by the device")
-                    .namespace(NAMESPACE),
+                Opts::new(format!("utilization_memory{}", utilization_suffix), "Memory Utilization as reported by the device")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
                 &["minor"],
             )
             .expect("Failed to create utilization_memory metric"),
             utilization_gpu: GaugeVec::new(
-                Opts::new("utilization_gpu", "GPU utilization as reported by the device")
-                    .namespace(NAMESPACE),
+                Opts::new(format!("utilization_gpu{}", utilization_suffix), "GPU utilization as reported by the device")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
                 &["minor"],
             )
             .expect("Failed to create utilization_gpu metric"),
             utilization_gpu_average: GaugeVec::new(
                 Opts::new(
-                    "utilization_gpu_average",
+                    format!("utilization_gpu_average{}", utilization_suffix),
                     "GPU utilization as reported by the device averaged over 10s",
                 )
-                .namespace(NAMESPACE),
+                .namespace(NAMESPACE)
+                .const_labels(const_labels.clone()),
                 &["minor"],
             )
             .expect("Failed to create utilization_gpu_average metric"),
+            sm_active: GaugeVec::new(
+                Opts::new("sm_active_ratio", "Fraction of time at least one warp was active on an SM, averaged over the SMs, from DCGM")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
+                &["minor"],
+            )
+            .expect("Failed to create sm_active metric"),
+            tensor_active: GaugeVec::new(
+                Opts::new("tensor_active_ratio", "Fraction of cycles the tensor pipes were active, from DCGM")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
+                &["minor"],
+            )
+            .expect("Failed to create tensor_active metric"),
+            dram_active: GaugeVec::new(
+                Opts::new("dram_active_ratio", "Fraction of cycles the device memory interface was busy, from DCGM")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
+                &["minor"],
+            )
+            .expect("Failed to create dram_active metric"),
+            gpu_num_cores: GaugeVec::new(
+                Opts::new("gpu_num_cores", "Number of CUDA cores")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
+                &["minor"],
+            )
+            .expect("Failed to create gpu_num_cores metric"),
+            gpu_num_sm: GaugeVec::new(
+                Opts::new("gpu_num_sm", "Number of streaming multiprocessors")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
+                &["minor"],
+            )
+            .expect("Failed to create gpu_num_sm metric"),
             // Clock speeds in MHz
             clock_graphics: GaugeVec::new(
                 Opts::new("clock_graphics_mhz", "Graphics clock speed in MHz")
-                    .namespace(NAMESPACE),
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
                 &["minor"],
             )
             .expect("Failed to create clock_graphics metric"),
             clock_sm: GaugeVec::new(
                 Opts::new("clock_sm_mhz", "SM clock speed in MHz")
-                    .namespace(NAMESPACE),
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
                 &["minor"],
             )
             .expect("Failed to create clock_sm metric"),
             clock_memory: GaugeVec::new(
                 Opts::new("clock_memory_mhz", "Memory clock speed in MHz")
-                    .namespace(NAMESPACE),
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
                 &["minor"],
             )
             .expect("Failed to create clock_memory metric"),
             clock_graphics_max: GaugeVec::new(
                 Opts::new("clock_graphics_max_mhz", "Maximum graphics clock speed in MHz")
-                    .namespace(NAMESPACE),
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
                 &["minor"],
             )
             .expect("Failed to create clock_graphics_max metric"),
             clock_sm_max: GaugeVec::new(
                 Opts::new("clock_sm_max_mhz", "Maximum SM clock speed in MHz")
-                    .namespace(NAMESPACE),
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
                 &["minor"],
             )
             .expect("Failed to create clock_sm_max metric"),
             clock_memory_max: GaugeVec::new(
                 Opts::new("clock_memory_max_mhz", "Maximum memory clock speed in MHz")
-                    .namespace(NAMESPACE),
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
                 &["minor"],
             )
             .expect("Failed to create clock_memory_max metric"),
+            clock_graphics_customer_boost_max: GaugeVec::new(
+                Opts::new("clock_graphics_customer_boost_max_mhz", "Customer-defined maximum graphics boost clock in MHz")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
+                &["minor"],
+            )
+            .expect("Failed to create clock_graphics_customer_boost_max metric"),
+            clock_user_locked: GaugeVec::new(
+                Opts::new("clock_user_locked", "Whether the driver reports the clocks held by a user-defined clock setting, as with nvidia-smi -lgc or -ac, regardless of other throttle reasons (1 = locked)")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
+                &["minor"],
+            )
+            .expect("Failed to create clock_user_locked metric"),
+            app_clock_shortfall: GaugeVec::new(
+                Opts::new("app_clock_shortfall_mhz", "How far the graphics clock is below the application clock set with nvidia-smi -ac in MHz")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
+                &["minor"],
+            )
+            .expect("Failed to create app_clock_shortfall metric"),
+            throttling_supported: GaugeVec::new(
+                Opts::new("throttling_supported", "Whether the device can throttle its clocks for a reason other than being idle (1 = supported)")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
+                &["minor"],
+            )
+            .expect("Failed to create throttling_supported metric"),
+            active_clock_limit: GaugeVec::new(
+                Opts::new("active_clock_limit", "Highest-priority reason currently limiting the clocks, as one series per reason (1 = binding)")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
+                &["minor", "reason"],
+            )
+            .expect("Failed to create active_clock_limit metric"),
+            throttle_reason_seconds: CounterVec::new(
+                Opts::new("throttle_reason_seconds_total", "Time spent with each clock throttle reason active in seconds, sampled in background mode")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
+                &["minor", "reason"],
+            )
+            .expect("Failed to create throttle_reason_seconds metric"),
+            memory_thermal_throttle_events: CounterVec::new(
+                Opts::new("memory_thermal_throttle_events_total", "Times the thermal slowdown covering the memory temperature started, sampled in background mode")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
+                &["minor"],
+            )
+            .expect("Failed to create memory_thermal_throttle_events metric"),
+            throttle_timer: Arc::new(Mutex::new(ThrottleTimer::default())),
+            clock_graphics_sampled_min: GaugeVec::new(
+                Opts::new("clock_graphics_sampled_min_mhz", "Lowest graphics clock sampled since the previous collection in MHz, in background mode")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
+                &["minor"],
+            )
+            .expect("Failed to create clock_graphics_sampled_min metric"),
+            clock_graphics_sampled_max: GaugeVec::new(
+                Opts::new("clock_graphics_sampled_max_mhz", "Highest graphics clock sampled since the previous collection in MHz, in background mode")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
+                &["minor"],
+            )
+            .expect("Failed to create clock_graphics_sampled_max metric"),
+            clock_graphics_range: Arc::new(Mutex::new(BTreeMap::new())),
+            pstate_max_clock_graphics: GaugeVec::new(
+                Opts::new("pstate_max_clock_graphics_mhz", "Maximum graphics clock of each supported P-State in MHz")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
+                &["minor", "pstate"],
+            )
+            .expect("Failed to create pstate_max_clock_graphics metric"),
             // Power limits in milliwatts
             power_limit: GaugeVec::new(
                 Opts::new("power_limit_milliwatts", "Power management limit in milliwatts")
-                    .namespace(NAMESPACE),
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
                 &["minor"],
             )
             .expect("Failed to create power_limit metric"),
             power_limit_default: GaugeVec::new(
                 Opts::new("power_limit_default_milliwatts", "Default power management limit in milliwatts")
-                    .namespace(NAMESPACE),
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
                 &["minor"],
             )
             .expect("Failed to create power_limit_default metric"),
+            power_limit_enforced: GaugeVec::new(
+                Opts::new("power_limit_enforced_milliwatts", "Power limit the device enforces in milliwatts, the lowest of the limits in effect")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
+                &["minor"],
+            )
+            .expect("Failed to create power_limit_enforced metric"),
+            // Energy consumption in millijoules since driver load
+            energy_consumption: CounterVec::new(
+                Opts::new("energy_consumption_millijoules_total", "Total energy consumed since the driver was loaded in millijoules")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
+                &["minor"],
+            )
+            .expect("Failed to create energy_consumption metric"),
             // Performance state (P0-P15)
             performance_state: GaugeVec::new(
                 Opts::new("performance_state", "Current performance state (P-State: 0-15, lower is better)")
-                    .namespace(NAMESPACE),
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
                 &["minor"],
             )
             .expect("Failed to create performance_state metric"),
+            performance_state_current: GaugeVec::new(
+                Opts::new("performance_state_current", "Whether the device is in the given P-State (1 = active state)")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
+                &["minor", "pstate"],
+            )
+            .expect("Failed to create performance_state_current metric"),
+            // GPU operation mode (GOM)
+            gpu_operation_mode: GaugeVec::new(
+                Opts::new("gpu_operation_mode", "Current GPU operation mode (0 = All On, 1 = Compute, 2 = Low Double Precision)")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
+                &["minor"],
+            )
+            .expect("Failed to create gpu_operation_mode metric"),
+            gpu_operation_mode_pending: GaugeVec::new(
+                Opts::new("gpu_operation_mode_pending", "GPU operation mode applied after the next reboot (0 = All On, 1 = Compute, 2 = Low Double Precision)")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
+                &["minor"],
+            )
+            .expect("Failed to create gpu_operation_mode_pending metric"),
+            display_active: GaugeVec::new(
+                Opts::new("display_active", "Whether a display is initialized on the device (1 = active)")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
+                &["minor"],
+            )
+            .expect("Failed to create display_active metric"),
+            display_connected: GaugeVec::new(
+                Opts::new("display_connected", "Whether a physical display is connected to the device (1 = connected)")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
+                &["minor"],
+            )
+            .expect("Failed to create display_connected metric"),
             // PCIe metrics
             pcie_link_gen: GaugeVec::new(
                 Opts::new("pcie_link_generation", "PCIe link generation")
-                    .namespace(NAMESPACE),
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
                 &["minor"],
             )
             .expect("Failed to create pcie_link_gen metric"),
             pcie_link_width: GaugeVec::new(
                 Opts::new("pcie_link_width", "PCIe link width")
-                    .namespace(NAMESPACE),
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
                 &["minor"],
             )
             .expect("Failed to create pcie_link_width metric"),
+            pcie_link_gen_max: GaugeVec::new(
+                Opts::new("pcie_max_link_generation", "Maximum PCIe link generation supported by the device and system")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
+                &["minor"],
+            )
+            .expect("Failed to create pcie_link_gen_max metric"),
+            pcie_link_width_max: GaugeVec::new(
+                Opts::new("pcie_max_link_width", "Maximum PCIe link width supported by the device and system")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
+                &["minor"],
+            )
+            .expect("Failed to create pcie_link_width_max metric"),
+            pcie_link_speed: GaugeVec::new(
+                Opts::new("pcie_link_speed_gts", "Current PCIe transfer rate per lane in GT/s")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
+                &["minor"],
+            )
+            .expect("Failed to create pcie_link_speed metric"),
             pcie_tx_throughput: GaugeVec::new(
                 Opts::new("pcie_tx_throughput_kb", "PCIe transmit throughput in KB/s")
-                    .namespace(NAMESPACE),
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
                 &["minor"],
             )
             .expect("Failed to create pcie_tx_throughput metric"),
             pcie_rx_throughput: GaugeVec::new(
                 Opts::new("pcie_rx_throughput_kb", "PCIe receive throughput in KB/s")
-                    .namespace(NAMESPACE),
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
                 &["minor"],
             )
             .expect("Failed to create pcie_rx_throughput metric"),
             // Encoder/Decoder utilization (0-100%)
             encoder_utilization: GaugeVec::new(
-                Opts::new("encoder_utilization", "Encoder utilization percentage (0-100)")
-                    .namespace(NAMESPACE),
+                Opts::new(format!("encoder_utilization{}", utilization_suffix), "Encoder utilization as reported by the device")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
                 &["minor"],
             )
             .expect("Failed to create encoder_utilization metric"),
             decoder_utilization: GaugeVec::new(
-                Opts::new("decoder_utilization", "Decoder utilization percentage (0-100)")
-                    .namespace(NAMESPACE),
+                Opts::new(format!("decoder_utilization{}", utilization_suffix), "Decoder utilization as reported by the device")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
                 &["minor"],
             )
             .expect("Failed to create decoder_utilization metric"),
+            jpeg_utilization: GaugeVec::new(
+                Opts::new(format!("jpeg_utilization{}", utilization_suffix), "JPEG decoder utilization as reported by the device")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
+                &["minor"],
+            )
+            .expect("Failed to create jpeg_utilization metric"),
+            ofa_utilization: GaugeVec::new(
+                Opts::new(format!("ofa_utilization{}", utilization_suffix), "Optical Flow Accelerator utilization as reported by the device")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
+                &["minor"],
+            )
+            .expect("Failed to create ofa_utilization metric"),
+            encoder_capacity: GaugeVec::new(
+                Opts::new("encoder_capacity", "Remaining encoder capacity as a percentage of the model limit (0-100)")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
+                &["minor", "codec"],
+            )
+            .expect("Failed to create encoder_capacity metric"),
+            encoder_sessions: GaugeVec::new(
+                Opts::new("encoder_sessions", "Number of active encoder sessions")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
+                &["minor"],
+            )
+            .expect("Failed to create encoder_sessions metric"),
+            encoder_session_fps: GaugeVec::new(
+                Opts::new("encoder_session_fps", "Average encoded frames per second of the active encoder sessions")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
+                &["minor", "codec", "resolution"],
+            )
+            .expect("Failed to create encoder_session_fps metric"),
+            encoder_session_latency: GaugeVec::new(
+                Opts::new("encoder_session_latency_microseconds", "Worst average encode latency of the active encoder sessions in microseconds")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
+                &["minor", "codec", "resolution"],
+            )
+            .expect("Failed to create encoder_session_latency metric"),
             // ECC errors
             ecc_errors_corrected: GaugeVec::new(
                 Opts::new("ecc_errors_corrected_total", "Total corrected ECC errors")
-                    .namespace(NAMESPACE),
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
                 &["minor"],
             )
             .expect("Failed to create ecc_errors_corrected metric"),
             ecc_errors_uncorrected: GaugeVec::new(
                 Opts::new("ecc_errors_uncorrected_total", "Total uncorrected ECC errors")
-                    .namespace(NAMESPACE),
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
                 &["minor"],
             )
             .expect("Failed to create ecc_errors_uncorrected metric"),
+            row_remap_availability: GaugeVec::new(
+                Opts::new("row_remap_availability", "Memory banks by the spare rows they have left for remapping (max, high, partial, low, none)")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
+                &["minor", "bucket"],
+            )
+            .expect("Failed to create row_remap_availability metric"),
+            // Health
+            recommended_recovery_action: GaugeVec::new(
+                Opts::new("recommended_recovery_action", "Recovery action the driver recommends for the GPU, as one series per action (1 = recommended)")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
+                &["minor", "action"],
+            )
+            .expect("Failed to create recommended_recovery_action metric"),
+            reset_required: GaugeVec::new(
+                Opts::new("reset_required", "Whether the GPU needs a reset before it can be used again (1 = reset required)")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
+                &["minor"],
+            )
+            .expect("Failed to create reset_required metric"),
+            retired_pages_pending: GaugeVec::new(
+                Opts::new("retired_pages_pending", "Whether retired memory pages are waiting for a reboot to take effect (1 = pending)")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
+                &["minor"],
+            )
+            .expect("Failed to create retired_pages_pending metric"),
+            ecc_sram_threshold_exceeded: GaugeVec::new(
+                Opts::new("ecc_sram_threshold_exceeded", "Whether the SRAM uncorrectable ECC errors crossed the threshold that calls for action (1 = exceeded)")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
+                &["minor"],
+            )
+            .expect("Failed to create ecc_sram_threshold_exceeded metric"),
+            inforom_valid: GaugeVec::new(
+                Opts::new("inforom_valid", "Whether the infoROM passes its checksum validation (0 = corrupted)")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
+                &["minor"],
+            )
+            .expect("Failed to create inforom_valid metric"),
+            inforom_info: GaugeVec::new(
+                Opts::new("inforom_info", "Versions of the infoROM image and objects (always 1)")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
+                &["minor", "image_version", "oem_version", "ecc_version", "power_version"],
+            )
+            .expect("Failed to create inforom_info metric"),
+            device_lost: GaugeVec::new(
+                Opts::new("device_lost", "Whether most queries to the GPU failed, as happens when it falls off the bus (1 = lost)")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
+                &["minor"],
+            )
+            .expect("Failed to create device_lost metric"),
+            // Topology
+            numa_node: GaugeVec::new(
+                Opts::new("gpu_numa_node", "NUMA node closest to the GPU")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
+                &["minor"],
+            )
+            .expect("Failed to create gpu_numa_node metric"),
+            cpu_affinity: GaugeVec::new(
+                Opts::new("gpu_cpu_affinity", "CPUs with an affinity to the GPU (always 1)")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
+                &["minor", "cpu"],
+            )
+            .expect("Failed to create gpu_cpu_affinity metric"),
+            raw_field: GaugeVec::new(
+                Opts::new("raw_field", "Value of an NVML field requested with --extra-field-ids, by field id")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
+                &["minor", "field_id"],
+            )
+            .expect("Failed to create raw_field metric"),
             // Process counts
             compute_processes: GaugeVec::new(
                 Opts::new("compute_processes", "Number of compute processes running")
-                    .namespace(NAMESPACE),
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
                 &["minor"],
             )
             .expect("Failed to create compute_processes metric"),
             graphics_processes: GaugeVec::new(
                 Opts::new("graphics_processes", "Number of graphics processes running")
-                    .namespace(NAMESPACE),
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
                 &["minor"],
             )
             .expect("Failed to create graphics_processes metric"),
+            compute_processes_memory: GaugeVec::new(
+                Opts::new("compute_processes_memory_bytes", "GPU memory used by the running compute processes in bytes")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
+                &["minor"],
+            )
+            .expect("Failed to create compute_processes_memory metric"),
+            graphics_processes_memory: GaugeVec::new(
+                Opts::new("graphics_processes_memory_bytes", "GPU memory used by the running graphics processes in bytes")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
+                &["minor"],
+            )
+            .expect("Failed to create graphics_processes_memory metric"),
+            process_memory_used: GaugeVec::new(
+                Opts::new("process_memory_used_bytes", "GPU memory used by a running process in bytes")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
+                &process_labels,
+            )
+            .expect("Failed to create process_memory_used metric"),
+            // Same family as process_memory_used, for processes on a MIG GPU instance
+            process_memory_used_mig: GaugeVec::new(
+                Opts::new("process_memory_used_bytes", "GPU memory used by a running process in bytes")
+                    .namespace(NAMESPACE)
+                    .const_labels(const_labels.clone()),
+                &mig_process_labels,
+            )
+            .expect("Failed to create process_memory_used_mig metric"),
+            proc_root: PathBuf::from("/proc"),
+            sys_root: PathBuf::from("/sys"),
+            power_samples: Arc::new(Mutex::new(BTreeMap::new())),
+            full_sample_buffers: Arc::new(Mutex::new(BTreeSet::new())),
+            last_metrics: Arc::new(Mutex::new(None)),
+            last_success: Arc::new(Mutex::new(None)),
+            collection_generation: Arc::new(AtomicU64::new(0)),
+            collected: Arc::new(AtomicBool::new(false)),
+            last_collection: Arc::new(Mutex::new(None)),
+            emitted: Arc::new(Mutex::new(HashMap::new())),
+            device_indices: Arc::new(Mutex::new(BTreeMap::new())),
+            lost_minors: Arc::new(Mutex::new(BTreeSet::new())),
+            colliding_minors: Arc::new(Mutex::new(BTreeSet::new())),
+            device_hosts: Arc::new(Mutex::new(BTreeMap::new())),
+            device_uuids: Arc::new(Mutex::new(BTreeMap::new())),
+            memory_peaks: Arc::new(Mutex::new(BTreeMap::new())),
         }
     }
 
     pub fn gather(&self) -> Vec<MetricFamily> {
+        // Failures are reported through the up metric
+        let _ = self.refresh();
+        self.families()
+    }
+
+    /// Gather metrics for the device at `index` only, alongside the core metrics.
+    /// Returns `None` if no device with that index was collected.
+    pub fn gather_device(&self, index: u32) -> Option<Vec<MetricFamily>> {
+        match self.refresh() {
+            Ok(data) => self.device_families(&data.devices, index),
+            Err(_) if self.serving_stale.get() == 1.0 => self.last_device_families(index),
+            Err(_) => None,
+        }
+    }
+
+    /// Export how far the time between two background collections overran (or fell
+    /// short of) the interval they were scheduled with
+    pub fn set_collection_interval_drift(&self, actual: Duration, configured: Duration) {
+        self.collection_interval_drift
+            .with_label_values(&[])
+            .set(actual.as_secs_f64() - configured.as_secs_f64());
+    }
+
+    /// Sample every device between collections: add the time since the previous
+    /// sample to the throttle reasons that were active then, and widen the range
+    /// of graphics clocks seen since the previous collection
+    pub fn sample_devices(&self) {
+        match self.collector.sample() {
+            Ok(samples) => self.record_samples(&samples, Instant::now()),
+            Err(e) => {
+                debug!("Failed to sample devices: {:#}", e);
+                // Don't attribute the gap to whatever was active before it
+                self.throttle_timer.lock().unwrap_or_else(|e| e.into_inner()).reset();
+            }
+        }
+    }
+
+    fn record_samples(&self, samples: &[DeviceSample], at: Instant) {
+        let mut timer = self.throttle_timer.lock().unwrap_or_else(|e| e.into_inner());
+        if samples.is_empty() {
+            // NVML isn't initialized yet, or lost its handle
+            timer.reset();
+        }
+        for sample in samples {
+            let minor = &sample.minor_number;
+            if let Some(mask) = sample.throttle_reasons {
+                if timer.rising_edges(minor, mask) & throttle::SW_THERMAL_SLOWDOWN != 0 {
+                    self.memory_thermal_throttle_events.with_label_values(&[minor]).inc();
+                }
+                for (reason, elapsed) in timer.record(minor, mask, at) {
+                    self.throttle_reason_seconds
+                        .with_label_values(&[minor, reason])
+                        .inc_by(elapsed.as_secs_f64());
+                }
+            }
+            if let Some(clock) = sample.clock_graphics {
+                self.widen_clock_graphics_range(minor, clock);
+            }
+        }
+    }
+
+    fn widen_clock_graphics_range(&self, minor: &str, clock: f64) {
+        let mut ranges = self.clock_graphics_range.lock().unwrap_or_else(|e| e.into_inner());
+        let (min, max) = ranges.entry(minor.to_string()).or_insert((clock, clock));
+        *min = min.min(clock);
+        *max = max.max(clock);
+    }
+
+    /// Export the graphics clock range sampled since the previous collection,
+    /// including the clock it read, and start a new one. Devices the sampler
+    /// didn't see, as outside background mode, have no series.
+    fn set_clock_graphics_range(&self, devices: &[Device]) {
+        let mut ranges = self.clock_graphics_range.lock().unwrap_or_else(|e| e.into_inner());
+        self.clock_graphics_sampled_min.reset();
+        self.clock_graphics_sampled_max.reset();
+        for device in devices {
+            let Some((mut min, mut max)) = ranges.remove(&device.minor_number) else {
+                continue;
+            };
+            if let Some(clock) = device.clock_graphics {
+                min = min.min(clock);
+                max = max.max(clock);
+            }
+            self.clock_graphics_sampled_min.with_label_values(&[&device.minor_number]).set(min);
+            self.clock_graphics_sampled_max.with_label_values(&[&device.minor_number]).set(max);
+        }
+        ranges.clear();
+    }
+
+    /// In delta mode, leave out the per-device series that are identical to the last
+    /// scrape, along with families left empty. Series without a minor label (`up`,
+    /// `device_count`, ...) are always kept. Returns the families untouched otherwise.
+    pub fn omit_unchanged(&self, mut mfs: Vec<MetricFamily>) -> Vec<MetricFamily> {
+        if !self.config.delta_mode {
+            return mfs;
+        }
+        let mut emitted = self.emitted.lock().unwrap_or_else(|e| e.into_inner());
+        // Rebuilt from this scrape, so a series that goes away and comes back is sent again
+        let mut current = HashMap::new();
+        for mf in &mut mfs {
+            let name = mf.get_name().to_string();
+            let metrics = mf.take_metric().into_iter().filter(|metric| {
+                let labels: Vec<(String, String)> = metric
+                    .get_label()
+                    .iter()
+                    .map(|l| (l.get_name().to_string(), l.get_value().to_string()))
+                    .collect();
+                let core = labels.iter().all(|(label, _)| label != "minor");
+                let key = (name.clone(), labels);
+                let changed = emitted.get(&key) != Some(metric);
+                current.insert(key, metric.clone());
+                core || changed
+            });
+            mf.set_metric(metrics.collect());
+        }
+        *emitted = current;
+        mfs.retain(|mf| self.config.always_emit_metric_types || !mf.get_metric().is_empty());
+        mfs
+    }
+
+    /// When the current metrics were collected, `None` before the first successful collection
+    pub fn last_collection_time(&self) -> Option<SystemTime> {
+        *self.last_collection.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Data of the last collection, `None` if it failed or nothing was collected yet
+    pub fn last_metrics(&self) -> Option<Metrics> {
+        self.last_metrics.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// Number of devices collections are expected to find, if configured
+    pub fn expected_device_count(&self) -> Option<usize> {
+        self.config.expected_device_count
+    }
+
+    /// Devices from the last collection, `None` if it failed or nothing was collected yet
+    pub fn last_devices(&self) -> Option<Vec<Device>> {
+        self.last_metrics().map(|metrics| metrics.devices)
+    }
+
+    /// Whether a collection has finished, failed or timed out yet
+    pub fn has_collected(&self) -> bool {
+        self.collected.load(Ordering::SeqCst)
+    }
+
+    /// Like `gather_device`, but from the last collection instead of a new one
+    pub fn last_device_families(&self, index: u32) -> Option<Vec<MetricFamily>> {
+        let devices = self.last_devices()?;
+        self.device_families(&devices, index)
+    }
+
+    fn device_families(&self, devices: &[Device], index: u32) -> Option<Vec<MetricFamily>> {
+        let index = index.to_string();
+        let device = devices.iter().find(|d| d.index == index)?;
+
+        let mut mfs: Vec<MetricFamily> = self
+            .keyed_families()
+            .into_iter()
+            .filter_map(|mut mf| {
+                // Core metrics carry no minor label and are always kept
+                let metrics = mf.take_metric().into_iter().filter(|m| {
+                    m.get_label()
+                        .iter()
+                        .find(|l| l.get_name() == "minor")
+                        .is_none_or(|l| l.get_value() == self.minor_label(&device.minor_number))
+                });
+                mf.set_metric(metrics.collect());
+                (!mf.get_metric().is_empty()).then_some(mf)
+            })
+            .collect();
+        if !self.config.remote_endpoints.is_empty() {
+            self.add_host_label(&mut mfs);
+        }
+        Some(mfs)
+    }
+
+    /// Run a collection and update all metrics, returning the collected data
+    pub fn refresh(&self) -> Result<Metrics> {
         debug!("Starting metrics collection...");
-        match collect_metrics() {
-            Ok(data) => {
+        let generation = self.collection_generation.load(Ordering::SeqCst);
+        let timer = self.collection_duration.start_timer();
+        let result = self.collector.collect();
+        timer.observe_duration();
+        if self.collection_generation.load(Ordering::SeqCst) != generation {
+            warn!("Discarding a collection that finished after timing out");
+            return Err(anyhow::anyhow!("Metrics collection finished after timing out"));
+        }
+        self.collected.store(true, Ordering::SeqCst);
+        let reinits = self.nvml_reinit_count.load(Ordering::Relaxed) as f64;
+        self.nvml_reinit.inc_by(reinits - self.nvml_reinit.get());
+        let parse_errors = self.smi_parse_error_count.load(Ordering::Relaxed) as f64;
+        self.smi_parse_errors.inc_by(parse_errors - self.smi_parse_errors.get());
+
+        match result {
+            Ok(mut data) => {
+                if self.config.device_order == DeviceOrder::Pci {
+                    sort_by_pci_bus_id(&mut data.devices);
+                }
+                // Matched by UUID, so before the UUIDs are redacted
+                if let Some(endpoint) = &self.config.dcgm_endpoint {
+                    dcgm::add_fields(endpoint, &mut data.devices);
+                }
+                if self.config.redact_uuid {
+                    for device in &mut data.devices {
+                        device.uuid = redact_uuid(&self.config.uuid_salt, &device.uuid);
+                    }
+                }
+                let colliding = disambiguate_minor_numbers(&mut data.devices);
+                let mut previous = self.colliding_minors.lock().unwrap_or_else(|e| e.into_inner());
+                let new_collisions = colliding.difference(&previous).count();
+                self.duplicate_label_collisions.inc_by(new_collisions as f64);
+                *previous = colliding;
+                drop(previous);
                 debug!("Successfully collected metrics: version={}, device_count={}", data.version, data.devices.len());
                 self.up.set(1.0);
+                self.last_scrape_success.set(1.0);
+                self.nvml_permission_denied.set(0.0);
+                self.serving_stale.set(0.0);
+                self.snapshot_age.with_label_values(&[]).set(0.0);
+                *self.last_success.lock().unwrap_or_else(|e| e.into_inner()) = Some(Instant::now());
+                *self.last_collection.lock().unwrap_or_else(|e| e.into_inner()) = Some(SystemTime::now());
+                self.last_collection_error_code.reset();
+                self.nvml_calls_per_scrape.set(data.nvml_calls as f64);
+                self.count_nvml_call_results(&data.nvml_call_results);
+                self.set_nvml_session_uptime(Instant::now());
                 self.info.with_label_values(&[&data.version]).set(1.0);
                 self.device_count.set(data.devices.len() as f64);
+                if let Some(expected) = self.config.expected_device_count {
+                    let mismatch = data.devices.len() != expected;
+                    self.device_count_mismatch
+                        .with_label_values(&[])
+                        .set(if mismatch { 1.0 } else { 0.0 });
+                }
+                if self.config.emit_node_aggregates {
+                    self.set_node_aggregates(&data.devices);
+                }
+                if self.config.remote_endpoints.is_empty() {
+                    self.set_visibility(data.devices.len());
+                }
+                // Sessions come and go between scrapes, so start from a clean slate
+                self.encoder_session_fps.reset();
+                self.encoder_session_latency.reset();
+                self.process_memory_used.reset();
+                self.process_memory_used_mig.reset();
+                self.cpu_affinity.reset();
+                self.fan_speed_percent.reset();
+                self.fan_speed_rpm.reset();
+                self.pstate_max_clock_graphics.reset();
+                // Versions are labels, so a firmware update would leave the old series behind
+                self.inforom_info.reset();
+                self.raw_field.reset();
+                self.device_collection_timed_out.reset();
+                for index in &data.timed_out_devices {
+                    self.device_collection_timed_out
+                        .with_label_values(&[&index.to_string()])
+                        .set(1.0);
+                }
+                self.retain_memory_peaks(&data.devices);
+                self.set_clock_graphics_range(&data.devices);
+                if self.config.enable_index_label {
+                    // Replaced rather than extended, so a removed device's index
+                    // doesn't linger on whatever later takes its minor number
+                    *self.device_indices.lock().unwrap_or_else(|e| e.into_inner()) = data
+                        .devices
+                        .iter()
+                        .map(|device| (device.minor_number.clone(), device.index.clone()))
+                        .collect();
+                }
+                if self.config.compat.is_some() {
+                    let mut uuids = self.device_uuids.lock().unwrap_or_else(|e| e.into_inner());
+                    for device in &data.devices {
+                        uuids.insert(device.minor_number.clone(), device.uuid.clone());
+                    }
+                }
+                for (host, up) in &data.remote_up {
+                    self.remote_up.with_label_values(&[host]).set(if *up { 1.0 } else { 0.0 });
+                }
+                let mut hosts = self.device_hosts.lock().unwrap_or_else(|e| e.into_inner());
+                for device in &data.devices {
+                    let Some(host) = &device.host else { continue };
+                    let prefix = format!("{}/", host);
+                    if let Some(minor) = device.minor_number.strip_prefix(&prefix) {
+                        hosts.insert(device.minor_number.clone(), (host.clone(), minor.to_string()));
+                    }
+                }
+                drop(hosts);
+
+                *self.lost_minors.lock().unwrap_or_else(|e| e.into_inner()) = data
+                    .devices
+                    .iter()
+                    .filter(|device| device.lost)
+                    .map(|device| device.minor_number.clone())
+                    .collect();
 
                 for device in &data.devices {
-                    self.device_info
-                        .with_label_values(&[
-                            &device.index,
-                            &device.minor_number,
-                            &device.uuid,
-                            &device.name,
-                        ])
-                        .set(1.0);
+                    // Nothing a lost GPU reports can be trusted, so it only gets the lost indicator
+                    if device.lost {
+                        self.device_lost
+                            .with_label_values(&[&device.minor_number])
+                            .set(1.0);
+                        continue;
+                    }
+                    if !self.config.drop_device_info_metric {
+                        self.device_info
+                            .with_label_values(&[
+                                &device.index,
+                                &device.minor_number,
+                                &device.uuid,
+                                &device.name,
+                                device.pci_bus_id.as_deref().unwrap_or_default(),
+                            ])
+                            .set(1.0);
+                    }
                     self.fan_speed
                         .with_label_values(&[&device.minor_number])
-                        .set(device.fan_speed);
+                        .set(self.whole(device.fan_speed));
+                    for (fan, speed) in &device.fan_speeds {
+                        self.fan_speed_percent
+                            .with_label_values(&[&device.minor_number, &fan.to_string()])
+                            .set(self.whole(*speed));
+                    }
+                    self.set_if_present(
+                        &self.fan_speed_max_percent,
+                        &device.minor_number,
+                        max_fan_speed(&device.fan_speeds).map(|speed| self.whole(speed)),
+                    );
+                    // Only fans reporting RPM get a series, cards reporting only percent get none
+                    for (fan, rpm) in &device.fan_speed_rpm {
+                        self.fan_speed_rpm
+                            .with_label_values(&[&device.minor_number, &fan.to_string()])
+                            .set(*rpm);
+                    }
                     self.memory_total
                         .with_label_values(&[&device.minor_number])
-                        .set(device.memory_total);
+                        .set(self.config.memory_unit.convert_bytes(device.memory_total));
                     self.memory_used
                         .with_label_values(&[&device.minor_number])
-                        .set(device.memory_used);
+                        .set(self.config.memory_unit.convert_bytes(device.memory_used));
+                    self.set_memory_used_peak(device);
+                    self.set_if_present(&self.memory_reserved, &device.minor_number, device.memory_reserved);
+                    if let Some(bandwidth) = memory_bandwidth(device.clock_memory, device.memory_bus_width) {
+                        self.memory_bandwidth
+                            .with_label_values(&[&device.minor_number])
+                            .set(bandwidth);
+                    }
                     self.power_usage
                         .with_label_values(&[&device.minor_number])
                         .set(device.power_usage);
                     self.power_usage_average
                         .with_label_values(&[&device.minor_number])
                         .set(device.power_usage_average);
+                    self.set_if_present(&self.power_usage_peak, &device.minor_number, device.power_usage_peak);
+                    self.set_if_present(&self.power_efficiency, &device.minor_number, power_efficiency(device));
                     self.temperatures
                         .with_label_values(&[&device.minor_number])
-                        .set(device.temperature);
+                        .set(self.whole(device.temperature));
+                    self.set_if_present(&self.memory_temperature_margin, &device.minor_number, memory_temperature_margin(device).map(|m| self.whole(m)));
+                    self.set_if_present(&self.target_temperature, &device.minor_number, device.target_temperature);
                     self.utilization_gpu
                         .with_label_values(&[&device.minor_number])
-                        .set(device.utilization_gpu);
+                        .set(self.utilization(device.utilization_gpu));
                     self.utilization_gpu_average
                         .with_label_values(&[&device.minor_number])
-                        .set(device.utilization_gpu_average);
+                        .set(self.utilization(device.utilization_gpu_average));
+                    self.set_if_present(&self.sm_active, &device.minor_number, device.sm_active);
+                    self.set_if_present(&self.tensor_active, &device.minor_number, device.tensor_active);
+                    self.set_if_present(&self.dram_active, &device.minor_number, device.dram_active);
+                    self.set_if_present(&self.gpu_num_cores, &device.minor_number, device.num_cores);
+                    self.set_if_present(&self.gpu_num_sm, &device.minor_number, device.num_sm);
                     self.utilization_memory
                         .with_label_values(&[&device.minor_number])
-                        .set(device.utilization_memory);
+                        .set(self.utilization(device.utilization_memory));
                     
-                    // Clock speeds - set 0 if not available
-                    self.clock_graphics
-                        .with_label_values(&[&device.minor_number])
-                        .set(device.clock_graphics.unwrap_or(0.0));
-                    self.clock_sm
-                        .with_label_values(&[&device.minor_number])
-                        .set(device.clock_sm.unwrap_or(0.0));
-                    self.clock_memory
-                        .with_label_values(&[&device.minor_number])
-                        .set(device.clock_memory.unwrap_or(0.0));
-                    self.clock_graphics_max
-                        .with_label_values(&[&device.minor_number])
-                        .set(device.clock_graphics_max.unwrap_or(0.0));
-                    self.clock_sm_max
-                        .with_label_values(&[&device.minor_number])
-                        .set(device.clock_sm_max.unwrap_or(0.0));
-                    self.clock_memory_max
-                        .with_label_values(&[&device.minor_number])
-                        .set(device.clock_memory_max.unwrap_or(0.0));
+                    // Clock speeds
+                    self.set_optional(&self.clock_graphics, &device.minor_number, device.clock_graphics);
+                    self.set_optional(&self.clock_sm, &device.minor_number, device.clock_sm);
+                    self.set_optional(&self.clock_memory, &device.minor_number, device.clock_memory);
+                    self.set_optional(&self.clock_graphics_max, &device.minor_number, device.clock_graphics_max);
+                    self.set_optional(&self.clock_sm_max, &device.minor_number, device.clock_sm_max);
+                    self.set_optional(&self.clock_memory_max, &device.minor_number, device.clock_memory_max);
+                    self.set_if_present(&self.clock_graphics_customer_boost_max, &device.minor_number, device.clock_graphics_customer_boost_max);
+                    self.set_if_present(&self.clock_user_locked, &device.minor_number, clock_user_locked(device));
+                    self.set_if_present(
+                        &self.app_clock_shortfall,
+                        &device.minor_number,
+                        app_clock_shortfall(device),
+                    );
+                    self.set_if_present(
+                        &self.throttling_supported,
+                        &device.minor_number,
+                        // Idleness is always reported and doesn't count as being able to throttle
+                        device.supported_throttle_reasons.map(|reasons| {
+                            if reasons & !throttle::GPU_IDLE != 0 { 1.0 } else { 0.0 }
+                        }),
+                    );
+                    self.set_active_clock_limit(&device.minor_number, device.current_throttle_reasons);
+                    for (pstate, clock) in &device.pstate_clock_graphics_max {
+                        self.pstate_max_clock_graphics
+                            .with_label_values(&[&device.minor_number, &format!("P{}", pstate)])
+                            .set(*clock);
+                    }
                     
                     // Power limits
-                    self.power_limit
-                        .with_label_values(&[&device.minor_number])
-                        .set(device.power_limit.unwrap_or(0.0));
-                    self.power_limit_default
-                        .with_label_values(&[&device.minor_number])
-                        .set(device.power_limit_default.unwrap_or(0.0));
+                    self.set_optional(&self.power_limit, &device.minor_number, device.power_limit);
+                    self.set_optional(&self.power_limit_default, &device.minor_number, device.power_limit_default);
+                    self.set_if_present(&self.power_limit_enforced, &device.minor_number, device.power_limit_enforced);
+
+                    // Energy
+                    self.set_optional_counter(&self.energy_consumption, &device.minor_number, device.energy_consumption);
                     
                     // Performance state
-                    self.performance_state
-                        .with_label_values(&[&device.minor_number])
-                        .set(device.performance_state.unwrap_or(0.0));
+                    let performance_state = device.performance_state.map(|ps| self.whole(ps));
+                    self.set_optional(&self.performance_state, &device.minor_number, performance_state);
+                    if self.config.performance_state_one_hot {
+                        self.set_performance_state_one_hot(&device.minor_number, performance_state);
+                    }
+
+                    // GPU operation mode - only supported on some Tesla/Quadro cards
+                    if let Some(mode) = device.gpu_operation_mode {
+                        self.gpu_operation_mode
+                            .with_label_values(&[&device.minor_number])
+                            .set(mode);
+                    }
+                    if let Some(mode) = device.gpu_operation_mode_pending {
+                        self.gpu_operation_mode_pending
+                            .with_label_values(&[&device.minor_number])
+                            .set(mode);
+                    }
+
+                    // Display status - only supported on workstation cards
+                    self.set_if_present(&self.display_active, &device.minor_number, device.display_active);
+                    self.set_if_present(&self.display_connected, &device.minor_number, device.display_connected);
                     
                     // PCIe metrics
-                    self.pcie_link_gen
-                        .with_label_values(&[&device.minor_number])
-                        .set(device.pcie_link_gen.unwrap_or(0.0));
-                    self.pcie_link_width
-                        .with_label_values(&[&device.minor_number])
-                        .set(device.pcie_link_width.unwrap_or(0.0));
-                    self.pcie_tx_throughput
-                        .with_label_values(&[&device.minor_number])
-                        .set(device.pcie_tx_throughput.unwrap_or(0.0));
-                    self.pcie_rx_throughput
-                        .with_label_values(&[&device.minor_number])
-                        .set(device.pcie_rx_throughput.unwrap_or(0.0));
+                    self.set_optional(&self.pcie_link_gen, &device.minor_number, device.pcie_link_gen);
+                    self.set_optional(&self.pcie_link_width, &device.minor_number, device.pcie_link_width);
+                    // A 0 fallback would look like an upgraded link, so unsupported maximums are skipped
+                    self.set_if_present(&self.pcie_link_gen_max, &device.minor_number, device.pcie_link_gen_max);
+                    self.set_if_present(&self.pcie_link_width_max, &device.minor_number, device.pcie_link_width_max);
+                    self.set_if_present(&self.pcie_link_speed, &device.minor_number, device.pcie_link_speed);
+                    self.set_optional(&self.pcie_tx_throughput, &device.minor_number, device.pcie_tx_throughput);
+                    self.set_optional(&self.pcie_rx_throughput, &device.minor_number, device.pcie_rx_throughput);
                     
                     // Encoder/Decoder
-                    self.encoder_utilization
-                        .with_label_values(&[&device.minor_number])
-                        .set(device.encoder_utilization.unwrap_or(0.0));
-                    self.decoder_utilization
-                        .with_label_values(&[&device.minor_number])
-                        .set(device.decoder_utilization.unwrap_or(0.0));
+                    self.set_optional(&self.encoder_utilization, &device.minor_number, device.encoder_utilization.map(|u| self.utilization(u)));
+                    self.set_optional(&self.decoder_utilization, &device.minor_number, device.decoder_utilization.map(|u| self.utilization(u)));
+                    // Skipped entirely on cards without these engines
+                    self.set_if_present(&self.jpeg_utilization, &device.minor_number, device.jpeg_utilization.map(|u| self.utilization(u)));
+                    self.set_if_present(&self.ofa_utilization, &device.minor_number, device.ofa_utilization.map(|u| self.utilization(u)));
+                    // Encoder capacity is skipped entirely on cards without an encoder
+                    for (codec, capacity) in [("h264", device.encoder_capacity_h264), ("hevc", device.encoder_capacity_hevc)] {
+                        if let Some(capacity) = capacity {
+                            self.encoder_capacity
+                                .with_label_values(&[&device.minor_number, codec])
+                                .set(capacity);
+                        }
+                    }
+                    self.set_optional(&self.encoder_sessions, &device.minor_number, device.encoder_sessions);
+                    self.set_encoder_sessions(device);
                     
                     // ECC errors
-                    self.ecc_errors_corrected
-                        .with_label_values(&[&device.minor_number])
-                        .set(device.ecc_errors_corrected.unwrap_or(0.0));
-                    self.ecc_errors_uncorrected
+                    self.set_optional(&self.ecc_errors_corrected, &device.minor_number, device.ecc_errors_corrected);
+                    self.set_optional(&self.ecc_errors_uncorrected, &device.minor_number, device.ecc_errors_uncorrected);
+                    self.set_row_remap_availability(device);
+                    self.set_if_present(&self.retired_pages_pending, &device.minor_number, device.retired_pages_pending);
+                    self.set_if_present(
+                        &self.ecc_sram_threshold_exceeded,
+                        &device.minor_number,
+                        device.ecc_sram_threshold_exceeded,
+                    );
+                    self.set_if_present(&self.inforom_valid, &device.minor_number, device.inforom_valid);
+                    if let Some(versions) = &device.inforom_versions {
+                        self.inforom_info
+                            .with_label_values(&[
+                                &device.minor_number,
+                                &versions.image,
+                                versions.oem.as_deref().unwrap_or_default(),
+                                versions.ecc.as_deref().unwrap_or_default(),
+                                versions.power.as_deref().unwrap_or_default(),
+                            ])
+                            .set(1.0);
+                    }
+
+                    // Health - only reported when the driver exposes a recovery action
+                    self.device_lost
                         .with_label_values(&[&device.minor_number])
-                        .set(device.ecc_errors_uncorrected.unwrap_or(0.0));
+                        .set(0.0);
+                    self.set_recovery_action(&device.minor_number, device.recovery_action);
+                    if let Some(reset_required) = device.reset_required {
+                        self.reset_required
+                            .with_label_values(&[&device.minor_number])
+                            .set(reset_required);
+                    }
                     
+                    if self.config.enable_topology_metrics {
+                        self.set_topology(device);
+                    }
+                    for (field_id, value) in &device.raw_fields {
+                        self.raw_field
+                            .with_label_values(&[&device.minor_number, &field_id.to_string()])
+                            .set(*value);
+                    }
+
                     // Processes
-                    self.compute_processes
-                        .with_label_values(&[&device.minor_number])
-                        .set(device.compute_processes.unwrap_or(0.0));
-                    self.graphics_processes
-                        .with_label_values(&[&device.minor_number])
-                        .set(device.graphics_processes.unwrap_or(0.0));
+                    self.set_optional(&self.compute_processes, &device.minor_number, device.compute_processes);
+                    self.set_optional(&self.graphics_processes, &device.minor_number, device.graphics_processes);
+                    // Process lists that aren't supported leave their sum unsupported too
+                    self.set_optional(
+                        &self.compute_processes_memory,
+                        &device.minor_number,
+                        device.compute_processes.map(|_| processes_memory(&device.processes, |p| p.compute)),
+                    );
+                    self.set_optional(
+                        &self.graphics_processes_memory,
+                        &device.minor_number,
+                        device.graphics_processes.map(|_| processes_memory(&device.processes, |p| p.graphics)),
+                    );
+                    self.set_process_memory(device);
                 }
+                if let Some(window) = self.config.power_summary_window {
+                    self.record_power_samples(&data.devices, window);
+                }
+                *self.last_metrics.lock().unwrap_or_else(|e| e.into_inner()) = Some(data.clone());
                 debug!("Processed {} devices", data.devices.len());
+                Ok(data)
             }
             Err(e) => {
-                warn!("Failed to collect metrics (NVML unavailable): {}. Reporting up=0, device_count=0", e);
+                let mut failed_call_results = self.failed_call_results.lock().unwrap_or_else(|e| e.into_inner());
+                self.count_nvml_call_results(&std::mem::take(&mut *failed_call_results));
+                drop(failed_call_results);
+                self.last_collection_error_code.reset();
+                self.last_collection_error_code
+                    .with_label_values(&[collection_error_code(&e)])
+                    .set(1.0);
+                let age = self.last_success.lock().unwrap_or_else(|e| e.into_inner()).map(|at| at.elapsed());
+                if let Some(age) = age {
+                    self.snapshot_age.with_label_values(&[]).set(age.as_secs_f64());
+                }
+                // Ride out a driver stutter on the last good collection, whose
+                // metrics are all still in place
+                if let (Some(tolerance), Some(age)) = (self.config.stale_tolerance, age) {
+                    if age < tolerance {
+                        warn!("Failed to collect metrics: {}. Serving the collection from {:?} ago", e, age);
+                        self.serving_stale.set(1.0);
+                        return Err(e);
+                    }
+                }
+                self.serving_stale.set(0.0);
+                if matches!(e.downcast_ref::<NvmlError>(), Some(NvmlError::NoPermission)) {
+                    warn!(
+                        "NVML denied access to the GPU devices: {}. Run the exporter as a user \
+                         that can open /dev/nvidia* (usually by adding it to the group owning \
+                         those devices, e.g. `video`)",
+                        e
+                    );
+                    self.nvml_permission_denied.set(1.0);
+                } else {
+                    warn!("Failed to collect metrics (NVML unavailable): {}. Reporting up=0, device_count=0", e);
+                    self.nvml_permission_denied.set(0.0);
+                }
                 self.up.set(0.0);
+                self.last_scrape_success.set(0.0);
                 self.device_count.set(0.0);
+                // Not left claiming remote exporters are up when none could be reached
+                self.remote_up.reset();
                 // Set driver_info to "unavailable" when NVML fails so the metric is always present
                 self.info.with_label_values(&["unavailable"]).set(1.0);
+                *self.last_metrics.lock().unwrap_or_else(|e| e.into_inner()) = None;
+                Err(e)
+            }
+        }
+    }
+
+    /// Every metric the exporter exports, apart from the power usage summary, which
+    /// is built by hand, and the MIG process memory, which joins the process memory family
+    fn collectors(&self) -> Vec<&dyn Collector> {
+        vec![
+            &self.device_count,
+            &self.visible_devices_restricted,
+            &self.host_device_count,
+            &self.device_count_mismatch,
+            &self.device_collection_timed_out,
+            &self.node_power_usage,
+            &self.node_memory_used,
+            &self.node_utilization_gpu_average,
+            &self.device_info,
+            &self.fan_speed,
+            &self.fan_speed_percent,
+            &self.fan_speed_max_percent,
+            &self.fan_speed_rpm,
+            &self.info,
+            &self.memory_total,
+            &self.memory_used,
+            &self.memory_used_peak,
+            &self.memory_reserved,
+            &self.memory_bandwidth,
+            &self.power_usage,
+            &self.power_usage_average,
+            &self.power_usage_peak,
+            &self.power_efficiency,
+            &self.temperatures,
+            &self.memory_temperature_margin,
+            &self.target_temperature,
+            &self.up,
+            &self.last_scrape_success,
+            &self.nvml_permission_denied,
+            &self.serving_stale,
+            &self.snapshot_age,
+            &self.collection_duration,
+            &self.last_collection_error_code,
+            &self.nvml_reinit,
+            &self.smi_parse_errors,
+            &self.sample_buffer_evictions,
+            &self.duplicate_label_collisions,
+            &self.nvml_calls_per_scrape,
+            &self.nvml_call_results,
+            &self.nvml_session_uptime,
+            &self.collection_interval_drift,
+            &self.remote_up,
+            &self.utilization_gpu,
+            &self.utilization_gpu_average,
+            &self.sm_active,
+            &self.tensor_active,
+            &self.dram_active,
+            &self.gpu_num_cores,
+            &self.gpu_num_sm,
+            &self.utilization_memory,
+            // Clock speeds
+            &self.clock_graphics,
+            &self.clock_sm,
+            &self.clock_memory,
+            &self.clock_graphics_max,
+            &self.clock_sm_max,
+            &self.clock_memory_max,
+            &self.clock_graphics_customer_boost_max,
+            &self.clock_user_locked,
+            &self.app_clock_shortfall,
+            &self.throttling_supported,
+            &self.active_clock_limit,
+            &self.throttle_reason_seconds,
+            &self.memory_thermal_throttle_events,
+            &self.clock_graphics_sampled_min,
+            &self.clock_graphics_sampled_max,
+            &self.pstate_max_clock_graphics,
+            // Power limits
+            &self.power_limit,
+            &self.power_limit_default,
+            &self.power_limit_enforced,
+            // Energy
+            &self.energy_consumption,
+            // Performance state
+            &self.performance_state,
+            &self.performance_state_current,
+            // GPU operation mode
+            &self.gpu_operation_mode,
+            &self.gpu_operation_mode_pending,
+            &self.display_active,
+            &self.display_connected,
+            // PCIe
+            &self.pcie_link_gen,
+            &self.pcie_link_width,
+            &self.pcie_link_gen_max,
+            &self.pcie_link_width_max,
+            &self.pcie_link_speed,
+            &self.pcie_tx_throughput,
+            &self.pcie_rx_throughput,
+            // Encoder/Decoder
+            &self.encoder_utilization,
+            &self.decoder_utilization,
+            &self.jpeg_utilization,
+            &self.ofa_utilization,
+            &self.encoder_capacity,
+            &self.encoder_sessions,
+            &self.encoder_session_fps,
+            &self.encoder_session_latency,
+            // ECC errors
+            &self.ecc_errors_corrected,
+            &self.ecc_errors_uncorrected,
+            &self.row_remap_availability,
+            // Health
+            &self.recommended_recovery_action,
+            &self.reset_required,
+            &self.retired_pages_pending,
+            &self.ecc_sram_threshold_exceeded,
+            &self.inforom_valid,
+            &self.inforom_info,
+            &self.device_lost,
+            // Topology
+            &self.numa_node,
+            &self.cpu_affinity,
+            // Extra NVML fields
+            &self.raw_field,
+            // Processes
+            &self.compute_processes,
+            &self.graphics_processes,
+            &self.compute_processes_memory,
+            &self.graphics_processes_memory,
+            &self.process_memory_used,
+        ]
+    }
+
+    /// Every metric the exporter can export with its type, help and labels, whether or
+    /// not any device currently reports it
+    pub fn catalog(&self) -> Vec<CatalogEntry> {
+        let mut entries: Vec<CatalogEntry> = Vec::new();
+        let mut collectors = self.collectors();
+        collectors.push(&self.process_memory_used_mig);
+        for collector in collectors {
+            let metric_type = collector
+                .collect()
+                .first()
+                .map_or(MetricType::UNTYPED, MetricFamily::get_field_type);
+            for desc in collector.desc() {
+                // Families split over several collectors are listed once with all of their labels
+                if let Some(entry) = entries.iter_mut().find(|e| e.name == desc.fq_name) {
+                    for label in &desc.variable_labels {
+                        if !entry.labels.contains(label) {
+                            entry.labels.push(label.clone());
+                        }
+                    }
+                    continue;
+                }
+                entries.push(CatalogEntry {
+                    name: desc.fq_name.clone(),
+                    metric_type: type_name(metric_type).to_string(),
+                    help: desc.help.clone(),
+                    labels: desc.variable_labels.clone(),
+                });
+            }
+        }
+        if self.config.power_summary_window.is_some() {
+            for mf in self.power_usage_summary() {
+                entries.push(CatalogEntry {
+                    name: mf.get_name().to_string(),
+                    metric_type: type_name(mf.get_field_type()).to_string(),
+                    help: mf.get_help().to_string(),
+                    labels: vec!["minor".to_string()],
+                });
+            }
+        }
+
+        if self.config.compat == Some(Compat::GoNvidiaGpuExporter) {
+            for entry in &mut entries {
+                if let Some((name, _)) = self.go_exporter_name(&entry.name) {
+                    entry.name = name.to_string();
+                    if entry.labels.iter().any(|l| l == "minor") && !entry.labels.iter().any(|l| l == "uuid") {
+                        entry.labels.push("uuid".to_string());
+                    }
+                }
+            }
+        }
+        if self.config.enable_index_label {
+            for entry in &mut entries {
+                if entry.labels.iter().any(|l| l == "minor") && !entry.labels.iter().any(|l| l == "index") {
+                    entry.labels.push("index".to_string());
+                }
+            }
+        }
+        if !self.config.minor_names.is_empty() {
+            for entry in &mut entries {
+                if entry.labels.iter().any(|l| l == "minor") {
+                    entry.labels.push("minor_number".to_string());
+                }
             }
         }
+        if !self.config.remote_endpoints.is_empty() {
+            for entry in &mut entries {
+                if entry.labels.iter().any(|l| l == "minor") {
+                    entry.labels.push("host".to_string());
+                }
+            }
+        }
+        let mut const_labels: Vec<&String> = self.config.const_labels.keys().collect();
+        const_labels.sort();
+        for entry in &mut entries {
+            entry.labels.extend(const_labels.iter().map(|name| name.to_string()));
+        }
+        entries
+    }
+
+    /// Snapshot the current metric families without triggering a new collection
+    pub fn families(&self) -> Vec<MetricFamily> {
+        let mut mfs = self.keyed_families();
+        if !self.config.remote_endpoints.is_empty() {
+            self.add_host_label(&mut mfs);
+        }
+        mfs
+    }
 
+    /// Like `families`, but with remote devices still keyed by their "host/minor"
+    /// minor number
+    fn keyed_families(&self) -> Vec<MetricFamily> {
         debug!("Collecting metric families...");
-        let mut mfs = Vec::new();
-        
-        // Helper to filter out empty metric families
-        let mut add_metrics = |metrics: Vec<MetricFamily>| {
-            for mf in metrics {
-                // Only add metric families that have at least one metric
-                if !mf.get_metric().is_empty() {
-                    mfs.push(mf);
-                } else {
-                    debug!("Skipping empty metric family: {}", mf.get_name());
+        let mut mfs: Vec<MetricFamily> = self.collectors().into_iter().flat_map(|c| c.collect()).collect();
+        mfs.extend(self.power_usage_summary());
+        self.drop_lost_device_series(&mut mfs);
+
+        // MIG processes carry an extra label but belong to the process memory family
+        let process_memory_name = &self.process_memory_used.desc()[0].fq_name;
+        if let Some(process_memory) = mfs.iter_mut().find(|mf| mf.get_name() == process_memory_name) {
+            for mut mig in self.process_memory_used_mig.collect() {
+                for metric in mig.take_metric() {
+                    process_memory.mut_metric().push(metric);
                 }
             }
-        };
-        
-        add_metrics(self.device_count.collect());
-        add_metrics(self.device_info.collect());
-        add_metrics(self.fan_speed.collect());
-        add_metrics(self.info.collect());
-        add_metrics(self.memory_total.collect());
-        add_metrics(self.memory_used.collect());
-        add_metrics(self.power_usage.collect());
-        add_metrics(self.power_usage_average.collect());
-        add_metrics(self.temperatures.collect());
-        add_metrics(self.up.collect());
-        add_metrics(self.utilization_gpu.collect());
-        add_metrics(self.utilization_gpu_average.collect());
-        add_metrics(self.utilization_memory.collect());
-        // Clock speeds
-        add_metrics(self.clock_graphics.collect());
-        add_metrics(self.clock_sm.collect());
-        add_metrics(self.clock_memory.collect());
-        add_metrics(self.clock_graphics_max.collect());
-        add_metrics(self.clock_sm_max.collect());
-        add_metrics(self.clock_memory_max.collect());
-        // Power limits
-        add_metrics(self.power_limit.collect());
-        add_metrics(self.power_limit_default.collect());
-        // Performance state
-        add_metrics(self.performance_state.collect());
-        // PCIe
-        add_metrics(self.pcie_link_gen.collect());
-        add_metrics(self.pcie_link_width.collect());
-        add_metrics(self.pcie_tx_throughput.collect());
-        add_metrics(self.pcie_rx_throughput.collect());
-        // Encoder/Decoder
-        add_metrics(self.encoder_utilization.collect());
-        add_metrics(self.decoder_utilization.collect());
-        // ECC errors
-        add_metrics(self.ecc_errors_corrected.collect());
-        add_metrics(self.ecc_errors_uncorrected.collect());
-        // Processes
-        add_metrics(self.compute_processes.collect());
-        add_metrics(self.graphics_processes.collect());
+        }
+
+        // Only keep metric families that have at least one metric, unless their
+        // HELP and TYPE lines are always wanted
+        mfs.retain(|mf| {
+            if mf.get_metric().is_empty() && !self.config.always_emit_metric_types {
+                debug!("Skipping empty metric family: {}", mf.get_name());
+                return false;
+            }
+            true
+        });
+
+        if self.config.compat == Some(Compat::GoNvidiaGpuExporter) {
+            self.rename_for_go_exporter(&mut mfs);
+        }
+        // Done before relabelling, which replaces the minor numbers the indices are keyed by
+        if self.config.enable_index_label {
+            self.add_index_label(&mut mfs);
+        }
+        if !self.config.minor_names.is_empty() {
+            self.relabel_minor(&mut mfs);
+        }
 
         debug!("Collected {} metric families total (after filtering empty ones)", mfs.len());
         mfs
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// Leave out the series of lost devices, including those set before they were
+    /// lost, but for `device_lost`
+    fn drop_lost_device_series(&self, mfs: &mut [MetricFamily]) {
+        let lost = self.lost_minors.lock().unwrap_or_else(|e| e.into_inner());
+        if lost.is_empty() {
+            return;
+        }
+        let device_lost_name = &self.device_lost.desc()[0].fq_name;
+        for mf in mfs.iter_mut().filter(|mf| mf.get_name() != device_lost_name) {
+            let metrics = mf.take_metric().into_iter().filter(|m| {
+                !m.get_label()
+                    .iter()
+                    .any(|l| l.get_name() == "minor" && lost.contains(l.get_value()))
+            });
+            mf.set_metric(metrics.collect());
+        }
+    }
 
-    #[test]
-    fn test_exporter_creation() {
-        let exporter = Exporter::new();
-        let mfs = exporter.gather();
-        // Should always have at least the 'up' metric
-        assert!(!mfs.is_empty());
+    /// Value of the `minor` label for a device's minor number
+    fn minor_label<'a>(&'a self, minor_number: &'a str) -> &'a str {
+        self.config
+            .minor_names
+            .get(minor_number)
+            .map_or(minor_number, String::as_str)
     }
 
-    #[test]
-    fn test_exporter_default_trait() {
-        let exporter = Exporter::default();
-        let mfs = exporter.gather();
-        // Should work the same as new()
-        assert!(!mfs.is_empty());
+    /// Replace `minor` label values with their configured names, keeping the raw
+    /// value in a `minor_number` label
+    fn relabel_minor(&self, mfs: &mut [MetricFamily]) {
+        for metric in mfs.iter_mut().flat_map(|mf| mf.mut_metric().iter_mut()) {
+            let mut labels = metric.take_label().into_vec();
+            if let Some(pos) = labels.iter().position(|l| l.get_name() == "minor") {
+                let minor_number = labels[pos].get_value().to_string();
+                labels[pos].set_value(self.minor_label(&minor_number).to_string());
+
+                let mut raw = LabelPair::default();
+                raw.set_name("minor_number".to_string());
+                raw.set_value(minor_number);
+                // Labels stay sorted by name
+                labels.insert(pos + 1, raw);
+            }
+            metric.set_label(labels.into());
+        }
     }
 
-    #[test]
-    fn test_exporter_metrics_structure() {
-        let exporter = Exporter::new();
-        let mfs = exporter.gather();
+    /// Add an `index` label right after the `minor` label of every series that
+    /// doesn't have one yet, so device metrics can be joined on either
+    fn add_index_label(&self, mfs: &mut [MetricFamily]) {
+        let indices = self.device_indices.lock().unwrap_or_else(|e| e.into_inner());
+        for metric in mfs.iter_mut().flat_map(|mf| mf.mut_metric().iter_mut()) {
+            let mut labels = metric.take_label().into_vec();
+            let minor = labels.iter().position(|l| l.get_name() == "minor");
+            let index = minor.and_then(|pos| Some((pos, indices.get(labels[pos].get_value())?)));
+            if let Some((pos, index)) = index.filter(|_| !labels.iter().any(|l| l.get_name() == "index")) {
+                let mut label = LabelPair::default();
+                label.set_name("index".to_string());
+                label.set_value(index.clone());
+                // In the order the catalog lists them
+                labels.insert(pos + 1, label);
+            }
+            metric.set_label(labels.into());
+        }
+    }
 
-        // Check that we have the expected metric families
-        let metric_names: Vec<String> = mfs.iter().map(|mf| mf.get_name().to_string()).collect();
-        
-        assert!(metric_names.contains(&format!("{}_up", NAMESPACE)));
-        assert!(metric_names.contains(&format!("{}_device_count", NAMESPACE)));
-        assert!(metric_names.contains(&format!("{}_driver_info", NAMESPACE)));
+    /// Name of the Go nvidia_gpu_exporter metric matching one of ours, with the factor
+    /// converting our values to its unit
+    fn go_exporter_name(&self, name: &str) -> Option<(&'static str, f64)> {
+        let name = name.strip_prefix(NAMESPACE)?.strip_prefix('_')?;
+        GO_EXPORTER_NAMES.iter().find_map(|(ours, go, unit)| {
+            let (suffix, factor) = match unit {
+                CompatUnit::Same => ("", 1.0),
+                CompatUnit::Percent => ("", 0.01),
+                CompatUnit::Utilization => (
+                    self.config.utilization_scale.suffix(),
+                    1.0 / self.config.utilization_scale.convert_percent(100.0),
+                ),
+                CompatUnit::Memory => (self.config.memory_unit.suffix(), 1.0 / self.config.memory_unit.convert_bytes(1.0)),
+                CompatUnit::Milli => ("", 0.001),
+                CompatUnit::Mega => ("", 1_000_000.0),
+            };
+            (name.strip_prefix(ours) == Some(suffix)).then_some((*go, factor))
+        })
     }
 
-    #[test]
-    fn test_all_expected_metrics_present() {
-        let exporter = Exporter::new();
-        let mfs = exporter.gather();
+    /// Rename the metrics the Go nvidia_gpu_exporter also exports to its names and
+    /// units, adding the `uuid` label it identifies GPUs by. The `minor` label stays,
+    /// so the per-device handling keyed by it still applies.
+    fn rename_for_go_exporter(&self, mfs: &mut [MetricFamily]) {
+        let uuids = self.device_uuids.lock().unwrap_or_else(|e| e.into_inner());
+        for mf in mfs.iter_mut() {
+            let Some((name, factor)) = self.go_exporter_name(mf.get_name()) else {
+                continue;
+            };
+            mf.set_name(name.to_string());
+            for metric in mf.mut_metric().iter_mut() {
+                if metric.has_gauge() {
+                    let value = metric.get_gauge().get_value();
+                    metric.mut_gauge().set_value(value * factor);
+                }
+                let mut labels = metric.take_label().into_vec();
+                let uuid = labels
+                    .iter()
+                    .find(|l| l.get_name() == "minor")
+                    .and_then(|minor| uuids.get(minor.get_value()));
+                if let Some(uuid) = uuid.filter(|_| !labels.iter().any(|l| l.get_name() == "uuid")) {
+                    let mut label = LabelPair::default();
+                    label.set_name("uuid".to_string());
+                    label.set_value(uuid.clone());
+                    // Labels stay sorted by name
+                    let pos = labels.partition_point(|l| l.get_name() < "uuid");
+                    labels.insert(pos, label);
+                }
+                metric.set_label(labels.into());
+            }
+        }
+    }
 
-        let metric_names: Vec<String> = mfs.iter().map(|mf| mf.get_name().to_string()).collect();
-        
-        // Core metrics that should always be present
-        let core_metrics = vec![
-            format!("{}_up", NAMESPACE),
-            format!("{}_device_count", NAMESPACE),
-            format!("{}_driver_info", NAMESPACE),
-        ];
+    /// Split the "host/minor" minor numbers of remote devices into a `host` label and
+    /// their minor number on that host. Done last, since the `index` label and the
+    /// configured minor names are keyed by the "host/minor" form.
+    fn add_host_label(&self, mfs: &mut [MetricFamily]) {
+        let hosts = self.device_hosts.lock().unwrap_or_else(|e| e.into_inner());
+        for metric in mfs.iter_mut().flat_map(|mf| mf.mut_metric().iter_mut()) {
+            let mut labels = metric.take_label().into_vec();
+            let mut host = None;
+            for label in labels.iter_mut().filter(|l| matches!(l.get_name(), "minor" | "minor_number")) {
+                if let Some((device_host, minor)) = hosts.get(label.get_value()) {
+                    label.set_value(minor.clone());
+                    host = Some(device_host.clone());
+                }
+            }
+            if let Some(host) = host {
+                let mut label = LabelPair::default();
+                label.set_name("host".to_string());
+                label.set_value(host);
+                // Labels stay sorted by name
+                let pos = labels.partition_point(|l| l.get_name() < "host");
+                labels.insert(pos, label);
+            }
+            metric.set_label(labels.into());
+        }
+    }
 
-        for expected in core_metrics {
-            assert!(
-                metric_names.contains(&expected),
-                "Missing core metric: {}",
-                expected
+    /// Set an optional per-device counter to an absolute value reported by the device.
+    /// A value lower than the current one means the driver reloaded, so the counter is reset.
+    fn set_optional_counter(&self, counter: &CounterVec, minor: &str, value: Option<f64>) {
+        match value {
+            Some(v) => {
+                let counter = counter.with_label_values(&[minor]);
+                let current = counter.get();
+                if v < current {
+                    counter.reset();
+                    counter.inc_by(v);
+                } else {
+                    counter.inc_by(v - current);
+                }
+            }
+            None if self.config.drop_zero_value_optional_metrics => {
+                let _ = counter.remove_label_values(&[minor]);
+            }
+            None => counter.with_label_values(&[minor]).reset(),
+        }
+    }
+
+    /// Export the active encoder sessions of a device, up to `max_sessions_per_gpu`.
+    /// Sessions sharing a codec and resolution are folded into one series: their
+    /// frame rates are summed and the worst latency is kept.
+    fn set_encoder_sessions(&self, device: &Device) {
+        let sessions = &device.encoder_session_details;
+        if sessions.len() > self.config.max_sessions_per_gpu {
+            debug!(
+                "Dropping {} of {} encoder sessions on GPU {}",
+                sessions.len() - self.config.max_sessions_per_gpu,
+                sessions.len(),
+                device.minor_number
             );
         }
 
-        // Get device count to determine if device-specific metrics should be present
-        let device_count_metric = mfs.iter()
-            .find(|mf| mf.get_name() == format!("{}_device_count", NAMESPACE))
-            .expect("device_count metric should be present");
-        let device_count = if let Some(metric) = device_count_metric.get_metric().first() {
-            metric.get_gauge().get_value() as u64
+        for session in sessions.iter().take(self.config.max_sessions_per_gpu) {
+            let labels = [device.minor_number.as_str(), &session.codec, &session.resolution];
+            self.encoder_session_fps.with_label_values(&labels).add(session.fps);
+            let latency = self.encoder_session_latency.with_label_values(&labels);
+            latency.set(latency.get().max(session.latency_us));
+        }
+    }
+
+    /// Raise the memory high-water mark of a device to its current used memory.
+    /// Peaks are kept per UUID, so they follow a GPU even if its minor number changes.
+    fn set_memory_used_peak(&self, device: &Device) {
+        let mut peaks = self.memory_peaks.lock().unwrap_or_else(|e| e.into_inner());
+        let peak = peaks.entry(device.uuid.clone()).or_insert(device.memory_used);
+        *peak = peak.max(device.memory_used);
+        self.memory_used_peak
+            .with_label_values(&[&device.minor_number])
+            .set(*peak);
+    }
+
+    /// Forget the memory peaks of GPUs the collection didn't find and drop all peak
+    /// series, which `set_memory_used_peak` sets again for the GPUs still present
+    fn retain_memory_peaks(&self, devices: &[Device]) {
+        let uuids: BTreeSet<&str> = devices.iter().map(|device| device.uuid.as_str()).collect();
+        self.memory_peaks
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .retain(|uuid, _| uuids.contains(uuid.as_str()));
+        self.memory_used_peak.reset();
+    }
+
+    /// Export the row remapper histogram of a device, one series per bucket.
+    /// Skipped on cards without row remapping.
+    fn set_row_remap_availability(&self, device: &Device) {
+        let minor = device.minor_number.as_str();
+        match &device.row_remap_availability {
+            Some(histogram) => {
+                for (bucket, banks) in [
+                    ("max", histogram.max),
+                    ("high", histogram.high),
+                    ("partial", histogram.partial),
+                    ("low", histogram.low),
+                    ("none", histogram.none),
+                ] {
+                    self.row_remap_availability.with_label_values(&[minor, bucket]).set(banks);
+                }
+            }
+            None => {
+                for bucket in ["max", "high", "partial", "low", "none"] {
+                    let _ = self.row_remap_availability.remove_label_values(&[minor, bucket]);
+                }
+            }
+        }
+    }
+
+    /// Export the time elapsed since the current NVML session started, at the first
+    /// successful collection or the first one after the watchdog re-initialized
+    /// NVML. Neither NVML nor procfs reveal when the driver was loaded, so the
+    /// `source` label records which of the two the session counts from.
+    fn set_nvml_session_uptime(&self, now: Instant) {
+        let reinits = self.nvml_reinit_count.load(Ordering::Relaxed);
+        let mut start = self.nvml_session_start.lock().unwrap_or_else(|e| e.into_inner());
+        let (started, session_reinits) = *start.get_or_insert((now, reinits));
+        // A re-initialization starts a new session, its first success its start
+        let started = if session_reinits == reinits {
+            started
         } else {
-            0
+            *start = Some((now, reinits));
+            self.nvml_session_uptime.reset();
+            now
         };
+        let source = if reinits == 0 { "exporter_nvml_init" } else { "nvml_reinit" };
+        self.nvml_session_uptime
+            .with_label_values(&[source])
+            .set(now.duration_since(started).as_secs_f64());
+    }
 
-        // Device-specific metrics should only be present if there are devices
-        if device_count > 0 {
-            let device_metrics = vec![
-                format!("{}_info", NAMESPACE),
-                format!("{}_temperatures", NAMESPACE),
-                format!("{}_fanspeed", NAMESPACE),
-                format!("{}_memory_total", NAMESPACE),
-                format!("{}_memory_used", NAMESPACE),
-                format!("{}_utilization_memory", NAMESPACE),
-                format!("{}_utilization_gpu", NAMESPACE),
-                format!("{}_utilization_gpu_average", NAMESPACE),
-                format!("{}_power_usage", NAMESPACE),
-                format!("{}_power_usage_average", NAMESPACE),
-                format!("{}_power_limit_milliwatts", NAMESPACE),
-                format!("{}_power_limit_default_milliwatts", NAMESPACE),
-                format!("{}_clock_graphics_mhz", NAMESPACE),
-                format!("{}_clock_sm_mhz", NAMESPACE),
-                format!("{}_clock_memory_mhz", NAMESPACE),
-                format!("{}_clock_graphics_max_mhz", NAMESPACE),
-                format!("{}_clock_sm_max_mhz", NAMESPACE),
-                format!("{}_clock_memory_max_mhz", NAMESPACE),
-                format!("{}_performance_state", NAMESPACE),
-                format!("{}_pcie_link_generation", NAMESPACE),
-                format!("{}_pcie_link_width", NAMESPACE),
-                format!("{}_pcie_tx_throughput_kb", NAMESPACE),
-                format!("{}_pcie_rx_throughput_kb", NAMESPACE),
-                format!("{}_encoder_utilization", NAMESPACE),
-                format!("{}_decoder_utilization", NAMESPACE),
-                format!("{}_ecc_errors_corrected_total", NAMESPACE),
-                format!("{}_ecc_errors_uncorrected_total", NAMESPACE),
-                format!("{}_compute_processes", NAMESPACE),
-                format!("{}_graphics_processes", NAMESPACE),
-            ];
+    /// Export whether fewer GPUs are visible than the host has, going by
+    /// NVIDIA_VISIBLE_DEVICES, the devices cgroup and the driver's list of the
+    /// host's GPUs, along with the host's GPU count when it can be read
+    fn set_visibility(&self, visible: usize) {
+        let host_devices = visibility::host_device_count(&self.proc_root);
+        let visible_devices = std::env::var(visibility::VISIBLE_DEVICES_ENV).ok();
+        let restricted = visibility::restricted_by_env(visible_devices.as_deref())
+            || visibility::cgroup_restricts_gpus(&self.sys_root)
+            || host_devices.is_some_and(|host| host > visible);
+        self.visible_devices_restricted.set(if restricted { 1.0 } else { 0.0 });
+        match host_devices {
+            Some(count) => self.host_device_count.with_label_values(&[]).set(count as f64),
+            None => {
+                let _ = self.host_device_count.remove_label_values(&[]);
+            }
+        }
+    }
 
-            for expected in device_metrics {
-                assert!(
-                    metric_names.contains(&expected),
-                    "Missing device metric: {} (device_count={})",
-                    expected,
-                    device_count
+    /// Export the NUMA node of a device and the CPUs it has an affinity to, up to
+    /// `MAX_AFFINITY_CPUS_PER_GPU`. Both are skipped when NVML doesn't report them.
+    fn set_topology(&self, device: &Device) {
+        self.set_if_present(&self.numa_node, &device.minor_number, device.numa_node);
+        if device.cpu_affinity.len() > MAX_AFFINITY_CPUS_PER_GPU {
+            debug!(
+                "Dropping {} of {} affine CPUs on GPU {}",
+                device.cpu_affinity.len() - MAX_AFFINITY_CPUS_PER_GPU,
+                device.cpu_affinity.len(),
+                device.minor_number
+            );
+        }
+        for cpu in device.cpu_affinity.iter().take(MAX_AFFINITY_CPUS_PER_GPU) {
+            self.cpu_affinity
+                .with_label_values(&[&device.minor_number, &cpu.to_string()])
+                .set(1.0);
+        }
+    }
+
+    /// Forget the memory high-water marks, the power usage peaks and the samples
+    /// gathered between collections, so they start over from the next collection
+    /// as if the exporter had just started. Returns the number of series cleared.
+    pub fn reset_peaks(&self) -> usize {
+        let mut cleared = 0;
+        let mut memory_peaks = self.memory_peaks.lock().unwrap_or_else(|e| e.into_inner());
+        cleared += memory_peaks.len();
+        memory_peaks.clear();
+        self.memory_used_peak.reset();
+        cleared += self
+            .power_usage_peak
+            .collect()
+            .iter()
+            .map(|mf| mf.get_metric().len())
+            .sum::<usize>();
+        self.power_usage_peak.reset();
+        let mut power_samples = self.power_samples.lock().unwrap_or_else(|e| e.into_inner());
+        cleared += power_samples.len();
+        power_samples.clear();
+        let mut clock_ranges = self.clock_graphics_range.lock().unwrap_or_else(|e| e.into_inner());
+        cleared += clock_ranges.len();
+        clock_ranges.clear();
+        cleared
+    }
+
+    /// Add the current power usage of each device to its sample window, dropping
+    /// the windows of devices that are no longer present. A full buffer is warned
+    /// about once per GPU and logged at debug level afterwards
+    fn record_power_samples(&self, devices: &[Device], window: Duration) {
+        let now = Instant::now();
+        let capacity = self.config.sample_buffer_capacity;
+        let mut samples = self.power_samples.lock().unwrap_or_else(|e| e.into_inner());
+        samples.retain(|minor, _| devices.iter().any(|d| &d.minor_number == minor && !d.lost));
+        let mut warned = self.full_sample_buffers.lock().unwrap_or_else(|e| e.into_inner());
+        warned.retain(|minor| samples.contains_key(minor));
+        for device in devices.iter().filter(|d| !d.lost) {
+            let evicted = samples
+                .entry(device.minor_number.clone())
+                .or_insert_with(|| SampleWindow::new(window, capacity))
+                .push(now, device.power_usage);
+            if evicted > 0 {
+                let message = format!(
+                    "Power usage sample buffer of GPU {} is full at {} samples, evicted {} still within the {} window",
+                    device.minor_number,
+                    capacity,
+                    evicted,
+                    humantime::format_duration(window)
                 );
+                // Only the first eviction per GPU is warned about, a full buffer stays full every scrape
+                if warned.insert(device.minor_number.clone()) {
+                    warn!("{}", message);
+                } else {
+                    debug!("{}", message);
+                }
+                self.sample_buffer_evictions.inc_by(evicted as f64);
             }
         }
     }
 
-    #[test]
-    fn test_exporter_clone() {
-        let exporter1 = Exporter::new();
-        let exporter2 = exporter1.clone();
-        
-        let mfs1 = exporter1.gather();
-        let mfs2 = exporter2.gather();
-        
-        // Both should produce metrics
-        assert!(!mfs1.is_empty());
-        assert!(!mfs2.is_empty());
-        
-        // Should have the same number of metric families
-        assert_eq!(mfs1.len(), mfs2.len());
+    /// Constant labels as label pairs, for the families built by hand
+    fn const_label_pairs(&self) -> Vec<LabelPair> {
+        self.config
+            .const_labels
+            .iter()
+            .map(|(name, value)| {
+                let mut label = LabelPair::default();
+                label.set_name(name.clone());
+                label.set_value(value.clone());
+                label
+            })
+            .collect()
     }
 
-    #[test]
-    fn test_up_metric_on_error() {
-        let exporter = Exporter::new();
-        
-        // First gather - if NVML is not available, up should be 0
-        let mfs = exporter.gather();
-        
-        // Find the up metric
-        let up_metric = mfs.iter().find(|mf| mf.get_name() == format!("{}_up", NAMESPACE));
-        assert!(up_metric.is_some(), "up metric should always be present");
+    /// Constant labels added to every metric
+    pub fn const_labels(&self) -> &HashMap<String, String> {
+        &self.config.const_labels
     }
 
-    #[test]
-    fn test_device_count_metric_always_present() {
-        let exporter = Exporter::new();
-        let mfs = exporter.gather();
-        
-        // Find the device_count metric
-        let device_count_metric = mfs.iter()
-            .find(|mf| mf.get_name() == format!("{}_device_count", NAMESPACE));
-        assert!(device_count_metric.is_some(), "device_count metric should always be present");
-        
-        // Verify it has at least one metric value
-        let mf = device_count_metric.unwrap();
-        assert!(!mf.get_metric().is_empty(), "device_count should have at least one metric value");
-        
-        // Get the actual value
-        let metric = &mf.get_metric()[0];
-        let value = metric.get_gauge().get_value();
-        
-        // Value should be >= 0 (0 if no GPUs or error, positive if GPUs found)
-        assert!(value >= 0.0, "device_count should be >= 0, got {}", value);
+    /// Summary of the power usage samples of each device over the configured window
+    fn power_usage_summary(&self) -> Vec<MetricFamily> {
+        let samples = self.power_samples.lock().unwrap_or_else(|e| e.into_inner());
+        let metrics: Vec<Metric> = samples
+            .iter()
+            .filter(|(_, window)| !window.is_empty())
+            .map(|(minor, window)| {
+                let quantiles: Vec<Quantile> = POWER_SUMMARY_QUANTILES
+                    .iter()
+                    .filter_map(|&q| {
+                        let mut quantile = Quantile::default();
+                        quantile.set_quantile(q);
+                        quantile.set_value(window.quantile(q)?);
+                        Some(quantile)
+                    })
+                    .collect();
+                let mut summary = Summary::default();
+                summary.set_sample_count(window.len() as u64);
+                summary.set_sample_sum(window.sum());
+                summary.set_quantile(quantiles.into());
+
+                let mut label = LabelPair::default();
+                label.set_name("minor".to_string());
+                label.set_value(minor.clone());
+                let mut labels = self.const_label_pairs();
+                labels.push(label);
+                // Labels stay sorted by name
+                labels.sort_by(|a, b| a.get_name().cmp(b.get_name()));
+                let mut metric = Metric::default();
+                metric.set_label(labels.into());
+                metric.set_summary(summary);
+                metric
+            })
+            .collect();
+
+        let mut mf = MetricFamily::default();
+        mf.set_name(format!("{}_power_usage_milliwatts", NAMESPACE));
+        mf.set_help("Distribution of the power usage in milliwatts over the sample window".to_string());
+        mf.set_field_type(MetricType::SUMMARY);
+        mf.set_metric(metrics.into());
+        vec![mf]
+    }
+
+    /// Export the P-State as one series per state P0-P15, with 1 for the active state.
+    /// The series are removed when the state is unknown or unsupported.
+    fn set_performance_state_one_hot(&self, minor: &str, state: Option<f64>) {
+        for pstate in 0..16 {
+            let label = format!("P{}", pstate);
+            match state {
+                Some(current) if current < 16.0 => {
+                    let active = if current as u32 == pstate { 1.0 } else { 0.0 };
+                    self.performance_state_current
+                        .with_label_values(&[minor, &label])
+                        .set(active);
+                }
+                _ => {
+                    let _ = self.performance_state_current.remove_label_values(&[minor, &label]);
+                }
+            }
+        }
+    }
+
+    /// Export the reason limiting the clocks as a one-hot set of series, removing
+    /// them all when the throttle reasons are unknown
+    fn set_active_clock_limit(&self, minor: &str, reasons: Option<u64>) {
+        let active = reasons.map(active_clock_limit);
+        let labels = CLOCK_LIMIT_REASONS.iter().map(|(reason, _)| *reason).chain([CLOCK_LIMIT_NONE]);
+        for reason in labels {
+            match active {
+                Some(active) => {
+                    let value = if active == reason { 1.0 } else { 0.0 };
+                    self.active_clock_limit.with_label_values(&[minor, reason]).set(value);
+                }
+                None => {
+                    let _ = self.active_clock_limit.remove_label_values(&[minor, reason]);
+                }
+            }
+        }
+    }
+
+    /// Export the recommended recovery action as a one-hot set of series, all 0 for
+    /// an action this exporter doesn't know, removing them when it's unsupported
+    fn set_recovery_action(&self, minor: &str, action: Option<u64>) {
+        for (name, value) in RECOVERY_ACTIONS {
+            match action {
+                Some(action) => {
+                    let recommended = if action == value { 1.0 } else { 0.0 };
+                    self.recommended_recovery_action.with_label_values(&[minor, name]).set(recommended);
+                }
+                None => {
+                    let _ = self.recommended_recovery_action.remove_label_values(&[minor, name]);
+                }
+            }
+        }
+    }
+
+    /// Export the totals and mean utilization across `devices`, leaving out the
+    /// lost ones, and leaving the aggregates out when there are none to aggregate
+    fn set_node_aggregates(&self, devices: &[Device]) {
+        let devices: Vec<&Device> = devices.iter().filter(|d| !d.lost).collect();
+        if devices.is_empty() {
+            self.node_power_usage.reset();
+            self.node_memory_used.reset();
+            self.node_utilization_gpu_average.reset();
+            return;
+        }
+        let power_usage: f64 = devices.iter().map(|d| d.power_usage).sum();
+        let memory_used: f64 = devices.iter().map(|d| d.memory_used).sum();
+        let utilization: f64 = devices.iter().map(|d| d.utilization_gpu_average).sum::<f64>() / devices.len() as f64;
+        self.node_power_usage.with_label_values(&[]).set(power_usage);
+        self.node_memory_used.with_label_values(&[]).set(memory_used);
+        self.node_utilization_gpu_average
+            .with_label_values(&[])
+            .set(self.utilization(utilization));
+    }
+
+    /// Export the GPU memory used by each process of a device, tagged with the
+    /// job resolved from the process's cgroup when `enable_cgroup_labels` is set,
+    /// its command name when `enable_process_names` is set and with the GPU
+    /// instance of processes on MIG devices
+    fn set_process_memory(&self, device: &Device) {
+        for process in &device.processes {
+            let Some(memory_used) = process.memory_used else {
+                continue;
+            };
+            let pid = process.pid.to_string();
+            let (job, name);
+            let mut labels = vec![device.minor_number.as_str(), &pid];
+            if self.config.enable_cgroup_labels {
+                job = cgroup::job_label(&self.proc_root, process.pid).unwrap_or_default();
+                labels.push(&job);
+            }
+            if self.config.enable_process_names {
+                name = process::process_name(&self.proc_root, process.pid).unwrap_or_default();
+                labels.push(&name);
+            }
+            match process.gpu_instance_id {
+                Some(gi_id) => {
+                    let gi_id = gi_id.to_string();
+                    labels.push(&gi_id);
+                    self.process_memory_used_mig.with_label_values(&labels).set(memory_used);
+                }
+                None => self.process_memory_used.with_label_values(&labels).set(memory_used),
+            }
+        }
+    }
+
+    /// Count the NVML calls of a collection when `enable_call_metrics` is set
+    fn count_nvml_call_results(&self, results: &CallResults) {
+        if !self.config.enable_call_metrics {
+            return;
+        }
+        for ((call, result), count) in results {
+            self.nvml_call_results
+                .with_label_values(&[call, result])
+                .inc_by(*count as f64);
+        }
+    }
+
+    /// Record a collection that did not finish within the scrape's time budget
+    pub fn record_collection_timeout(&self) {
+        self.collection_generation.fetch_add(1, Ordering::SeqCst);
+        self.collected.store(true, Ordering::SeqCst);
+        self.up.set(0.0);
+        self.last_scrape_success.set(0.0);
+    }
+
+    /// Set a per-device metric that has no meaningful fallback, removing the series
+    /// when the value is missing
+    fn set_if_present(&self, gauge: &GaugeVec, minor: &str, value: Option<f64>) {
+        match value {
+            Some(v) => gauge.with_label_values(&[minor]).set(v),
+            None => {
+                let _ = gauge.remove_label_values(&[minor]);
+            }
+        }
+    }
+
+    /// Round a metric that is a whole number by nature when `round_integers` is
+    /// enabled, dropping float noise like 49.99999 picked up on the way
+    fn whole(&self, value: f64) -> f64 {
+        if self.config.round_integers {
+            value.round()
+        } else {
+            value
+        }
+    }
+
+    /// Convert a utilization percentage to the configured scale, rounding the
+    /// percentage first so that `round_integers` doesn't flatten ratios to 0 or 1
+    fn utilization(&self, percent: f64) -> f64 {
+        self.config.utilization_scale.convert_percent(self.whole(percent))
+    }
+
+    /// Set an optional per-device metric, reporting 0 when unsupported unless
+    /// `drop_zero_value_optional_metrics` is enabled, in which case the series is removed
+    fn set_optional(&self, gauge: &GaugeVec, minor: &str, value: Option<f64>) {
+        match value {
+            Some(v) => gauge.with_label_values(&[minor]).set(v),
+            None if self.config.drop_zero_value_optional_metrics => {
+                let _ = gauge.remove_label_values(&[minor]);
+            }
+            None => gauge.with_label_values(&[minor]).set(0.0),
+        }
+    }
+}
+
+/// Stable label value for the error that failed a collection. Errors that don't come
+/// from NVML (e.g. the nvidia-smi fallback failing) are reported as `other`.
+fn collection_error_code(e: &anyhow::Error) -> &'static str {
+    e.downcast_ref::<NvmlError>().map_or("other", nvml_error_code)
+}
+
+/// Name of a metric type as used in the exposition format
+fn type_name(metric_type: MetricType) -> &'static str {
+    match metric_type {
+        MetricType::COUNTER => "counter",
+        MetricType::GAUGE => "gauge",
+        MetricType::SUMMARY => "summary",
+        MetricType::HISTOGRAM => "histogram",
+        MetricType::UNTYPED => "untyped",
+    }
+}
+
+/// Speed of the fastest fan, `None` when no fan reports one
+fn max_fan_speed(fans: &[(u32, f64)]) -> Option<f64> {
+    fans.iter().map(|(_, speed)| *speed).reduce(f64::max)
+}
+
+/// Whether the driver reports the clocks held by a user-defined clock setting,
+/// from its applications clocks setting throttle reason (which NVML also calls
+/// `UserDefinedClocks`) alone, so other throttle reasons don't count. NVML has no
+/// query for the locked clocks themselves. `None` when the throttle reasons
+/// aren't reported.
+fn clock_user_locked(device: &Device) -> Option<f64> {
+    let reasons = device.current_throttle_reasons?;
+    Some(if reasons & throttle::APPLICATIONS_CLOCKS_SETTING != 0 { 1.0 } else { 0.0 })
+}
+
+/// How far the graphics clock falls short of the application clock, if one is set.
+/// Running above it (boosting) counts as no shortfall, and so does an idle GPU, which
+/// drops its clocks without anything throttling it.
+fn app_clock_shortfall(device: &Device) -> Option<f64> {
+    let shortfall = (device.app_clock_graphics? - device.clock_graphics?).max(0.0);
+    let idle = device.current_throttle_reasons.is_some_and(|reasons| reasons & !throttle::GPU_IDLE == 0);
+    Some(if idle { 0.0 } else { shortfall })
+}
+
+/// Pick the highest-priority reason of `CLOCK_LIMIT_REASONS` active in a throttle
+/// reasons bitmask
+fn active_clock_limit(reasons: u64) -> &'static str {
+    CLOCK_LIMIT_REASONS
+        .iter()
+        .find(|(_, bits)| reasons & bits != 0)
+        .map_or(CLOCK_LIMIT_NONE, |(reason, _)| reason)
+}
+
+/// Sort devices by PCI bus ID and renumber their indices to match. Devices
+/// without a known bus ID go last in NVML order. The minor number is unchanged.
+fn sort_by_pci_bus_id(devices: &mut [Device]) {
+    devices.sort_by(|a, b| match (&a.pci_bus_id, &b.pci_bus_id) {
+        (Some(a), Some(b)) => a.cmp(b),
+        (Some(_), None) => std::cmp::Ordering::Less,
+        (None, Some(_)) => std::cmp::Ordering::Greater,
+        (None, None) => std::cmp::Ordering::Equal,
+    });
+    for (index, device) in devices.iter_mut().enumerate() {
+        device.index = index.to_string();
+    }
+}
+
+/// Memory used by the processes matching `listed`, skipping those that don't report it
+fn processes_memory(processes: &[GpuProcess], listed: fn(&GpuProcess) -> bool) -> f64 {
+    processes.iter().filter(|p| listed(p)).filter_map(|p| p.memory_used).sum()
+}
+
+/// Give devices sharing a minor number (as MIG setups can report) their UUID as
+/// minor number instead, so their series don't overwrite each other. Unlike the
+/// index, the UUID doesn't change as devices come and go or with --device-order.
+/// Returns the minor numbers given to the re-keyed devices.
+fn disambiguate_minor_numbers(devices: &mut [Device]) -> BTreeSet<String> {
+    let mut counts: BTreeMap<String, usize> = BTreeMap::new();
+    for device in devices.iter() {
+        *counts.entry(device.minor_number.clone()).or_default() += 1;
+    }
+
+    let mut colliding = BTreeSet::new();
+    for device in devices.iter_mut().filter(|d| counts[&d.minor_number] > 1) {
+        let minor_number = device.uuid.clone();
+        warn!(
+            "GPU {} shares minor number {} with another GPU, labelling it {} instead",
+            device.index, device.minor_number, minor_number
+        );
+        device.minor_number = minor_number.clone();
+        colliding.insert(minor_number);
+    }
+    colliding
+}
+
+/// Stable stand-in for a GPU UUID: the hex SHA-256 of the salt followed by the UUID
+fn redact_uuid(salt: &str, uuid: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(salt.as_bytes());
+    hasher.update(uuid.as_bytes());
+    format!("{:x}", hasher.finalize())
+}
+
+/// Margin between the memory temperature and its slowdown threshold, when the
+/// device reports both
+fn memory_temperature_margin(device: &Device) -> Option<f64> {
+    Some(device.memory_temperature_threshold? - device.memory_temperature?)
+}
+
+/// GPU utilization percent per watt, unless the device reports no power usage
+fn power_efficiency(device: &Device) -> Option<f64> {
+    let watts = device.power_usage / 1000.0;
+    (watts > 0.0).then(|| device.utilization_gpu / watts)
+}
+
+/// Theoretical memory bandwidth in bytes/s from the memory clock in MHz and bus width in bits.
+///
+/// GDDR and HBM both transfer twice per clock as reported by NVML (e.g. 9501 MHz on a
+/// 320-bit GDDR6X RTX 3080 gives 760 GB/s, 1215 MHz on a 5120-bit HBM2 A100 gives 1555 GB/s).
+fn memory_bandwidth(clock_memory_mhz: Option<f64>, bus_width_bits: Option<f64>) -> Option<f64> {
+    const TRANSFERS_PER_CLOCK: f64 = 2.0;
+    let clock_hz = clock_memory_mhz? * 1_000_000.0;
+    let bus_width_bytes = bus_width_bits? / 8.0;
+    Some(clock_hz * TRANSFERS_PER_CLOCK * bus_width_bytes)
+}
+
+/// Encode metric families in the text exposition format. Unlike `TextEncoder`,
+/// which rejects families without samples, this writes only the HELP and TYPE
+/// lines for them.
+pub fn encode_text<W: Write>(mfs: &[MetricFamily], writer: &mut W) -> prometheus::Result<()> {
+    for mf in mfs {
+        if !mf.get_metric().is_empty() {
+            TextEncoder::new().encode(std::slice::from_ref(mf), writer)?;
+            continue;
+        }
+        if mf.get_name().is_empty() {
+            return Err(prometheus::Error::Msg(format!("MetricFamily has no name: {:?}", mf)));
+        }
+        let help = mf.get_help().replace('\\', "\\\\").replace('\n', "\\n");
+        let kind = format!("{:?}", mf.get_field_type()).to_lowercase();
+        writeln!(writer, "# HELP {} {}", mf.get_name(), help)?;
+        writeln!(writer, "# TYPE {} {}", mf.get_name(), kind)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::{EncoderSession, InforomVersions, MockMetricsCollector, RowRemapAvailability};
+    use std::collections::HashSet;
+    use std::sync::atomic::AtomicUsize;
+    use tracing_test::traced_test;
+
+    fn mock_exporter(device: Device, config: ExporterConfig) -> Exporter {
+        let mut collector = MockMetricsCollector::new();
+        collector.expect_collect().returning(move || {
+            Ok(Metrics {
+                version: "525.116.04".to_string(),
+                devices: vec![device.clone()],
+                ..Default::default()
+            })
+        });
+        Exporter::with_collector(Arc::new(collector), config)
+    }
+
+    fn mock_device() -> Device {
+        Device {
+            index: "0".to_string(),
+            minor_number: "0".to_string(),
+            name: "NVIDIA GeForce RTX 3080".to_string(),
+            uuid: "GPU-12345678-1234-1234-1234-123456789012".to_string(),
+            pci_bus_id: Some("0000:65:00.0".to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn encode(mfs: &[MetricFamily]) -> String {
+        let mut buffer = Vec::new();
+        encode_text(mfs, &mut buffer).expect("Failed to encode metrics");
+        String::from_utf8(buffer).unwrap()
+    }
+
+    fn find_family<'a>(mfs: &'a [MetricFamily], suffix: &str) -> Option<&'a MetricFamily> {
+        let name = format!("{}_{}", NAMESPACE, suffix);
+        mfs.iter().find(|mf| mf.get_name() == name)
     }
 
     #[test]
-    fn test_device_count_set_to_zero_on_error() {
+    fn test_exporter_creation() {
         let exporter = Exporter::new();
         let mfs = exporter.gather();
-        
-        // Find the device_count metric
-        let device_count_metric = mfs.iter()
-            .find(|mf| mf.get_name() == format!("{}_device_count", NAMESPACE))
-            .expect("device_count metric should always be present");
-        
-        // Find the up metric to determine if there was an error
-        let up_metric = mfs.iter()
-            .find(|mf| mf.get_name() == format!("{}_up", NAMESPACE))
-            .expect("up metric should always be present");
-        
-        let up_value = up_metric.get_metric()[0].get_gauge().get_value();
-        let device_count_value = device_count_metric.get_metric()[0].get_gauge().get_value();
-        
-        // If up is 0 (error case), device_count should also be 0
-        if up_value == 0.0 {
-            assert_eq!(
-                device_count_value, 0.0,
-                "When up metric is 0 (error), device_count should be 0, got {}",
-                device_count_value
-            );
-        }
-        
-        // device_count should always be >= 0 regardless
-        assert!(device_count_value >= 0.0, "device_count should always be >= 0");
+        // Should always have at least the 'up' metric
+        assert!(!mfs.is_empty());
     }
 
     #[test]
-    fn test_namespace_consistency() {
-        let exporter = Exporter::new();
+    fn test_exporter_default_trait() {
+        let exporter = Exporter::default();
         let mfs = exporter.gather();
+        // Should work the same as new()
+        assert!(!mfs.is_empty());
+    }
 
-        // All metrics should have the correct namespace
-        for mf in mfs {
-            let name = mf.get_name();
-            if !name.is_empty() {
-                assert!(
-                    name.starts_with(&format!("{}_", NAMESPACE)),
-                    "Metric {} doesn't have correct namespace",
-                    name
-                );
-            }
-        }
+    #[test]
+    fn test_always_emit_metric_types() {
+        let exporter_without_devices = |always_emit_metric_types| {
+            let mut collector = MockMetricsCollector::new();
+            collector.expect_collect().returning(|| Ok(Metrics::default()));
+            let config = ExporterConfig {
+                always_emit_metric_types,
+                ..Default::default()
+            };
+            Exporter::with_collector(Arc::new(collector), config)
+        };
+
+        let text = encode(&exporter_without_devices(false).gather());
+        assert!(!text.contains("nvidia_temperatures"), "{}", text);
+
+        let text = encode(&exporter_without_devices(true).gather());
+        assert!(text.contains("# HELP nvidia_temperatures Temperature as reported by the device\n"), "{}", text);
+        assert!(text.contains("# TYPE nvidia_temperatures gauge\n"), "{}", text);
+        assert!(!text.lines().any(|line| line.starts_with("nvidia_temperatures")), "{}", text);
+        // Families with samples are encoded as before
+        assert!(text.contains("# TYPE nvidia_up gauge\nnvidia_up 1\n"), "{}", text);
     }
 
     #[test]
-    fn test_metric_labels() {
+    fn test_node_aggregates() {
+        let devices = vec![
+            Device {
+                power_usage: 250_000.0,
+                memory_used: 4.0 * 1024.0 * 1024.0 * 1024.0,
+                utilization_gpu_average: 80.0,
+                ..mock_device()
+            },
+            Device {
+                index: "1".to_string(),
+                minor_number: "1".to_string(),
+                power_usage: 150_000.0,
+                memory_used: 2.0 * 1024.0 * 1024.0 * 1024.0,
+                utilization_gpu_average: 40.0,
+                ..mock_device()
+            },
+        ];
+        let exporter_with = |devices: Vec<Device>, emit_node_aggregates| {
+            let mut collector = MockMetricsCollector::new();
+            collector.expect_collect().returning(move || {
+                Ok(Metrics {
+                    devices: devices.clone(),
+                    ..Default::default()
+                })
+            });
+            let config = ExporterConfig {
+                emit_node_aggregates,
+                ..Default::default()
+            };
+            Exporter::with_collector(Arc::new(collector), config)
+        };
+        let value = |mfs: &[MetricFamily], name: &str| find_family(mfs, name).map(|mf| mf.get_metric()[0].get_gauge().get_value());
+
+        let mfs = exporter_with(devices.clone(), true).gather();
+        assert_eq!(value(&mfs, "node_power_usage_milliwatts"), Some(400_000.0));
+        assert_eq!(value(&mfs, "node_memory_used_bytes"), Some(6.0 * 1024.0 * 1024.0 * 1024.0));
+        assert_eq!(value(&mfs, "node_utilization_gpu_average"), Some(60.0));
+
+        // Opt-in, and nothing to aggregate without devices
+        let mfs = exporter_with(devices, false).gather();
+        assert_eq!(value(&mfs, "node_power_usage_milliwatts"), None);
+        let mfs = exporter_with(Vec::new(), true).gather();
+        assert_eq!(value(&mfs, "node_power_usage_milliwatts"), None);
+        assert_eq!(value(&mfs, "node_utilization_gpu_average"), None);
+    }
+
+    #[test]
+    fn test_exporter_metrics_structure() {
         let exporter = Exporter::new();
         let mfs = exporter.gather();
 
-        // Check that device-specific metrics have the expected labels
-        let device_metrics = vec!["temperatures", "power_usage", "fanspeed", "memory_total", 
-                                  "memory_used", "utilization_gpu", "utilization_memory"];
+        // Check that we have the expected metric families
+        let metric_names: Vec<String> = mfs.iter().map(|mf| mf.get_name().to_string()).collect();
         
-        for metric_suffix in device_metrics {
-            let metric_name = format!("{}_{}", NAMESPACE, metric_suffix);
-            if let Some(mf) = mfs.iter().find(|m| m.get_name() == metric_name) {
-                // If there are any metrics, they should have the 'minor' label
-                for metric in mf.get_metric() {
-                    let labels: Vec<String> = metric.get_label()
-                        .iter()
-                        .map(|l| l.get_name().to_string())
-                        .collect();
-                    
-                    if !labels.is_empty() {
-                        assert!(labels.contains(&"minor".to_string()),
-                            "Metric {} should have 'minor' label", metric_name);
-                    }
-                }
-            }
-        }
+        assert!(metric_names.contains(&format!("{}_up", NAMESPACE)));
+        assert!(metric_names.contains(&format!("{}_device_count", NAMESPACE)));
+        assert!(metric_names.contains(&format!("{}_driver_info", NAMESPACE)));
     }
 
     #[test]
-    fn test_new_metrics_structure() {
+    fn test_all_expected_metrics_present() {
         let exporter = Exporter::new();
         let mfs = exporter.gather();
+
         let metric_names: Vec<String> = mfs.iter().map(|mf| mf.get_name().to_string()).collect();
         
+        // Core metrics that should always be present
+        let core_metrics = vec![
+            format!("{}_up", NAMESPACE),
+            format!("{}_device_count", NAMESPACE),
+            format!("{}_driver_info", NAMESPACE),
+        ];
+
+        for expected in core_metrics {
+            assert!(
+                metric_names.contains(&expected),
+                "Missing core metric: {}",
+                expected
+            );
+        }
+
         // Get device count to determine if device-specific metrics should be present
         let device_count_metric = mfs.iter()
             .find(|mf| mf.get_name() == format!("{}_device_count", NAMESPACE))
@@ -705,33 +3106,2692 @@ mod tests {
 
         // Device-specific metrics should only be present if there are devices
         if device_count > 0 {
-            // Test clock metrics are present
-            assert!(metric_names.contains(&format!("{}_clock_graphics_mhz", NAMESPACE)));
-            assert!(metric_names.contains(&format!("{}_clock_memory_mhz", NAMESPACE)));
-            
-            // Test power limit metrics
-            assert!(metric_names.contains(&format!("{}_power_limit_milliwatts", NAMESPACE)));
-            
-            // Test PCIe metrics
-            assert!(metric_names.contains(&format!("{}_pcie_link_generation", NAMESPACE)));
-            assert!(metric_names.contains(&format!("{}_pcie_link_width", NAMESPACE)));
-            
-            // Test encoder/decoder metrics
-            assert!(metric_names.contains(&format!("{}_encoder_utilization", NAMESPACE)));
-            assert!(metric_names.contains(&format!("{}_decoder_utilization", NAMESPACE)));
-            
-            // Test ECC metrics
-            assert!(metric_names.contains(&format!("{}_ecc_errors_corrected_total", NAMESPACE)));
-            
-            // Test process metrics
-            assert!(metric_names.contains(&format!("{}_compute_processes", NAMESPACE)));
-            assert!(metric_names.contains(&format!("{}_graphics_processes", NAMESPACE)));
-        } else {
-            // When no devices, these metrics may be filtered out (which is expected)
-            // Just verify core metrics are present
-            assert!(metric_names.contains(&format!("{}_up", NAMESPACE)));
-            assert!(metric_names.contains(&format!("{}_device_count", NAMESPACE)));
-            assert!(metric_names.contains(&format!("{}_driver_info", NAMESPACE)));
-        }
+            let device_metrics = vec![
+                format!("{}_info", NAMESPACE),
+                format!("{}_temperatures", NAMESPACE),
+                format!("{}_fanspeed", NAMESPACE),
+                format!("{}_memory_total", NAMESPACE),
+                format!("{}_memory_used", NAMESPACE),
+                format!("{}_utilization_memory", NAMESPACE),
+                format!("{}_utilization_gpu", NAMESPACE),
+                format!("{}_utilization_gpu_average", NAMESPACE),
+                format!("{}_power_usage", NAMESPACE),
+                format!("{}_power_usage_average", NAMESPACE),
+                format!("{}_power_limit_milliwatts", NAMESPACE),
+                format!("{}_power_limit_default_milliwatts", NAMESPACE),
+                format!("{}_clock_graphics_mhz", NAMESPACE),
+                format!("{}_clock_sm_mhz", NAMESPACE),
+                format!("{}_clock_memory_mhz", NAMESPACE),
+                format!("{}_clock_graphics_max_mhz", NAMESPACE),
+                format!("{}_clock_sm_max_mhz", NAMESPACE),
+                format!("{}_clock_memory_max_mhz", NAMESPACE),
+                format!("{}_performance_state", NAMESPACE),
+                format!("{}_pcie_link_generation", NAMESPACE),
+                format!("{}_pcie_link_width", NAMESPACE),
+                format!("{}_pcie_tx_throughput_kb", NAMESPACE),
+                format!("{}_pcie_rx_throughput_kb", NAMESPACE),
+                format!("{}_encoder_utilization", NAMESPACE),
+                format!("{}_decoder_utilization", NAMESPACE),
+                format!("{}_ecc_errors_corrected_total", NAMESPACE),
+                format!("{}_ecc_errors_uncorrected_total", NAMESPACE),
+                format!("{}_compute_processes", NAMESPACE),
+                format!("{}_graphics_processes", NAMESPACE),
+            ];
+
+            for expected in device_metrics {
+                assert!(
+                    metric_names.contains(&expected),
+                    "Missing device metric: {} (device_count={})",
+                    expected,
+                    device_count
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_exporter_clone() {
+        let exporter1 = Exporter::new();
+        let exporter2 = exporter1.clone();
+        
+        let mfs1 = exporter1.gather();
+        let mfs2 = exporter2.gather();
+        
+        // Both should produce metrics
+        assert!(!mfs1.is_empty());
+        assert!(!mfs2.is_empty());
+        
+        // Should have the same number of metric families
+        assert_eq!(mfs1.len(), mfs2.len());
+    }
+
+    #[test]
+    fn test_up_metric_on_error() {
+        let exporter = Exporter::new();
+        
+        // First gather - if NVML is not available, up should be 0
+        let mfs = exporter.gather();
+        
+        // Find the up metric
+        let up_metric = mfs.iter().find(|mf| mf.get_name() == format!("{}_up", NAMESPACE));
+        assert!(up_metric.is_some(), "up metric should always be present");
+    }
+
+    fn failing_exporter(error: anyhow::Error) -> Exporter {
+        let mut collector = MockMetricsCollector::new();
+        collector.expect_collect().return_once(move || Err(error));
+        Exporter::with_collector(Arc::new(collector), ExporterConfig::default())
+    }
+
+    #[test]
+    fn test_permission_denied_metric() {
+        let exporter = failing_exporter(NvmlError::NoPermission.into());
+        let mfs = exporter.gather();
+
+        let mf = find_family(&mfs, "nvml_permission_denied").expect("nvml_permission_denied should be present");
+        assert_eq!(mf.get_metric()[0].get_gauge().get_value(), 1.0);
+        let mf = find_family(&mfs, "up").unwrap();
+        assert_eq!(mf.get_metric()[0].get_gauge().get_value(), 0.0);
+    }
+
+    #[test]
+    fn test_other_errors_are_not_permission_denied() {
+        let exporter = failing_exporter(NvmlError::DriverNotLoaded.into());
+        let mfs = exporter.gather();
+
+        let mf = find_family(&mfs, "nvml_permission_denied").unwrap();
+        assert_eq!(mf.get_metric()[0].get_gauge().get_value(), 0.0);
+    }
+
+    #[test]
+    fn test_last_collection_error_code() {
+        let collections = Arc::new(AtomicU64::new(0));
+        let mut collector = MockMetricsCollector::new();
+        collector.expect_collect().returning({
+            let collections = Arc::clone(&collections);
+            move || match collections.fetch_add(1, Ordering::Relaxed) {
+                0 => Err(NvmlError::NoPermission.into()),
+                1 => Err(NvmlError::DriverNotLoaded.into()),
+                _ => Ok(Metrics {
+                    devices: vec![mock_device()],
+                    ..Default::default()
+                }),
+            }
+        });
+        let exporter = Exporter::with_collector(Arc::new(collector), ExporterConfig::default());
+
+        let text = encode(&exporter.gather());
+        assert!(text.contains(r#"nvidia_last_collection_error_code{code="no_permission"} 1"#));
+
+        // Only the current code is exported
+        let text = encode(&exporter.gather());
+        assert!(text.contains(r#"nvidia_last_collection_error_code{code="driver_not_loaded"} 1"#));
+        assert!(!text.contains("no_permission"));
+
+        // A successful collection clears it
+        let mfs = exporter.gather();
+        assert!(find_family(&mfs, "last_collection_error_code").is_none());
+    }
+
+    /// Exporter whose first collection succeeds and all later ones fail
+    fn flaky_exporter(stale_tolerance: Option<Duration>) -> Exporter {
+        let collections = Arc::new(AtomicU64::new(0));
+        let mut collector = MockMetricsCollector::new();
+        collector.expect_collect().returning(move || match collections.fetch_add(1, Ordering::Relaxed) {
+            0 => Ok(Metrics {
+                version: "525.116.04".to_string(),
+                devices: vec![Device {
+                    temperature: 65.0,
+                    ..mock_device()
+                }],
+                ..Default::default()
+            }),
+            _ => Err(NvmlError::Unknown.into()),
+        });
+        let config = ExporterConfig {
+            stale_tolerance,
+            ..ExporterConfig::default()
+        };
+        Exporter::with_collector(Arc::new(collector), config)
+    }
+
+    fn gauge_value(mfs: &[MetricFamily], name: &str) -> Option<f64> {
+        find_family(mfs, name).map(|mf| mf.get_metric()[0].get_gauge().get_value())
+    }
+
+    #[test]
+    fn test_stale_tolerance_fresh_success() {
+        let exporter = flaky_exporter(Some(Duration::from_secs(3600)));
+        let mfs = exporter.gather();
+
+        assert_eq!(gauge_value(&mfs, "up"), Some(1.0));
+        assert_eq!(gauge_value(&mfs, "serving_stale"), Some(0.0));
+        assert_eq!(gauge_value(&mfs, "snapshot_age_seconds"), Some(0.0));
+    }
+
+    #[test]
+    fn test_stale_tolerance_serves_last_success() {
+        let exporter = flaky_exporter(Some(Duration::from_secs(3600)));
+        exporter.gather();
+        let mfs = exporter.gather();
+
+        assert_eq!(gauge_value(&mfs, "up"), Some(1.0));
+        assert_eq!(gauge_value(&mfs, "last_scrape_success"), Some(1.0));
+        assert_eq!(gauge_value(&mfs, "serving_stale"), Some(1.0));
+        assert!(gauge_value(&mfs, "snapshot_age_seconds").unwrap() > 0.0);
+        assert_eq!(gauge_value(&mfs, "device_count"), Some(1.0));
+        assert_eq!(gauge_value(&mfs, "temperatures"), Some(65.0));
+        // The failure is still visible
+        assert!(find_family(&mfs, "last_collection_error_code").is_some());
+        assert!(exporter.last_devices().is_some());
+        assert!(exporter.gather_device(0).is_some());
+    }
+
+    #[test]
+    fn test_last_collection_time_only_on_success() {
+        let exporter = flaky_exporter(None);
+        exporter.refresh().unwrap();
+        let collected = exporter.last_collection_time().unwrap();
+
+        std::thread::sleep(Duration::from_millis(10));
+        assert!(exporter.refresh().is_err());
+        exporter.record_collection_timeout();
+        assert_eq!(exporter.last_collection_time(), Some(collected));
+    }
+
+    #[test]
+    fn test_stale_tolerance_elapsed() {
+        let exporter = flaky_exporter(Some(Duration::ZERO));
+        exporter.gather();
+        let mfs = exporter.gather();
+
+        assert_eq!(gauge_value(&mfs, "up"), Some(0.0));
+        assert_eq!(gauge_value(&mfs, "serving_stale"), Some(0.0));
+        assert!(gauge_value(&mfs, "snapshot_age_seconds").unwrap() > 0.0);
+        assert_eq!(gauge_value(&mfs, "device_count"), Some(0.0));
+        assert!(exporter.last_devices().is_none());
+
+        // Without a tolerance every failure reports up=0
+        let exporter = flaky_exporter(None);
+        exporter.gather();
+        assert_eq!(gauge_value(&exporter.gather(), "up"), Some(0.0));
+    }
+
+    #[test]
+    fn test_delta_mode() {
+        let temperatures = Arc::new(Mutex::new(vec![60.0, 60.0, 65.0].into_iter()));
+        let mut collector = MockMetricsCollector::new();
+        collector.expect_collect().returning(move || {
+            Ok(Metrics {
+                version: "525.116.04".to_string(),
+                devices: vec![Device {
+                    temperature: temperatures.lock().unwrap().next().unwrap(),
+                    fan_speed: 40.0,
+                    ..mock_device()
+                }],
+                ..Default::default()
+            })
+        });
+        let config = ExporterConfig {
+            delta_mode: true,
+            ..ExporterConfig::default()
+        };
+        let exporter = Exporter::with_collector(Arc::new(collector), config);
+
+        // Everything goes out on the first scrape
+        let mfs = exporter.omit_unchanged(exporter.gather());
+        assert_eq!(gauge_value(&mfs, "temperatures"), Some(60.0));
+        assert_eq!(gauge_value(&mfs, "fanspeed"), Some(40.0));
+
+        // Nothing changed, so only the core metrics are left
+        let mfs = exporter.omit_unchanged(exporter.gather());
+        assert!(find_family(&mfs, "temperatures").is_none());
+        assert!(find_family(&mfs, "fanspeed").is_none());
+        assert_eq!(gauge_value(&mfs, "up"), Some(1.0));
+        assert_eq!(gauge_value(&mfs, "device_count"), Some(1.0));
+
+        // Only the changed temperature is sent
+        let mfs = exporter.omit_unchanged(exporter.gather());
+        assert_eq!(gauge_value(&mfs, "temperatures"), Some(65.0));
+        assert!(find_family(&mfs, "fanspeed").is_none());
+        assert_eq!(gauge_value(&mfs, "up"), Some(1.0));
+    }
+
+    #[test]
+    fn test_delta_mode_disabled() {
+        let exporter = mock_exporter(mock_device(), ExporterConfig::default());
+        exporter.omit_unchanged(exporter.gather());
+        let mfs = exporter.omit_unchanged(exporter.gather());
+        assert!(find_family(&mfs, "temperatures").is_some());
+    }
+
+    #[test]
+    fn test_collection_duration_buckets() {
+        let config = ExporterConfig {
+            collection_duration_buckets: vec![0.1, 0.5, 2.0],
+            ..ExporterConfig::default()
+        };
+        let exporter = mock_exporter(mock_device(), config);
+        let mfs = exporter.gather();
+
+        let mf = find_family(&mfs, "collection_duration_seconds").expect("collection_duration_seconds should be present");
+        let histogram = mf.get_metric()[0].get_histogram();
+        let bounds: Vec<f64> = histogram.get_bucket().iter().map(|b| b.get_upper_bound()).collect();
+        assert_eq!(bounds, vec![0.1, 0.5, 2.0]);
+        assert_eq!(histogram.get_sample_count(), 1);
+
+        let exporter = mock_exporter(mock_device(), ExporterConfig::default());
+        let mfs = exporter.gather();
+        let mf = find_family(&mfs, "collection_duration_seconds").unwrap();
+        assert_eq!(mf.get_metric()[0].get_histogram().get_bucket().len(), DEFAULT_BUCKETS.len());
+    }
+
+    #[test]
+    fn test_collection_error_code() {
+        assert_eq!(collection_error_code(&NvmlError::GpuLost.into()), "gpu_lost");
+        assert_eq!(collection_error_code(&NvmlError::LibraryNotFound.into()), "library_not_found");
+        assert_eq!(collection_error_code(&NvmlError::UnexpectedVariant(42).into()), "wrapper_error");
+        assert_eq!(collection_error_code(&anyhow::anyhow!("nvidia-smi failed")), "other");
+    }
+
+    #[test]
+    fn test_device_count_metric_always_present() {
+        let exporter = Exporter::new();
+        let mfs = exporter.gather();
+        
+        // Find the device_count metric
+        let device_count_metric = mfs.iter()
+            .find(|mf| mf.get_name() == format!("{}_device_count", NAMESPACE));
+        assert!(device_count_metric.is_some(), "device_count metric should always be present");
+        
+        // Verify it has at least one metric value
+        let mf = device_count_metric.unwrap();
+        assert!(!mf.get_metric().is_empty(), "device_count should have at least one metric value");
+        
+        // Get the actual value
+        let metric = &mf.get_metric()[0];
+        let value = metric.get_gauge().get_value();
+        
+        // Value should be >= 0 (0 if no GPUs or error, positive if GPUs found)
+        assert!(value >= 0.0, "device_count should be >= 0, got {}", value);
+    }
+
+    #[test]
+    fn test_device_count_set_to_zero_on_error() {
+        let exporter = Exporter::new();
+        let mfs = exporter.gather();
+        
+        // Find the device_count metric
+        let device_count_metric = mfs.iter()
+            .find(|mf| mf.get_name() == format!("{}_device_count", NAMESPACE))
+            .expect("device_count metric should always be present");
+        
+        // Find the up metric to determine if there was an error
+        let up_metric = mfs.iter()
+            .find(|mf| mf.get_name() == format!("{}_up", NAMESPACE))
+            .expect("up metric should always be present");
+        
+        let up_value = up_metric.get_metric()[0].get_gauge().get_value();
+        let device_count_value = device_count_metric.get_metric()[0].get_gauge().get_value();
+        
+        // If up is 0 (error case), device_count should also be 0
+        if up_value == 0.0 {
+            assert_eq!(
+                device_count_value, 0.0,
+                "When up metric is 0 (error), device_count should be 0, got {}",
+                device_count_value
+            );
+        }
+        
+        // device_count should always be >= 0 regardless
+        assert!(device_count_value >= 0.0, "device_count should always be >= 0");
+    }
+
+    #[test]
+    fn test_namespace_consistency() {
+        let exporter = Exporter::new();
+        let mfs = exporter.gather();
+
+        // All metrics should have the correct namespace
+        for mf in mfs {
+            let name = mf.get_name();
+            if !name.is_empty() {
+                assert!(
+                    name.starts_with(&format!("{}_", NAMESPACE)),
+                    "Metric {} doesn't have correct namespace",
+                    name
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_metric_labels() {
+        let exporter = Exporter::new();
+        let mfs = exporter.gather();
+
+        // Check that device-specific metrics have the expected labels
+        let device_metrics = vec!["temperatures", "power_usage", "fanspeed", "memory_total", 
+                                  "memory_used", "utilization_gpu", "utilization_memory"];
+        
+        for metric_suffix in device_metrics {
+            let metric_name = format!("{}_{}", NAMESPACE, metric_suffix);
+            if let Some(mf) = mfs.iter().find(|m| m.get_name() == metric_name) {
+                // If there are any metrics, they should have the 'minor' label
+                for metric in mf.get_metric() {
+                    let labels: Vec<String> = metric.get_label()
+                        .iter()
+                        .map(|l| l.get_name().to_string())
+                        .collect();
+                    
+                    if !labels.is_empty() {
+                        assert!(labels.contains(&"minor".to_string()),
+                            "Metric {} should have 'minor' label", metric_name);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_new_metrics_structure() {
+        let exporter = Exporter::new();
+        let mfs = exporter.gather();
+        let metric_names: Vec<String> = mfs.iter().map(|mf| mf.get_name().to_string()).collect();
+        
+        // Get device count to determine if device-specific metrics should be present
+        let device_count_metric = mfs.iter()
+            .find(|mf| mf.get_name() == format!("{}_device_count", NAMESPACE))
+            .expect("device_count metric should be present");
+        let device_count = if let Some(metric) = device_count_metric.get_metric().first() {
+            metric.get_gauge().get_value() as u64
+        } else {
+            0
+        };
+
+        // Device-specific metrics should only be present if there are devices
+        if device_count > 0 {
+            // Test clock metrics are present
+            assert!(metric_names.contains(&format!("{}_clock_graphics_mhz", NAMESPACE)));
+            assert!(metric_names.contains(&format!("{}_clock_memory_mhz", NAMESPACE)));
+            
+            // Test power limit metrics
+            assert!(metric_names.contains(&format!("{}_power_limit_milliwatts", NAMESPACE)));
+            
+            // Test PCIe metrics
+            assert!(metric_names.contains(&format!("{}_pcie_link_generation", NAMESPACE)));
+            assert!(metric_names.contains(&format!("{}_pcie_link_width", NAMESPACE)));
+            
+            // Test encoder/decoder metrics
+            assert!(metric_names.contains(&format!("{}_encoder_utilization", NAMESPACE)));
+            assert!(metric_names.contains(&format!("{}_decoder_utilization", NAMESPACE)));
+            
+            // Test ECC metrics
+            assert!(metric_names.contains(&format!("{}_ecc_errors_corrected_total", NAMESPACE)));
+            
+            // Test process metrics
+            assert!(metric_names.contains(&format!("{}_compute_processes", NAMESPACE)));
+            assert!(metric_names.contains(&format!("{}_graphics_processes", NAMESPACE)));
+        } else {
+            // When no devices, these metrics may be filtered out (which is expected)
+            // Just verify core metrics are present
+            assert!(metric_names.contains(&format!("{}_up", NAMESPACE)));
+            assert!(metric_names.contains(&format!("{}_device_count", NAMESPACE)));
+            assert!(metric_names.contains(&format!("{}_driver_info", NAMESPACE)));
+        }
+    }
+
+    #[test]
+    fn test_unsupported_optional_metric_reports_zero_by_default() {
+        let device = Device {
+            clock_graphics: None,
+            ..mock_device()
+        };
+        let exporter = mock_exporter(device, ExporterConfig::default());
+        let mfs = exporter.gather();
+
+        let mf = find_family(&mfs, "clock_graphics_mhz").expect("clock_graphics_mhz should be present");
+        assert_eq!(mf.get_metric().len(), 1);
+        assert_eq!(mf.get_metric()[0].get_gauge().get_value(), 0.0);
+    }
+
+    #[test]
+    fn test_drop_zero_value_optional_metrics() {
+        let device = Device {
+            clock_graphics: None,
+            clock_memory: Some(9501.0),
+            ..mock_device()
+        };
+        let config = ExporterConfig {
+            drop_zero_value_optional_metrics: true,
+            ..Default::default()
+        };
+        let exporter = mock_exporter(device, config);
+        let mfs = exporter.gather();
+
+        assert!(
+            find_family(&mfs, "clock_graphics_mhz").is_none(),
+            "Unsupported clock should produce no series"
+        );
+        let mf = find_family(&mfs, "clock_memory_mhz").expect("clock_memory_mhz should be present");
+        assert_eq!(mf.get_metric()[0].get_gauge().get_value(), 9501.0);
+    }
+
+    #[test]
+    fn test_encoder_session_details_capped() {
+        let session = |codec: &str, resolution: &str, fps: f64, latency_us: f64| EncoderSession {
+            codec: codec.to_string(),
+            resolution: resolution.to_string(),
+            fps,
+            latency_us,
+        };
+        let device = Device {
+            encoder_session_details: vec![
+                session("h264", "1920x1080", 30.0, 900.0),
+                session("h264", "1920x1080", 60.0, 1200.0),
+                session("hevc", "3840x2160", 24.0, 2500.0),
+                session("h264", "1280x720", 30.0, 500.0),
+            ],
+            ..mock_device()
+        };
+        let config = ExporterConfig {
+            max_sessions_per_gpu: 3,
+            ..Default::default()
+        };
+        let exporter = mock_exporter(device, config);
+        let mfs = exporter.gather();
+        let text = encode(&mfs);
+
+        assert!(text.contains(r#"nvidia_encoder_session_fps{codec="h264",minor="0",resolution="1920x1080"} 90"#));
+        assert!(text.contains(r#"nvidia_encoder_session_latency_microseconds{codec="h264",minor="0",resolution="1920x1080"} 1200"#));
+        assert!(text.contains(r#"nvidia_encoder_session_fps{codec="hevc",minor="0",resolution="3840x2160"} 24"#));
+        // The fourth session is beyond the cap
+        assert!(!text.contains("1280x720"));
+    }
+
+    #[test]
+    fn test_encoder_session_details_unsupported() {
+        let exporter = mock_exporter(mock_device(), ExporterConfig::default());
+        let mfs = exporter.gather();
+
+        assert!(find_family(&mfs, "encoder_session_fps").is_none());
+        assert!(find_family(&mfs, "encoder_session_latency_microseconds").is_none());
+    }
+
+    fn mock_process_device() -> Device {
+        Device {
+            processes: vec![
+                GpuProcess { pid: 4242, memory_used: Some(1048576.0), gpu_instance_id: None, compute: true, graphics: false },
+                GpuProcess { pid: 4343, memory_used: Some(2097152.0), gpu_instance_id: None, compute: true, graphics: false },
+                GpuProcess { pid: 4444, memory_used: None, gpu_instance_id: None, compute: true, graphics: false },
+            ],
+            ..mock_device()
+        }
+    }
+
+    #[test]
+    fn test_process_memory_without_cgroup_labels() {
+        let exporter = mock_exporter(mock_process_device(), ExporterConfig::default());
+        let text = encode(&exporter.gather());
+
+        assert!(text.contains(r#"nvidia_process_memory_used_bytes{minor="0",pid="4242"} 1048576"#));
+        assert!(text.contains(r#"nvidia_process_memory_used_bytes{minor="0",pid="4343"} 2097152"#));
+        assert!(!text.contains(r#"pid="4444""#), "Processes without memory usage are skipped");
+        assert!(!text.contains("job="));
+    }
+
+    #[test]
+    fn test_process_memory_mig_instances() {
+        let device = Device {
+            processes: vec![
+                GpuProcess { pid: 4242, memory_used: Some(1048576.0), gpu_instance_id: Some(1), compute: true, graphics: false },
+                GpuProcess { pid: 4343, memory_used: Some(2097152.0), gpu_instance_id: Some(2), compute: true, graphics: false },
+                GpuProcess { pid: 4444, memory_used: Some(4194304.0), gpu_instance_id: Some(1), compute: true, graphics: false },
+            ],
+            ..mock_device()
+        };
+        let exporter = mock_exporter(device, ExporterConfig::default());
+        let mfs = exporter.gather();
+        let text = encode(&mfs);
+
+        assert!(text.contains(r#"nvidia_process_memory_used_bytes{gi_id="1",minor="0",pid="4242"} 1048576"#));
+        assert!(text.contains(r#"nvidia_process_memory_used_bytes{gi_id="2",minor="0",pid="4343"} 2097152"#));
+        assert!(text.contains(r#"nvidia_process_memory_used_bytes{gi_id="1",minor="0",pid="4444"} 4194304"#));
+        // All processes end up in a single family
+        assert_eq!(text.matches("# TYPE nvidia_process_memory_used_bytes").count(), 1);
+        assert_eq!(find_family(&mfs, "process_memory_used_bytes").unwrap().get_metric().len(), 3);
+    }
+
+    #[test]
+    fn test_process_memory_by_type() {
+        let device = Device {
+            compute_processes: Some(2.0),
+            graphics_processes: Some(2.0),
+            processes: vec![
+                GpuProcess { pid: 4242, memory_used: Some(1048576.0), gpu_instance_id: None, compute: true, graphics: false },
+                GpuProcess { pid: 4343, memory_used: Some(2097152.0), gpu_instance_id: None, compute: false, graphics: true },
+                // Listed as both, so counted in both sums
+                GpuProcess { pid: 4444, memory_used: Some(4194304.0), gpu_instance_id: None, compute: true, graphics: true },
+                GpuProcess { pid: 4545, memory_used: None, gpu_instance_id: None, compute: true, graphics: false },
+            ],
+            ..mock_device()
+        };
+        let exporter = mock_exporter(device.clone(), ExporterConfig::default());
+        let mfs = exporter.gather();
+
+        let per_process = |listed: fn(&GpuProcess) -> bool| {
+            device.processes.iter().filter(|p| listed(p)).filter_map(|p| p.memory_used).sum::<f64>()
+        };
+        let compute = find_family(&mfs, "compute_processes_memory_bytes").unwrap();
+        assert_eq!(compute.get_metric()[0].get_gauge().get_value(), 5242880.0);
+        assert_eq!(compute.get_metric()[0].get_gauge().get_value(), per_process(|p| p.compute));
+        let graphics = find_family(&mfs, "graphics_processes_memory_bytes").unwrap();
+        assert_eq!(graphics.get_metric()[0].get_gauge().get_value(), 6291456.0);
+        assert_eq!(graphics.get_metric()[0].get_gauge().get_value(), per_process(|p| p.graphics));
+    }
+
+    #[test]
+    fn test_process_memory_by_type_unsupported() {
+        let config = ExporterConfig {
+            drop_zero_value_optional_metrics: true,
+            ..Default::default()
+        };
+        let exporter = mock_exporter(mock_device(), config);
+        let mfs = exporter.gather();
+
+        assert!(find_family(&mfs, "compute_processes_memory_bytes").is_none());
+        assert!(find_family(&mfs, "graphics_processes_memory_bytes").is_none());
+    }
+
+    #[test]
+    fn test_process_memory_with_cgroup_labels() {
+        let proc_root = tempfile::tempdir().unwrap();
+        let proc_dir = proc_root.path().join("4242");
+        std::fs::create_dir(&proc_dir).unwrap();
+        std::fs::write(
+            proc_dir.join("cgroup"),
+            "0::/system.slice/slurmstepd.scope/job_987/step_0/user/task_0\n",
+        )
+        .unwrap();
+
+        let config = ExporterConfig {
+            enable_cgroup_labels: true,
+            ..Default::default()
+        };
+        let mut exporter = mock_exporter(mock_process_device(), config);
+        exporter.proc_root = proc_root.path().to_path_buf();
+        let text = encode(&exporter.gather());
+
+        assert!(text.contains(r#"nvidia_process_memory_used_bytes{job="987",minor="0",pid="4242"} 1048576"#));
+        // No procfs entry for this process, so the job can't be resolved
+        assert!(text.contains(r#"nvidia_process_memory_used_bytes{job="",minor="0",pid="4343"} 2097152"#));
+    }
+
+    #[test]
+    fn test_process_memory_with_process_names() {
+        let proc_root = tempfile::tempdir().unwrap();
+        let proc_dir = proc_root.path().join("4242");
+        std::fs::create_dir(&proc_dir).unwrap();
+        std::fs::write(proc_dir.join("comm"), "python3\n").unwrap();
+
+        let config = ExporterConfig {
+            enable_process_names: true,
+            ..Default::default()
+        };
+        let mut exporter = mock_exporter(mock_process_device(), config);
+        exporter.proc_root = proc_root.path().to_path_buf();
+        let text = encode(&exporter.gather());
+
+        assert!(text.contains(r#"nvidia_process_memory_used_bytes{minor="0",pid="4242",process_name="python3"} 1048576"#));
+        // No procfs entry for this process, so the name can't be resolved
+        assert!(text.contains(r#"nvidia_process_memory_used_bytes{minor="0",pid="4343",process_name=""} 2097152"#));
+    }
+
+    #[test]
+    fn test_memory_reserved() {
+        let mib = 1024.0 * 1024.0;
+        let device = Device {
+            memory_total: 10240.0 * mib,
+            memory_used: 5120.0 * mib,
+            memory_reserved: Some(512.0 * mib),
+            ..mock_device()
+        };
+        let exporter = mock_exporter(device, ExporterConfig::default());
+        let mfs = exporter.gather();
+
+        let value = |name: &str| find_family(&mfs, name).unwrap().get_metric()[0].get_gauge().get_value();
+        assert_eq!(value("memory_reserved_bytes"), 512.0 * mib);
+        assert_eq!(value("memory_used"), 5120.0 * mib);
+
+        // Nothing is exported when the driver only has the v1 query
+        let exporter = mock_exporter(mock_device(), ExporterConfig::default());
+        assert!(find_family(&exporter.gather(), "memory_reserved_bytes").is_none());
+    }
+
+    #[test]
+    fn test_memory_used_peak() {
+        let gib = 1024.0 * 1024.0 * 1024.0;
+        let readings = Arc::new(Mutex::new(vec![4.0, 2.0, 1.0, 3.0, 6.0, 5.0].into_iter()));
+        let mut collector = MockMetricsCollector::new();
+        collector.expect_collect().returning(move || {
+            let used = readings.lock().unwrap().next().unwrap();
+            Ok(Metrics {
+                version: "525.116.04".to_string(),
+                devices: vec![Device {
+                    memory_used: used * gib,
+                    ..mock_device()
+                }],
+                ..Default::default()
+            })
+        });
+        let exporter = Exporter::with_collector(Arc::new(collector), ExporterConfig::default());
+
+        let peaks: Vec<f64> = (0..6)
+            .map(|_| {
+                let mfs = exporter.gather();
+                find_family(&mfs, "memory_used_peak_bytes").unwrap().get_metric()[0]
+                    .get_gauge()
+                    .get_value()
+                    / gib
+            })
+            .collect();
+        assert_eq!(peaks, vec![4.0, 4.0, 4.0, 4.0, 6.0, 6.0]);
+    }
+
+    #[test]
+    fn test_memory_used_peak_pruned() {
+        let mut collector = MockMetricsCollector::new();
+        let mut collections = 0;
+        collector.expect_collect().returning(move || {
+            collections += 1;
+            // The GPU is replaced by another one after the first collection
+            let device = match collections {
+                1 => mock_device(),
+                _ => Device {
+                    minor_number: "1".to_string(),
+                    uuid: "GPU-replacement".to_string(),
+                    ..mock_device()
+                },
+            };
+            Ok(Metrics {
+                version: "525.116.04".to_string(),
+                devices: vec![device],
+                ..Default::default()
+            })
+        });
+        let exporter = Exporter::with_collector(Arc::new(collector), ExporterConfig::default());
+        exporter.refresh().unwrap();
+        let text = encode(&exporter.gather());
+
+        assert!(!text.contains(r#"nvidia_memory_used_peak_bytes{minor="0"}"#));
+        assert!(text.contains(r#"nvidia_memory_used_peak_bytes{minor="1"}"#));
+        let peaks = exporter.memory_peaks.lock().unwrap();
+        assert_eq!(peaks.keys().collect::<Vec<_>>(), vec!["GPU-replacement"]);
+    }
+
+    #[test]
+    fn test_fan_speed_rpm() {
+        let device = Device {
+            fan_speed: 45.0,
+            fan_speed_rpm: vec![(0, 1520.0), (1, 1480.0)],
+            ..mock_device()
+        };
+        let exporter = mock_exporter(device, ExporterConfig::default());
+        let text = encode(&exporter.gather());
+
+        assert!(text.contains(r#"nvidia_fanspeed{minor="0"} 45"#));
+        assert!(text.contains(r#"nvidia_fan_speed_rpm{fan="0",minor="0"} 1520"#));
+        assert!(text.contains(r#"nvidia_fan_speed_rpm{fan="1",minor="0"} 1480"#));
+    }
+
+    #[test]
+    fn test_fan_speed_max_percent() {
+        let device = Device {
+            fan_speed: 45.0,
+            fan_speeds: vec![(0, 45.0), (1, 72.0), (2, 60.0)],
+            ..mock_device()
+        };
+        let exporter = mock_exporter(device, ExporterConfig::default());
+        let mfs = exporter.gather();
+
+        let per_fan = find_family(&mfs, "fan_speed_percent").expect("fan_speed_percent should be present");
+        let highest = per_fan
+            .get_metric()
+            .iter()
+            .map(|metric| metric.get_gauge().get_value())
+            .fold(f64::MIN, f64::max);
+        assert_eq!(per_fan.get_metric().len(), 3);
+        let max = find_family(&mfs, "fan_speed_max_percent").expect("fan_speed_max_percent should be present");
+        assert_eq!(max.get_metric()[0].get_gauge().get_value(), highest);
+        assert_eq!(highest, 72.0);
+
+        // Nothing without per-fan data
+        let exporter = mock_exporter(mock_device(), ExporterConfig::default());
+        let mfs = exporter.gather();
+        assert!(find_family(&mfs, "fan_speed_percent").is_none());
+        assert!(find_family(&mfs, "fan_speed_max_percent").is_none());
+    }
+
+    #[test]
+    fn test_fan_speed_rpm_unsupported() {
+        let device = Device {
+            fan_speed: 45.0,
+            ..mock_device()
+        };
+        let exporter = mock_exporter(device, ExporterConfig::default());
+        let mfs = exporter.gather();
+
+        assert!(find_family(&mfs, "fanspeed").is_some());
+        assert!(find_family(&mfs, "fan_speed_rpm").is_none());
+    }
+
+    #[test]
+    fn test_duplicate_minor_numbers() {
+        let devices = vec![
+            Device {
+                uuid: "GPU-aaaa".to_string(),
+                temperature: 50.0,
+                ..mock_device()
+            },
+            Device {
+                index: "1".to_string(),
+                uuid: "GPU-bbbb".to_string(),
+                temperature: 70.0,
+                ..mock_device()
+            },
+        ];
+        let mut collector = MockMetricsCollector::new();
+        collector.expect_collect().returning(move || {
+            Ok(Metrics {
+                version: "525.116.04".to_string(),
+                devices: devices.clone(),
+                ..Default::default()
+            })
+        });
+        let config = ExporterConfig {
+            redact_uuid: true,
+            ..Default::default()
+        };
+        let exporter = Exporter::with_collector(Arc::new(collector), config);
+        let text = encode(&exporter.gather());
+
+        // Both devices survive under their redacted UUID, without leaking the real one
+        let salt = &exporter.config.uuid_salt;
+        assert!(text.contains(&format!(r#"nvidia_temperatures{{minor="{}"}} 50"#, redact_uuid(salt, "GPU-aaaa"))));
+        assert!(text.contains(&format!(r#"nvidia_temperatures{{minor="{}"}} 70"#, redact_uuid(salt, "GPU-bbbb"))));
+        assert!(!text.contains(r#"nvidia_temperatures{minor="0"}"#));
+        assert!(!text.contains("GPU-aaaa") && !text.contains("GPU-bbbb"));
+        assert!(text.contains("nvidia_duplicate_label_collisions_total 2"));
+
+        // The same collision is counted once
+        let text = encode(&exporter.gather());
+        assert!(text.contains("nvidia_duplicate_label_collisions_total 2"));
+    }
+
+    #[test]
+    fn test_unique_minor_numbers_untouched() {
+        let mut devices = vec![
+            Device {
+                minor_number: "0".to_string(),
+                ..mock_device()
+            },
+            Device {
+                minor_number: "1".to_string(),
+                ..mock_device()
+            },
+        ];
+
+        assert!(disambiguate_minor_numbers(&mut devices).is_empty());
+        assert_eq!(devices[0].minor_number, "0");
+        assert_eq!(devices[1].minor_number, "1");
+    }
+
+    #[test]
+    fn test_row_remap_availability() {
+        let device = Device {
+            row_remap_availability: Some(RowRemapAvailability {
+                max: 630.0,
+                high: 8.0,
+                partial: 1.0,
+                low: 1.0,
+                none: 0.0,
+            }),
+            ..mock_device()
+        };
+        let exporter = mock_exporter(device, ExporterConfig::default());
+        let text = encode(&exporter.gather());
+
+        assert!(text.contains(r#"nvidia_row_remap_availability{bucket="max",minor="0"} 630"#));
+        assert!(text.contains(r#"nvidia_row_remap_availability{bucket="high",minor="0"} 8"#));
+        assert!(text.contains(r#"nvidia_row_remap_availability{bucket="partial",minor="0"} 1"#));
+        assert!(text.contains(r#"nvidia_row_remap_availability{bucket="low",minor="0"} 1"#));
+        assert!(text.contains(r#"nvidia_row_remap_availability{bucket="none",minor="0"} 0"#));
+    }
+
+    #[test]
+    fn test_row_remap_availability_unsupported() {
+        let exporter = mock_exporter(mock_device(), ExporterConfig::default());
+        let mfs = exporter.gather();
+
+        assert!(find_family(&mfs, "row_remap_availability").is_none());
+    }
+
+    #[test]
+    fn test_round_integers() {
+        let device = Device {
+            temperature: 64.6,
+            fan_speed: 49.99999,
+            utilization_gpu: 72.4,
+            utilization_memory: 30.5,
+            encoder_utilization: Some(12.2),
+            performance_state: Some(1.9999),
+            power_usage: 123_456.7,
+            clock_memory: Some(9501.0),
+            memory_bus_width: Some(384.0),
+            ..mock_device()
+        };
+        let config = ExporterConfig {
+            round_integers: true,
+            ..Default::default()
+        };
+        let exporter = mock_exporter(device, config);
+        let text = encode(&exporter.gather());
+
+        assert!(text.contains(r#"nvidia_temperatures{minor="0"} 65"#));
+        assert!(text.contains(r#"nvidia_fanspeed{minor="0"} 50"#));
+        assert!(text.contains(r#"nvidia_utilization_gpu{minor="0"} 72"#));
+        assert!(text.contains(r#"nvidia_utilization_memory{minor="0"} 31"#));
+        assert!(text.contains(r#"nvidia_encoder_utilization{minor="0"} 12"#));
+        assert!(text.contains(r#"nvidia_performance_state{minor="0"} 2"#));
+        // Metrics that aren't whole numbers by nature are left alone
+        assert!(text.contains(r#"nvidia_power_usage{minor="0"} 123456.7"#));
+        assert!(text.contains(r#"nvidia_memory_bandwidth_bytes_per_second{minor="0"} 912096000000"#));
+    }
+
+    #[test]
+    fn test_round_integers_disabled() {
+        let device = Device {
+            temperature: 64.6,
+            fan_speed: 49.99999,
+            ..mock_device()
+        };
+        let exporter = mock_exporter(device, ExporterConfig::default());
+        let text = encode(&exporter.gather());
+
+        assert!(text.contains(r#"nvidia_temperatures{minor="0"} 64.6"#));
+        assert!(text.contains(r#"nvidia_fanspeed{minor="0"} 49.99999"#));
+    }
+
+    #[test]
+    fn test_topology_metrics() {
+        let device = Device {
+            numa_node: Some(1.0),
+            // Affinity mask 0x0f0 on a device close to NUMA node 1
+            cpu_affinity: vec![4, 5, 6, 7],
+            ..mock_device()
+        };
+        let config = ExporterConfig {
+            enable_topology_metrics: true,
+            ..Default::default()
+        };
+        let exporter = mock_exporter(device, config);
+        let text = encode(&exporter.gather());
+
+        assert!(text.contains(r#"nvidia_gpu_numa_node{minor="0"} 1"#));
+        for cpu in 4..8 {
+            assert!(text.contains(&format!(r#"nvidia_gpu_cpu_affinity{{cpu="{}",minor="0"}} 1"#, cpu)));
+        }
+        assert!(!text.contains(r#"nvidia_gpu_cpu_affinity{cpu="3""#));
+    }
+
+    #[test]
+    fn test_topology_metrics_capped_and_unsupported() {
+        let device = Device {
+            numa_node: None,
+            cpu_affinity: (0..1024).collect(),
+            ..mock_device()
+        };
+        let config = ExporterConfig {
+            enable_topology_metrics: true,
+            ..Default::default()
+        };
+        let exporter = mock_exporter(device, config);
+        let mfs = exporter.gather();
+
+        assert!(find_family(&mfs, "gpu_numa_node").is_none());
+        let mf = find_family(&mfs, "gpu_cpu_affinity").unwrap();
+        assert_eq!(mf.get_metric().len(), MAX_AFFINITY_CPUS_PER_GPU);
+    }
+
+    #[test]
+    fn test_topology_metrics_disabled() {
+        let device = Device {
+            numa_node: Some(0.0),
+            cpu_affinity: vec![0, 1],
+            ..mock_device()
+        };
+        let exporter = mock_exporter(device, ExporterConfig::default());
+        let mfs = exporter.gather();
+
+        assert!(find_family(&mfs, "gpu_numa_node").is_none());
+        assert!(find_family(&mfs, "gpu_cpu_affinity").is_none());
+    }
+
+    #[test]
+    fn test_performance_state_one_hot() {
+        let device = Device {
+            performance_state: Some(2.0),
+            ..mock_device()
+        };
+        let config = ExporterConfig {
+            performance_state_one_hot: true,
+            ..Default::default()
+        };
+        let exporter = mock_exporter(device, config);
+        let mfs = exporter.gather();
+
+        let mf = find_family(&mfs, "performance_state_current").expect("performance_state_current should be present");
+        assert_eq!(mf.get_metric().len(), 16);
+        let active: Vec<&str> = mf
+            .get_metric()
+            .iter()
+            .filter(|m| m.get_gauge().get_value() == 1.0)
+            .flat_map(|m| m.get_label().iter().filter(|l| l.get_name() == "pstate"))
+            .map(|l| l.get_value())
+            .collect();
+        assert_eq!(active, vec!["P2"]);
+        // The numeric gauge is still exported
+        let mf = find_family(&mfs, "performance_state").unwrap();
+        assert_eq!(mf.get_metric()[0].get_gauge().get_value(), 2.0);
+    }
+
+    #[test]
+    fn test_performance_state_one_hot_disabled() {
+        let device = Device {
+            performance_state: Some(2.0),
+            ..mock_device()
+        };
+        let exporter = mock_exporter(device, ExporterConfig::default());
+
+        assert!(find_family(&exporter.gather(), "performance_state_current").is_none());
+    }
+
+    #[test]
+    fn test_device_order_pci() {
+        let device = |index: &str, pci_bus_id: Option<&str>| Device {
+            index: index.to_string(),
+            minor_number: index.to_string(),
+            pci_bus_id: pci_bus_id.map(str::to_string),
+            ..mock_device()
+        };
+        let mut collector = MockMetricsCollector::new();
+        collector.expect_collect().returning(move || {
+            Ok(Metrics {
+                version: "525.116.04".to_string(),
+                devices: vec![
+                    device("0", Some("0000:b1:00.0")),
+                    device("1", None),
+                    device("2", Some("0000:3b:00.0")),
+                ],
+                ..Default::default()
+            })
+        });
+        let config = ExporterConfig {
+            device_order: DeviceOrder::Pci,
+            ..Default::default()
+        };
+        let exporter = Exporter::with_collector(Arc::new(collector), config);
+
+        let data = exporter.refresh().unwrap();
+        let order: Vec<(&str, &str)> = data
+            .devices
+            .iter()
+            .map(|d| (d.index.as_str(), d.minor_number.as_str()))
+            .collect();
+        assert_eq!(order, vec![("0", "2"), ("1", "0"), ("2", "1")]);
+
+        let text = encode(&exporter.families());
+        assert!(text.contains(r#"index="0",minor="2""#));
+        assert!(text.contains(r#"index="1",minor="0""#));
+    }
+
+    #[test]
+    fn test_power_usage_summary() {
+        let mut collector = MockMetricsCollector::new();
+        let mut scrape = 0;
+        collector.expect_collect().returning(move || {
+            // 1..=100 W, one sample per scrape
+            scrape += 1;
+            Ok(Metrics {
+                version: "525.116.04".to_string(),
+                devices: vec![Device {
+                    power_usage: scrape as f64 * 1000.0,
+                    ..mock_device()
+                }],
+                ..Default::default()
+            })
+        });
+        let config = ExporterConfig {
+            power_summary_window: Some(Duration::from_secs(300)),
+            ..Default::default()
+        };
+        let exporter = Exporter::with_collector(Arc::new(collector), config);
+        for _ in 0..99 {
+            exporter.refresh().unwrap();
+        }
+        let mfs = exporter.gather();
+
+        let mf = find_family(&mfs, "power_usage_milliwatts").expect("power_usage_milliwatts should be present");
+        assert_eq!(mf.get_field_type(), MetricType::SUMMARY);
+        let summary = mf.get_metric()[0].get_summary();
+        assert_eq!(summary.get_sample_count(), 100);
+        assert_eq!(summary.get_sample_sum(), 5_050_000.0);
+        let quantile = |q: f64| {
+            summary
+                .get_quantile()
+                .iter()
+                .find(|quantile| quantile.get_quantile() == q)
+                .map(|quantile| quantile.get_value())
+        };
+        assert_eq!(quantile(0.5), Some(50_000.0));
+        assert_eq!(quantile(0.95), Some(95_000.0));
+
+        let text = encode(&mfs);
+        assert!(text.contains(r#"nvidia_power_usage_milliwatts{minor="0",quantile="0.95"} 95000"#));
+    }
+
+    #[test]
+    fn test_power_usage_summary_sample_buffer_capacity() {
+        let config = ExporterConfig {
+            power_summary_window: Some(Duration::from_secs(300)),
+            sample_buffer_capacity: 5,
+            ..Default::default()
+        };
+        let exporter = mock_exporter(mock_device(), config);
+        for _ in 0..9 {
+            exporter.refresh().unwrap();
+            let samples = exporter.power_samples.lock().unwrap();
+            assert!(samples.values().all(|window| window.len() <= 5));
+        }
+        let mfs = exporter.gather();
+
+        let mf = find_family(&mfs, "power_usage_milliwatts").expect("power_usage_milliwatts should be present");
+        assert_eq!(mf.get_metric()[0].get_summary().get_sample_count(), 5);
+        // The gather collects a tenth sample: 10 samples within the window for 5 slots
+        let mf = find_family(&mfs, "sample_buffer_evictions_total").expect("sample_buffer_evictions_total should be present");
+        assert_eq!(mf.get_metric()[0].get_counter().get_value(), 5.0);
+    }
+
+    #[test]
+    #[traced_test]
+    fn test_power_usage_summary_full_buffer_warned_once() {
+        let config = ExporterConfig {
+            power_summary_window: Some(Duration::from_secs(300)),
+            sample_buffer_capacity: 2,
+            ..Default::default()
+        };
+        let exporter = mock_exporter(mock_device(), config);
+        for _ in 0..5 {
+            exporter.refresh().unwrap();
+        }
+
+        assert!(logs_contain("Power usage sample buffer of GPU 0 is full"));
+        logs_assert(|lines| {
+            let full = |level: &str| {
+                lines
+                    .iter()
+                    .filter(|line| line.contains(level) && line.contains("sample buffer of GPU 0 is full"))
+                    .count()
+            };
+            match (full("WARN"), full("DEBUG")) {
+                (1, 2) => Ok(()),
+                counts => Err(format!("warned and debug logged {:?} times", counts)),
+            }
+        });
+    }
+
+    #[test]
+    fn test_power_usage_summary_disabled() {
+        let exporter = mock_exporter(mock_device(), ExporterConfig::default());
+
+        assert!(find_family(&exporter.gather(), "power_usage_milliwatts").is_none());
+    }
+
+    #[test]
+    fn test_power_usage_peak() {
+        let device = Device {
+            power_usage: 300_000.0,
+            power_usage_peak: Some(410_000.0),
+            ..mock_device()
+        };
+        let exporter = mock_exporter(device, ExporterConfig::default());
+        let mfs = exporter.gather();
+
+        let mf = find_family(&mfs, "power_usage_peak_milliwatts").expect("power_usage_peak_milliwatts should be present");
+        assert_eq!(mf.get_metric()[0].get_gauge().get_value(), 410_000.0);
+
+        // Nothing is exported without power samples
+        let exporter = mock_exporter(mock_device(), ExporterConfig::default());
+        assert!(find_family(&exporter.gather(), "power_usage_peak_milliwatts").is_none());
+    }
+
+    #[test]
+    fn test_power_efficiency() {
+        let device = Device {
+            utilization_gpu: 90.0,
+            power_usage: 300_000.0,
+            ..mock_device()
+        };
+        let exporter = mock_exporter(device, ExporterConfig::default());
+        let mfs = exporter.gather();
+
+        let mf = find_family(&mfs, "power_efficiency").expect("power_efficiency should be present");
+        assert_eq!(mf.get_metric()[0].get_gauge().get_value(), 0.3);
+    }
+
+    #[test]
+    fn test_power_efficiency_without_power() {
+        let device = Device {
+            utilization_gpu: 90.0,
+            power_usage: 0.0,
+            ..mock_device()
+        };
+        let exporter = mock_exporter(device, ExporterConfig::default());
+
+        assert!(find_family(&exporter.gather(), "power_efficiency").is_none());
+    }
+
+    #[test]
+    fn test_memory_temperature_margin() {
+        let device = Device {
+            memory_temperature: Some(78.0),
+            memory_temperature_threshold: Some(95.0),
+            ..mock_device()
+        };
+        let exporter = mock_exporter(device, ExporterConfig::default());
+        let mfs = exporter.gather();
+
+        let mf = find_family(&mfs, "memory_temperature_margin_celsius")
+            .expect("memory_temperature_margin_celsius should be present");
+        assert_eq!(mf.get_metric()[0].get_gauge().get_value(), 17.0);
+    }
+
+    #[test]
+    fn test_memory_temperature_margin_without_memory_sensor() {
+        // Consumer GPUs have no memory sensor but may still report a threshold
+        let device = Device {
+            memory_temperature: None,
+            memory_temperature_threshold: Some(95.0),
+            ..mock_device()
+        };
+        let exporter = mock_exporter(device, ExporterConfig::default());
+
+        assert!(find_family(&exporter.gather(), "memory_temperature_margin_celsius").is_none());
+    }
+
+    #[test]
+    fn test_gpu_target_temperature() {
+        let device = Device {
+            target_temperature: Some(83.0),
+            ..mock_device()
+        };
+        let exporter = mock_exporter(device, ExporterConfig::default());
+        let mfs = exporter.gather();
+
+        let mf = find_family(&mfs, "gpu_target_temperature_celsius")
+            .expect("gpu_target_temperature_celsius should be present");
+        assert_eq!(mf.get_metric()[0].get_gauge().get_value(), 83.0);
+
+        // Cards without a target temperature setting emit nothing
+        let exporter = mock_exporter(mock_device(), ExporterConfig::default());
+        assert!(find_family(&exporter.gather(), "gpu_target_temperature_celsius").is_none());
+    }
+
+    #[test]
+    fn test_relabel_minor() {
+        let mut collector = MockMetricsCollector::new();
+        collector.expect_collect().returning(|| {
+            Ok(Metrics {
+                version: "525.116.04".to_string(),
+                devices: vec![
+                    mock_device(),
+                    Device {
+                        index: "1".to_string(),
+                        minor_number: "1".to_string(),
+                        ..mock_device()
+                    },
+                ],
+                ..Default::default()
+            })
+        });
+        let config = ExporterConfig {
+            minor_names: BTreeMap::from([("0".to_string(), "gpu-a".to_string())]),
+            ..Default::default()
+        };
+        let exporter = Exporter::with_collector(Arc::new(collector), config);
+        let text = encode(&exporter.gather());
+
+        assert!(text.contains(r#"nvidia_temperatures{minor="gpu-a",minor_number="0"} 0"#));
+        // Unmapped minors keep their numeric value
+        assert!(text.contains(r#"nvidia_temperatures{minor="1",minor_number="1"} 0"#));
+        assert!(!text.contains(r#"minor="0""#));
+
+        let device = encode(&exporter.gather_device(0).unwrap());
+        assert!(device.contains(r#"nvidia_temperatures{minor="gpu-a",minor_number="0"} 0"#));
+        assert!(!device.contains(r#"minor_number="1""#));
+    }
+
+    #[test]
+    fn test_const_labels() {
+        let config = ExporterConfig {
+            const_labels: HashMap::from([
+                ("cluster".to_string(), "gpu-east".to_string()),
+                ("team".to_string(), "ml".to_string()),
+            ]),
+            power_summary_window: Some(Duration::from_secs(300)),
+            ..Default::default()
+        };
+        let exporter = mock_exporter(mock_device(), config);
+        let text = encode(&exporter.gather());
+
+        assert!(text.contains(r#"nvidia_up{cluster="gpu-east",team="ml"} 1"#));
+        assert!(text.contains(r#"nvidia_device_count{cluster="gpu-east",team="ml"} 1"#));
+        assert!(text.contains(r#"nvidia_temperatures{cluster="gpu-east",minor="0",team="ml"} 0"#));
+        assert!(text.contains(r#"nvidia_power_usage_milliwatts_count{cluster="gpu-east",minor="0",team="ml"} 1"#));
+
+        let catalog = exporter.catalog();
+        let temperature = catalog.iter().find(|e| e.name == "nvidia_temperatures").unwrap();
+        assert_eq!(temperature.labels, ["minor", "cluster", "team"]);
+    }
+
+    #[test]
+    fn test_reserved_label_names_cover_catalog() {
+        let config = ExporterConfig {
+            enable_cgroup_labels: true,
+            power_summary_window: Some(Duration::from_secs(300)),
+            minor_names: BTreeMap::from([("0".to_string(), "gpu-a".to_string())]),
+            enable_index_label: true,
+            enable_process_names: true,
+            ..Default::default()
+        };
+        let exporter = Exporter::with_collector(Arc::new(MockMetricsCollector::new()), config);
+
+        for entry in exporter.catalog() {
+            for label in &entry.labels {
+                assert!(RESERVED_LABEL_NAMES.contains(&label.as_str()), "{} of {} isn't reserved", label, entry.name);
+            }
+        }
+    }
+
+    #[test]
+    fn test_index_label() {
+        let mut collector = MockMetricsCollector::new();
+        collector.expect_collect().returning(|| {
+            Ok(Metrics {
+                version: "525.116.04".to_string(),
+                devices: vec![
+                    Device {
+                        index: "0".to_string(),
+                        minor_number: "3".to_string(),
+                        power_usage: 65_000.0,
+                        ..mock_device()
+                    },
+                    Device {
+                        index: "1".to_string(),
+                        minor_number: "1".to_string(),
+                        ..mock_device()
+                    },
+                ],
+                ..Default::default()
+            })
+        });
+        let config = ExporterConfig {
+            enable_index_label: true,
+            ..Default::default()
+        };
+        let exporter = Exporter::with_collector(Arc::new(collector), config);
+        let mfs = exporter.gather();
+        let text = encode(&mfs);
+
+        assert!(text.contains(r#"nvidia_temperatures{minor="3",index="0"} 0"#));
+        assert!(text.contains(r#"nvidia_temperatures{minor="1",index="1"} 0"#));
+        assert!(text.contains(r#"nvidia_power_usage{minor="3",index="0"} 65000"#));
+        // The info metric already carries the index and doesn't get it twice
+        let info = find_family(&mfs, "info").unwrap();
+        assert_eq!(info.get_metric()[0].get_label().iter().filter(|l| l.get_name() == "index").count(), 1);
+        // Metrics without a device aren't labelled
+        assert!(text.contains("nvidia_device_count 2"));
+
+        let catalog = exporter.catalog();
+        let temperature = catalog.iter().find(|e| e.name == "nvidia_temperatures").unwrap();
+        assert_eq!(temperature.labels, ["minor", "index"]);
+    }
+
+    #[test]
+    fn test_host_label() {
+        let remote_device = |host: &str, minor: &str, index: &str, temperature| Device {
+            index: index.to_string(),
+            minor_number: format!("{}/{}", host, minor),
+            host: Some(host.to_string()),
+            temperature,
+            ..mock_device()
+        };
+        let mut collector = MockMetricsCollector::new();
+        collector.expect_collect().returning(move || {
+            Ok(Metrics {
+                devices: vec![
+                    remote_device("gpu-1:9445", "0", "0", 40.0),
+                    remote_device("gpu-2:9445", "0", "1", 50.0),
+                ],
+                remote_up: BTreeMap::from([
+                    ("gpu-1:9445".to_string(), true),
+                    ("gpu-2:9445".to_string(), true),
+                    ("gpu-3:9445".to_string(), false),
+                ]),
+                ..Default::default()
+            })
+        });
+        let config = ExporterConfig {
+            remote_endpoints: ["gpu-1", "gpu-2", "gpu-3"]
+                .into_iter()
+                .map(|host| crate::remote::parse_remote_endpoint(host).unwrap())
+                .collect(),
+            minor_names: BTreeMap::from([("gpu-2:9445/0".to_string(), "trainer".to_string())]),
+            ..Default::default()
+        };
+        let exporter = Exporter::with_collector(Arc::new(collector), config);
+        let text = encode(&exporter.gather());
+
+        // Both devices are minor 0 on their host, told apart by the host label
+        assert!(text.contains(r#"nvidia_temperatures{host="gpu-1:9445",minor="0",minor_number="0"} 40"#));
+        assert!(text.contains(r#"nvidia_temperatures{host="gpu-2:9445",minor="trainer",minor_number="0"} 50"#));
+        assert!(text.contains(r#"nvidia_remote_up{host="gpu-1:9445"} 1"#));
+        assert!(text.contains(r#"nvidia_remote_up{host="gpu-3:9445"} 0"#));
+
+        let device = exporter.last_device_families(1).unwrap();
+        assert!(encode(&device).contains(r#"nvidia_temperatures{host="gpu-2:9445",minor="trainer",minor_number="0"} 50"#));
+        assert!(!encode(&device).contains(r#"host="gpu-1:9445",minor="0""#));
+
+        let catalog = exporter.catalog();
+        let temperature = catalog.iter().find(|e| e.name == "nvidia_temperatures").unwrap();
+        assert_eq!(temperature.labels, ["minor", "minor_number", "host"]);
+    }
+
+    #[test]
+    fn test_index_label_pruned() {
+        let collections = AtomicUsize::new(0);
+        let mut collector = MockMetricsCollector::new();
+        collector.expect_collect().returning(move || {
+            // The second collection no longer finds the GPU with minor number 3
+            let minors: &[&str] = if collections.fetch_add(1, Ordering::SeqCst) == 0 { &["3", "1"] } else { &["1"] };
+            Ok(Metrics {
+                version: "525.116.04".to_string(),
+                devices: minors
+                    .iter()
+                    .enumerate()
+                    .map(|(index, minor)| Device {
+                        index: index.to_string(),
+                        minor_number: minor.to_string(),
+                        ..mock_device()
+                    })
+                    .collect(),
+                ..Default::default()
+            })
+        });
+        let config = ExporterConfig {
+            enable_index_label: true,
+            ..Default::default()
+        };
+        let exporter = Exporter::with_collector(Arc::new(collector), config);
+
+        exporter.refresh().unwrap();
+        exporter.refresh().unwrap();
+        let indices = exporter.device_indices.lock().unwrap().clone();
+        assert_eq!(indices, BTreeMap::from([("1".to_string(), "0".to_string())]));
+    }
+
+    #[test]
+    fn test_device_collection_timed_out() {
+        let mut collector = MockMetricsCollector::new();
+        collector.expect_collect().returning(|| {
+            Ok(Metrics {
+                devices: vec![mock_device()],
+                timed_out_devices: vec![1],
+                ..Default::default()
+            })
+        });
+        let exporter = Exporter::with_collector(Arc::new(collector), ExporterConfig::default());
+        let text = encode(&exporter.gather());
+
+        assert!(text.contains(r#"nvidia_device_collection_timed_out{index="1"} 1"#));
+        assert!(text.contains("nvidia_device_count 1"));
+        assert!(text.contains(r#"nvidia_temperatures{minor="0"}"#));
+    }
+
+    #[test]
+    fn test_visible_devices_restricted() {
+        let proc_root = tempfile::tempdir().unwrap();
+        let sys_root = tempfile::tempdir().unwrap();
+        let exporter = || {
+            let mut exporter = mock_exporter(mock_device(), ExporterConfig::default());
+            exporter.proc_root = proc_root.path().to_path_buf();
+            exporter.sys_root = sys_root.path().to_path_buf();
+            exporter
+        };
+        let restricted = |mfs: &[MetricFamily]| {
+            find_family(mfs, "visible_devices_restricted").unwrap().get_metric()[0].get_gauge().get_value()
+        };
+
+        std::env::set_var(visibility::VISIBLE_DEVICES_ENV, "GPU-12345678-1234-1234-1234-123456789012");
+        let mfs = exporter().gather();
+        std::env::remove_var(visibility::VISIBLE_DEVICES_ENV);
+        assert_eq!(restricted(&mfs), 1.0);
+        // The host's GPUs can't be counted without the driver's procfs entries
+        assert!(find_family(&mfs, "host_device_count").is_none());
+
+        let mfs = exporter().gather();
+        assert_eq!(restricted(&mfs), 0.0);
+
+        // The driver lists more GPUs than are visible
+        for bus_id in ["0000:65:00.0", "0000:b3:00.0"] {
+            std::fs::create_dir_all(proc_root.path().join("driver/nvidia/gpus").join(bus_id)).unwrap();
+        }
+        let mfs = exporter().gather();
+        assert_eq!(restricted(&mfs), 1.0);
+        assert_eq!(find_family(&mfs, "host_device_count").unwrap().get_metric()[0].get_gauge().get_value(), 2.0);
+        assert_eq!(find_family(&mfs, "device_count").unwrap().get_metric()[0].get_gauge().get_value(), 1.0);
+    }
+
+    #[test]
+    fn test_go_exporter_compat() {
+        let device = Device {
+            temperature: 65.0,
+            fan_speed: 40.0,
+            power_usage: 65_500.0,
+            memory_used: 2.0 * 1024.0 * 1024.0 * 1024.0,
+            utilization_gpu: 75.0,
+            clock_graphics: Some(1500.0),
+            performance_state: Some(2.0),
+            ..mock_device()
+        };
+        let config = ExporterConfig {
+            compat: Some(Compat::GoNvidiaGpuExporter),
+            memory_unit: MemoryUnit::Mib,
+            ..Default::default()
+        };
+        let exporter = mock_exporter(device, config);
+        let text = encode(&exporter.gather());
+
+        let labels = r#"{minor="0",uuid="GPU-12345678-1234-1234-1234-123456789012"}"#;
+        for expected in [
+            format!("nvidia_smi_temperature_gpu{} 65", labels),
+            format!("nvidia_smi_fan_speed_ratio{} 0.4", labels),
+            format!("nvidia_smi_power_draw_watts{} 65.5", labels),
+            format!("nvidia_smi_memory_used_bytes{} 2147483648", labels),
+            format!("nvidia_smi_utilization_gpu_ratio{} 0.75", labels),
+            format!("nvidia_smi_clocks_current_graphics_clock_hz{} 1500000000", labels),
+            format!("nvidia_smi_pstate{} 2", labels),
+        ] {
+            assert!(text.contains(&expected), "{} missing from\n{}", expected, text);
+        }
+        // The info metric already has the uuid label
+        assert!(text.contains(r#"nvidia_smi_gpu_info{index="0",minor="0","#));
+        // Renamed metrics are gone under their own names, the others keep them
+        assert!(!text.contains("nvidia_temperatures"));
+        assert!(!text.contains("nvidia_memory_used_mib"));
+        assert!(text.contains("nvidia_device_count 1"));
+
+        let catalog = exporter.catalog();
+        let temperature = catalog.iter().find(|e| e.name == "nvidia_smi_temperature_gpu").unwrap();
+        assert_eq!(temperature.labels, ["minor", "uuid"]);
+    }
+
+    #[test]
+    fn test_index_label_disabled() {
+        let exporter = mock_exporter(mock_device(), ExporterConfig::default());
+        let text = encode(&exporter.gather());
+
+        assert!(text.contains(r#"nvidia_temperatures{minor="0"} 0"#));
+    }
+
+    #[test]
+    fn test_catalog() {
+        // The catalog is built without collecting, so it lists metrics no device reports
+        let mut collector = MockMetricsCollector::new();
+        collector.expect_collect().never();
+        let exporter = Exporter::with_collector(Arc::new(collector), ExporterConfig::default());
+        let catalog = exporter.catalog();
+
+        let temperature = catalog.iter().find(|e| e.name == "nvidia_temperatures").unwrap();
+        assert_eq!(
+            *temperature,
+            CatalogEntry {
+                name: "nvidia_temperatures".to_string(),
+                metric_type: "gauge".to_string(),
+                help: "Temperature as reported by the device".to_string(),
+                labels: vec!["minor".to_string()],
+            }
+        );
+        let process_memory = catalog.iter().find(|e| e.name == "nvidia_process_memory_used_bytes").unwrap();
+        assert_eq!(process_memory.labels, ["minor", "pid", "gi_id"]);
+        let up = catalog.iter().find(|e| e.name == "nvidia_up").unwrap();
+        assert!(up.labels.is_empty());
+        assert!(!catalog.iter().any(|e| e.name == "nvidia_power_usage_milliwatts"));
+
+        let names: HashSet<&str> = catalog.iter().map(|e| e.name.as_str()).collect();
+        assert_eq!(names.len(), catalog.len(), "catalog entries should be unique");
+    }
+
+    #[test]
+    fn test_catalog_follows_config() {
+        let config = ExporterConfig {
+            power_summary_window: Some(Duration::from_secs(300)),
+            minor_names: BTreeMap::from([("0".to_string(), "gpu-a".to_string())]),
+            ..Default::default()
+        };
+        let exporter = Exporter::with_collector(Arc::new(MockMetricsCollector::new()), config);
+        let catalog = exporter.catalog();
+
+        let summary = catalog.iter().find(|e| e.name == "nvidia_power_usage_milliwatts").unwrap();
+        assert_eq!(summary.metric_type, "summary");
+        assert_eq!(summary.labels, ["minor", "minor_number"]);
+        let temperature = catalog.iter().find(|e| e.name == "nvidia_temperatures").unwrap();
+        assert_eq!(temperature.labels, ["minor", "minor_number"]);
+    }
+
+    #[test]
+    fn test_power_limit_enforced() {
+        // The configured limit is clamped by a lower one, e.g. set out of band
+        let device = Device {
+            power_limit: Some(300_000.0),
+            power_limit_enforced: Some(250_000.0),
+            ..mock_device()
+        };
+        let exporter = mock_exporter(device, ExporterConfig::default());
+        let mfs = exporter.gather();
+
+        let mf = find_family(&mfs, "power_limit_enforced_milliwatts").expect("power_limit_enforced_milliwatts should be present");
+        assert_eq!(mf.get_metric()[0].get_gauge().get_value(), 250_000.0);
+        assert_eq!(
+            find_family(&mfs, "power_limit_milliwatts").unwrap().get_metric()[0].get_gauge().get_value(),
+            300_000.0
+        );
+
+        // Nothing is exported when unsupported, even without drop_zero_value_optional_metrics
+        let exporter = mock_exporter(mock_device(), ExporterConfig::default());
+        assert!(find_family(&exporter.gather(), "power_limit_enforced_milliwatts").is_none());
+    }
+
+    #[test]
+    fn test_clock_user_locked_independent_of_throttling() {
+        let indicators = |current_throttle_reasons| {
+            let device = Device {
+                current_throttle_reasons: Some(current_throttle_reasons),
+                ..mock_device()
+            };
+            let exporter = mock_exporter(device, ExporterConfig::default());
+            let mfs = exporter.gather();
+            let locked = find_family(&mfs, "clock_user_locked").expect("clock_user_locked should be present").get_metric()[0]
+                .get_gauge()
+                .get_value();
+            let limit = find_family(&mfs, "active_clock_limit")
+                .expect("active_clock_limit should be present")
+                .get_metric()
+                .iter()
+                .find(|m| m.get_gauge().get_value() == 1.0)
+                .map(|m| m.get_label().iter().find(|l| l.get_name() == "reason").unwrap().get_value().to_string());
+            (locked, limit)
+        };
+
+        // Locked with nvidia-smi -lgc while thermally throttled
+        assert_eq!(indicators(0x2 | 0x20), (1.0, Some("thermal".to_string())));
+        // Locked, with nothing else holding the clocks
+        assert_eq!(indicators(0x2), (1.0, Some("clock_setting".to_string())));
+        // Throttled without a lock
+        assert_eq!(indicators(0x20), (0.0, Some("thermal".to_string())));
+        assert_eq!(indicators(0x0), (0.0, Some("none".to_string())));
+    }
+
+    #[test]
+    fn test_clock_user_locked_ignores_pstate_range() {
+        // P8 allows less than the maximum clock without any lock
+        let device = Device {
+            performance_state: Some(8.0),
+            clock_graphics_max: Some(1410.0),
+            clock_graphics: Some(420.0),
+            current_throttle_reasons: Some(0x1),
+            ..mock_device()
+        };
+        assert_eq!(clock_user_locked(&device), Some(0.0));
+        assert_eq!(clock_user_locked(&Device { current_throttle_reasons: None, ..device }), None);
+    }
+
+    #[test]
+    fn test_app_clock_shortfall() {
+        let shortfall = |app_clock, clock| {
+            let device = Device {
+                app_clock_graphics: app_clock,
+                clock_graphics: Some(clock),
+                current_throttle_reasons: Some(0x4),
+                ..mock_device()
+            };
+            let exporter = mock_exporter(device, ExporterConfig::default());
+            find_family(&exporter.gather(), "app_clock_shortfall_mhz").map(|mf| mf.get_metric()[0].get_gauge().get_value())
+        };
+
+        // Running at the application clock, or boosting above it
+        assert_eq!(shortfall(Some(1410.0), 1410.0), Some(0.0));
+        assert_eq!(shortfall(Some(1410.0), 1755.0), Some(0.0));
+        // Throttled below it
+        assert_eq!(shortfall(Some(1410.0), 1110.0), Some(300.0));
+        // No application clock set
+        assert_eq!(shortfall(None, 1110.0), None);
+
+        // An idle GPU drops its clocks without being throttled
+        for reasons in [Some(0x0), Some(0x1)] {
+            let device = Device {
+                app_clock_graphics: Some(1410.0),
+                clock_graphics: Some(210.0),
+                current_throttle_reasons: reasons,
+                ..mock_device()
+            };
+            assert_eq!(app_clock_shortfall(&device), Some(0.0), "{:?}", reasons);
+        }
+    }
+
+    #[test]
+    fn test_redact_uuid() {
+        let uuid = "GPU-12345678-1234-1234-1234-123456789012";
+
+        let redacted = redact_uuid("tenant-a", uuid);
+        assert_eq!(redacted.len(), 64);
+        assert!(!redacted.contains(uuid));
+        assert_eq!(redacted, redact_uuid("tenant-a", uuid), "Same salt gives the same value");
+        assert_ne!(redacted, redact_uuid("tenant-b", uuid), "Different salts give different values");
+        assert_ne!(redacted, redact_uuid("tenant-a", "GPU-87654321-4321-4321-4321-210987654321"));
+    }
+
+    #[test]
+    fn test_redact_uuid_in_exported_data() {
+        let config = ExporterConfig {
+            redact_uuid: true,
+            uuid_salt: "tenant-a".to_string(),
+            ..Default::default()
+        };
+        let exporter = mock_exporter(mock_device(), config);
+        let data = exporter.refresh().unwrap();
+        let expected = redact_uuid("tenant-a", &mock_device().uuid);
+
+        assert_eq!(data.devices[0].uuid, expected);
+        let text = encode(&exporter.families());
+        assert!(text.contains(&format!(r#"uuid="{}""#, expected)));
+        assert!(!text.contains(&mock_device().uuid));
+    }
+
+    #[test]
+    fn test_pcie_link_downtraining() {
+        let device = Device {
+            pcie_link_gen: Some(3.0),
+            pcie_link_width: Some(8.0),
+            pcie_link_gen_max: Some(4.0),
+            pcie_link_width_max: Some(16.0),
+            ..mock_device()
+        };
+        let exporter = mock_exporter(device, ExporterConfig::default());
+        let mfs = exporter.gather();
+        let value = |name: &str| find_family(&mfs, name).unwrap().get_metric()[0].get_gauge().get_value();
+
+        assert!(value("pcie_link_generation") < value("pcie_max_link_generation"));
+        assert!(value("pcie_link_width") < value("pcie_max_link_width"));
+        assert_eq!(value("pcie_max_link_generation"), 4.0);
+        assert_eq!(value("pcie_max_link_width"), 16.0);
+    }
+
+    #[test]
+    fn test_pcie_link_speed() {
+        let device = Device {
+            pcie_link_gen: Some(4.0),
+            pcie_link_speed: Some(16.0),
+            ..mock_device()
+        };
+        let exporter = mock_exporter(device, ExporterConfig::default());
+        let mfs = exporter.gather();
+
+        let mf = find_family(&mfs, "pcie_link_speed_gts").expect("pcie_link_speed_gts should be present");
+        assert_eq!(mf.get_metric()[0].get_gauge().get_value(), 16.0);
+
+        // Unsupported cards emit nothing rather than a 0 GT/s link
+        let exporter = mock_exporter(mock_device(), ExporterConfig::default());
+        assert!(find_family(&exporter.gather(), "pcie_link_speed_gts").is_none());
+    }
+
+    #[test]
+    fn test_pcie_link_max_unsupported() {
+        let exporter = mock_exporter(mock_device(), ExporterConfig::default());
+        let mfs = exporter.gather();
+
+        assert!(find_family(&mfs, "pcie_max_link_generation").is_none());
+        assert!(find_family(&mfs, "pcie_max_link_width").is_none());
+    }
+
+    #[test]
+    fn test_nvml_call_results() {
+        let mut collector = MockMetricsCollector::new();
+        collector.expect_collect().returning(|| {
+            Ok(Metrics {
+                version: "525.116.04".to_string(),
+                devices: vec![mock_device()],
+                nvml_calls: 3,
+                nvml_call_results: BTreeMap::from([
+                    (("temperature", "success"), 2),
+                    (("clock_graphics", "not_supported"), 1),
+                ]),
+                ..Default::default()
+            })
+        });
+        let config = ExporterConfig {
+            enable_call_metrics: true,
+            ..ExporterConfig::default()
+        };
+        let exporter = Exporter::with_collector(Arc::new(collector), config);
+
+        exporter.gather();
+        let text = encode(&exporter.gather());
+        assert!(text.contains(r#"nvidia_nvml_call_result_total{call="temperature",result="success"} 4"#));
+        assert!(text.contains(r#"nvidia_nvml_call_result_total{call="clock_graphics",result="not_supported"} 2"#));
+
+        // Failed collections pass their calls on through the collector
+        let mut collector = MockMetricsCollector::new();
+        collector
+            .expect_collect()
+            .returning(|| Err(NvmlError::GpuLost.into()));
+        let config = ExporterConfig {
+            enable_call_metrics: true,
+            ..ExporterConfig::default()
+        };
+        let mut exporter = Exporter::with_collector(Arc::new(collector), config);
+        exporter.failed_call_results = Arc::new(Mutex::new(BTreeMap::from([(("device_count", "gpu_lost"), 1)])));
+        let text = encode(&exporter.gather());
+        assert!(text.contains(r#"nvidia_nvml_call_result_total{call="device_count",result="gpu_lost"} 1"#));
+        assert!(exporter.failed_call_results.lock().unwrap().is_empty());
+
+        // Not exported unless enabled
+        let mut collector = MockMetricsCollector::new();
+        collector.expect_collect().returning(|| {
+            Ok(Metrics {
+                nvml_call_results: BTreeMap::from([(("temperature", "success"), 1)]),
+                ..Default::default()
+            })
+        });
+        let exporter = Exporter::with_collector(Arc::new(collector), ExporterConfig::default());
+        assert!(find_family(&exporter.gather(), "nvml_call_result_total").is_none());
+    }
+
+    #[test]
+    fn test_nvml_calls_per_scrape() {
+        let mut collector = MockMetricsCollector::new();
+        collector.expect_collect().returning(|| {
+            Ok(Metrics {
+                version: "525.116.04".to_string(),
+                devices: vec![mock_device()],
+                nvml_calls: 42,
+                ..Default::default()
+            })
+        });
+        let exporter = Exporter::with_collector(Arc::new(collector), ExporterConfig::default());
+        let mfs = exporter.gather();
+
+        let mf = find_family(&mfs, "nvml_calls_per_scrape").expect("nvml_calls_per_scrape should be present");
+        assert_eq!(mf.get_metric()[0].get_gauge().get_value(), 42.0);
+    }
+
+    #[test]
+    fn test_nvml_session_uptime_advances() {
+        let exporter = mock_exporter(mock_device(), ExporterConfig::default());
+        assert!(find_family(&exporter.families(), "nvml_session_uptime_seconds").is_none());
+
+        let start = Instant::now();
+        exporter.set_nvml_session_uptime(start);
+        exporter.set_nvml_session_uptime(start + Duration::from_secs(90));
+        let mfs = exporter.families();
+
+        let mf = find_family(&mfs, "nvml_session_uptime_seconds").expect("nvml_session_uptime_seconds should be present");
+        let metric = &mf.get_metric()[0];
+        assert_eq!(metric.get_gauge().get_value(), 90.0);
+        assert_eq!(metric.get_label()[0].get_name(), "source");
+        assert_eq!(metric.get_label()[0].get_value(), "exporter_nvml_init");
+
+        exporter.set_nvml_session_uptime(start + Duration::from_secs(150));
+        let mfs = exporter.families();
+        let mf = find_family(&mfs, "nvml_session_uptime_seconds").unwrap();
+        assert_eq!(mf.get_metric()[0].get_gauge().get_value(), 150.0);
+
+        // The watchdog re-initializing NVML starts a new session
+        exporter.nvml_reinit_count.fetch_add(1, Ordering::Relaxed);
+        exporter.set_nvml_session_uptime(start + Duration::from_secs(200));
+        exporter.set_nvml_session_uptime(start + Duration::from_secs(230));
+        let mfs = exporter.families();
+        let mf = find_family(&mfs, "nvml_session_uptime_seconds").unwrap();
+        assert_eq!(mf.get_metric().len(), 1);
+        assert_eq!(mf.get_metric()[0].get_gauge().get_value(), 30.0);
+        assert_eq!(mf.get_metric()[0].get_label()[0].get_value(), "nvml_reinit");
+    }
+
+    #[test]
+    fn test_nvml_session_uptime_starts_at_first_success() {
+        let exporter = mock_exporter(mock_device(), ExporterConfig::default());
+        exporter.gather();
+
+        let mfs = exporter.families();
+        let mf = find_family(&mfs, "nvml_session_uptime_seconds").expect("nvml_session_uptime_seconds should be present");
+        assert!(mf.get_metric()[0].get_gauge().get_value() < 1.0);
+    }
+
+    #[test]
+    fn test_nvml_reinit_counter_tracks_collector() {
+        let exporter = mock_exporter(mock_device(), ExporterConfig::default());
+        let mfs = exporter.gather();
+        let mf = find_family(&mfs, "nvml_reinit_total").expect("nvml_reinit_total should be present");
+        assert_eq!(mf.get_metric()[0].get_counter().get_value(), 0.0);
+
+        exporter.nvml_reinit_count.fetch_add(2, Ordering::Relaxed);
+        let mfs = exporter.gather();
+        let mf = find_family(&mfs, "nvml_reinit_total").unwrap();
+        assert_eq!(mf.get_metric()[0].get_counter().get_value(), 2.0);
+    }
+
+    #[test]
+    fn test_smi_parse_errors_counter_tracks_collector() {
+        let exporter = mock_exporter(mock_device(), ExporterConfig::default());
+        exporter.smi_parse_error_count.fetch_add(3, Ordering::Relaxed);
+        let mfs = exporter.gather();
+
+        let mf = find_family(&mfs, "smi_parse_errors_total").expect("smi_parse_errors_total should be present");
+        assert_eq!(mf.get_metric()[0].get_counter().get_value(), 3.0);
+    }
+
+    #[test]
+    fn test_encoder_capacity_per_codec() {
+        let device = Device {
+            encoder_capacity_h264: Some(75.0),
+            encoder_capacity_hevc: Some(50.0),
+            encoder_sessions: Some(2.0),
+            ..mock_device()
+        };
+        let exporter = mock_exporter(device, ExporterConfig::default());
+        let mfs = exporter.gather();
+
+        let mf = find_family(&mfs, "encoder_capacity").expect("encoder_capacity should be present");
+        let capacity_for = |codec: &str| {
+            mf.get_metric()
+                .iter()
+                .find(|m| m.get_label().iter().any(|l| l.get_name() == "codec" && l.get_value() == codec))
+                .map(|m| m.get_gauge().get_value())
+        };
+        assert_eq!(capacity_for("h264"), Some(75.0));
+        assert_eq!(capacity_for("hevc"), Some(50.0));
+
+        let sessions = find_family(&mfs, "encoder_sessions").expect("encoder_sessions should be present");
+        assert_eq!(sessions.get_metric()[0].get_gauge().get_value(), 2.0);
+    }
+
+    #[test]
+    fn test_encoder_capacity_skipped_when_unsupported() {
+        let exporter = mock_exporter(mock_device(), ExporterConfig::default());
+        let mfs = exporter.gather();
+
+        assert!(find_family(&mfs, "encoder_capacity").is_none());
+    }
+
+    #[test]
+    fn test_energy_consumption_counter() {
+        let device = Device {
+            energy_consumption: Some(123456789.0),
+            ..mock_device()
+        };
+        let exporter = mock_exporter(device, ExporterConfig::default());
+        let mfs = exporter.gather();
+
+        let mf = find_family(&mfs, "energy_consumption_millijoules_total")
+            .expect("energy_consumption_millijoules_total should be present");
+        assert!(mf.get_name().ends_with("_total"));
+        assert_eq!(mf.get_field_type(), prometheus::proto::MetricType::COUNTER);
+        assert_eq!(mf.get_metric()[0].get_counter().get_value(), 123456789.0);
+
+        // A second scrape reporting the same total should not double count
+        let mfs = exporter.gather();
+        let mf = find_family(&mfs, "energy_consumption_millijoules_total").unwrap();
+        assert_eq!(mf.get_metric()[0].get_counter().get_value(), 123456789.0);
+    }
+
+    #[test]
+    fn test_device_lost() {
+        let lost = Device {
+            lost: true,
+            ..mock_device()
+        };
+        let exporter = mock_exporter(lost, ExporterConfig::default());
+        let mfs = exporter.gather();
+
+        let mf = find_family(&mfs, "device_lost").expect("device_lost should be present");
+        assert_eq!(mf.get_metric()[0].get_gauge().get_value(), 1.0);
+        // The collection itself still succeeds
+        let mf = find_family(&mfs, "up").unwrap();
+        assert_eq!(mf.get_metric()[0].get_gauge().get_value(), 1.0);
+        // Without the readings of the lost GPU, rather than zeros in their place
+        for name in ["temperatures", "power_usage_milliwatts", "memory_used_bytes", "utilization_gpu", "device_info"] {
+            assert!(find_family(&mfs, name).is_none(), "{} is exported", name);
+        }
+
+        let exporter = mock_exporter(mock_device(), ExporterConfig::default());
+        let mfs = exporter.gather();
+        let mf = find_family(&mfs, "device_lost").unwrap();
+        assert_eq!(mf.get_metric()[0].get_gauge().get_value(), 0.0);
+    }
+
+    #[test]
+    fn test_device_lost_after_collections() {
+        let mut collector = MockMetricsCollector::new();
+        let mut collections = 0;
+        collector.expect_collect().returning(move || {
+            collections += 1;
+            let lost = Device {
+                minor_number: "1".to_string(),
+                lost: collections > 1,
+                ..mock_device()
+            };
+            Ok(Metrics {
+                version: "525.116.04".to_string(),
+                devices: vec![mock_device(), lost],
+                ..Default::default()
+            })
+        });
+        let exporter = Exporter::with_collector(Arc::new(collector), ExporterConfig::default());
+        exporter.refresh().unwrap();
+        let text = encode(&exporter.gather());
+
+        // The series the GPU had before it was lost are gone, the other GPU's remain
+        assert!(!text.contains(r#"nvidia_temperatures{minor="1"}"#));
+        assert!(text.contains(r#"nvidia_temperatures{minor="0"}"#));
+        assert!(text.contains(r#"nvidia_device_lost{minor="1"} 1"#));
+        assert!(text.contains(r#"nvidia_device_lost{minor="0"} 0"#));
+    }
+
+    #[test]
+    fn test_unidentified_lost_device_keeps_healthy_series() {
+        let mut collector = MockMetricsCollector::new();
+        collector.expect_collect().returning(|| {
+            // As collected for a GPU NVML can't identify, at the minor number of the healthy one
+            let lost = Device {
+                index: "0".to_string(),
+                minor_number: "lost-0".to_string(),
+                lost: true,
+                ..Default::default()
+            };
+            let healthy = Device {
+                index: "1".to_string(),
+                minor_number: "0".to_string(),
+                ..mock_device()
+            };
+            Ok(Metrics {
+                version: "525.116.04".to_string(),
+                devices: vec![lost, healthy],
+                ..Default::default()
+            })
+        });
+        let exporter = Exporter::with_collector(Arc::new(collector), ExporterConfig::default());
+        let text = encode(&exporter.gather());
+
+        assert!(text.contains(r#"nvidia_temperatures{minor="0"}"#));
+        assert!(text.contains(r#"nvidia_device_lost{minor="0"} 0"#));
+        assert!(text.contains(r#"nvidia_device_lost{minor="lost-0"} 1"#));
+    }
+
+    #[test]
+    fn test_clock_graphics_customer_boost_max() {
+        let device = Device {
+            clock_graphics_max: Some(1980.0),
+            clock_graphics_customer_boost_max: Some(1755.0),
+            ..mock_device()
+        };
+        let exporter = mock_exporter(device, ExporterConfig::default());
+        let mfs = exporter.gather();
+
+        let mf = find_family(&mfs, "clock_graphics_customer_boost_max_mhz")
+            .expect("clock_graphics_customer_boost_max_mhz should be present");
+        assert_eq!(mf.get_metric()[0].get_gauge().get_value(), 1755.0);
+        assert_eq!(
+            find_family(&mfs, "clock_graphics_max_mhz").unwrap().get_metric()[0].get_gauge().get_value(),
+            1980.0
+        );
+
+        // Nothing is exported when unsupported, even without drop_zero_value_optional_metrics
+        let exporter = mock_exporter(mock_device(), ExporterConfig::default());
+        assert!(find_family(&exporter.gather(), "clock_graphics_customer_boost_max_mhz").is_none());
+    }
+
+    #[test]
+    fn test_throttling_supported() {
+        // Nothing, idle alone, then idle with SW power cap and HW slowdown
+        for (mask, supported) in [(0x0, 0.0), (0x1, 0.0), (0x1 | 0x4 | 0x8, 1.0)] {
+            let device = Device {
+                supported_throttle_reasons: Some(mask),
+                ..mock_device()
+            };
+            let exporter = mock_exporter(device, ExporterConfig::default());
+            let mfs = exporter.gather();
+
+            let mf = find_family(&mfs, "throttling_supported").expect("throttling_supported should be present");
+            assert_eq!(mf.get_metric()[0].get_gauge().get_value(), supported, "mask {:#x}", mask);
+        }
+
+        // An unknown mask says nothing about throttling either way
+        let exporter = mock_exporter(mock_device(), ExporterConfig::default());
+        assert!(find_family(&exporter.gather(), "throttling_supported").is_none());
+    }
+
+    #[test]
+    fn test_pstate_max_clock_graphics() {
+        let device = Device {
+            pstate_clock_graphics_max: vec![(0, 1410.0), (8, 210.0)],
+            ..mock_device()
+        };
+        let exporter = mock_exporter(device, ExporterConfig::default());
+        let mfs = exporter.gather();
+
+        let mf = find_family(&mfs, "pstate_max_clock_graphics_mhz").expect("pstate_max_clock_graphics_mhz should be present");
+        let clocks: BTreeMap<String, f64> = mf
+            .get_metric()
+            .iter()
+            .map(|m| {
+                let pstate = m.get_label().iter().find(|l| l.get_name() == "pstate").unwrap().get_value();
+                (pstate.to_string(), m.get_gauge().get_value())
+            })
+            .collect();
+        assert_eq!(clocks, BTreeMap::from([("P0".to_string(), 1410.0), ("P8".to_string(), 210.0)]));
+
+        // Nothing is exported for cards without P-State clocks, or when they aren't collected
+        let exporter = mock_exporter(mock_device(), ExporterConfig::default());
+        assert!(find_family(&exporter.gather(), "pstate_max_clock_graphics_mhz").is_none());
+    }
+
+    #[test]
+    fn test_raw_field() {
+        let device = Device {
+            raw_fields: vec![(82, 71.0), (196, 3.5)],
+            ..mock_device()
+        };
+        let exporter = mock_exporter(device, ExporterConfig::default());
+        let mfs = exporter.gather();
+
+        let mf = find_family(&mfs, "raw_field").expect("raw_field should be present");
+        let values: BTreeMap<String, f64> = mf
+            .get_metric()
+            .iter()
+            .map(|m| {
+                let field_id = m.get_label().iter().find(|l| l.get_name() == "field_id").unwrap().get_value();
+                (field_id.to_string(), m.get_gauge().get_value())
+            })
+            .collect();
+        assert_eq!(values, BTreeMap::from([("196".to_string(), 3.5), ("82".to_string(), 71.0)]));
+
+        // Nothing is exported without extra field ids
+        let exporter = mock_exporter(mock_device(), ExporterConfig::default());
+        assert!(find_family(&exporter.gather(), "raw_field").is_none());
+    }
+
+    #[test]
+    fn test_active_clock_limit() {
+        assert_eq!(active_clock_limit(0x0), "none");
+        assert_eq!(active_clock_limit(0x1), "low_utilization");
+        assert_eq!(active_clock_limit(0x1 | 0x2), "clock_setting");
+        assert_eq!(active_clock_limit(0x100), "clock_setting");
+        assert_eq!(active_clock_limit(0x10 | 0x2), "sync_boost");
+        assert_eq!(active_clock_limit(0x8 | 0x10), "hw_slowdown");
+        assert_eq!(active_clock_limit(0x4 | 0x8), "power");
+        assert_eq!(active_clock_limit(0x80), "power");
+        assert_eq!(active_clock_limit(0x20 | 0x4 | 0x1), "thermal");
+        assert_eq!(active_clock_limit(0x40 | 0x80), "thermal");
+        // Bits NVML may add later don't count as a reason
+        assert_eq!(active_clock_limit(0x1000), "none");
+    }
+
+    #[test]
+    fn test_active_clock_limit_one_hot() {
+        let device = Device {
+            current_throttle_reasons: Some(0x4 | 0x1),
+            ..mock_device()
+        };
+        let exporter = mock_exporter(device, ExporterConfig::default());
+        let mfs = exporter.gather();
+
+        let mf = find_family(&mfs, "active_clock_limit").expect("active_clock_limit should be present");
+        assert_eq!(mf.get_metric().len(), CLOCK_LIMIT_REASONS.len() + 1);
+        for metric in mf.get_metric() {
+            let reason = metric.get_label().iter().find(|l| l.get_name() == "reason").unwrap().get_value();
+            let expected = if reason == "power" { 1.0 } else { 0.0 };
+            assert_eq!(metric.get_gauge().get_value(), expected, "reason {}", reason);
+        }
+
+        // Nothing is exported when the throttle reasons are unknown
+        let exporter = mock_exporter(mock_device(), ExporterConfig::default());
+        assert!(find_family(&exporter.gather(), "active_clock_limit").is_none());
+    }
+
+    #[test]
+    fn test_throttle_reason_seconds() {
+        let exporter = mock_exporter(mock_device(), ExporterConfig::default());
+        let start = Instant::now();
+        let sample = |mask, secs| {
+            let sample = DeviceSample {
+                minor_number: "0".to_string(),
+                throttle_reasons: Some(mask),
+                clock_graphics: None,
+            };
+            exporter.record_samples(&[sample], start + Duration::from_secs(secs));
+        };
+
+        // 2s power capped, 1s also thermally slowed down, 1s idle
+        sample(0x4, 0);
+        sample(0x4, 1);
+        sample(0x4 | 0x20, 2);
+        sample(0x1, 3);
+        sample(0x1, 4);
+        // A gap in sampling isn't attributed to anything
+        exporter.record_samples(&[], start + Duration::from_secs(5));
+        sample(0x1, 100);
+
+        let mfs = exporter.gather();
+        let mf = find_family(&mfs, "throttle_reason_seconds_total").expect("throttle_reason_seconds_total should be present");
+        let seconds: BTreeMap<&str, f64> = mf
+            .get_metric()
+            .iter()
+            .map(|metric| {
+                let reason = metric.get_label().iter().find(|l| l.get_name() == "reason").unwrap().get_value();
+                (reason, metric.get_counter().get_value())
+            })
+            .collect();
+        assert_eq!(
+            seconds,
+            BTreeMap::from([("gpu_idle", 1.0), ("sw_power_cap", 3.0), ("sw_thermal_slowdown", 1.0)])
+        );
+    }
+
+    #[test]
+    fn test_clock_graphics_sampled_range() {
+        let series = [1980.0, 1410.0, 1755.0, 2010.0, 1890.0];
+        let next = AtomicUsize::new(0);
+        let mut collector = MockMetricsCollector::new();
+        collector.expect_collect().returning(|| {
+            Ok(Metrics {
+                devices: vec![Device {
+                    clock_graphics: Some(1800.0),
+                    ..mock_device()
+                }],
+                ..Default::default()
+            })
+        });
+        collector.expect_sample().returning(move || {
+            let clock = series[next.fetch_add(1, Ordering::SeqCst) % series.len()];
+            Ok(vec![DeviceSample {
+                minor_number: "0".to_string(),
+                throttle_reasons: None,
+                clock_graphics: Some(clock),
+            }])
+        });
+        let exporter = Exporter::with_collector(Arc::new(collector), ExporterConfig::default());
+        let range = |mfs: &[MetricFamily]| {
+            let value = |name| find_family(mfs, name).map(|mf| mf.get_metric()[0].get_gauge().get_value());
+            (value("clock_graphics_sampled_min_mhz"), value("clock_graphics_sampled_max_mhz"))
+        };
+
+        // Nothing sampled yet
+        assert_eq!(range(&exporter.gather()), (None, None));
+
+        for _ in 0..series.len() {
+            exporter.sample_devices();
+        }
+        assert_eq!(range(&exporter.gather()), (Some(1410.0), Some(2010.0)));
+
+        // Each collection starts a new range, which includes the clock it read
+        exporter.sample_devices();
+        assert_eq!(range(&exporter.gather()), (Some(1800.0), Some(1980.0)));
+        assert_eq!(range(&exporter.gather()), (None, None));
+    }
+
+    #[test]
+    fn test_memory_thermal_throttle_events() {
+        let exporter = mock_exporter(mock_device(), ExporterConfig::default());
+        let start = Instant::now();
+        let sample = |mask, secs| {
+            let sample = DeviceSample {
+                minor_number: "0".to_string(),
+                throttle_reasons: Some(mask),
+                clock_graphics: None,
+            };
+            exporter.record_samples(&[sample], start + Duration::from_secs(secs));
+        };
+        let events = |exporter: &Exporter| {
+            find_family(&exporter.gather(), "memory_thermal_throttle_events_total")
+                .map(|mf| mf.get_metric()[0].get_counter().get_value())
+        };
+
+        // Already throttling when first seen, which isn't a start
+        sample(0x20, 0);
+        sample(0x20, 1);
+        assert_eq!(events(&exporter), None);
+
+        // Then two more starts, the second while also power capped; other reasons
+        // coming and going don't count
+        for (secs, mask) in [(2, 0x0), (3, 0x20), (4, 0x20), (5, 0x4), (6, 0x24), (7, 0x64), (8, 0x0)] {
+            sample(mask, secs);
+        }
+        assert_eq!(events(&exporter), Some(2.0));
+
+        // A gap in sampling forgets the previous state
+        exporter.record_samples(&[], start + Duration::from_secs(9));
+        sample(0x20, 10);
+        assert_eq!(events(&exporter), Some(2.0));
+    }
+
+    #[test]
+    fn test_retired_pages_pending() {
+        for pending in [0.0, 1.0] {
+            let device = Device {
+                retired_pages_pending: Some(pending),
+                ..mock_device()
+            };
+            let exporter = mock_exporter(device, ExporterConfig::default());
+            let mfs = exporter.gather();
+
+            let mf = find_family(&mfs, "retired_pages_pending").expect("retired_pages_pending should be present");
+            assert_eq!(mf.get_metric()[0].get_gauge().get_value(), pending);
+        }
+
+        // Cards without page retirement emit nothing
+        let exporter = mock_exporter(mock_device(), ExporterConfig::default());
+        assert!(find_family(&exporter.gather(), "retired_pages_pending").is_none());
+    }
+
+    #[test]
+    fn test_ecc_sram_threshold_exceeded() {
+        for exceeded in [0.0, 1.0] {
+            let device = Device {
+                ecc_sram_threshold_exceeded: Some(exceeded),
+                ..mock_device()
+            };
+            let exporter = mock_exporter(device, ExporterConfig::default());
+            let mfs = exporter.gather();
+
+            let mf = find_family(&mfs, "ecc_sram_threshold_exceeded").expect("ecc_sram_threshold_exceeded should be present");
+            assert_eq!(mf.get_metric()[0].get_gauge().get_value(), exceeded);
+        }
+
+        // Cards before Hopper emit nothing
+        let exporter = mock_exporter(mock_device(), ExporterConfig::default());
+        assert!(find_family(&exporter.gather(), "ecc_sram_threshold_exceeded").is_none());
+    }
+
+    #[test]
+    fn test_inforom() {
+        for valid in [0.0, 1.0] {
+            let device = Device {
+                inforom_valid: Some(valid),
+                inforom_versions: Some(InforomVersions {
+                    image: "G500.0200.00.03".to_string(),
+                    oem: Some("2.0".to_string()),
+                    ecc: Some("6.16".to_string()),
+                    power: None,
+                }),
+                ..mock_device()
+            };
+            let exporter = mock_exporter(device, ExporterConfig::default());
+            let mfs = exporter.gather();
+
+            let mf = find_family(&mfs, "inforom_valid").expect("inforom_valid should be present");
+            assert_eq!(mf.get_metric()[0].get_gauge().get_value(), valid);
+            assert!(encode(&mfs).contains(
+                r#"nvidia_inforom_info{ecc_version="6.16",image_version="G500.0200.00.03",minor="0",oem_version="2.0",power_version=""} 1"#
+            ));
+        }
+
+        // Consumer cards without an infoROM emit nothing
+        let exporter = mock_exporter(mock_device(), ExporterConfig::default());
+        let mfs = exporter.gather();
+        assert!(find_family(&mfs, "inforom_valid").is_none());
+        assert!(find_family(&mfs, "inforom_info").is_none());
+    }
+
+    #[test]
+    fn test_reset_required_states() {
+        for state in [0.0, 1.0] {
+            let device = Device {
+                reset_required: Some(state),
+                ..mock_device()
+            };
+            let exporter = mock_exporter(device, ExporterConfig::default());
+            let mfs = exporter.gather();
+
+            let mf = find_family(&mfs, "reset_required").expect("reset_required should be present");
+            assert_eq!(mf.get_metric()[0].get_gauge().get_value(), state);
+        }
+
+        // Drivers without a recovery action API emit nothing
+        let exporter = mock_exporter(mock_device(), ExporterConfig::default());
+        let mfs = exporter.gather();
+        assert!(find_family(&mfs, "reset_required").is_none());
+    }
+
+    #[test]
+    fn test_recommended_recovery_action_one_hot() {
+        let recommended = |action: Option<u64>| -> BTreeMap<String, f64> {
+            let device = Device {
+                recovery_action: action,
+                ..mock_device()
+            };
+            let exporter = mock_exporter(device, ExporterConfig::default());
+            let mfs = exporter.gather();
+            find_family(&mfs, "recommended_recovery_action")
+                .map(|mf| {
+                    mf.get_metric()
+                        .iter()
+                        .map(|metric| {
+                            let action = metric.get_label().iter().find(|l| l.get_name() == "action").unwrap();
+                            (action.get_value().to_string(), metric.get_gauge().get_value())
+                        })
+                        .collect()
+                })
+                .unwrap_or_default()
+        };
+        let one_hot = |hot: &str| -> BTreeMap<String, f64> {
+            RECOVERY_ACTIONS
+                .iter()
+                .map(|(action, _)| (action.to_string(), if *action == hot { 1.0 } else { 0.0 }))
+                .collect()
+        };
+
+        assert_eq!(recommended(Some(0)), one_hot("none"));
+        assert_eq!(recommended(Some(1)), one_hot("gpu_reset"));
+        assert_eq!(recommended(Some(2)), one_hot("node_reboot"));
+        assert_eq!(recommended(Some(4)), one_hot("drain_and_reset"));
+        // An action added by a newer driver doesn't claim to be one of the known ones
+        assert_eq!(recommended(Some(9)), one_hot(""));
+        // Drivers without the recovery action API emit nothing
+        assert!(recommended(None).is_empty());
+    }
+
+    #[test]
+    fn test_memory_unit_conversion() {
+        let device = Device {
+            memory_total: 10737418240.0,
+            memory_used: 5368709120.0,
+            ..mock_device()
+        };
+
+        let cases = [
+            (MemoryUnit::Bytes, "memory_total", "memory_used", 10737418240.0, 5368709120.0),
+            (MemoryUnit::Mib, "memory_total_mib", "memory_used_mib", 10240.0, 5120.0),
+            (MemoryUnit::Gib, "memory_total_gib", "memory_used_gib", 10.0, 5.0),
+        ];
+        for (unit, total_name, used_name, total, used) in cases {
+            let config = ExporterConfig {
+                memory_unit: unit,
+                ..Default::default()
+            };
+            let exporter = mock_exporter(device.clone(), config);
+            let mfs = exporter.gather();
+
+            let total_mf = find_family(&mfs, total_name)
+                .unwrap_or_else(|| panic!("{} should be present for {:?}", total_name, unit));
+            assert_eq!(total_mf.get_metric()[0].get_gauge().get_value(), total);
+            let used_mf = find_family(&mfs, used_name)
+                .unwrap_or_else(|| panic!("{} should be present for {:?}", used_name, unit));
+            assert_eq!(used_mf.get_metric()[0].get_gauge().get_value(), used);
+        }
+    }
+
+    #[test]
+    fn test_jpeg_ofa_utilization() {
+        let device = Device {
+            decoder_utilization: Some(20.0),
+            jpeg_utilization: Some(35.0),
+            ofa_utilization: Some(60.0),
+            ..mock_device()
+        };
+        let exporter = mock_exporter(device, ExporterConfig::default());
+        let mfs = exporter.gather();
+
+        for (name, value) in [("decoder_utilization", 20.0), ("jpeg_utilization", 35.0), ("ofa_utilization", 60.0)] {
+            let mf = find_family(&mfs, name).unwrap_or_else(|| panic!("{} should be present", name));
+            assert_eq!(mf.get_metric()[0].get_gauge().get_value(), value);
+        }
+
+        // Cards without the engines get no series, unlike the encoder and decoder
+        let exporter = mock_exporter(mock_device(), ExporterConfig::default());
+        let mfs = exporter.gather();
+        assert!(find_family(&mfs, "jpeg_utilization").is_none());
+        assert!(find_family(&mfs, "ofa_utilization").is_none());
+    }
+
+    #[test]
+    fn test_utilization_scale_ratio() {
+        let device = Device {
+            utilization_gpu: 75.0,
+            utilization_gpu_average: 50.0,
+            utilization_memory: 25.0,
+            encoder_utilization: Some(10.0),
+            decoder_utilization: Some(5.0),
+            ..mock_device()
+        };
+        let config = ExporterConfig {
+            utilization_scale: UtilizationScale::Ratio,
+            ..Default::default()
+        };
+        let exporter = mock_exporter(device, config);
+        let mfs = exporter.gather();
+
+        for (name, value) in [
+            ("utilization_gpu_ratio", 0.75),
+            ("utilization_gpu_average_ratio", 0.5),
+            ("utilization_memory_ratio", 0.25),
+            ("encoder_utilization_ratio", 0.1),
+            ("decoder_utilization_ratio", 0.05),
+        ] {
+            let mf = find_family(&mfs, name).unwrap_or_else(|| panic!("{} should be present", name));
+            assert_eq!(mf.get_metric()[0].get_gauge().get_value(), value, "{}", name);
+        }
+        assert!(find_family(&mfs, "utilization_gpu").is_none());
+    }
+
+    #[test]
+    fn test_utilization_scale_ratio_with_rounding() {
+        let device = Device {
+            utilization_gpu: 72.4,
+            ..mock_device()
+        };
+        let config = ExporterConfig {
+            utilization_scale: UtilizationScale::Ratio,
+            round_integers: true,
+            ..Default::default()
+        };
+        let exporter = mock_exporter(device, config);
+        let mfs = exporter.gather();
+
+        let mf = find_family(&mfs, "utilization_gpu_ratio").unwrap();
+        assert_eq!(mf.get_metric()[0].get_gauge().get_value(), 0.72);
+    }
+
+    #[test]
+    fn test_utilization_scale_percent_default() {
+        let device = Device {
+            utilization_gpu: 75.0,
+            ..mock_device()
+        };
+        let exporter = mock_exporter(device, ExporterConfig::default());
+        let mfs = exporter.gather();
+
+        let mf = find_family(&mfs, "utilization_gpu").unwrap();
+        assert_eq!(mf.get_metric()[0].get_gauge().get_value(), 75.0);
+        assert!(find_family(&mfs, "utilization_gpu_ratio").is_none());
+    }
+
+    #[test]
+    fn test_gpu_operation_mode() {
+        let device = Device {
+            gpu_operation_mode: Some(1.0),
+            gpu_operation_mode_pending: Some(0.0),
+            ..mock_device()
+        };
+        let exporter = mock_exporter(device, ExporterConfig::default());
+        let mfs = exporter.gather();
+
+        let current = find_family(&mfs, "gpu_operation_mode").expect("gpu_operation_mode should be present");
+        assert_eq!(current.get_metric()[0].get_gauge().get_value(), 1.0);
+        let pending = find_family(&mfs, "gpu_operation_mode_pending")
+            .expect("gpu_operation_mode_pending should be present");
+        assert_eq!(pending.get_metric()[0].get_gauge().get_value(), 0.0);
+
+        // Cards without GOM support emit nothing
+        let exporter = mock_exporter(mock_device(), ExporterConfig::default());
+        let mfs = exporter.gather();
+        assert!(find_family(&mfs, "gpu_operation_mode").is_none());
+        assert!(find_family(&mfs, "gpu_operation_mode_pending").is_none());
+    }
+
+    #[test]
+    fn test_display_status() {
+        // A display connected to the card, but nothing driving it
+        let device = Device {
+            display_active: Some(0.0),
+            display_connected: Some(1.0),
+            ..mock_device()
+        };
+        let exporter = mock_exporter(device, ExporterConfig::default());
+        let mfs = exporter.gather();
+
+        let active = find_family(&mfs, "display_active").expect("display_active should be present");
+        assert_eq!(active.get_metric()[0].get_gauge().get_value(), 0.0);
+        let connected = find_family(&mfs, "display_connected").expect("display_connected should be present");
+        assert_eq!(connected.get_metric()[0].get_gauge().get_value(), 1.0);
+
+        // Headless cards emit nothing
+        let exporter = mock_exporter(mock_device(), ExporterConfig::default());
+        let mfs = exporter.gather();
+        assert!(find_family(&mfs, "display_active").is_none());
+        assert!(find_family(&mfs, "display_connected").is_none());
+    }
+
+    #[test]
+    fn test_drop_device_info_metric() {
+        let config = ExporterConfig {
+            drop_device_info_metric: true,
+            ..Default::default()
+        };
+        let exporter = mock_exporter(mock_device(), config);
+        let mfs = exporter.gather();
+
+        let exposition = encode(&mfs);
+
+        assert!(!exposition.contains(&mock_device().uuid), "UUID leaked into exposition");
+        assert!(find_family(&mfs, "info").is_none());
+        // The driver version and numeric telemetry are kept
+        assert!(find_family(&mfs, "driver_info").is_some());
+        assert!(find_family(&mfs, "temperatures").is_some());
+    }
+
+    #[test]
+    fn test_memory_bandwidth() {
+        let device = Device {
+            clock_memory: Some(9501.0),
+            memory_bus_width: Some(320.0),
+            ..mock_device()
+        };
+        let exporter = mock_exporter(device, ExporterConfig::default());
+        let mfs = exporter.gather();
+
+        let mf = find_family(&mfs, "memory_bandwidth_bytes_per_second")
+            .expect("memory_bandwidth_bytes_per_second should be present");
+        assert_eq!(mf.get_metric()[0].get_gauge().get_value(), 760_080_000_000.0);
+
+        // HBM2 A100: 1215 MHz on a 5120-bit bus
+        assert_eq!(memory_bandwidth(Some(1215.0), Some(5120.0)), Some(1_555_200_000_000.0));
+    }
+
+    #[test]
+    fn test_gpu_num_cores_and_sm() {
+        let device = Device {
+            num_cores: Some(6912.0),
+            num_sm: Some(108.0),
+            ..mock_device()
+        };
+        let exporter = mock_exporter(device, ExporterConfig::default());
+        let mfs = exporter.gather();
+
+        let mf = find_family(&mfs, "gpu_num_cores").expect("gpu_num_cores should be present");
+        assert_eq!(mf.get_metric()[0].get_gauge().get_value(), 6912.0);
+        let mf = find_family(&mfs, "gpu_num_sm").expect("gpu_num_sm should be present");
+        assert_eq!(mf.get_metric()[0].get_gauge().get_value(), 108.0);
+
+        // GPUs without the core count emit neither
+        let exporter = mock_exporter(mock_device(), ExporterConfig::default());
+        let mfs = exporter.gather();
+        assert!(find_family(&mfs, "gpu_num_cores").is_none());
+        assert!(find_family(&mfs, "gpu_num_sm").is_none());
+    }
+
+    #[test]
+    fn test_dcgm_fields() {
+        let device = Device {
+            sm_active: Some(0.75),
+            tensor_active: Some(0.4),
+            dram_active: Some(0.25),
+            ..mock_device()
+        };
+        let exporter = mock_exporter(device, ExporterConfig::default());
+        let mfs = exporter.gather();
+        let value = |name: &str| find_family(&mfs, name).map(|mf| mf.get_metric()[0].get_gauge().get_value());
+
+        assert_eq!(value("sm_active_ratio"), Some(0.75));
+        assert_eq!(value("tensor_active_ratio"), Some(0.4));
+        assert_eq!(value("dram_active_ratio"), Some(0.25));
+
+        // Nothing without DCGM
+        let exporter = mock_exporter(mock_device(), ExporterConfig::default());
+        let mfs = exporter.gather();
+        assert!(find_family(&mfs, "sm_active_ratio").is_none());
+        assert!(find_family(&mfs, "tensor_active_ratio").is_none());
+        assert!(find_family(&mfs, "dram_active_ratio").is_none());
+    }
+
+    #[test]
+    fn test_memory_bandwidth_missing_inputs() {
+        let device = Device {
+            clock_memory: Some(9501.0),
+            memory_bus_width: None,
+            ..mock_device()
+        };
+        let exporter = mock_exporter(device, ExporterConfig::default());
+        let mfs = exporter.gather();
+
+        assert!(find_family(&mfs, "memory_bandwidth_bytes_per_second").is_none());
+        assert_eq!(memory_bandwidth(None, Some(320.0)), None);
+    }
+
+    #[test]
+    fn test_info_pci_bus_id_label() {
+        let exporter = mock_exporter(mock_device(), ExporterConfig::default());
+        let mfs = exporter.gather();
+
+        let mf = find_family(&mfs, "info").expect("info should be present");
+        let bus_id = mf.get_metric()[0]
+            .get_label()
+            .iter()
+            .find(|l| l.get_name() == "pci_bus_id")
+            .map(|l| l.get_value().to_string())
+            .expect("info should have a pci_bus_id label");
+        assert_eq!(bus_id, "0000:65:00.0");
+
+        let parts: Vec<&str> = bus_id.split([':', '.']).collect();
+        assert_eq!(parts.iter().map(|p| p.len()).collect::<Vec<_>>(), vec![4, 2, 2, 1]);
     }
 }
@@ -1,109 +1,941 @@
 use axum::{
-    extract::Request,
-    http::StatusCode,
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, StatusCode},
     response::{Html, Response},
-    routing::get,
-    Router,
+    routing::{get, post},
+    Json, Router,
 };
-use clap::Parser;
-use prometheus::{Encoder, TextEncoder};
+use clap::{Parser, Subcommand};
+use prometheus::proto::MetricFamily;
+use std::io::Write;
 use std::net::SocketAddr;
+use std::os::unix::fs::FileTypeExt;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tracing::{debug, info, warn};
 
+mod background;
+mod cgroup;
+mod dcgm;
+mod dump;
 mod exporter;
 mod metrics;
+mod privileges;
+mod probe;
+mod process;
+mod push;
+mod ratelimit;
+mod remote;
+mod samples;
+mod shutdown;
+mod smi;
+mod status;
+mod throttle;
+mod visibility;
 
-use exporter::Exporter;
+use dump::DumpFormat;
+use ratelimit::TokenBucket;
+use remote::RemoteEndpoint;
+use shutdown::Drain;
+use exporter::{
+    encode_text, CatalogEntry, Compat, DeviceOrder, Exporter, ExporterConfig, MemoryUnit, UtilizationScale,
+    RESERVED_LABEL_NAMES,
+};
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    /// Address to listen on for web interface and telemetry
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Address to listen on for web interface and telemetry, or "unix:<path>" for a Unix domain socket
     #[arg(long, default_value = "0.0.0.0:9445")]
     web_listen_address: String,
 
     /// Path under which to expose metrics
     #[arg(long, default_value = "/metrics")]
     web_telemetry_path: String,
+
+    /// Switch to this user (name or uid) once NVML is initialized and the listener is bound
+    #[arg(long, value_parser = privileges::parse_user)]
+    run_as_user: Option<privileges::User>,
+
+    /// Switch to this group (name or gid) once NVML is initialized and the listener is
+    /// bound (default: the primary group of --run-as-user)
+    #[arg(long, value_parser = privileges::parse_group)]
+    run_as_group: Option<privileges::Group>,
+
+    /// Skip optional metrics the GPU doesn't support instead of reporting 0
+    #[arg(long)]
+    drop_zero_value_optional_metrics: bool,
+
+    /// Suppress the nvidia_info metric, which exposes GPU UUIDs
+    #[arg(long)]
+    drop_device_info_metric: bool,
+
+    /// Unit used to report memory_total/memory_used
+    #[arg(long, value_enum, default_value_t = MemoryUnit::Bytes)]
+    memory_unit: MemoryUnit,
+
+    /// Scale used to report the GPU, memory and video engine utilization
+    #[arg(long, value_enum, default_value_t = UtilizationScale::Percent)]
+    utilization_scale: UtilizationScale,
+
+    /// Maximum time to wait for NVML collection before failing a scrape (e.g. "10s", "500ms")
+    #[arg(long, default_value = "10s", value_parser = humantime::parse_duration)]
+    collection_timeout: Duration,
+
+    /// Bound collections by the scrape timeout Prometheus sends in a header, and answer
+    /// scrapes that run out of time with the previous collection instead of a 503
+    #[arg(long)]
+    scrape_timeout_header: bool,
+
+    /// Answer scrapes of the telemetry path over this rate with 429 Too Many Requests,
+    /// allowing bursts of up to a second's worth (e.g. "0.5" for one every 2s)
+    #[arg(long, value_parser = parse_scrape_rate)]
+    max_scrapes_per_second: Option<f64>,
+
+    /// On shutdown, how long to let the requests in flight finish before exiting
+    #[arg(long, default_value = "10s", value_parser = humantime::parse_duration)]
+    shutdown_grace: Duration,
+
+    /// Retry binding the listen address this many times while it is still in use, e.g.
+    /// by the previous process during a rolling restart
+    #[arg(long, default_value_t = 0)]
+    bind_retries: u32,
+
+    /// How long to wait between two --bind-retries
+    #[arg(long, default_value = "1s", value_parser = humantime::parse_duration)]
+    bind_retry_interval: Duration,
+
+    /// Collect in the background at this interval (e.g. "15s") and serve scrapes from
+    /// the latest collection instead of collecting on every scrape
+    #[arg(long, value_parser = humantime::parse_duration)]
+    collect_interval: Option<Duration>,
+
+    /// Randomly move each background collection by up to this fraction of --collect-interval (0-1)
+    #[arg(long, default_value_t = 0.0, value_parser = parse_jitter)]
+    collect_jitter: f64,
+
+    /// With --collect-interval, also sample the throttle reasons and graphics clock at
+    /// this interval (e.g. "1s") to export the time spent in each reason and the clock
+    /// range between collections
+    #[arg(
+        long,
+        alias = "throttle-sample-interval",
+        requires = "collect_interval",
+        value_parser = humantime::parse_duration
+    )]
+    sample_interval: Option<Duration>,
+
+    /// Collect each GPU on its own thread and leave out those whose collection takes
+    /// longer than this (e.g. "2s"), so one wedged GPU doesn't fail the whole scrape
+    #[arg(long, value_parser = humantime::parse_duration)]
+    per_device_timeout: Option<Duration>,
+
+    /// Re-initialize NVML after this many consecutive failed collections (0 disables)
+    #[arg(long, default_value_t = exporter::DEFAULT_NVML_REINIT_THRESHOLD)]
+    nvml_reinit_threshold: u32,
+
+    /// Maximum number of encoder sessions exported per GPU
+    #[arg(long, default_value_t = exporter::DEFAULT_MAX_SESSIONS_PER_GPU)]
+    max_sessions_per_gpu: usize,
+
+    /// Tag per-process metrics with the SLURM job id (or cgroup leaf) read from /proc/<pid>/cgroup
+    #[arg(long)]
+    enable_cgroup_labels: bool,
+
+    /// Tag per-process metrics with the process's command name read from /proc/<pid>/comm
+    #[arg(long)]
+    enable_process_names: bool,
+
+    /// Also export the P-State as a one-hot set (nvidia_performance_state_current{pstate="P0".."P15"})
+    #[arg(long)]
+    performance_state_one_hot: bool,
+
+    /// Collect the maximum graphics clock of every supported P-State (nvidia_pstate_max_clock_graphics_mhz)
+    #[arg(long)]
+    collect_pstate_clocks: bool,
+
+    /// Comma-separated NVML field ids to query on every GPU and export as is
+    /// (nvidia_raw_field{field_id="..."}); ids a GPU doesn't support are skipped
+    #[arg(long, value_delimiter = ',')]
+    extra_field_ids: Vec<u32>,
+
+    /// Order used to assign the index label; only the index label changes, not minor
+    #[arg(long, value_enum, default_value_t = DeviceOrder::Nvml)]
+    device_order: DeviceOrder,
+
+    /// Rename metrics to match another exporter, for dashboards and alerts built on it
+    #[arg(long, value_enum)]
+    compat: Option<Compat>,
+
+    /// Export a summary of the power usage sampled at each scrape over this window (e.g. "5m")
+    #[arg(long, value_parser = humantime::parse_duration)]
+    power_summary_window: Option<Duration>,
+
+    /// Most power usage samples kept per GPU for --power-summary-window; the oldest
+    /// are evicted beyond it, even within the window
+    #[arg(long, default_value_t = exporter::DEFAULT_SAMPLE_BUFFER_CAPACITY, value_parser = parse_sample_buffer_capacity)]
+    sample_buffer_capacity: usize,
+
+    /// Comma-separated upper bounds in seconds of the nvidia_collection_duration_seconds
+    /// buckets, e.g. "0.05,0.1,0.5,1" (default: the Prometheus client defaults)
+    #[arg(long, value_parser = parse_buckets)]
+    collection_duration_buckets: Option<Buckets>,
+
+    /// Keep serving the last successful collection for this long (e.g. "1m") while collections fail, before reporting up=0
+    #[arg(long, value_parser = humantime::parse_duration)]
+    stale_tolerance: Option<Duration>,
+
+    /// Replace a minor label value with a custom name, e.g. "0=gpu-a" (repeatable).
+    /// The raw value is kept in a minor_number label
+    #[arg(long, value_parser = parse_minor_name)]
+    relabel_minor: Vec<(String, String)>,
+
+    /// Add a constant label to every metric, e.g. "cluster=gpu-east" (repeatable)
+    #[arg(long = "label", value_name = "NAME=VALUE", value_parser = parse_const_label)]
+    const_labels: Vec<(String, String)>,
+
+    /// Export the NUMA node and CPU affinity of each GPU
+    #[arg(long)]
+    enable_topology_metrics: bool,
+
+    /// Serve a table of the GPUs from the last collection at the root page
+    #[arg(long)]
+    enable_status_page: bool,
+
+    /// Serve POST /admin/reset, which clears the peaks and sample windows (for test harnesses)
+    #[arg(long)]
+    enable_admin_endpoint: bool,
+
+    /// Timestamp every scraped sample with the time of the collection it comes from
+    #[arg(long)]
+    sample_timestamps: bool,
+
+    /// Leave per-device series unchanged since the previous scrape out of /metrics (non-standard, see README)
+    #[arg(long)]
+    delta_mode: bool,
+
+    /// Write the HELP and TYPE lines of metrics without samples, e.g. the device metrics on a node without GPUs
+    #[arg(long)]
+    always_emit_metric_types: bool,
+
+    /// Also export node totals of the power and memory used and the mean GPU utilization across GPUs
+    #[arg(long)]
+    emit_node_aggregates: bool,
+
+    /// Read the SM, tensor and DRAM activity of each GPU from the node's dcgm-exporter
+    #[arg(long)]
+    enable_dcgm_fields: bool,
+
+    /// dcgm-exporter to read --enable-dcgm-fields from, as "host[:port][/path]"
+    #[arg(long, default_value = "localhost:9400", value_parser = dcgm::parse_endpoint)]
+    dcgm_exporter_endpoint: RemoteEndpoint,
+
+    /// Round whole-number metrics (temperatures, utilization, fan speed, P-State) before exporting
+    #[arg(long)]
+    round_integers: bool,
+
+    /// Collect by running nvidia-smi when NVML fails to initialize
+    #[arg(long)]
+    enable_smi_fallback: bool,
+
+    /// Collect from another exporter ("host[:port][/path]") instead of the local GPUs;
+    /// repeat to aggregate several. Devices get a `host` label (nvidia_remote_up{host})
+    #[arg(long, value_parser = remote::parse_remote_endpoint)]
+    remote_endpoint: Vec<RemoteEndpoint>,
+
+    /// Fail /healthz with 503, and set nvidia_device_count_mismatch, when a collection
+    /// finds a different number of GPUs
+    #[arg(long)]
+    expected_gpu_count: Option<usize>,
+
+    /// Exit with an error at startup if the first collection finds fewer GPUs than this
+    /// (1 when given without a count, as --require-gpus)
+    #[arg(long, value_name = "COUNT", num_args = 0..=1, require_equals = true, default_missing_value = "1")]
+    require_gpus: Option<usize>,
+
+    /// Count the NVML calls of each query by result (nvidia_nvml_call_result_total)
+    #[arg(long)]
+    enable_call_metrics: bool,
+
+    /// Add the GPU index as an `index` label to every device metric
+    #[arg(long)]
+    enable_index_label: bool,
+
+    /// Replace GPU UUIDs with a salted SHA-256 hash wherever they are exported
+    #[arg(long)]
+    redact_uuid: bool,
+
+    /// Salt for --redact-uuid
+    #[arg(long, default_value = "")]
+    uuid_salt: String,
 }
 
-#[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    tracing_subscriber::fmt::init();
+/// Parse a --collect-jitter fraction, which must be within [0, 1]
+fn parse_jitter(s: &str) -> Result<f64, String> {
+    let jitter: f64 = s
+        .parse()
+        .map_err(|_| format!("'{}' is not a number", s))?;
+    if !(0.0..=1.0).contains(&jitter) {
+        return Err(format!("jitter must be between 0 and 1, got {}", jitter));
+    }
+    Ok(jitter)
+}
 
-    let args = Args::parse();
+/// Parse a --max-scrapes-per-second value, which must be a positive number
+fn parse_scrape_rate(s: &str) -> Result<f64, String> {
+    let rate: f64 = s
+        .parse()
+        .map_err(|_| format!("'{}' is not a number", s))?;
+    if !(rate.is_finite() && rate > 0.0) {
+        return Err(format!("scrape rate must be positive, got {}", rate));
+    }
+    Ok(rate)
+}
 
-    let exporter = Exporter::new();
-    let exporter_clone = exporter.clone();
-
-    let app = Router::new()
-        .route(
-            &args.web_telemetry_path,
-            axum::routing::get(move |_req: Request| async move {
-                debug!("Metrics endpoint called");
-                
-                debug!("Gathering metrics from exporter...");
-                let metric_families = exporter_clone.gather();
-                debug!("Gathered {} metric families", metric_families.len());
-                
-                debug!("Creating encoder...");
-                let encoder = TextEncoder::new();
-                let mut buffer = Vec::new();
-                
-                debug!("Encoding {} metric families...", metric_families.len());
-                if let Err(e) = encoder.encode(&metric_families, &mut buffer) {
-                    warn!("Failed to encode metrics: {}", e);
-                    return Response::builder()
-                        .status(StatusCode::INTERNAL_SERVER_ERROR)
-                        .body(format!("Failed to encode metrics: {}", e))
-                        .expect("Failed to build error response");
-                }
-                debug!("Encoded metrics to buffer of {} bytes", buffer.len());
-                
-                debug!("Converting buffer to UTF-8 string...");
-                match String::from_utf8(buffer) {
-                    Ok(body) => {
-                        debug!("Successfully created response body ({} bytes)", body.len());
-                        Response::builder()
-                            .status(StatusCode::OK)
-                            .header("Content-Type", "text/plain; version=0.0.4")
-                            .body(body)
-                            .expect("Failed to build response")
-                    }
-                    Err(e) => {
-                        warn!("Failed to encode metrics as UTF-8: {}", e);
-                        Response::builder()
-                            .status(StatusCode::INTERNAL_SERVER_ERROR)
-                            .body(format!("Failed to encode metrics as UTF-8: {}", e))
-                            .expect("Failed to build error response")
-                    }
-                }
-            }),
+/// Parse a --sample-buffer-capacity value, which must be a positive integer
+fn parse_sample_buffer_capacity(s: &str) -> Result<usize, String> {
+    let capacity: usize = s
+        .parse()
+        .map_err(|_| format!("'{}' is not a number", s))?;
+    if capacity == 0 {
+        return Err("sample buffer capacity must be at least 1".to_string());
+    }
+    Ok(capacity)
+}
+
+/// Histogram bucket upper bounds; an alias so clap takes the whole list as one value
+type Buckets = Vec<f64>;
+
+/// Parse comma-separated histogram bucket bounds, which must be positive and increasing
+fn parse_buckets(s: &str) -> Result<Buckets, String> {
+    let buckets = s
+        .split(',')
+        .map(|bound| {
+            let bound = bound.trim();
+            match bound.parse::<f64>() {
+                Ok(value) if value > 0.0 && value.is_finite() => Ok(value),
+                Ok(_) => Err(format!("bucket bounds must be positive, got {}", bound)),
+                Err(_) => Err(format!("'{}' is not a number", bound)),
+            }
+        })
+        .collect::<Result<Buckets, String>>()?;
+    if let Some(pair) = buckets.windows(2).find(|pair| pair[0] >= pair[1]) {
+        return Err(format!("bucket bounds must be increasing, got {} before {}", pair[0], pair[1]));
+    }
+    Ok(buckets)
+}
+
+/// Parse a "<minor>=<name>" mapping for --relabel-minor
+fn parse_minor_name(s: &str) -> Result<(String, String), String> {
+    let (minor, name) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected <minor>=<name>, got '{}'", s))?;
+    let minor: u32 = minor
+        .parse()
+        .map_err(|_| format!("minor number '{}' is not a non-negative integer", minor))?;
+    if name.is_empty() {
+        return Err(format!("missing name for minor number {}", minor));
+    }
+    Ok((minor.to_string(), name.to_string()))
+}
+
+/// Parse a "<name>=<value>" constant label for --label, following the Prometheus
+/// label name rules and keeping clear of the labels the exporter sets itself
+fn parse_const_label(s: &str) -> Result<(String, String), String> {
+    let (name, value) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected <name>=<value>, got '{}'", s))?;
+    let mut chars = name.chars();
+    let valid = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+    if !valid {
+        return Err(format!("'{}' is not a valid label name", name));
+    }
+    if name.starts_with("__") {
+        return Err(format!("label names starting with __ are reserved, got '{}'", name));
+    }
+    if RESERVED_LABEL_NAMES.contains(&name) {
+        return Err(format!("'{}' is already used as a label by the exporter", name));
+    }
+    if value.is_empty() {
+        return Err(format!("missing value for label '{}'", name));
+    }
+    Ok((name.to_string(), value.to_string()))
+}
+
+#[derive(Subcommand, Debug, PartialEq)]
+enum Command {
+    /// Serve metrics over HTTP (default)
+    Serve,
+    /// Perform a single collection, print it to stdout and exit
+    #[command(group = clap::ArgGroup::new("output").args(["format", "json"]))]
+    Dump {
+        /// Output format
+        #[arg(long, value_enum, default_value_t = DumpFormat::Prometheus)]
+        format: DumpFormat,
+        /// Print the collected data as JSON, same as --format json
+        #[arg(long, conflicts_with = "format")]
+        json: bool,
+        /// Comma-separated device fields to include in the JSON output (default: all), or
+        /// the CSV columns in order (default: the documented set)
+        #[arg(long, visible_alias = "fields", requires = "output", value_delimiter = ',', value_parser = dump::parse_json_field)]
+        json_fields: Vec<String>,
+    },
+    /// Perform a single collection, print a one-line summary and exit non-zero if it failed
+    Probe,
+    /// Print every metric the exporter can export, with its type, labels and help, as JSON
+    ListMetrics,
+    /// Collect and push metrics to a Pushgateway on a timer until interrupted
+    Push {
+        /// Pushgateway to push to, e.g. "http://pushgateway:9091"
+        #[arg(long, value_parser = push::parse_pushgateway_url)]
+        pushgateway_url: String,
+        /// Job name the metrics are grouped under
+        #[arg(long, default_value = "nvidia-gpu-exporter", value_parser = push::parse_job)]
+        job: String,
+        /// Time between pushes (e.g. "15s")
+        #[arg(long, default_value = "15s", value_parser = humantime::parse_duration)]
+        interval: Duration,
+    },
+}
+
+/// Header in which Prometheus sends the scrape timeout of the job, in seconds
+const SCRAPE_TIMEOUT_HEADER: &str = "x-prometheus-scrape-timeout-seconds";
+
+/// Time left for encoding and sending the response when the collection timeout
+/// comes from the scrape timeout header
+const SCRAPE_TIMEOUT_MARGIN: Duration = Duration::from_millis(500);
+
+/// Shared state for the HTTP handlers
+#[derive(Clone)]
+struct AppState {
+    exporter: Exporter,
+    collection_timeout: Duration,
+    /// Held by the collection in flight, even past its timeout, so a wedged
+    /// collection doesn't have scrapes pile more on top of it
+    collection_lock: Arc<tokio::sync::Mutex<()>>,
+    /// Take the collection timeout from the scrape timeout header, serving the
+    /// previous collection when it runs out
+    scrape_timeout_header: bool,
+    /// Scrapes read the latest background collection instead of collecting
+    background: bool,
+    /// Render the GPU status table at the root page
+    status_page: bool,
+    /// Timestamp the samples with the time they were collected
+    sample_timestamps: bool,
+    /// Limits the scrapes of the telemetry path, shared by every request
+    scrape_limiter: Option<Arc<Mutex<TokenBucket>>>,
+    /// Serve the admin endpoints
+    admin_endpoint: bool,
+}
+
+impl AppState {
+    /// Collection timeout of a scrape: with `scrape_timeout_header`, the scrape
+    /// timeout Prometheus sent minus a margin, never cutting it by more than half,
+    /// otherwise or when the header is missing or invalid `--collection-timeout`
+    fn collection_timeout(&self, headers: &HeaderMap) -> Duration {
+        let Some(value) = headers.get(SCRAPE_TIMEOUT_HEADER).filter(|_| self.scrape_timeout_header) else {
+            return self.collection_timeout;
+        };
+        match value.to_str().ok().and_then(|value| Duration::try_from_secs_f64(value.trim().parse().ok()?).ok()) {
+            Some(scrape_timeout) if !scrape_timeout.is_zero() => {
+                scrape_timeout.saturating_sub(SCRAPE_TIMEOUT_MARGIN).max(scrape_timeout / 2)
+            }
+            _ => {
+                debug!("Ignoring invalid {} header {:?}", SCRAPE_TIMEOUT_HEADER, value);
+                self.collection_timeout
+            }
+        }
+    }
+
+    /// Take a token from the scrape limiter, returning the 429 response to send
+    /// instead when the limit is reached
+    fn limit_scrape(&self) -> Option<Response<String>> {
+        let limiter = self.scrape_limiter.as_ref()?;
+        let retry_after = limiter
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .try_acquire(Instant::now())
+            .err()?;
+        debug!("Rejecting scrape over the rate limit, retry after {:?}", retry_after);
+        Some(
+            Response::builder()
+                .status(StatusCode::TOO_MANY_REQUESTS)
+                // Whole seconds, rounded up so the retry finds a token
+                .header(header::RETRY_AFTER, retry_after.as_secs_f64().ceil().max(1.0).to_string())
+                .body("Too many scrapes, slow down\n".to_string())
+                .expect("Failed to build error response"),
         )
-        .route(
-            "/",
-            get(|| async {
-                Html(
-                    r#"
-                    <html>
-                        <head><title>NVIDIA GPU Exporter</title></head>
-                        <body>
-                            <h1>NVIDIA GPU Exporter</h1>
-                            <p><a href='/metrics'>Metrics</a></p>
-                        </body>
-                    </html>
-                    "#,
-                )
-            }),
+    }
+
+    /// Encode the metrics of a scrape, timestamped with their collection time when
+    /// `sample_timestamps` is set
+    fn respond(&self, headers: &HeaderMap, mut metric_families: Vec<MetricFamily>) -> Response<String> {
+        if self.sample_timestamps {
+            if let Some(collected) = self.exporter.last_collection_time() {
+                set_timestamps(&mut metric_families, collected);
+            }
+        }
+        encode_response(headers, &metric_families)
+    }
+}
+
+/// Step of a scrape an error comes from, reported in JSON error responses
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Stage {
+    /// Collecting the metrics from the GPUs
+    Collect,
+    /// Encoding the collected metrics into the response
+    Encode,
+}
+
+/// Whether the request's `Accept` header lists `application/json`
+fn accepts_json(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|media_range| {
+            let media_type = media_range.split(';').next().unwrap_or_default();
+            media_type.trim().eq_ignore_ascii_case("application/json")
+        })
+}
+
+/// Response for a failed scrape: `{"error": "...", "stage": "..."}` when the
+/// request accepts JSON, so tooling doesn't have to parse the message, plain
+/// text otherwise
+fn error_response(headers: &HeaderMap, status: StatusCode, stage: Stage, error: String) -> Response<String> {
+    let builder = Response::builder().status(status);
+    let response = if accepts_json(headers) {
+        let body = serde_json::json!({ "error": error, "stage": stage });
+        builder.header(header::CONTENT_TYPE, "application/json").body(body.to_string())
+    } else {
+        builder.body(error)
+    };
+    response.expect("Failed to build error response")
+}
+
+/// Attach a timestamp to every sample, which the text format then carries after the value
+fn set_timestamps(metric_families: &mut [MetricFamily], at: SystemTime) {
+    let timestamp_ms = at.duration_since(UNIX_EPOCH).map_or(0, |since| since.as_millis() as i64);
+    for mf in metric_families {
+        for metric in mf.mut_metric().iter_mut() {
+            metric.set_timestamp_ms(timestamp_ms);
+        }
+    }
+}
+
+fn router(state: AppState, telemetry_path: &str) -> Router {
+    let device_path = format!("{}/gpu/:index", telemetry_path.trim_end_matches('/'));
+    let catalog_path = format!("{}/catalog", telemetry_path.trim_end_matches('/'));
+
+    let mut app = Router::new()
+        .route(telemetry_path, get(metrics_handler))
+        .route(&device_path, get(device_metrics_handler))
+        .route(&catalog_path, get(catalog_handler))
+        .route("/healthz", get(healthz_handler))
+        .route("/", get(root_handler));
+    if state.admin_endpoint {
+        app = app.route("/admin/reset", post(admin_reset_handler));
+    }
+    app.with_state(state)
+}
+
+async fn root_handler(State(state): State<AppState>) -> Html<String> {
+    if state.status_page {
+        // Rendered from the last collection, without collecting again
+        let devices = state.exporter.last_devices();
+        return Html(status::render(devices.as_deref(), state.exporter.has_collected()));
+    }
+    Html(
+        r#"
+        <html>
+            <head><title>NVIDIA GPU Exporter</title></head>
+            <body>
+                <h1>NVIDIA GPU Exporter</h1>
+                <p><a href='/metrics'>Metrics</a></p>
+            </body>
+        </html>
+        "#
+        .to_string(),
+    )
+}
+
+/// Query parameters of the metrics endpoint
+#[derive(Debug, Default, serde::Deserialize)]
+struct MetricsQuery {
+    /// Collect now instead of serving the latest background collection ("1" or "true")
+    fresh: Option<String>,
+    /// "json" for the collected data as JSON instead of the text format
+    format: Option<String>,
+}
+
+impl MetricsQuery {
+    /// Whether the scrape asked for a fresh collection, with `?fresh=1` or `Cache-Control: no-cache`
+    fn wants_fresh(&self, headers: &HeaderMap) -> bool {
+        let no_cache = headers
+            .get_all(header::CACHE_CONTROL)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|directive| directive.trim().eq_ignore_ascii_case("no-cache"));
+        no_cache || matches!(self.fresh.as_deref(), Some("1" | "true"))
+    }
+}
+
+async fn metrics_handler(
+    State(state): State<AppState>,
+    Query(query): Query<MetricsQuery>,
+    headers: HeaderMap,
+) -> Response<String> {
+    debug!("Metrics endpoint called");
+    if let Some(response) = state.limit_scrape() {
+        return response;
+    }
+    match query.format.as_deref() {
+        None | Some("prometheus") => {}
+        Some("json") => return metrics_json(&state, &query, &headers).await,
+        Some(format) => {
+            return Response::builder()
+                .status(StatusCode::BAD_REQUEST)
+                .body(format!("Unsupported format {:?}, expected prometheus or json", format))
+                .expect("Failed to build error response")
+        }
+    }
+
+    // A fresh collection also updates what later cached scrapes are served
+    if state.background && !query.wants_fresh(&headers) {
+        return state.respond(&headers, state.exporter.omit_unchanged(state.exporter.families()));
+    }
+
+    debug!("Gathering metrics from exporter...");
+    let timeout = state.collection_timeout(&headers);
+    let metric_families = match collect_with_timeout(&state, timeout, |exporter| exporter.gather()).await {
+        Ok(metric_families) => metric_families,
+        // Serve what the last collection left behind, with up reporting the timeout
+        Err(CollectionFailure::TimedOut(_)) if state.scrape_timeout_header => state.exporter.families(),
+        Err(failure) => return failure.into_response(&headers),
+    };
+    debug!("Gathered {} metric families", metric_families.len());
+
+    state.respond(&headers, state.exporter.omit_unchanged(metric_families))
+}
+
+/// The collected data as JSON, as `dump --json` prints it, which exporters
+/// aggregating this one with --remote-endpoint read
+async fn metrics_json(state: &AppState, query: &MetricsQuery, headers: &HeaderMap) -> Response<String> {
+    let metrics = if state.background && !query.wants_fresh(headers) {
+        state.exporter.last_metrics()
+    } else {
+        let timeout = state.collection_timeout(headers);
+        match collect_with_timeout(state, timeout, |exporter| exporter.refresh().ok()).await {
+            Ok(metrics) => metrics,
+            Err(failure) => return failure.into_response(headers),
+        }
+    };
+    let Some(metrics) = metrics else {
+        return error_response(
+            headers,
+            StatusCode::SERVICE_UNAVAILABLE,
+            Stage::Collect,
+            "Metrics collection failed".to_string(),
         );
+    };
+    match serde_json::to_string(&metrics) {
+        Ok(body) => Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(body)
+            .expect("Failed to build response"),
+        Err(e) => error_response(
+            headers,
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Stage::Encode,
+            format!("Failed to encode metrics as JSON: {}", e),
+        ),
+    }
+}
 
-    let addr: SocketAddr = args.web_listen_address.parse()?;
-    info!("Starting HTTP server on {}", addr);
+async fn device_metrics_handler(
+    State(state): State<AppState>,
+    Path(index): Path<u32>,
+    headers: HeaderMap,
+) -> Response<String> {
+    debug!("Device metrics endpoint called for index {}", index);
+
+    let result = if state.background {
+        Ok(state.exporter.last_device_families(index))
+    } else {
+        let timeout = state.collection_timeout(&headers);
+        collect_with_timeout(&state, timeout, move |exporter| exporter.gather_device(index))
+            .await
+            .map_err(|failure| failure.into_response(&headers))
+    };
+    match result {
+        Ok(Some(metric_families)) => state.respond(&headers, metric_families),
+        Ok(None) => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(format!("No GPU with index {}", index))
+            .expect("Failed to build error response"),
+        Err(response) => response,
+    }
+}
+
+/// The metrics catalog needs no collection, so it is served even without GPUs
+async fn catalog_handler(State(state): State<AppState>) -> Json<Vec<CatalogEntry>> {
+    Json(state.exporter.catalog())
+}
+
+/// Healthy while the last collection succeeded and, with --expected-gpu-count,
+/// found the expected number of GPUs, so a GPU dropping off the bus fails the
+/// check. Answered without collecting, so probes don't add to the NVML load.
+async fn healthz_handler(State(state): State<AppState>) -> Response<String> {
+    let (status, body) = match (state.exporter.last_metrics(), state.exporter.expected_device_count()) {
+        (None, _) if !state.exporter.has_collected() => (StatusCode::SERVICE_UNAVAILABLE, "Not collected yet".to_string()),
+        (None, _) => (StatusCode::SERVICE_UNAVAILABLE, "Metrics collection failed".to_string()),
+        (Some(metrics), Some(expected)) if metrics.devices.len() != expected => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            format!("Found {} GPU(s), expected {}", metrics.devices.len(), expected),
+        ),
+        (Some(_), _) => (StatusCode::OK, "OK".to_string()),
+    };
+    Response::builder()
+        .status(status)
+        .body(body)
+        .expect("Failed to build response")
+}
+
+/// Clear the peaks and sample windows, answering with the number of series cleared
+async fn admin_reset_handler(State(state): State<AppState>) -> Json<serde_json::Value> {
+    let cleared = state.exporter.reset_peaks();
+    info!("Reset {} peak and sample series", cleared);
+    Json(serde_json::json!({ "cleared": cleared }))
+}
+
+/// Why a collection run by `collect_with_timeout` produced nothing
+enum CollectionFailure {
+    /// The collection task panicked or was cancelled
+    Failed(tokio::task::JoinError),
+    /// The collection did not finish within the timeout
+    TimedOut(Duration),
+}
+
+impl CollectionFailure {
+    fn into_response(self, headers: &HeaderMap) -> Response<String> {
+        let (status, error) = match self {
+            CollectionFailure::Failed(e) => (StatusCode::INTERNAL_SERVER_ERROR, format!("Metrics collection failed: {}", e)),
+            CollectionFailure::TimedOut(timeout) => {
+                (StatusCode::SERVICE_UNAVAILABLE, format!("Metrics collection timed out after {:?}", timeout))
+            }
+        };
+        error_response(headers, status, Stage::Collect, error)
+    }
+}
+
+/// Run a collection on the blocking pool, bounded by the collection timeout.
+///
+/// NVML calls are blocking and can wedge on a driver fault, so they must not run on
+/// the async workers or hold the scrape open indefinitely. Only one collection runs
+/// at a time: a scrape arriving while one is in flight waits for it within its own
+/// timeout, rather than starting another that would wedge the same way.
+async fn collect_with_timeout<T, F>(state: &AppState, timeout: Duration, collect: F) -> Result<T, CollectionFailure>
+where
+    T: Send + 'static,
+    F: FnOnce(Exporter) -> T + Send + 'static,
+{
+    let exporter = state.exporter.clone();
+    let lock = state.collection_lock.clone();
+    let collection = async move {
+        let guard = lock.lock_owned().await;
+        tokio::task::spawn_blocking(move || {
+            let _guard = guard;
+            collect(exporter)
+        })
+        .await
+    };
+    match tokio::time::timeout(timeout, collection).await {
+        Ok(Ok(result)) => Ok(result),
+        Ok(Err(e)) => {
+            warn!("Metrics collection task failed: {}", e);
+            Err(CollectionFailure::Failed(e))
+        }
+        Err(_) => {
+            warn!("Metrics collection timed out after {:?}", timeout);
+            state.exporter.record_collection_timeout();
+            Err(CollectionFailure::TimedOut(timeout))
+        }
+    }
+}
+
+fn encode_response(headers: &HeaderMap, metric_families: &[MetricFamily]) -> Response<String> {
+    let mut buffer = Vec::new();
+
+    debug!("Encoding {} metric families...", metric_families.len());
+    if let Err(e) = encode_text(metric_families, &mut buffer) {
+        warn!("Failed to encode metrics: {}", e);
+        return error_response(
+            headers,
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Stage::Encode,
+            format!("Failed to encode metrics: {}", e),
+        );
+    }
+    debug!("Encoded metrics to buffer of {} bytes", buffer.len());
+
+    debug!("Converting buffer to UTF-8 string...");
+    match String::from_utf8(buffer) {
+        Ok(body) => {
+            debug!("Successfully created response body ({} bytes)", body.len());
+            Response::builder()
+                .status(StatusCode::OK)
+                .header("Content-Type", "text/plain; version=0.0.4")
+                .body(body)
+                .expect("Failed to build response")
+        }
+        Err(e) => {
+            warn!("Failed to encode metrics as UTF-8: {}", e);
+            error_response(
+                headers,
+                StatusCode::INTERNAL_SERVER_ERROR,
+                Stage::Encode,
+                format!("Failed to encode metrics as UTF-8: {}", e),
+            )
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<ExitCode> {
+    let args = Args::parse();
+
+    let exporter = Exporter::with_config(ExporterConfig {
+        drop_zero_value_optional_metrics: args.drop_zero_value_optional_metrics,
+        memory_unit: args.memory_unit,
+        utilization_scale: args.utilization_scale,
+        drop_device_info_metric: args.drop_device_info_metric,
+        nvml_reinit_threshold: args.nvml_reinit_threshold,
+        max_sessions_per_gpu: args.max_sessions_per_gpu,
+        enable_cgroup_labels: args.enable_cgroup_labels,
+        enable_process_names: args.enable_process_names,
+        performance_state_one_hot: args.performance_state_one_hot,
+        device_order: args.device_order,
+        power_summary_window: args.power_summary_window,
+        sample_buffer_capacity: args.sample_buffer_capacity,
+        minor_names: args.relabel_minor.iter().cloned().collect(),
+        redact_uuid: args.redact_uuid,
+        uuid_salt: args.uuid_salt.clone(),
+        enable_topology_metrics: args.enable_topology_metrics,
+        round_integers: args.round_integers,
+        enable_smi_fallback: args.enable_smi_fallback,
+        enable_index_label: args.enable_index_label,
+        const_labels: args.const_labels.iter().cloned().collect(),
+        collect_pstate_clocks: args.collect_pstate_clocks,
+        stale_tolerance: args.stale_tolerance,
+        delta_mode: args.delta_mode,
+        enable_call_metrics: args.enable_call_metrics,
+        extra_field_ids: args.extra_field_ids.clone(),
+        remote_endpoints: args.remote_endpoint.clone(),
+        compat: args.compat,
+        expected_device_count: args.expected_gpu_count,
+        per_device_timeout: args.per_device_timeout,
+        always_emit_metric_types: args.always_emit_metric_types,
+        emit_node_aggregates: args.emit_node_aggregates,
+        dcgm_endpoint: args.enable_dcgm_fields.then(|| args.dcgm_exporter_endpoint.clone()),
+        collection_duration_buckets: args
+            .collection_duration_buckets
+            .clone()
+            .unwrap_or_else(|| prometheus::DEFAULT_BUCKETS.to_vec()),
+    });
+
+    match args.command {
+        Some(Command::Dump { format, json, json_fields }) => {
+            // Keep stdout clean for the dumped metrics
+            tracing_subscriber::fmt().with_writer(std::io::stderr).init();
+            let format = if json { DumpFormat::Json } else { format };
+            dump::run(&exporter, format, &json_fields, &mut std::io::stdout().lock())?;
+            Ok(ExitCode::SUCCESS)
+        }
+        Some(Command::Probe) => {
+            tracing_subscriber::fmt().with_writer(std::io::stderr).init();
+            Ok(probe::run(&exporter, &mut std::io::stdout().lock()))
+        }
+        Some(Command::ListMetrics) => {
+            tracing_subscriber::fmt().with_writer(std::io::stderr).init();
+            let mut out = std::io::stdout().lock();
+            serde_json::to_writer_pretty(&mut out, &exporter.catalog())?;
+            writeln!(out)?;
+            Ok(ExitCode::SUCCESS)
+        }
+        Some(Command::Push { pushgateway_url, job, interval }) => {
+            tracing_subscriber::fmt::init();
+            if let Some(minimum) = args.require_gpus {
+                probe::require_gpus(&exporter, minimum)?;
+            }
+            info!("Pushing metrics to {} every {:?} as job {}", pushgateway_url, interval, job);
+            let pusher = push::Pusher::new(exporter, pushgateway_url, job);
+            tokio::select! {
+                _ = push::run(pusher, interval) => {}
+                _ = tokio::signal::ctrl_c() => {
+                    info!("Received shutdown signal, stopping pushes");
+                }
+            }
+            Ok(ExitCode::SUCCESS)
+        }
+        Some(Command::Serve) | None => {
+            tracing_subscriber::fmt::init();
+            if let Some(minimum) = args.require_gpus {
+                probe::require_gpus(&exporter, minimum)?;
+            }
+            serve(&args, exporter).await?;
+            Ok(ExitCode::SUCCESS)
+        }
+    }
+}
+
+async fn serve(args: &Args, exporter: Exporter) -> anyhow::Result<()> {
+    // Shared with the background collections, so fresh scrapes don't run alongside them
+    let collection_lock = Arc::new(tokio::sync::Mutex::new(()));
+    if let Some(interval) = args.collect_interval {
+        info!("Collecting every {:?} in the background (jitter {})", interval, args.collect_jitter);
+        let schedule = background::Schedule::new(interval, args.collect_jitter);
+        tokio::spawn(background::run(
+            exporter.clone(),
+            schedule,
+            collection_lock.clone(),
+            args.collection_timeout,
+        ));
+        if let Some(interval) = args.sample_interval {
+            info!("Sampling devices every {:?}", interval);
+            tokio::spawn(background::sample_devices(exporter.clone(), interval));
+        }
+    }
+
+    let state = AppState {
+        exporter: exporter.clone(),
+        collection_timeout: args.collection_timeout,
+        collection_lock,
+        scrape_timeout_header: args.scrape_timeout_header,
+        background: args.collect_interval.is_some(),
+        status_page: args.enable_status_page,
+        sample_timestamps: args.sample_timestamps,
+        scrape_limiter: args
+            .max_scrapes_per_second
+            .map(|rate| Arc::new(Mutex::new(TokenBucket::new(rate, Instant::now())))),
+        admin_endpoint: args.enable_admin_endpoint,
+    };
+    let drain = Drain::new(args.shutdown_grace);
+    let app = drain.track(router(state, &args.web_telemetry_path));
 
-    let listener = tokio::net::TcpListener::bind(addr).await?;
-    
     // Set up signal handling for graceful shutdown
-    let server = axum::serve(listener, app);
     let shutdown_signal = async {
         tokio::signal::ctrl_c()
             .await
@@ -111,37 +943,634 @@ async fn main() -> anyhow::Result<()> {
         warn!("Received shutdown signal, shutting down gracefully...");
     };
 
+    if let Some(path) = args.web_listen_address.strip_prefix("unix:") {
+        let path = PathBuf::from(path);
+        let listener = bind_unix(&path)?;
+        drop_privileges(args, &exporter).await?;
+        return serve_unix(listener, path, app, shutdown_signal, drain).await;
+    }
+
+    let addr: SocketAddr = args.web_listen_address.parse()?;
+    info!("Starting HTTP server on {}", addr);
+
+    let listener = bind_with_retry(args.bind_retries, args.bind_retry_interval, || tokio::net::TcpListener::bind(addr)).await?;
+    drop_privileges(args, &exporter).await?;
+    serve_tcp(listener, app, shutdown_signal, drain).await;
+
+    Ok(())
+}
+
+/// Serve `app` on `listener` until `shutdown` completes, then stop accepting
+/// connections and let the requests in flight finish within the grace period
+async fn serve_tcp(
+    listener: tokio::net::TcpListener,
+    app: Router,
+    shutdown: impl std::future::Future<Output = ()> + Send + 'static,
+    drain: Drain,
+) {
+    let (draining_tx, draining_rx) = tokio::sync::oneshot::channel();
+    let server = axum::serve(listener, app).with_graceful_shutdown({
+        let drain = drain.clone();
+        async move {
+            shutdown.await;
+            drain.begin();
+            let _ = draining_tx.send(());
+        }
+    });
+
     tokio::select! {
         result = server => {
             if let Err(e) = result {
                 eprintln!("Server error: {}", e);
             }
         }
-        _ = shutdown_signal => {
-            info!("Shutdown signal received, server stopping...");
+        _ = async {
+            let _ = draining_rx.await;
+            drain.grace_elapsed().await;
+        } => {}
+    }
+}
+
+/// Switch to --run-as-user/--run-as-group, if given. A collection runs first so
+/// NVML is initialized and opens the devices while still privileged. Device nodes
+/// the new user can't open are warned about, as re-initializing NVML needs them.
+async fn drop_privileges(args: &Args, exporter: &Exporter) -> anyhow::Result<()> {
+    if args.run_as_user.is_none() && args.run_as_group.is_none() {
+        return Ok(());
+    }
+    let collector = exporter.clone();
+    if let Err(e) = tokio::task::spawn_blocking(move || collector.refresh()).await? {
+        warn!("Collection before dropping privileges failed: {:#}", e);
+    }
+    privileges::drop_to(args.run_as_user.as_ref(), args.run_as_group.as_ref())?;
+    for path in privileges::inaccessible_devices(std::path::Path::new("/dev")) {
+        warn!(
+            "{} can't be opened as the new user, so re-initializing NVML will fail. \
+             Make it readable and writable by the user (usually through the group owning it, e.g. `video`)",
+            path.display()
+        );
+    }
+    Ok(())
+}
+
+/// Call `bind` until it succeeds, retrying up to `retries` times, `interval` apart,
+/// while the address is in use. Other errors are returned right away.
+async fn bind_with_retry<T, F, Fut>(retries: u32, interval: Duration, mut bind: F) -> std::io::Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = std::io::Result<T>>,
+{
+    let mut attempt = 0;
+    loop {
+        match bind().await {
+            Err(e) if e.kind() == std::io::ErrorKind::AddrInUse && attempt < retries => {
+                attempt += 1;
+                warn!("Failed to bind: {}, retrying in {:?} ({}/{})", e, interval, attempt, retries);
+                tokio::time::sleep(interval).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Bind a Unix domain socket at `path`, replacing a socket left behind
+fn bind_unix(path: &std::path::Path) -> anyhow::Result<tokio::net::UnixListener> {
+    // A socket left behind by an unclean exit would make the bind fail
+    if std::fs::metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+        std::fs::remove_file(path)?;
+    }
+    Ok(tokio::net::UnixListener::bind(path)?)
+}
+
+/// Serve `app` on the Unix domain socket `listener` bound at `path` until
+/// `shutdown` completes, then let the requests in flight finish within the grace
+/// period and remove the socket file
+async fn serve_unix(
+    listener: tokio::net::UnixListener,
+    path: PathBuf,
+    app: Router,
+    shutdown: impl std::future::Future<Output = ()>,
+    drain: Drain,
+) -> anyhow::Result<()> {
+    info!("Starting HTTP server on unix:{}", path.display());
+
+    // Dropped to tell the connections to finish their current request and close
+    let (stop_tx, stop_rx) = tokio::sync::watch::channel(());
+    let mut connections = tokio::task::JoinSet::new();
+    tokio::pin!(shutdown);
+    let result = loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let stream = match accepted {
+                    Ok((stream, _)) => stream,
+                    Err(e) => break Err(e.into()),
+                };
+                let service = hyper_util::service::TowerToHyperService::new(app.clone());
+                let mut stop_rx = stop_rx.clone();
+                connections.spawn(async move {
+                    let connection = hyper::server::conn::http1::Builder::new()
+                        .serve_connection(hyper_util::rt::TokioIo::new(stream), service);
+                    tokio::pin!(connection);
+                    let result = tokio::select! {
+                        result = connection.as_mut() => result,
+                        _ = stop_rx.changed() => {
+                            connection.as_mut().graceful_shutdown();
+                            connection.await
+                        }
+                    };
+                    if let Err(e) = result {
+                        debug!("Error serving Unix socket connection: {}", e);
+                    }
+                });
+            }
+            // Reap the finished connections
+            Some(_) = connections.join_next(), if !connections.is_empty() => {}
+            _ = &mut shutdown => {
+                drain.begin();
+                break Ok(());
+            }
         }
+    };
+
+    drop(stop_tx);
+    tokio::select! {
+        _ = async { while connections.join_next().await.is_some() {} } => {}
+        _ = drain.grace_elapsed() => connections.abort_all(),
+    }
+
+    if let Err(e) = std::fs::remove_file(&path) {
+        warn!("Failed to remove socket {}: {}", path.display(), e);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::{Device, Metrics, MockMetricsCollector};
+    use axum::body::{to_bytes, Body};
+    use axum::http::{Request as HttpRequest, StatusCode};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tower::ServiceExt;
+
+    fn test_state(exporter: Exporter) -> AppState {
+        AppState {
+            exporter,
+            collection_timeout: Duration::from_secs(10),
+            collection_lock: Arc::new(tokio::sync::Mutex::new(())),
+            scrape_timeout_header: false,
+            background: false,
+            status_page: false,
+            sample_timestamps: false,
+            scrape_limiter: None,
+            admin_endpoint: false,
+        }
+    }
+
+    fn mock_exporter(devices: Vec<Device>) -> Exporter {
+        let mut collector = MockMetricsCollector::new();
+        collector.expect_collect().returning(move || {
+            Ok(Metrics {
+                version: "525.116.04".to_string(),
+                devices: devices.clone(),
+                ..Default::default()
+            })
+        });
+        Exporter::with_collector(Arc::new(collector), ExporterConfig::default())
+    }
+
+    fn mock_device(index: u32, minor: u32) -> Device {
+        Device {
+            index: index.to_string(),
+            minor_number: minor.to_string(),
+            name: "NVIDIA GeForce RTX 3080".to_string(),
+            uuid: format!("GPU-{}", index),
+            temperature: 60.0 + index as f64,
+            ..Default::default()
+        }
+    }
+
+    async fn get(app: Router, uri: &str) -> (StatusCode, String) {
+        let response = app
+            .oneshot(HttpRequest::builder().uri(uri).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        let status = response.status();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, String::from_utf8_lossy(&body).into_owned())
+    }
+
+    #[test]
+    fn test_args_default_values() {
+        // Test that default values are correctly set
+        let args = Args::parse_from(["nvidia-gpu-exporter"]);
+        
+        assert_eq!(args.web_listen_address, "0.0.0.0:9445");
+        assert_eq!(args.web_telemetry_path, "/metrics");
+        assert!(!args.drop_zero_value_optional_metrics);
+        assert_eq!(args.memory_unit, MemoryUnit::Bytes);
+        assert_eq!(args.utilization_scale, UtilizationScale::Percent);
+        assert!(!args.drop_device_info_metric);
+        assert_eq!(args.collection_timeout, Duration::from_secs(10));
+        assert_eq!(args.max_scrapes_per_second, None);
+        assert_eq!(args.shutdown_grace, Duration::from_secs(10));
+        assert_eq!(args.bind_retries, 0);
+        assert_eq!(args.bind_retry_interval, Duration::from_secs(1));
+        assert_eq!(args.collect_interval, None);
+        assert_eq!(args.collect_jitter, 0.0);
+        assert_eq!(args.sample_interval, None);
+        assert_eq!(args.per_device_timeout, None);
+        assert_eq!(args.nvml_reinit_threshold, 3);
+        assert_eq!(args.max_sessions_per_gpu, 16);
+        assert!(!args.enable_cgroup_labels);
+        assert!(!args.enable_process_names);
+        assert!(!args.performance_state_one_hot);
+        assert!(!args.collect_pstate_clocks);
+        assert!(args.extra_field_ids.is_empty());
+        assert!(args.remote_endpoint.is_empty());
+        assert_eq!(args.device_order, DeviceOrder::Nvml);
+        assert_eq!(args.compat, None);
+        assert_eq!(args.power_summary_window, None);
+        assert_eq!(args.sample_buffer_capacity, exporter::DEFAULT_SAMPLE_BUFFER_CAPACITY);
+        assert_eq!(args.stale_tolerance, None);
+        assert_eq!(args.collection_duration_buckets, None);
+        assert!(args.relabel_minor.is_empty());
+        assert!(args.const_labels.is_empty());
+        assert!(!args.enable_topology_metrics);
+        assert!(!args.enable_status_page);
+        assert!(!args.enable_admin_endpoint);
+        assert!(!args.sample_timestamps);
+        assert!(!args.scrape_timeout_header);
+        assert!(!args.delta_mode);
+        assert!(!args.always_emit_metric_types);
+        assert!(!args.emit_node_aggregates);
+        assert!(!args.enable_dcgm_fields);
+        assert_eq!(args.dcgm_exporter_endpoint.host, "localhost:9400");
+        assert_eq!(args.dcgm_exporter_endpoint.path, "/metrics");
+        assert!(!args.round_integers);
+        assert!(!args.enable_smi_fallback);
+        assert!(!args.enable_call_metrics);
+        assert!(!args.enable_index_label);
+        assert!(!args.redact_uuid);
+        assert_eq!(args.uuid_salt, "");
+        assert_eq!(args.run_as_user, None);
+        assert_eq!(args.run_as_group, None);
+        assert_eq!(args.command, None);
+    }
+
+    #[test]
+    fn test_args_dump_subcommand() {
+        let args = Args::parse_from(["nvidia-gpu-exporter", "--memory-unit", "gib", "dump", "--json"]);
+        assert_eq!(
+            args.command,
+            Some(Command::Dump {
+                format: DumpFormat::Prometheus,
+                json: true,
+                json_fields: vec![],
+            })
+        );
+        assert_eq!(args.memory_unit, MemoryUnit::Gib);
+    }
+
+    #[test]
+    fn test_args_dump_format() {
+        for (value, format) in [
+            ("prometheus", DumpFormat::Prometheus),
+            ("json", DumpFormat::Json),
+            ("csv", DumpFormat::Csv),
+        ] {
+            let args = Args::parse_from(["nvidia-gpu-exporter", "dump", "--format", value]);
+            assert_eq!(
+                args.command,
+                Some(Command::Dump {
+                    format,
+                    json: false,
+                    json_fields: vec![],
+                })
+            );
+        }
+
+        assert!(Args::try_parse_from(["nvidia-gpu-exporter", "dump", "--format", "xml"]).is_err());
+        assert!(Args::try_parse_from(["nvidia-gpu-exporter", "dump", "--format", "csv", "--json"]).is_err());
+    }
+
+    #[test]
+    fn test_args_dump_json_fields() {
+        let args = Args::parse_from(["nvidia-gpu-exporter", "dump", "--json", "--json-fields", "index,temperature"]);
+        assert_eq!(
+            args.command,
+            Some(Command::Dump {
+                format: DumpFormat::Prometheus,
+                json: true,
+                json_fields: vec!["index".to_string(), "temperature".to_string()],
+            })
+        );
+        let args = Args::parse_from(["nvidia-gpu-exporter", "dump", "--format", "csv", "--fields", "temperature,index"]);
+        assert_eq!(
+            args.command,
+            Some(Command::Dump {
+                format: DumpFormat::Csv,
+                json: false,
+                json_fields: vec!["temperature".to_string(), "index".to_string()],
+            })
+        );
+
+        assert!(Args::try_parse_from(["nvidia-gpu-exporter", "dump", "--json", "--json-fields", "index,temprature"]).is_err());
+        // The fields only apply to the JSON and CSV output
+        assert!(Args::try_parse_from(["nvidia-gpu-exporter", "dump", "--json-fields", "index"]).is_err());
+    }
+
+    #[test]
+    fn test_args_probe_subcommand() {
+        let args = Args::parse_from(["nvidia-gpu-exporter", "probe"]);
+        assert_eq!(args.command, Some(Command::Probe));
+    }
+
+    #[test]
+    fn test_args_list_metrics_subcommand() {
+        let args = Args::parse_from(["nvidia-gpu-exporter", "list-metrics"]);
+        assert_eq!(args.command, Some(Command::ListMetrics));
+    }
+
+    #[test]
+    fn test_args_push_subcommand() {
+        let args = Args::parse_from([
+            "nvidia-gpu-exporter",
+            "push",
+            "--pushgateway-url",
+            "http://pushgateway:9091",
+            "--interval",
+            "30s",
+        ]);
+        assert_eq!(
+            args.command,
+            Some(Command::Push {
+                pushgateway_url: "http://pushgateway:9091".to_string(),
+                job: "nvidia-gpu-exporter".to_string(),
+                interval: Duration::from_secs(30),
+            })
+        );
+        assert!(Args::try_parse_from(["nvidia-gpu-exporter", "push"]).is_err());
+        let push = |args: &[&str]| Args::try_parse_from([&["nvidia-gpu-exporter", "push"], args].concat());
+        assert!(push(&["--pushgateway-url", "pushgateway:9091"]).is_ok());
+        assert!(push(&["--pushgateway-url", "http://"]).is_err());
+        assert!(push(&["--pushgateway-url", "ftp://pushgateway:9091"]).is_err());
+        assert!(push(&["--pushgateway-url", "http://pushgateway:9091", "--job", "a/b"]).is_err());
+        assert!(push(&["--pushgateway-url", "http://pushgateway:9091", "--job", ""]).is_err());
+    }
+
+    #[test]
+    fn test_require_gpus_args() {
+        let require_gpus = |args: &[&str]| {
+            Args::try_parse_from([&["nvidia-gpu-exporter"], args].concat()).map(|args| args.require_gpus)
+        };
+        assert_eq!(require_gpus(&[]).unwrap(), None);
+        assert_eq!(require_gpus(&["--require-gpus"]).unwrap(), Some(1));
+        assert_eq!(require_gpus(&["--require-gpus=8"]).unwrap(), Some(8));
+        // The count must be attached, so a following subcommand isn't taken for it
+        assert_eq!(require_gpus(&["--require-gpus", "serve"]).unwrap(), Some(1));
+        assert!(require_gpus(&["--require-gpus=eight"]).is_err());
+    }
+
+    #[test]
+    fn test_sample_buffer_capacity_args() {
+        let capacity = |value: &str| {
+            Args::try_parse_from(["nvidia-gpu-exporter", "--sample-buffer-capacity", value]).map(|args| args.sample_buffer_capacity)
+        };
+        assert_eq!(capacity("600").unwrap(), 600);
+        assert!(capacity("0").is_err());
+        assert!(capacity("-1").is_err());
+    }
+
+    #[test]
+    fn test_args_run_as() {
+        let args = Args::parse_from(["nvidia-gpu-exporter", "--run-as-user", "root", "--run-as-group", "0"]);
+        assert_eq!(args.run_as_user.map(|u| (u.uid, u.gid)), Some((0, 0)));
+        assert_eq!(args.run_as_group.map(|g| g.gid), Some(0));
+
+        // Unknown users and groups are rejected at startup
+        assert!(Args::try_parse_from(["nvidia-gpu-exporter", "--run-as-user", "no-such-user-for-the-exporter"]).is_err());
+        assert!(Args::try_parse_from(["nvidia-gpu-exporter", "--run-as-group", "no-such-group-for-the-exporter"]).is_err());
+    }
+
+    #[test]
+    fn test_args_device_order() {
+        let args = Args::parse_from(["nvidia-gpu-exporter", "--device-order", "pci"]);
+        assert_eq!(args.device_order, DeviceOrder::Pci);
+    }
+
+    /// Serve a scrape taking `collection` over TCP, with a shutdown grace of
+    /// `grace`. Returns the server address, the shutdown trigger, the drain and
+    /// the server task.
+    async fn slow_tcp_server(
+        collection: Duration,
+        grace: Duration,
+    ) -> (SocketAddr, tokio::sync::oneshot::Sender<()>, Drain, tokio::task::JoinHandle<()>) {
+        let mut collector = MockMetricsCollector::new();
+        collector.expect_collect().returning(move || {
+            std::thread::sleep(collection);
+            Ok(Metrics::default())
+        });
+        let exporter = Exporter::with_collector(Arc::new(collector), ExporterConfig::default());
+        let drain = Drain::new(grace);
+        let app = drain.track(router(test_state(exporter), "/metrics"));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+        let shutdown = async {
+            let _ = shutdown_rx.await;
+        };
+        let server = tokio::spawn(serve_tcp(listener, app, shutdown, drain.clone()));
+        (addr, shutdown_tx, drain, server)
+    }
+
+    /// Send a scrape over a raw connection, returning the stream to read the response from
+    async fn start_scrape(addr: SocketAddr, drain: &Drain) -> tokio::net::TcpStream {
+        use tokio::io::AsyncWriteExt;
+
+        let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        while drain.in_flight() == 0 {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+        stream
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_drains_in_flight_scrape() {
+        use tokio::io::AsyncReadExt;
+
+        let (addr, shutdown_tx, drain, server) =
+            slow_tcp_server(Duration::from_millis(300), Duration::from_secs(10)).await;
+        let mut stream = start_scrape(addr, &drain).await;
+
+        // Shut down while the scrape is still collecting
+        shutdown_tx.send(()).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        assert!(response.contains("nvidia_up 1"));
+        tokio::time::timeout(Duration::from_secs(5), server).await.unwrap().unwrap();
+        assert_eq!(drain.in_flight(), 0);
+        // No new connections are accepted
+        assert!(tokio::net::TcpStream::connect(addr).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_grace_elapsed() {
+        let (addr, shutdown_tx, drain, server) =
+            slow_tcp_server(Duration::from_secs(1), Duration::from_millis(100)).await;
+        let _stream = start_scrape(addr, &drain).await;
+
+        // The server exits once the grace period is over, without waiting for the scrape
+        let started = Instant::now();
+        shutdown_tx.send(()).unwrap();
+        tokio::time::timeout(Duration::from_millis(500), server).await.unwrap().unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(100));
+    }
+
+    #[tokio::test]
+    async fn test_bind_with_retry() {
+        use std::io::{Error, ErrorKind};
+
+        // Fails once with the address in use, then succeeds
+        let mut attempts = 0;
+        let result = bind_with_retry(3, Duration::from_millis(10), || {
+            attempts += 1;
+            let result = if attempts == 1 { Err(Error::from(ErrorKind::AddrInUse)) } else { Ok(attempts) };
+            async move { result }
+        })
+        .await;
+        assert_eq!(result.unwrap(), 2);
+
+        // Gives up after the retries
+        let mut attempts = 0;
+        let result: std::io::Result<()> = bind_with_retry(2, Duration::from_millis(10), || {
+            attempts += 1;
+            async { Err(Error::from(ErrorKind::AddrInUse)) }
+        })
+        .await;
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::AddrInUse);
+        assert_eq!(attempts, 3);
+
+        // Other errors aren't retried
+        let mut attempts = 0;
+        let result: std::io::Result<()> = bind_with_retry(2, Duration::from_millis(10), || {
+            attempts += 1;
+            async { Err(Error::from(ErrorKind::PermissionDenied)) }
+        })
+        .await;
+        assert_eq!(result.unwrap_err().kind(), ErrorKind::PermissionDenied);
+        assert_eq!(attempts, 1);
+    }
+
+    #[tokio::test]
+    async fn test_bind_with_retry_port_in_use() {
+        let taken = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = taken.local_addr().unwrap();
+        // The previous process lets go of the port while the retries are running
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            drop(taken);
+        });
+
+        let listener = bind_with_retry(20, Duration::from_millis(20), || tokio::net::TcpListener::bind(addr)).await;
+        assert_eq!(listener.unwrap().local_addr().unwrap(), addr);
+    }
+
+    #[tokio::test]
+    async fn test_serve_unix_socket() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("exporter.sock");
+        let app = router(test_state(mock_exporter(vec![mock_device(0, 0)])), "/metrics");
+        let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel::<()>();
+        let listener = bind_unix(&path).unwrap();
+        let server = tokio::spawn({
+            let path = path.clone();
+            let shutdown = async {
+                let _ = shutdown_rx.await;
+            };
+            async move { serve_unix(listener, path, app, shutdown, Drain::new(Duration::from_secs(1))).await }
+        });
+
+        // Wait for the listener to come up
+        let mut stream = loop {
+            match tokio::net::UnixStream::connect(&path).await {
+                Ok(stream) => break stream,
+                Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        };
+        stream
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+
+        assert!(response.starts_with("HTTP/1.1 200 OK"));
+        assert!(response.contains("nvidia_up 1"));
+
+        shutdown_tx.send(()).unwrap();
+        server.await.unwrap().unwrap();
+        assert!(!path.exists(), "Socket file should be removed on shutdown");
     }
 
-    Ok(())
-}
+    #[test]
+    fn test_args_const_labels() {
+        let args = Args::parse_from([
+            "nvidia-gpu-exporter",
+            "--label",
+            "cluster=gpu-east",
+            "--label",
+            "region=us_1",
+        ]);
+        assert_eq!(
+            args.const_labels,
+            vec![
+                ("cluster".to_string(), "gpu-east".to_string()),
+                ("region".to_string(), "us_1".to_string()),
+            ]
+        );
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use axum::body::Body;
-    use axum::http::{Request as HttpRequest, StatusCode};
-    use tower::ServiceExt;
+        for invalid in ["cluster", "1cluster=a", "clus-ter=a", "__name=a", "minor=0", "cluster="] {
+            assert!(
+                Args::try_parse_from(["nvidia-gpu-exporter", "--label", invalid]).is_err(),
+                "--label {} should be rejected",
+                invalid
+            );
+        }
+    }
 
     #[test]
-    fn test_args_default_values() {
-        // Test that default values are correctly set
-        let args = Args {
-            web_listen_address: "0.0.0.0:9445".to_string(),
-            web_telemetry_path: "/metrics".to_string(),
-        };
-        
-        assert_eq!(args.web_listen_address, "0.0.0.0:9445");
-        assert_eq!(args.web_telemetry_path, "/metrics");
+    fn test_args_relabel_minor() {
+        let args = Args::parse_from([
+            "nvidia-gpu-exporter",
+            "--relabel-minor",
+            "0=gpu-a",
+            "--relabel-minor",
+            "1=gpu-b",
+        ]);
+        assert_eq!(
+            args.relabel_minor,
+            vec![
+                ("0".to_string(), "gpu-a".to_string()),
+                ("1".to_string(), "gpu-b".to_string())
+            ]
+        );
+
+        for invalid in ["gpu-a", "a=gpu-a", "-1=gpu-a", "0="] {
+            assert!(
+                Args::try_parse_from(["nvidia-gpu-exporter", "--relabel-minor", invalid]).is_err(),
+                "{} should be rejected",
+                invalid
+            );
+        }
     }
 
     #[test]
@@ -151,39 +1580,91 @@ mod tests {
         let _cmd = Args::command();
     }
 
+    #[test]
+    fn test_args_collection_timeout() {
+        let args = Args::parse_from(["nvidia-gpu-exporter", "--collection-timeout", "500ms"]);
+        assert_eq!(args.collection_timeout, Duration::from_millis(500));
+    }
+
+    #[test]
+    fn test_args_max_scrapes_per_second() {
+        let args = Args::parse_from(["nvidia-gpu-exporter", "--max-scrapes-per-second", "0.5"]);
+        assert_eq!(args.max_scrapes_per_second, Some(0.5));
+
+        for rate in ["0", "-1", "inf", "fast"] {
+            assert!(Args::try_parse_from(["nvidia-gpu-exporter", "--max-scrapes-per-second", rate]).is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_metrics_endpoint_rate_limit() {
+        let state = AppState {
+            scrape_limiter: Some(Arc::new(Mutex::new(TokenBucket::new(1.0, Instant::now())))),
+            ..test_state(mock_exporter(vec![mock_device(0, 0)]))
+        };
+        let app = router(state, "/metrics");
+
+        let mut statuses = Vec::new();
+        for _ in 0..5 {
+            let response = app
+                .clone()
+                .oneshot(HttpRequest::builder().uri("/metrics").body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            if response.status() == StatusCode::TOO_MANY_REQUESTS {
+                assert_eq!(response.headers().get(header::RETRY_AFTER).unwrap(), "1");
+            }
+            statuses.push(response.status());
+        }
+        // The first scrape of the second goes through, the rest is rejected
+        assert_eq!(statuses[0], StatusCode::OK);
+        assert!(statuses[1..].iter().all(|status| *status == StatusCode::TOO_MANY_REQUESTS));
+
+        // Other routes aren't limited
+        let (status, _) = get(app.clone(), "/metrics/gpu/0").await;
+        assert_eq!(status, StatusCode::OK);
+        let (status, _) = get(app, "/").await;
+        assert_eq!(status, StatusCode::OK);
+    }
+
+    #[test]
+    fn test_args_collect_jitter() {
+        let args = Args::parse_from([
+            "nvidia-gpu-exporter",
+            "--collect-interval",
+            "15s",
+            "--collect-jitter",
+            "0.1",
+        ]);
+        assert_eq!(args.collect_interval, Some(Duration::from_secs(15)));
+        assert_eq!(args.collect_jitter, 0.1);
+
+        assert!(Args::try_parse_from(["nvidia-gpu-exporter", "--collect-jitter", "1.5"]).is_err());
+        assert!(Args::try_parse_from(["nvidia-gpu-exporter", "--collect-jitter", "-0.1"]).is_err());
+    }
+
+    #[test]
+    fn test_args_sample_interval() {
+        let args = Args::parse_from(["nvidia-gpu-exporter", "--collect-interval", "15s", "--sample-interval", "1s"]);
+        assert_eq!(args.sample_interval, Some(Duration::from_secs(1)));
+
+        // The flag's former name still works
+        let args = Args::parse_from([
+            "nvidia-gpu-exporter",
+            "--collect-interval",
+            "15s",
+            "--throttle-sample-interval",
+            "500ms",
+        ]);
+        assert_eq!(args.sample_interval, Some(Duration::from_millis(500)));
+
+        // Sampling only happens in background mode
+        assert!(Args::try_parse_from(["nvidia-gpu-exporter", "--sample-interval", "1s"]).is_err());
+    }
+
     #[tokio::test]
     async fn test_metrics_endpoint_response() {
-        let exporter = Exporter::new();
-        let exporter_clone = exporter.clone();
-
-        let app = Router::new()
-            .route(
-                "/metrics",
-                axum::routing::get(move |_req: Request| async move {
-                    let encoder = TextEncoder::new();
-                    let metric_families = exporter_clone.gather();
-                    let mut buffer = Vec::new();
-                    
-                    if let Err(e) = encoder.encode(&metric_families, &mut buffer) {
-                        return Response::builder()
-                            .status(StatusCode::INTERNAL_SERVER_ERROR)
-                            .body(format!("Failed to encode metrics: {}", e))
-                            .expect("Failed to build error response");
-                    }
-                    
-                    match String::from_utf8(buffer) {
-                        Ok(body) => Response::builder()
-                            .status(StatusCode::OK)
-                            .header("Content-Type", "text/plain; version=0.0.4")
-                            .body(body)
-                            .expect("Failed to build response"),
-                        Err(e) => Response::builder()
-                            .status(StatusCode::INTERNAL_SERVER_ERROR)
-                            .body(format!("Failed to encode metrics as UTF-8: {}", e))
-                            .expect("Failed to build error response"),
-                    }
-                }),
-            );
+        let app = router(test_state(Exporter::new()), "/metrics");
 
         // Test the metrics endpoint
         let response = app
@@ -201,7 +1682,6 @@ mod tests {
         let headers = response.headers().clone();
         
         if status != StatusCode::OK {
-            use axum::body::to_bytes;
             let body_bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
             let body_str = String::from_utf8_lossy(&body_bytes);
             eprintln!("Response status: {}, body: {}", status, body_str);
@@ -217,23 +1697,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_root_endpoint() {
-        let app = Router::new()
-            .route(
-                "/",
-                get(|| async {
-                    Html(
-                        r#"
-                        <html>
-                            <head><title>NVIDIA GPU Exporter</title></head>
-                            <body>
-                                <h1>NVIDIA GPU Exporter</h1>
-                                <p><a href='/metrics'>Metrics</a></p>
-                            </body>
-                        </html>
-                        "#,
-                    )
-                }),
-            );
+        let app = router(test_state(Exporter::new()), "/metrics");
 
         let response = app
             .oneshot(
@@ -247,4 +1711,653 @@ mod tests {
 
         assert_eq!(response.status(), StatusCode::OK);
     }
+
+    #[tokio::test]
+    async fn test_catalog_endpoint() {
+        let mut collector = MockMetricsCollector::new();
+        collector.expect_collect().never();
+        let exporter = Exporter::with_collector(Arc::new(collector), ExporterConfig::default());
+        let app = router(test_state(exporter), "/metrics");
+
+        let (status, body) = get(app, "/metrics/catalog").await;
+        assert_eq!(status, StatusCode::OK);
+        let catalog: Vec<serde_json::Value> = serde_json::from_str(&body).unwrap();
+        let temperature = catalog
+            .iter()
+            .find(|entry| entry["name"] == "nvidia_temperatures")
+            .expect("nvidia_temperatures missing from the catalog");
+        assert_eq!(temperature["type"], "gauge");
+        assert_eq!(temperature["labels"], serde_json::json!(["minor"]));
+    }
+
+    #[tokio::test]
+    async fn test_status_page() {
+        let exporter = mock_exporter(vec![mock_device(0, 0)]);
+        let state = AppState {
+            status_page: true,
+            ..test_state(exporter.clone())
+        };
+        let app = router(state, "/metrics");
+
+        exporter.refresh().unwrap();
+        let (status, body) = get(app, "/").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.contains("<td>NVIDIA GeForce RTX 3080</td><td>60</td>"));
+    }
+
+    #[tokio::test]
+    async fn test_status_page_nvml_unavailable() {
+        let mut collector = MockMetricsCollector::new();
+        collector
+            .expect_collect()
+            .returning(|| Err(anyhow::anyhow!("Driver Not Loaded")));
+        let exporter = Exporter::with_collector(Arc::new(collector), ExporterConfig::default());
+        let state = AppState {
+            status_page: true,
+            ..test_state(exporter.clone())
+        };
+        let app = router(state, "/metrics");
+
+        let (_, body) = get(app.clone(), "/").await;
+        assert!(body.contains("Not collected yet"));
+
+        let _ = exporter.refresh();
+        let (status, body) = get(app, "/").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.contains("NVML unavailable"));
+    }
+
+    #[tokio::test]
+    async fn test_metrics_endpoint_collection_timeout() {
+        let mut collector = MockMetricsCollector::new();
+        collector.expect_collect().returning(|| {
+            // Simulate an NVML call wedged in the driver
+            std::thread::sleep(Duration::from_millis(500));
+            Ok(Metrics {
+                version: "525.116.04".to_string(),
+                devices: vec![],
+                ..Default::default()
+            })
+        });
+        let exporter = Exporter::with_collector(Arc::new(collector), ExporterConfig::default());
+        let state = AppState {
+            exporter: exporter.clone(),
+            collection_timeout: Duration::from_millis(50),
+            collection_lock: Arc::new(tokio::sync::Mutex::new(())),
+            scrape_timeout_header: false,
+            background: false,
+            status_page: false,
+            sample_timestamps: false,
+            scrape_limiter: None,
+            admin_endpoint: false,
+        };
+        let app = router(state, "/metrics");
+
+        let response = app
+            .oneshot(
+                HttpRequest::builder()
+                    .uri("/metrics")
+                    .body(Body::empty())
+                    .unwrap()
+            )
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        let mfs = exporter.families();
+        let value_of = |name: &str| {
+            mfs.iter()
+                .find(|mf| mf.get_name() == name)
+                .map(|mf| mf.get_metric()[0].get_gauge().get_value())
+        };
+        assert_eq!(value_of("nvidia_up"), Some(0.0));
+        assert_eq!(value_of("nvidia_last_scrape_success"), Some(0.0));
+    }
+
+    #[test]
+    fn test_scrape_timeout_header() {
+        let mut state = test_state(mock_exporter(vec![]));
+        let timeout_for = |state: &AppState, value: Option<&str>| {
+            let mut headers = HeaderMap::new();
+            if let Some(value) = value {
+                headers.insert(SCRAPE_TIMEOUT_HEADER, value.parse().unwrap());
+            }
+            state.collection_timeout(&headers)
+        };
+        // Ignored unless enabled
+        assert_eq!(timeout_for(&state, Some("30")), Duration::from_secs(10));
+
+        state.scrape_timeout_header = true;
+        let timeout_for = |value| timeout_for(&state, value);
+
+        assert_eq!(timeout_for(None), Duration::from_secs(10));
+        assert_eq!(timeout_for(Some("30")), Duration::from_millis(29_500));
+        assert_eq!(timeout_for(Some("2.5")), Duration::from_secs(2));
+        // Short timeouts are halved rather than cut to nothing by the margin
+        assert_eq!(timeout_for(Some("0.1")), Duration::from_millis(50));
+        for invalid in ["0", "-1", "soon", ""] {
+            assert_eq!(timeout_for(Some(invalid)), Duration::from_secs(10), "{:?}", invalid);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_metrics_endpoint_scrape_timeout_header() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let mut collector = MockMetricsCollector::new();
+        collector.expect_collect().returning(move || {
+            // The first collection is quick, the ones after wedge in the driver
+            if calls.fetch_add(1, Ordering::SeqCst) > 0 {
+                std::thread::sleep(Duration::from_millis(500));
+            }
+            Ok(Metrics {
+                version: "525.116.04".to_string(),
+                devices: vec![mock_device(0, 0)],
+                ..Default::default()
+            })
+        });
+        let exporter = Exporter::with_collector(Arc::new(collector), ExporterConfig::default());
+        exporter.refresh().unwrap();
+        let state = AppState {
+            scrape_timeout_header: true,
+            ..test_state(exporter)
+        };
+        let app = router(state, "/metrics");
+
+        let started = std::time::Instant::now();
+        let response = app
+            .oneshot(
+                HttpRequest::builder()
+                    .uri("/metrics")
+                    .header("X-Prometheus-Scrape-Timeout-Seconds", "0.05")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert!(started.elapsed() < Duration::from_millis(400), "took {:?}", started.elapsed());
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // The previous collection is served, flagged as not up to date
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body = String::from_utf8_lossy(&body);
+        assert!(body.contains("nvidia_temperatures{minor=\"0\"} 60"));
+        assert!(body.contains("nvidia_up 0"));
+    }
+
+    #[test]
+    fn test_args_collection_duration_buckets() {
+        let args = Args::parse_from(["nvidia-gpu-exporter", "--collection-duration-buckets", "0.05, 0.1,0.5,1"]);
+        assert_eq!(args.collection_duration_buckets, Some(vec![0.05, 0.1, 0.5, 1.0]));
+
+        for invalid in ["", "0.1,fast", "0,1", "-1,1", "0.5,0.1", "0.1,0.1", "1,inf"] {
+            assert!(
+                Args::try_parse_from(["nvidia-gpu-exporter", "--collection-duration-buckets", invalid]).is_err(),
+                "{:?}",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn test_args_remote_endpoint() {
+        let args = Args::parse_from([
+            "nvidia-gpu-exporter",
+            "--remote-endpoint",
+            "gpu-1:9445",
+            "--remote-endpoint",
+            "http://gpu-2/metrics",
+        ]);
+        let hosts: Vec<&str> = args.remote_endpoint.iter().map(|e| e.host.as_str()).collect();
+        assert_eq!(hosts, vec!["gpu-1:9445", "gpu-2:9445"]);
+
+        assert!(Args::try_parse_from(["nvidia-gpu-exporter", "--remote-endpoint", "https://gpu-1"]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_metrics_endpoint_json() {
+        let app = router(test_state(mock_exporter(vec![mock_device(0, 0), mock_device(1, 1)])), "/metrics");
+
+        let response = app
+            .clone()
+            .oneshot(HttpRequest::builder().uri("/metrics?format=json").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers().get(header::CONTENT_TYPE).unwrap(), "application/json");
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let metrics: Metrics = serde_json::from_slice(&body).unwrap();
+        assert_eq!(metrics.version, "525.116.04");
+        assert_eq!(metrics.devices.len(), 2);
+        assert_eq!(metrics.devices[1].temperature, 61.0);
+
+        let (status, _) = get(app, "/metrics?format=xml").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    /// Send a GET request accepting JSON, returning the status, content type and body
+    async fn get_json(app: Router, uri: &str) -> (StatusCode, Option<String>, serde_json::Value) {
+        let request = HttpRequest::builder()
+            .uri(uri)
+            .header(header::ACCEPT, "application/json")
+            .body(Body::empty())
+            .unwrap();
+        let response = app.oneshot(request).await.unwrap();
+        let status = response.status();
+        let content_type = response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .map(|value| value.to_str().unwrap().to_string());
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        (status, content_type, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[test]
+    fn test_accepts_json() {
+        let accepts = |accept: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::ACCEPT, accept.parse().unwrap());
+            accepts_json(&headers)
+        };
+        assert!(accepts("application/json"));
+        assert!(accepts("text/plain;version=0.0.4, Application/JSON;q=0.5"));
+        assert!(!accepts("text/plain;version=0.0.4;q=0.5,*/*;q=0.1"));
+        assert!(!accepts("application/jsonl"));
+        assert!(!accepts_json(&HeaderMap::new()));
+    }
+
+    #[tokio::test]
+    async fn test_encode_error_json() {
+        // A family without a name can't be encoded
+        let app = Router::new().route(
+            "/metrics",
+            axum::routing::get(|headers: HeaderMap| async move { encode_response(&headers, &[MetricFamily::default()]) }),
+        );
+
+        let (status, content_type, body) = get_json(app.clone(), "/metrics").await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(content_type.as_deref(), Some("application/json"));
+        assert_eq!(body["stage"], "encode");
+        assert!(body["error"].as_str().unwrap().starts_with("Failed to encode metrics: "), "{}", body);
+        assert_eq!(body.as_object().unwrap().len(), 2);
+
+        // Plain text unless JSON is accepted
+        let (status, body) = get(app, "/metrics").await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(body.starts_with("Failed to encode metrics: "), "{}", body);
+    }
+
+    #[tokio::test]
+    async fn test_collection_error_json() {
+        let mut collector = MockMetricsCollector::new();
+        collector
+            .expect_collect()
+            .returning(|| Err(anyhow::anyhow!("NVML: GPU is lost")));
+        let exporter = Exporter::with_collector(Arc::new(collector), ExporterConfig::default());
+        let app = router(test_state(exporter), "/metrics");
+
+        let (status, _, body) = get_json(app.clone(), "/metrics?format=json").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body, serde_json::json!({ "error": "Metrics collection failed", "stage": "collect" }));
+
+        let (status, body) = get(app, "/metrics?format=json").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body, "Metrics collection failed");
+    }
+
+    #[tokio::test]
+    async fn test_admin_reset() {
+        let gib = 1024.0 * 1024.0 * 1024.0;
+        let readings = Arc::new(Mutex::new(vec![6.0, 4.0, 4.0].into_iter()));
+        let mut collector = MockMetricsCollector::new();
+        collector.expect_collect().returning(move || {
+            let used = readings.lock().unwrap().next().unwrap();
+            Ok(Metrics {
+                devices: vec![Device {
+                    memory_used: used * gib,
+                    ..mock_device(0, 0)
+                }],
+                ..Default::default()
+            })
+        });
+        let exporter = Exporter::with_collector(Arc::new(collector), ExporterConfig::default());
+        let peak = |exporter: &Exporter| {
+            exporter
+                .gather()
+                .into_iter()
+                .find(|mf| mf.get_name() == "nvidia_memory_used_peak_bytes")
+                .map(|mf| mf.get_metric()[0].get_gauge().get_value() / gib)
+        };
+        let reset = || HttpRequest::builder().method("POST").uri("/admin/reset").body(Body::empty()).unwrap();
+
+        // Not served unless enabled
+        let response = router(test_state(exporter.clone()), "/metrics").oneshot(reset()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        assert_eq!(peak(&exporter), Some(6.0));
+        assert_eq!(peak(&exporter), Some(6.0));
+
+        let state = AppState {
+            admin_endpoint: true,
+            ..test_state(exporter.clone())
+        };
+        let response = router(state, "/metrics").oneshot(reset()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body, serde_json::json!({ "cleared": 1 }));
+
+        // The peak starts over from the current value
+        assert_eq!(peak(&exporter), Some(4.0));
+    }
+
+    #[tokio::test]
+    async fn test_healthz_device_count() {
+        let exporter = |expected_device_count| {
+            let mut collector = MockMetricsCollector::new();
+            collector.expect_collect().returning(|| {
+                Ok(Metrics {
+                    devices: vec![mock_device(0, 0), mock_device(1, 1)],
+                    ..Default::default()
+                })
+            });
+            let config = ExporterConfig {
+                expected_device_count,
+                ..Default::default()
+            };
+            Exporter::with_collector(Arc::new(collector), config)
+        };
+        let mismatch = |exporter: &Exporter| {
+            exporter
+                .families()
+                .into_iter()
+                .find(|mf| mf.get_name() == "nvidia_device_count_mismatch")
+                .map(|mf| mf.get_metric()[0].get_gauge().get_value())
+        };
+
+        let matching = exporter(Some(2));
+        let (status, body) = get(router(test_state(matching.clone()), "/metrics"), "/healthz").await;
+        assert_eq!((status, body.as_str()), (StatusCode::SERVICE_UNAVAILABLE, "Not collected yet"));
+        matching.refresh().unwrap();
+        let (status, body) = get(router(test_state(matching.clone()), "/metrics"), "/healthz").await;
+        assert_eq!((status, body.as_str()), (StatusCode::OK, "OK"));
+        assert_eq!(mismatch(&matching), Some(0.0));
+
+        let mismatched = exporter(Some(4));
+        let _ = mismatched.refresh();
+        let (status, body) = get(router(test_state(mismatched.clone()), "/metrics"), "/healthz").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body, "Found 2 GPU(s), expected 4");
+        assert_eq!(mismatch(&mismatched), Some(1.0));
+
+        // Without an expected count, any number of GPUs is healthy
+        let unset = exporter(None);
+        unset.refresh().unwrap();
+        let (status, _) = get(router(test_state(unset.clone()), "/metrics"), "/healthz").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(mismatch(&unset), None);
+    }
+
+    #[tokio::test]
+    async fn test_healthz_does_not_collect() {
+        let mut collector = MockMetricsCollector::new();
+        collector
+            .expect_collect()
+            .times(1)
+            .returning(|| Err(anyhow::anyhow!("Driver Not Loaded")));
+        let exporter = Exporter::with_collector(Arc::new(collector), ExporterConfig::default());
+        let _ = exporter.refresh();
+
+        for _ in 0..3 {
+            let (status, body) = get(router(test_state(exporter.clone()), "/metrics"), "/healthz").await;
+            assert_eq!((status, body.as_str()), (StatusCode::SERVICE_UNAVAILABLE, "Metrics collection failed"));
+        }
+    }
+
+    #[test]
+    fn test_args_compat() {
+        let args = Args::parse_from(["nvidia-gpu-exporter", "--compat", "go-nvidia-gpu-exporter"]);
+        assert_eq!(args.compat, Some(Compat::GoNvidiaGpuExporter));
+
+        assert!(Args::try_parse_from(["nvidia-gpu-exporter", "--compat", "dcgm-exporter"]).is_err());
+    }
+
+    #[test]
+    fn test_args_extra_field_ids() {
+        let args = Args::parse_from(["nvidia-gpu-exporter", "--extra-field-ids", "82,196"]);
+        assert_eq!(args.extra_field_ids, vec![82, 196]);
+
+        for invalid in ["82,power", "-1"] {
+            assert!(
+                Args::try_parse_from(["nvidia-gpu-exporter", "--extra-field-ids", invalid]).is_err(),
+                "{:?}",
+                invalid
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sample_timestamps() {
+        let exporter = mock_exporter(vec![mock_device(0, 0)]);
+        let state = AppState {
+            background: true,
+            sample_timestamps: true,
+            ..test_state(exporter.clone())
+        };
+        let app = router(state, "/metrics");
+
+        exporter.refresh().unwrap();
+        let collected = exporter.last_collection_time().unwrap();
+        let timestamp_ms = collected.duration_since(UNIX_EPOCH).unwrap().as_millis();
+        // Served later, but stamped with the collection time
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let (status, body) = get(app, "/metrics").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.contains(&format!("nvidia_up 1 {}\n", timestamp_ms)), "{}", body);
+        assert!(body.contains(&format!("nvidia_temperatures{{minor=\"0\"}} 60 {}\n", timestamp_ms)));
+        for line in body.lines().filter(|line| !line.starts_with('#')) {
+            assert!(line.ends_with(&format!(" {}", timestamp_ms)), "{}", line);
+        }
+
+        // Samples carry no timestamp by default
+        let app = router(AppState { background: true, ..test_state(exporter) }, "/metrics");
+        let (_, body) = get(app, "/metrics").await;
+        assert!(body.contains("nvidia_up 1\n"));
+    }
+
+    #[tokio::test]
+    async fn test_device_metrics_endpoint() {
+        let exporter = mock_exporter(vec![mock_device(0, 0), mock_device(1, 3)]);
+        let app = router(test_state(exporter), "/metrics");
+
+        let (status, body) = get(app, "/metrics/gpu/1").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.contains("nvidia_temperatures{minor=\"3\"} 61"));
+        assert!(!body.contains("minor=\"0\""), "Other devices should be filtered out");
+        // Core metrics are always included
+        assert!(body.contains("nvidia_up 1"));
+        assert!(body.contains("nvidia_device_count 2"));
+        assert!(body.contains("nvidia_driver_info{version=\"525.116.04\"} 1"));
+    }
+
+    #[tokio::test]
+    async fn test_background_mode_serves_last_collection() {
+        let exporter = mock_exporter(vec![mock_device(0, 0), mock_device(1, 3)]);
+        let state = AppState {
+            background: true,
+            ..test_state(exporter.clone())
+        };
+        let app = router(state, "/metrics");
+
+        // Nothing has been collected yet
+        let (status, _) = get(app.clone(), "/metrics/gpu/1").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+
+        exporter.refresh().unwrap();
+        let (status, body) = get(app.clone(), "/metrics").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.contains("nvidia_device_count 2"));
+        let (status, body) = get(app, "/metrics/gpu/1").await;
+        assert_eq!(status, StatusCode::OK);
+        assert!(body.contains("nvidia_temperatures{minor=\"3\"} 61"));
+    }
+
+    #[tokio::test]
+    async fn test_background_mode_fresh_collection() {
+        let collections = Arc::new(AtomicUsize::new(0));
+        let mut collector = MockMetricsCollector::new();
+        collector.expect_collect().returning({
+            let collections = Arc::clone(&collections);
+            move || {
+                let n = collections.fetch_add(1, Ordering::SeqCst) as u32;
+                Ok(Metrics {
+                    devices: vec![mock_device(0, n)],
+                    ..Default::default()
+                })
+            }
+        });
+        let exporter = Exporter::with_collector(Arc::new(collector), ExporterConfig::default());
+        let state = AppState {
+            background: true,
+            ..test_state(exporter.clone())
+        };
+        let app = router(state, "/metrics");
+        exporter.refresh().unwrap();
+
+        let (_, body) = get(app.clone(), "/metrics").await;
+        assert_eq!(collections.load(Ordering::SeqCst), 1);
+        assert!(body.contains("nvidia_temperatures{minor=\"0\"}"));
+
+        let (status, body) = get(app.clone(), "/metrics?fresh=1").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(collections.load(Ordering::SeqCst), 2);
+        assert!(body.contains("nvidia_temperatures{minor=\"1\"}"));
+
+        let response = app
+            .clone()
+            .oneshot(
+                HttpRequest::builder()
+                    .uri("/metrics")
+                    .header("Cache-Control", "max-age=0, no-cache")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(collections.load(Ordering::SeqCst), 3);
+
+        // The fresh collections updated the cache served to normal scrapes
+        let (_, body) = get(app, "/metrics").await;
+        assert_eq!(collections.load(Ordering::SeqCst), 3);
+        assert!(body.contains("nvidia_temperatures{minor=\"2\"}"));
+    }
+
+    #[tokio::test]
+    async fn test_fresh_scrape_during_background_collection() {
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let overlapped = Arc::new(AtomicUsize::new(0));
+        let mut collector = MockMetricsCollector::new();
+        let (collections, overlaps) = (in_flight.clone(), overlapped.clone());
+        collector.expect_collect().returning(move || {
+            if collections.fetch_add(1, Ordering::SeqCst) > 0 {
+                overlaps.fetch_add(1, Ordering::SeqCst);
+            }
+            std::thread::sleep(Duration::from_millis(100));
+            collections.fetch_sub(1, Ordering::SeqCst);
+            Ok(Metrics {
+                version: "525.116.04".to_string(),
+                devices: vec![],
+                ..Default::default()
+            })
+        });
+        let exporter = Exporter::with_collector(Arc::new(collector), ExporterConfig::default());
+        let state = AppState {
+            background: true,
+            ..test_state(exporter.clone())
+        };
+        let schedule = background::Schedule::new(Duration::from_secs(60), 0.0);
+        let lock = state.collection_lock.clone();
+        let task = tokio::spawn(background::run(exporter, schedule, lock, state.collection_timeout));
+        // Let the background collection start
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        let response = router(state, "/metrics")
+            .oneshot(
+                HttpRequest::builder()
+                    .uri("/metrics?fresh=1")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        task.abort();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        // The fresh collection waited for the background one to finish
+        assert_eq!(overlapped.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test]
+    async fn test_device_metrics_endpoint_unknown_index() {
+        let exporter = mock_exporter(vec![mock_device(0, 0)]);
+        let app = router(test_state(exporter), "/metrics");
+
+        let (status, _) = get(app, "/metrics/gpu/7").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn test_device_metrics_endpoint_non_numeric_index() {
+        let exporter = mock_exporter(vec![mock_device(0, 0)]);
+        let app = router(test_state(exporter), "/metrics");
+
+        let (status, _) = get(app, "/metrics/gpu/abc").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_collection_timeout_single_flight() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let mut collector = MockMetricsCollector::new();
+        let collections = calls.clone();
+        collector.expect_collect().returning(move || {
+            collections.fetch_add(1, Ordering::SeqCst);
+            std::thread::sleep(Duration::from_millis(300));
+            Ok(Metrics {
+                version: "525.116.04".to_string(),
+                devices: vec![],
+                ..Default::default()
+            })
+        });
+        let exporter = Exporter::with_collector(Arc::new(collector), ExporterConfig::default());
+        let state = AppState {
+            collection_timeout: Duration::from_millis(50),
+            ..test_state(exporter.clone())
+        };
+        let app = router(state.clone(), "/metrics");
+
+        for _ in 0..3 {
+            let response = app
+                .clone()
+                .oneshot(HttpRequest::builder().uri("/metrics").body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        }
+        // The scrapes after the first waited for the wedged collection instead of starting their own
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        // Finishing late, the collection leaves the timeout recorded
+        let _ = state.collection_lock.lock().await;
+        let value_of = |name: &str| {
+            exporter
+                .families()
+                .iter()
+                .find(|mf| mf.get_name() == name)
+                .map(|mf| mf.get_metric()[0].get_gauge().get_value())
+        };
+        assert_eq!(value_of("nvidia_up"), Some(0.0));
+        assert_eq!(value_of("nvidia_last_scrape_success"), Some(0.0));
+    }
 }
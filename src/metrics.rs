@@ -1,17 +1,64 @@
+use crate::smi::SmiCollector;
 use anyhow::Result;
-use nvml_wrapper::NVML;
+use serde::{Deserialize, Serialize};
+use std::cell::Cell;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ops::Range;
+use std::os::raw::c_ulong;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
+use nvml_wrapper::enums::device::SampleValue;
+use nvml_wrapper::error::{nvml_sym, nvml_try, NvmlError};
+use nvml_wrapper::enum_wrappers::device::{InfoRom, Sampling};
+use nvml_wrapper::struct_wrappers::device::{FieldValueSample, MemoryInfo, Sample};
+use nvml_wrapper::structs::device::FieldId;
+use nvml_wrapper::sys_exports::field_id::{NVML_FI_DEV_GET_GPU_RECOVERY_ACTION, NVML_FI_DEV_MEMORY_TEMP};
+use nvml_wrapper::Nvml;
+use nvml_wrapper_sys::bindings::{
+    nvmlEccSramErrorStatus_v1_t, nvmlMemory_t, nvmlRowRemapperHistogramValues_t, nvmlTemperatureThresholds_enum_NVML_TEMPERATURE_THRESHOLD_ACOUSTIC_CURR,
+    NvmlLib,
+};
+use tracing::{debug, info_span, warn, Span};
+
+/// `nvmlDeviceGpuRecoveryAction_t` values that can only be cleared by resetting the GPU
+/// (GPU reset, node reboot, drain and reset)
+const RECOVERY_ACTIONS_REQUIRING_RESET: [u64; 3] = [1, 2, 4];
+
+/// Size of the CPU and NUMA node masks requested from NVML, enough for 1024 CPUs
+const AFFINITY_MASK_WORDS: usize = 1024 / c_ulong::BITS as usize;
+
+/// `NVML_AFFINITY_SCOPE_NODE`, which the wrapper doesn't re-export
+const AFFINITY_SCOPE_NODE: u32 = 0;
 
 /// Complete metrics collection from NVML
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Metrics {
     /// NVIDIA driver version
     pub version: String,
     /// List of GPU devices with their metrics
     pub devices: Vec<Device>,
+    /// Number of NVML calls made during the collection
+    pub nvml_calls: u64,
+    /// NVML calls made during the collection by query and result ("success" or
+    /// the error code)
+    #[serde(skip)]
+    pub nvml_call_results: CallResults,
+    /// Whether each remote exporter could be collected from, by host (empty unless
+    /// collecting from remote exporters)
+    #[serde(skip)]
+    pub remote_up: BTreeMap<String, bool>,
+    /// Indices of the devices left out because their collection ran past the
+    /// per-device timeout
+    #[serde(skip)]
+    pub timed_out_devices: Vec<u32>,
 }
 
 /// GPU device metrics collected from NVML
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Device {
     /// Device index (0, 1, 2, ...)
     pub index: String,
@@ -21,36 +68,74 @@ pub struct Device {
     pub name: String,
     /// Unique GPU identifier
     pub uuid: String,
+    /// PCI bus ID in lspci format (e.g., "0000:65:00.0", None if not available)
+    pub pci_bus_id: Option<String>,
+    /// Remote exporter the device was collected from (None for local GPUs)
+    pub host: Option<String>,
     
     // Temperature & Cooling
     /// GPU temperature in Celsius
     pub temperature: f64,
+    /// Memory (HBM) temperature in Celsius (None if there is no memory sensor)
+    pub memory_temperature: Option<f64>,
+    /// Memory temperature at which the GPU starts slowing down in Celsius (None if not supported)
+    pub memory_temperature_threshold: Option<f64>,
+    /// Temperature the fans are set to hold the GPU at in Celsius (None if not supported)
+    pub target_temperature: Option<f64>,
     /// Fan speed percentage (0-100)
     pub fan_speed: f64,
+    /// Fan speed percentages as (fan index, percent), for the fans that report it (empty if none do)
+    pub fan_speeds: Vec<(u32, f64)>,
+    /// Fan speeds in RPM as (fan index, RPM), for the fans that report it (empty if none do)
+    pub fan_speed_rpm: Vec<(u32, f64)>,
     
     // Power Metrics
     /// Current power usage in milliwatts
     pub power_usage: f64,
     /// Average power usage over 10s in milliwatts
     pub power_usage_average: f64,
+    /// Highest power usage in milliwatts sampled by the driver since the previous
+    /// collection (None if power samples are not supported)
+    pub power_usage_peak: Option<f64>,
     /// Power management limit in milliwatts (None if not supported)
     pub power_limit: Option<f64>,
     /// Default power management limit in milliwatts (None if not supported)
     pub power_limit_default: Option<f64>,
+    /// Power limit the device actually enforces in milliwatts, the lowest of the
+    /// limits in effect (None if not supported)
+    pub power_limit_enforced: Option<f64>,
+    /// Total energy consumed since the driver was last loaded in millijoules (None if not supported)
+    pub energy_consumption: Option<f64>,
     
     // Memory Metrics
     /// Total GPU memory in bytes
     pub memory_total: f64,
     /// Used GPU memory in bytes
     pub memory_used: f64,
+    /// GPU memory reserved by the driver and firmware in bytes, which is neither
+    /// used nor free (None on drivers predating the v2 memory query)
+    pub memory_reserved: Option<f64>,
     /// Memory utilization percentage (0-100)
     pub utilization_memory: f64,
+    /// Memory bus width in bits (None if not supported)
+    pub memory_bus_width: Option<f64>,
     
     // GPU Utilization
     /// Current GPU utilization percentage (0-100)
     pub utilization_gpu: f64,
     /// Average GPU utilization over 10s (0-100)
     pub utilization_gpu_average: f64,
+    /// Fraction of time an SM had a warp active, from DCGM (0-1, None without DCGM fields)
+    pub sm_active: Option<f64>,
+    /// Fraction of cycles the tensor pipes were active, from DCGM (0-1, None without DCGM fields)
+    pub tensor_active: Option<f64>,
+    /// Fraction of cycles the memory interface was busy, from DCGM (0-1, None without DCGM fields)
+    pub dram_active: Option<f64>,
+    /// Number of CUDA cores (None if not supported)
+    pub num_cores: Option<f64>,
+    /// Number of streaming multiprocessors, derived from the CUDA cores and the
+    /// cores per SM of the compute capability (None if either is unknown)
+    pub num_sm: Option<f64>,
     
     // Clock Speeds (in MHz, None if not supported)
     /// Current graphics clock speed in MHz
@@ -65,16 +150,47 @@ pub struct Device {
     pub clock_sm_max: Option<f64>,
     /// Maximum memory clock speed in MHz
     pub clock_memory_max: Option<f64>,
+    /// Customer-defined maximum graphics boost clock in MHz (None if not supported)
+    pub clock_graphics_customer_boost_max: Option<f64>,
+    /// Graphics application clock in MHz, when set to something other than the
+    /// default with `nvidia-smi -ac` (None if not set or not supported)
+    pub app_clock_graphics: Option<f64>,
+    /// Clock throttle reasons the device can report, as an NVML bitmask (None if not supported)
+    pub supported_throttle_reasons: Option<u64>,
+    /// Clock throttle reasons currently active, as an NVML bitmask (None if not supported)
+    pub current_throttle_reasons: Option<u64>,
+    /// Maximum graphics clock in MHz of each supported P-State, by P-State number
+    /// (empty unless P-State clocks are collected)
+    pub pstate_clock_graphics_max: Vec<(u32, f64)>,
     
     // Performance State
     /// Current P-State (0-15, where P0 is maximum performance, None if not supported)
     pub performance_state: Option<f64>,
     
+    // GPU Operation Mode (GOM)
+    /// Current GPU operation mode (0 = All On, 1 = Compute, 2 = Low DP, None if not supported)
+    pub gpu_operation_mode: Option<f64>,
+    /// GPU operation mode that will apply after the next reboot (None if not supported)
+    pub gpu_operation_mode_pending: Option<f64>,
+
+    // Display
+    /// Whether a display is initialized on the device, e.g. an X server running on it
+    /// (1 = active, None if not supported)
+    pub display_active: Option<f64>,
+    /// Whether a physical display is connected to the device (1 = connected, None if not supported)
+    pub display_connected: Option<f64>,
+
     // PCIe Information
     /// Current PCIe link generation (1-4+, None if not supported)
     pub pcie_link_gen: Option<f64>,
     /// Current PCIe link width in lanes (None if not supported)
     pub pcie_link_width: Option<f64>,
+    /// Maximum PCIe link generation supported by the device and system (None if not supported)
+    pub pcie_link_gen_max: Option<f64>,
+    /// Maximum PCIe link width in lanes supported by the device and system (None if not supported)
+    pub pcie_link_width_max: Option<f64>,
+    /// Current PCIe transfer rate per lane in GT/s (None if not supported)
+    pub pcie_link_speed: Option<f64>,
     /// PCIe transmit throughput in KB/s (None if not supported)
     pub pcie_tx_throughput: Option<f64>,
     /// PCIe receive throughput in KB/s (None if not supported)
@@ -85,18 +201,117 @@ pub struct Device {
     pub encoder_utilization: Option<f64>,
     /// Video decoder utilization percentage (0-100, None if not supported)
     pub decoder_utilization: Option<f64>,
+    /// JPEG decoder utilization percentage (0-100, None if there is no JPEG engine)
+    pub jpeg_utilization: Option<f64>,
+    /// Optical Flow Accelerator utilization percentage (0-100, None if there is no OFA engine)
+    pub ofa_utilization: Option<f64>,
+    /// Remaining H.264 encoder capacity as a percentage of the model limit (None if not supported)
+    pub encoder_capacity_h264: Option<f64>,
+    /// Remaining HEVC encoder capacity as a percentage of the model limit (None if not supported)
+    pub encoder_capacity_hevc: Option<f64>,
+    /// Number of active encoder sessions (None if not supported)
+    pub encoder_sessions: Option<f64>,
+    /// Details of the active encoder sessions (empty if none or not supported)
+    pub encoder_session_details: Vec<EncoderSession>,
     
     // ECC Errors (Data Center GPUs only)
     /// Total corrected ECC errors over GPU lifetime (None if ECC not supported)
     pub ecc_errors_corrected: Option<f64>,
     /// Total uncorrected ECC errors over GPU lifetime (None if ECC not supported)
     pub ecc_errors_uncorrected: Option<f64>,
+    /// Memory banks by how many spare rows they have left for remapping (None if not supported)
+    pub row_remap_availability: Option<RowRemapAvailability>,
+    /// Whether memory pages are waiting for a reboot to be retired (0/1, None if not
+    /// supported, e.g. GPUs with row remapping instead of page retirement)
+    pub retired_pages_pending: Option<f64>,
+    /// Whether the SRAM uncorrectable ECC errors crossed the threshold that calls
+    /// for action (0/1, None if not supported; Hopper and newer)
+    pub ecc_sram_threshold_exceeded: Option<f64>,
+    /// Whether the infoROM checksum is valid (0 = corrupted, None if not supported,
+    /// e.g. consumer cards)
+    pub inforom_valid: Option<f64>,
+    /// Versions of the infoROM image and objects (None if not supported)
+    pub inforom_versions: Option<InforomVersions>,
     
+    // Health
+    /// Recovery action the driver recommends, as an `nvmlDeviceGpuRecoveryAction_t`
+    /// value (None if not supported)
+    pub recovery_action: Option<u64>,
+    /// Whether the GPU needs a reset before it can be used again (0/1, None if not supported)
+    pub reset_required: Option<f64>,
+
     // Running Processes
     /// Number of compute processes currently running on this GPU (None if not supported)
     pub compute_processes: Option<f64>,
     /// Number of graphics processes currently running on this GPU (None if not supported)
     pub graphics_processes: Option<f64>,
+    /// Compute and graphics processes currently running on this GPU (empty if not supported)
+    pub processes: Vec<GpuProcess>,
+
+    // Topology
+    /// NUMA node closest to the GPU (None if not supported)
+    pub numa_node: Option<f64>,
+    /// CPUs with an affinity to the GPU, ascending (empty if not supported)
+    pub cpu_affinity: Vec<u32>,
+
+    /// Values of the extra NVML fields requested by id, as (field id, value), for
+    /// the fields the device supports
+    pub raw_fields: Vec<(u32, f64)>,
+
+    // Health
+    /// Whether most queries to the device failed, as happens when it falls off the bus.
+    /// The readings every GPU reports are then 0 and left out of the metrics.
+    pub lost: bool,
+}
+
+/// A process running on a GPU
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct GpuProcess {
+    pub pid: u32,
+    /// GPU memory used by the process in bytes (None if not available)
+    pub memory_used: Option<f64>,
+    /// MIG GPU instance the process runs on (None unless MIG is enabled)
+    pub gpu_instance_id: Option<u32>,
+    /// Whether the process is listed among the compute processes
+    pub compute: bool,
+    /// Whether the process is listed among the graphics processes
+    pub graphics: bool,
+}
+
+/// Histogram of the memory banks by the spare rows they have left for remapping
+/// failing rows. Banks running out of spare rows are the signal to RMA a GPU.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct RowRemapAvailability {
+    /// Banks with all their spare rows available
+    pub max: f64,
+    pub high: f64,
+    pub partial: f64,
+    pub low: f64,
+    /// Banks with no spare rows left
+    pub none: f64,
+}
+
+/// Versions of the infoROM image and of the objects stored in it
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct InforomVersions {
+    pub image: String,
+    /// Version of each object, None when the object isn't present
+    pub oem: Option<String>,
+    pub ecc: Option<String>,
+    pub power: Option<String>,
+}
+
+/// An active video encoder session
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct EncoderSession {
+    /// Codec in use ("h264" or "hevc")
+    pub codec: String,
+    /// Encoding resolution as "<width>x<height>"
+    pub resolution: String,
+    /// Moving average of encoded frames per second
+    pub fps: f64,
+    /// Moving average of the encode latency in microseconds
+    pub latency_us: f64,
 }
 
 /// Trait for collecting GPU metrics
@@ -104,175 +319,1235 @@ pub struct Device {
 #[cfg_attr(test, mockall::automock)]
 pub trait MetricsCollector {
     fn collect(&self) -> Result<Metrics>;
+
+    /// Current throttle reasons and graphics clock of every device. Meant to be
+    /// called more often than `collect`, so it's cheap or empty.
+    fn sample(&self) -> Result<Vec<DeviceSample>> {
+        Ok(Vec::new())
+    }
+}
+
+/// Count of NVML calls by query and result ("success" or the error code)
+pub type CallResults = BTreeMap<(&'static str, &'static str), u64>;
+
+/// State of a device read by the background sampler between collections
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct DeviceSample {
+    pub minor_number: String,
+    /// Clock throttle reason mask (None if not supported)
+    pub throttle_reasons: Option<u64>,
+    /// Graphics clock in MHz (None if not supported)
+    pub clock_graphics: Option<f64>,
 }
 
 /// Real NVML implementation
-pub struct NvmlCollector;
+pub struct NvmlCollector {
+    /// Shared with the threads collecting devices under a per-device timeout,
+    /// which may outlive a re-initialization
+    watchdog: ReinitWatchdog<Arc<Nvml>>,
+    /// Collector used instead while NVML can't be initialized
+    fallback: Option<SmiCollector>,
+    /// Query the clocks of every supported P-State, one NVML call per state
+    pstate_clocks: bool,
+    /// NVML field ids to query and export as is
+    extra_field_ids: Vec<u32>,
+    /// Extra field ids already warned about, so an unsupported one is only logged once
+    skipped_field_ids: Arc<Mutex<HashSet<u32>>>,
+    /// Timestamp of the newest power sample seen per GPU UUID, so each collection
+    /// only looks at the samples taken since the previous one
+    power_samples_seen: Arc<Mutex<HashMap<String, u64>>>,
+    /// Collect each device on its own thread, leaving out those that take longer
+    /// (None collects them one after the other, without a limit)
+    per_device_timeout: Option<Duration>,
+    /// Indices of the devices whose collection thread is still running after
+    /// timing out
+    busy_devices: Arc<Mutex<HashSet<u32>>>,
+    /// NVML calls made by failed collections, which have no `Metrics` to carry
+    /// them, until the exporter takes them
+    failed_call_results: Arc<Mutex<CallResults>>,
+}
 
 impl MetricsCollector for NvmlCollector {
     fn collect(&self) -> Result<Metrics> {
-        collect_metrics_impl()
+        let result = self.watchdog.run(|nvml| collect_metrics_impl(nvml, self));
+        if result.is_err() {
+            let mut failed = self.failed_call_results.lock().unwrap_or_else(|e| e.into_inner());
+            for (key, count) in NVML_CALL_RESULTS.with(RefCell::take) {
+                *failed.entry(key).or_default() += count;
+            }
+        }
+        match (&self.fallback, result) {
+            (Some(fallback), Err(e)) if !self.watchdog.is_initialized() => {
+                debug!("NVML unavailable ({:#}), collecting with nvidia-smi", e);
+                fallback.collect()
+            }
+            (_, result) => result,
+        }
+    }
+
+    fn sample(&self) -> Result<Vec<DeviceSample>> {
+        // Never initializes NVML, that is left to the collections
+        let result = self.watchdog.with_handle(|nvml| {
+            let mut samples = Vec::new();
+            for index in 0..nvml.device_count()? {
+                let device = nvml.device_by_index(index)?;
+                samples.push(DeviceSample {
+                    minor_number: device.minor_number()?.to_string(),
+                    throttle_reasons: device.current_throttle_reasons().ok().map(|reasons| reasons.bits()),
+                    clock_graphics: device.clock_info(nvml_wrapper::enum_wrappers::device::Clock::Graphics).ok().map(|clock| clock as f64),
+                });
+            }
+            Ok(samples)
+        });
+        result.unwrap_or_else(|| Ok(Vec::new()))
     }
 }
 
 impl NvmlCollector {
-    pub fn new() -> Self {
-        Self
+    /// Create a collector that re-initializes NVML after `reinit_threshold`
+    /// consecutive failed collections (0 disables re-initialization)
+    pub fn new(reinit_threshold: u32) -> Self {
+        Self {
+            watchdog: ReinitWatchdog::new(reinit_threshold, || Ok(Arc::new(Nvml::init()?))),
+            fallback: None,
+            pstate_clocks: false,
+            extra_field_ids: Vec::new(),
+            skipped_field_ids: Arc::new(Mutex::new(HashSet::new())),
+            power_samples_seen: Arc::new(Mutex::new(HashMap::new())),
+            per_device_timeout: None,
+            busy_devices: Arc::new(Mutex::new(HashSet::new())),
+            failed_call_results: Arc::new(Mutex::new(BTreeMap::new())),
+        }
+    }
+
+    /// Collect with `fallback` whenever NVML fails to initialize
+    pub fn with_fallback(self, fallback: SmiCollector) -> Self {
+        Self {
+            fallback: Some(fallback),
+            ..self
+        }
+    }
+
+    /// Also collect the maximum graphics clock of every supported P-State
+    pub fn with_pstate_clocks(self) -> Self {
+        Self {
+            pstate_clocks: true,
+            ..self
+        }
+    }
+
+    /// Also query these NVML field ids and export their raw values
+    pub fn with_extra_field_ids(self, extra_field_ids: Vec<u32>) -> Self {
+        Self {
+            extra_field_ids,
+            ..self
+        }
+    }
+
+    /// Collect each device on its own thread, leaving out the devices whose
+    /// collection takes longer than `timeout`
+    pub fn with_per_device_timeout(self, timeout: Duration) -> Self {
+        Self {
+            per_device_timeout: Some(timeout),
+            ..self
+        }
+    }
+
+    /// Shared count of NVML re-initializations triggered by the watchdog
+    pub fn reinit_count(&self) -> Arc<AtomicU64> {
+        self.watchdog.reinit_count()
+    }
+
+    /// Shared NVML calls of the failed collections not taken yet
+    pub fn failed_call_results(&self) -> Arc<Mutex<CallResults>> {
+        Arc::clone(&self.failed_call_results)
+    }
+
+    fn device_collector(&self) -> DeviceCollector {
+        DeviceCollector {
+            pstate_clocks: self.pstate_clocks,
+            extra_field_ids: self.extra_field_ids.clone(),
+            skipped_field_ids: Arc::clone(&self.skipped_field_ids),
+            power_samples_seen: Arc::clone(&self.power_samples_seen),
+        }
     }
 }
 
-impl Default for NvmlCollector {
-    fn default() -> Self {
-        Self::new()
+/// Caches a library handle across collections and drops it after repeated
+/// failures, so the next attempt starts from a fresh handle. A handle can stay
+/// broken after a driver hiccup until it is re-initialized.
+pub struct ReinitWatchdog<H> {
+    init: Box<dyn Fn() -> Result<H> + Send + Sync>,
+    threshold: u32,
+    state: Mutex<WatchdogState<H>>,
+    reinits: Arc<AtomicU64>,
+}
+
+struct WatchdogState<H> {
+    handle: Option<H>,
+    failure_streak: u32,
+}
+
+impl<H> ReinitWatchdog<H> {
+    pub fn new(threshold: u32, init: impl Fn() -> Result<H> + Send + Sync + 'static) -> Self {
+        Self {
+            init: Box::new(init),
+            threshold,
+            state: Mutex::new(WatchdogState {
+                handle: None,
+                failure_streak: 0,
+            }),
+            reinits: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Run `f` against the cached handle, initializing it first if needed
+    pub fn run<T>(&self, f: impl FnOnce(&H) -> Result<T>) -> Result<T> {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+
+        let result = match state.handle {
+            Some(ref handle) => f(handle),
+            None => (self.init)().and_then(|handle| f(state.handle.insert(handle))),
+        };
+
+        match result {
+            Ok(_) => state.failure_streak = 0,
+            Err(_) => {
+                state.failure_streak += 1;
+                if self.threshold > 0 && state.failure_streak >= self.threshold {
+                    if state.handle.take().is_some() {
+                        warn!(
+                            "Collection failed {} times in a row, re-initializing NVML",
+                            state.failure_streak
+                        );
+                        self.reinits.fetch_add(1, Ordering::Relaxed);
+                    }
+                    state.failure_streak = 0;
+                }
+            }
+        }
+
+        result
+    }
+
+    /// Run `f` against the cached handle if there is one, without initializing
+    /// it or counting a failure towards re-initialization
+    pub fn with_handle<T>(&self, f: impl FnOnce(&H) -> Result<T>) -> Option<Result<T>> {
+        let state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        state.handle.as_ref().map(f)
+    }
+
+    pub fn reinit_count(&self) -> Arc<AtomicU64> {
+        Arc::clone(&self.reinits)
+    }
+
+    /// Whether a handle is cached, i.e. the last initialization succeeded and the
+    /// handle hasn't been dropped since
+    pub fn is_initialized(&self) -> bool {
+        self.state.lock().unwrap_or_else(|e| e.into_inner()).handle.is_some()
     }
 }
 
-pub fn collect_metrics() -> Result<Metrics> {
-    NvmlCollector::new().collect()
+thread_local! {
+    /// NVML calls made so far by the collection running on this thread
+    static NVML_CALLS: Cell<u64> = const { Cell::new(0) };
+    /// NVML calls that failed because of the device, as counted by `is_device_failure`
+    static NVML_FAILURES: Cell<u64> = const { Cell::new(0) };
+    /// NVML calls made so far by the collection running on this thread, by query and result
+    static NVML_CALL_RESULTS: RefCell<CallResults> = const { RefCell::new(BTreeMap::new()) };
 }
 
-fn collect_metrics_impl() -> Result<Metrics> {
-    let nvml = NVML::init()?;
-    let version = nvml.sys_driver_version()?;
+/// Count an NVML call made for `call` towards the current collection, passing its
+/// result through
+fn nvml_call<T>(call: &'static str, result: std::result::Result<T, NvmlError>) -> std::result::Result<T, NvmlError> {
+    NVML_CALLS.with(|calls| calls.set(calls.get() + 1));
+    if matches!(result, Err(ref e) if is_device_failure(e)) {
+        NVML_FAILURES.with(|failures| failures.set(failures.get() + 1));
+    }
+    let code = result.as_ref().map_or_else(nvml_error_code, |_| "success");
+    NVML_CALL_RESULTS.with(|results| *results.borrow_mut().entry((call, code)).or_default() += 1);
+    result
+}
 
-    let device_count = nvml.device_count()?;
-    let mut devices = Vec::new();
+/// Whether an NVML error points at the device itself rather than at the query:
+/// unsupported features, symbols missing from an older driver and queries needing
+/// root fail the same way on a healthy GPU, so they don't make it look lost
+fn is_device_failure(e: &NvmlError) -> bool {
+    matches!(
+        e,
+        NvmlError::GpuLost
+            | NvmlError::ResetRequired
+            | NvmlError::Unknown
+            | NvmlError::Timeout
+            | NvmlError::Uninitialized
+            | NvmlError::IrqIssue
+            | NvmlError::DriverNotLoaded
+    )
+}
 
-    for index in 0..device_count {
-        let device = nvml.device_by_index(index)?;
+/// Stable snake_case code of an NVML error
+pub fn nvml_error_code(e: &NvmlError) -> &'static str {
+    match e {
+        NvmlError::Uninitialized => "uninitialized",
+        NvmlError::InvalidArg => "invalid_argument",
+        NvmlError::NotSupported => "not_supported",
+        NvmlError::NoPermission => "no_permission",
+        NvmlError::NotFound => "not_found",
+        NvmlError::InsufficientSize(_) => "insufficient_size",
+        NvmlError::InsufficientPower => "insufficient_power",
+        NvmlError::DriverNotLoaded => "driver_not_loaded",
+        NvmlError::Timeout => "timeout",
+        NvmlError::IrqIssue => "irq_issue",
+        NvmlError::LibraryNotFound => "library_not_found",
+        NvmlError::LibloadingError(_) => "library_load_failed",
+        NvmlError::FunctionNotFound | NvmlError::FailedToLoadSymbol(_) => "function_not_found",
+        NvmlError::CorruptedInfoROM => "corrupted_inforom",
+        NvmlError::GpuLost => "gpu_lost",
+        NvmlError::ResetRequired => "reset_required",
+        NvmlError::OperatingSystem => "operating_system",
+        NvmlError::LibRmVersionMismatch => "lib_rm_version_mismatch",
+        NvmlError::InUse => "in_use",
+        NvmlError::InsufficientMemory => "insufficient_memory",
+        NvmlError::NoData => "no_data",
+        NvmlError::VgpuEccNotSupported => "vgpu_ecc_not_supported",
+        NvmlError::Unknown => "unknown",
+        // Errors raised by the wrapper itself rather than NVML
+        _ => "wrapper_error",
+    }
+}
 
-        let uuid = device.uuid()?;
-        let name = device.name()?;
-        let minor_number = device.minor_number()?.to_string();
+/// NVML calls and failures counted so far on this thread
+fn nvml_call_stats() -> (u64, u64) {
+    (NVML_CALLS.with(Cell::get), NVML_FAILURES.with(Cell::get))
+}
 
-        let temperature = device.temperature(nvml_wrapper::enum_wrappers::device::TemperatureSensor::Gpu)? as f64;
+/// NVML calls counted on a thread collecting a device, to add to the collection
+/// running on another
+struct CallStats {
+    calls: u64,
+    failures: u64,
+    results: CallResults,
+}
 
-        let power_usage = device.power_usage()? as f64;
+impl CallStats {
+    /// Add these calls to the collection running on this thread
+    fn merge(self) {
+        NVML_CALLS.with(|calls| calls.set(calls.get() + self.calls));
+        NVML_FAILURES.with(|failures| failures.set(failures.get() + self.failures));
+        NVML_CALL_RESULTS.with(|results| {
+            let mut results = results.borrow_mut();
+            for (key, count) in self.results {
+                *results.entry(key).or_default() += count;
+            }
+        });
+    }
+}
 
-        // For average power usage, we'll use the current value as a placeholder
-        // NVML doesn't have a direct average function, so we'll use the current value
-        // In a real implementation, you might want to track historical values
-        let power_usage_average = power_usage;
+/// Run `f` on a fresh count of NVML calls, returning its result with the calls it made
+fn with_call_stats<T>(f: impl FnOnce() -> T) -> (T, CallStats) {
+    NVML_CALLS.with(|calls| calls.set(0));
+    NVML_FAILURES.with(|failures| failures.set(0));
+    NVML_CALL_RESULTS.with(|results| results.borrow_mut().clear());
+    let result = f();
+    let stats = CallStats {
+        calls: NVML_CALLS.with(Cell::get),
+        failures: NVML_FAILURES.with(Cell::get),
+        results: NVML_CALL_RESULTS.with(RefCell::take),
+    };
+    (result, stats)
+}
 
-        // Fan speed - use fan index 0 (first fan)
-        let fan_speed = device.fan_speed(0).unwrap_or(0) as f64;
+/// Run `collect` for every device index on its own thread and wait for them until
+/// `timeout` has passed, so a wedged device doesn't hold up the others. Returns
+/// `None` for the devices that didn't finish in time. Their threads are left to
+/// finish on their own, since NVML calls can't be interrupted; until they do, the
+/// device is in `busy` and left out right away rather than given another thread.
+fn collect_with_deadline<T: Send + 'static>(
+    indices: Range<u32>,
+    timeout: Duration,
+    busy: &Arc<Mutex<HashSet<u32>>>,
+    collect: impl Fn(u32) -> T + Send + Sync + 'static,
+) -> Vec<(u32, Option<T>)> {
+    let deadline = Instant::now() + timeout;
+    let collect = Arc::new(collect);
+    let pending: Vec<_> = indices
+        .map(|index| {
+            if !busy.lock().unwrap_or_else(|e| e.into_inner()).insert(index) {
+                debug!("GPU {} is still being collected since an earlier timeout", index);
+                return (index, None);
+            }
+            let (sender, receiver) = mpsc::channel();
+            let (collect, busy) = (Arc::clone(&collect), Arc::clone(busy));
+            std::thread::spawn(move || {
+                let result = collect(index);
+                busy.lock().unwrap_or_else(|e| e.into_inner()).remove(&index);
+                // The receiver is gone when the collection gave up on this device
+                let _ = sender.send(result);
+            });
+            (index, Some(receiver))
+        })
+        .collect();
+    pending
+        .into_iter()
+        .map(|(index, receiver)| {
+            let result = receiver.and_then(|receiver| {
+                receiver
+                    .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+                    .ok()
+            });
+            (index, result)
+        })
+        .collect()
+}
 
-        let memory_info = device.memory_info()?;
-        let memory_total = memory_info.total as f64;
-        let memory_used = memory_info.used as f64;
+/// Whether a device looks like it fell off the bus: a lost GPU fails nearly every
+/// query, while a healthy one only fails the odd query
+fn device_lost(calls: u64, failures: u64) -> bool {
+    calls > 0 && failures * 2 > calls
+}
 
-        let utilization = device.utilization_rates()?;
-        let utilization_gpu = utilization.gpu as f64;
-        let utilization_memory = utilization.memory as f64;
+fn collect_metrics_impl(nvml: &Arc<Nvml>, collector: &NvmlCollector) -> Result<Metrics> {
+    NVML_CALLS.with(|calls| calls.set(0));
+    NVML_FAILURES.with(|failures| failures.set(0));
+    NVML_CALL_RESULTS.with(|results| results.borrow_mut().clear());
+    let version = nvml_call("driver_version", nvml.sys_driver_version())?;
 
-        // For average GPU utilization, we'll use the current value as a placeholder
-        // Similar to power usage average
-        let utilization_gpu_average = utilization_gpu;
+    let device_count = nvml_call("device_count", nvml.device_count())?;
+    let device_collector = collector.device_collector();
+    let mut timed_out_devices = Vec::new();
+    let devices = match collector.per_device_timeout {
+        None => (0..device_count)
+            .map(|index| device_collector.collect(nvml, index))
+            .collect::<Result<Vec<_>>>()?,
+        Some(timeout) => {
+            let nvml = Arc::clone(nvml);
+            let results = collect_with_deadline(0..device_count, timeout, &collector.busy_devices, move |index| {
+                with_call_stats(|| device_collector.collect(&nvml, index))
+            });
+            let mut devices = Vec::new();
+            for (index, result) in results {
+                match result {
+                    Some((device, stats)) => {
+                        stats.merge();
+                        devices.push(device?);
+                    }
+                    None => {
+                        warn!("Skipping GPU {}, its collection didn't finish within {:?}", index, timeout);
+                        timed_out_devices.push(index);
+                    }
+                }
+            }
+            devices
+        }
+    };
+    retain_power_samples_seen(&collector.power_samples_seen, &devices);
+
+    Ok(Metrics {
+        version,
+        devices,
+        nvml_calls: NVML_CALLS.with(Cell::get),
+        nvml_call_results: NVML_CALL_RESULTS.with(RefCell::take),
+        remote_up: BTreeMap::new(),
+        timed_out_devices,
+    })
+}
+
+/// What collecting a single device needs, cloned into the threads that collect
+/// the devices under a per-device timeout
+#[derive(Clone)]
+struct DeviceCollector {
+    pstate_clocks: bool,
+    extra_field_ids: Vec<u32>,
+    skipped_field_ids: Arc<Mutex<HashSet<u32>>>,
+    power_samples_seen: Arc<Mutex<HashMap<String, u64>>>,
+}
+
+impl DeviceCollector {
+    fn collect(&self, nvml: &Nvml, index: u32) -> Result<Device> {
+        let device = match nvml_call("device_by_index", nvml.device_by_index(index)) {
+            Ok(device) => device,
+            Err(NvmlError::GpuLost) => {
+                warn!("GPU {} is lost, it can't even be looked up", index);
+                return Ok(lost_device(index));
+            }
+            Err(e) => return Err(e.into()),
+        };
+        let Some((uuid, name, minor_number)) = identify(&device, index)? else {
+            return Ok(lost_device(index));
+        };
+
+        // Tag everything logged while collecting this device with its index and uuid
+        let span = device_span(index, &uuid);
+        let _enter = span.enter();
+
+        let pci_bus_id = optional("pci_bus_id", device.pci_info())
+            .map(|info| format_pci_bus_id(&info.bus_id));
+
+        // Failures of the queries below tell whether the device is lost
+        let (calls_before, failures_before) = nvml_call_stats();
+
+        let core = query_core(&device);
+        // HBM memory sensor and its slowdown threshold (data center GPUs)
+        let memory_temperature = field_value("memory_temperature", &device, NVML_FI_DEV_MEMORY_TEMP)
+            .map(|t| t as f64);
+        let memory_temperature_threshold = optional("memory_temperature_threshold", device.temperature_threshold(nvml_wrapper::enum_wrappers::device::TemperatureThreshold::MemoryMax))
+            .map(|t| t as f64);
+
+        let target_temperature = optional("target_temperature", target_temperature(&device)).map(|t| t as f64);
+
+        // Fan speed - use fan index 0 (first fan)
+        let fan_speed = nvml_call("fan_speed", device.fan_speed(0)).unwrap_or(0) as f64;
+        let fan_count = optional("fan_count", device.num_fans()).unwrap_or(0);
+        // Percentage of each fan, of which the first is fan_speed
+        let fan_speeds = (0..fan_count)
+            .filter_map(|fan| optional("fan_speeds", device.fan_speed(fan)).map(|speed| (fan, speed as f64)))
+            .collect();
+        // RPM of each fan, which newer drivers report next to the percentage
+        let fan_speed_rpm = (0..fan_count)
+            .filter_map(|fan| optional("fan_speed_rpm", device.fan_speed_rpm(fan)).map(|rpm| (fan, rpm as f64)))
+            .collect();
+
+        let memory_bus_width = optional("memory_bus_width", device.memory_bus_width())
+            .map(|w| w as f64);
+
+        // NVML has no SM count, so it follows from the cores and the compute capability
+        let num_cores = optional("num_cores", device.num_cores());
+        let num_sm = num_cores.zip(optional("cuda_compute_capability", device.cuda_compute_capability()))
+            .and_then(|(cores, capability)| Some(cores / cores_per_sm(capability.major, capability.minor)?));
+        let (num_cores, num_sm) = (num_cores.map(|c| c as f64), num_sm.map(|s| s as f64));
 
-        // Clock speeds - use .ok() to handle unsupported GPUs gracefully
-        let clock_graphics = device.clock_info(nvml_wrapper::enum_wrappers::device::Clock::Graphics)
-            .ok().map(|c| c as f64);
-        let clock_sm = device.clock_info(nvml_wrapper::enum_wrappers::device::Clock::SM)
-            .ok().map(|c| c as f64);
-        let clock_memory = device.clock_info(nvml_wrapper::enum_wrappers::device::Clock::Memory)
-            .ok().map(|c| c as f64);
+        // Clock speeds - unsupported GPUs report None
+        let clock_graphics = optional("clock_graphics", device.clock_info(nvml_wrapper::enum_wrappers::device::Clock::Graphics))
+            .map(|c| c as f64);
+        let clock_sm = optional("clock_sm", device.clock_info(nvml_wrapper::enum_wrappers::device::Clock::SM))
+            .map(|c| c as f64);
+        let clock_memory = optional("clock_memory", device.clock_info(nvml_wrapper::enum_wrappers::device::Clock::Memory))
+            .map(|c| c as f64);
         
-        let clock_graphics_max = device.max_clock_info(nvml_wrapper::enum_wrappers::device::Clock::Graphics)
-            .ok().map(|c| c as f64);
-        let clock_sm_max = device.max_clock_info(nvml_wrapper::enum_wrappers::device::Clock::SM)
-            .ok().map(|c| c as f64);
-        let clock_memory_max = device.max_clock_info(nvml_wrapper::enum_wrappers::device::Clock::Memory)
-            .ok().map(|c| c as f64);
+        let clock_graphics_max = optional("clock_graphics_max", device.max_clock_info(nvml_wrapper::enum_wrappers::device::Clock::Graphics))
+            .map(|c| c as f64);
+        let clock_sm_max = optional("clock_sm_max", device.max_clock_info(nvml_wrapper::enum_wrappers::device::Clock::SM))
+            .map(|c| c as f64);
+        let clock_memory_max = optional("clock_memory_max", device.max_clock_info(nvml_wrapper::enum_wrappers::device::Clock::Memory))
+            .map(|c| c as f64);
+        let clock_graphics_customer_boost_max = optional("clock_graphics_customer_boost_max", device.max_customer_boost_clock(nvml_wrapper::enum_wrappers::device::Clock::Graphics))
+            .map(|c| c as f64);
+        // Application clocks left at their default don't count as set
+        let app_clock_graphics = optional("app_clock_graphics", device.applications_clock(nvml_wrapper::enum_wrappers::device::Clock::Graphics))
+            .filter(|clock| {
+                optional("app_clock_graphics_default", device.default_applications_clock(nvml_wrapper::enum_wrappers::device::Clock::Graphics))
+                    != Some(*clock)
+            })
+            .map(|c| c as f64);
 
         // Power limits
-        let power_limit = device.power_management_limit().ok().map(|p| p as f64);
-        let power_limit_default = device.power_management_limit_default().ok().map(|p| p as f64);
+        let power_limit = optional("power_limit", device.power_management_limit())
+            .map(|p| p as f64);
+        let power_limit_default = optional("power_limit_default", device.power_management_limit_default())
+            .map(|p| p as f64);
+        let power_limit_enforced = optional("power_limit_enforced", device.enforced_power_limit())
+            .map(|p| p as f64);
+
+        // Energy consumption in millijoules since driver load (Volta and newer)
+        let energy_consumption = optional("energy_consumption", device.total_energy_consumption())
+            .map(|e| e as f64);
 
         // Performance state (P-State: P0-P15, where P0 is maximum performance)
-        let performance_state = device.performance_state()
-            .ok().map(|ps| ps as u32 as f64);
+        let performance_state = optional("performance_state", device.performance_state())
+            .map(|ps| ps as u32 as f64);
+
+        let supported_throttle_reasons = optional("supported_throttle_reasons", device.supported_throttle_reasons())
+            .map(|reasons| reasons.bits());
+        let current_throttle_reasons = optional("current_throttle_reasons", device.current_throttle_reasons())
+            .map(|reasons| reasons.bits());
+        let pstate_clock_graphics_max = if self.pstate_clocks {
+            optional("supported_performance_states", device.supported_performance_states())
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|ps| {
+                    optional("pstate_clock_graphics", device.min_max_clock_of_pstate(nvml_wrapper::enum_wrappers::device::Clock::Graphics, ps))
+                        .map(|(_, max)| (ps as u32, max as f64))
+                })
+                .collect()
+        } else {
+            Vec::new()
+        };
+
+        // GPU operation mode (Tesla/Quadro only), coded in NVML enum order
+        let (gpu_operation_mode, gpu_operation_mode_pending) = match optional("gpu_operation_mode", device.gpu_operation_mode()) {
+            Some(gom) => (Some(gom.current as u32 as f64), Some(gom.pending as u32 as f64)),
+            None => (None, None),
+        };
+
+        // Display status (workstation cards; headless data center cards don't support it)
+        let display_active = optional("display_active", device.is_display_active())
+            .map(|active| if active { 1.0 } else { 0.0 });
+        let display_connected = optional("display_connected", device.is_display_connected())
+            .map(|connected| if connected { 1.0 } else { 0.0 });
 
         // PCIe information
-        let pcie_link_gen = device.current_pcie_link_gen().ok().map(|g| g as f64);
-        let pcie_link_width = device.current_pcie_link_width().ok().map(|w| w as f64);
+        let pcie_link_gen = optional("pcie_link_gen", device.current_pcie_link_gen())
+            .map(|g| g as f64);
+        let pcie_link_width = optional("pcie_link_width", device.current_pcie_link_width())
+            .map(|w| w as f64);
+        let pcie_link_gen_max = optional("pcie_link_gen_max", device.max_pcie_link_gen())
+            .map(|g| g as f64);
+        let pcie_link_width_max = optional("pcie_link_width_max", device.max_pcie_link_width())
+            .map(|w| w as f64);
+        // Reported in MT/s per lane, e.g. 16000 on a gen 4 link
+        let pcie_link_speed = optional("pcie_link_speed", device.pcie_link_speed())
+            .map(|s| s as f64 / 1000.0);
         
         // PCIe throughput (in KB/s)
-        let pcie_tx_throughput = device.pcie_throughput(nvml_wrapper::enum_wrappers::device::PcieUtilCounter::Send)
-            .ok().map(|t| t as f64);
-        let pcie_rx_throughput = device.pcie_throughput(nvml_wrapper::enum_wrappers::device::PcieUtilCounter::Receive)
-            .ok().map(|t| t as f64);
+        let pcie_tx_throughput = optional("pcie_tx_throughput", device.pcie_throughput(nvml_wrapper::enum_wrappers::device::PcieUtilCounter::Send))
+            .map(|t| t as f64);
+        let pcie_rx_throughput = optional("pcie_rx_throughput", device.pcie_throughput(nvml_wrapper::enum_wrappers::device::PcieUtilCounter::Receive))
+            .map(|t| t as f64);
 
         // Encoder/Decoder utilization
-        let encoder_utilization = device.encoder_utilization()
-            .ok().map(|info| info.utilization as f64);
-        let decoder_utilization = device.decoder_utilization()
-            .ok().map(|info| info.utilization as f64);
+        let encoder_utilization = optional("encoder_utilization", device.encoder_utilization())
+            .map(|info| info.utilization as f64);
+        let decoder_utilization = optional("decoder_utilization", device.decoder_utilization())
+            .map(|info| info.utilization as f64);
+        let jpeg_utilization = optional("jpeg_utilization", engine_utilization(&device, Engine::Jpeg))
+            .map(|u| u as f64);
+        let ofa_utilization = optional("ofa_utilization", engine_utilization(&device, Engine::Ofa))
+            .map(|u| u as f64);
+
+        // Encoder capacity (percent of the model's encoding limit still available)
+        let encoder_capacity_h264 = optional("encoder_capacity_h264", device.encoder_capacity(nvml_wrapper::enum_wrappers::device::EncoderType::H264))
+            .map(|c| c as f64);
+        let encoder_capacity_hevc = optional("encoder_capacity_hevc", device.encoder_capacity(nvml_wrapper::enum_wrappers::device::EncoderType::HEVC))
+            .map(|c| c as f64);
+        let encoder_sessions = optional("encoder_sessions", device.encoder_stats())
+            .map(|stats| stats.session_count as f64);
+        let encoder_session_details = optional("encoder_session_details", device.encoder_sessions())
+            .unwrap_or_default()
+            .into_iter()
+            .map(|session| EncoderSession {
+                codec: match session.codec_type {
+                    nvml_wrapper::enum_wrappers::device::EncoderType::H264 => "h264",
+                    nvml_wrapper::enum_wrappers::device::EncoderType::HEVC => "hevc",
+                }
+                .to_string(),
+                resolution: format!("{}x{}", session.hres, session.vres),
+                fps: session.average_fps as f64,
+                latency_us: session.average_latency as f64,
+            })
+            .collect();
 
         // ECC errors (only for GPUs that support ECC)
-        let ecc_errors_corrected = device.total_ecc_errors(
+        let ecc_errors_corrected = optional("ecc_errors_corrected", device.total_ecc_errors(
             nvml_wrapper::enum_wrappers::device::MemoryError::Corrected,
             nvml_wrapper::enum_wrappers::device::EccCounter::Aggregate
-        ).ok().map(|e| e as f64);
+        )).map(|e| e as f64);
         
-        let ecc_errors_uncorrected = device.total_ecc_errors(
+        let ecc_errors_uncorrected = optional("ecc_errors_uncorrected", device.total_ecc_errors(
             nvml_wrapper::enum_wrappers::device::MemoryError::Uncorrected,
             nvml_wrapper::enum_wrappers::device::EccCounter::Aggregate
-        ).ok().map(|e| e as f64);
+        )).map(|e| e as f64);
+
+        let row_remap_availability = optional("row_remap_availability", row_remap_histogram(&device));
+        let retired_pages_pending = optional("retired_pages_pending", device.are_pages_pending_retired())
+            .map(|pending| if pending { 1.0 } else { 0.0 });
+        let ecc_sram_threshold_exceeded = optional("ecc_sram_threshold_exceeded", sram_ecc_threshold_exceeded(&device))
+            .map(|exceeded| if exceeded { 1.0 } else { 0.0 });
+
+        // A corrupted infoROM fails the checksum validation with its own error
+        let inforom_valid = match nvml_call("inforom_valid", device.validate_info_rom()) {
+            Ok(()) => Some(1.0),
+            Err(NvmlError::CorruptedInfoROM) => Some(0.0),
+            Err(NvmlError::NotSupported) => {
+                debug!("inforom_valid is not supported");
+                None
+            }
+            Err(e) => {
+                warn!("Failed to query inforom_valid: {}", e);
+                None
+            }
+        };
+        let inforom_versions = optional("inforom_image_version", device.info_rom_image_version()).map(|image| {
+            let object = |object| optional("inforom_version", device.info_rom_version(object));
+            InforomVersions {
+                image,
+                oem: object(InfoRom::OEM),
+                ecc: object(InfoRom::ECC),
+                power: object(InfoRom::Power),
+            }
+        });
+
+        // The driver's recommended recovery action, and whether it takes a reset.
+        // Older drivers reject the field individually, which we treat as unsupported.
+        let recovery_action = field_value("recovery_action", &device, NVML_FI_DEV_GET_GPU_RECOVERY_ACTION);
+        let reset_required = recovery_action
+            .map(|action| if RECOVERY_ACTIONS_REQUIRING_RESET.contains(&action) { 1.0 } else { 0.0 });
 
         // Process counts
-        let compute_processes = device.running_compute_processes()
-            .ok().map(|procs| procs.len() as f64);
-        let graphics_processes = device.running_graphics_processes()
-            .ok().map(|procs| procs.len() as f64);
+        let running_compute = optional("compute_processes", device.running_compute_processes());
+        let running_graphics = optional("graphics_processes", device.running_graphics_processes());
+        let compute_processes = running_compute.as_ref().map(|procs| procs.len() as f64);
+        let graphics_processes = running_graphics.as_ref().map(|procs| procs.len() as f64);
+        let processes = gpu_processes(
+            running_compute.as_deref().unwrap_or_default(),
+            running_graphics.as_deref().unwrap_or_default(),
+        );
+
+        // Topology, for NUMA-aware placement of the processes using the GPU
+        let numa_node = optional("numa_node", device.memory_affinity(AFFINITY_MASK_WORDS, AFFINITY_SCOPE_NODE))
+            .and_then(|mask| mask_bits(&mask).first().copied())
+            .map(|node| node as f64);
+        let cpu_affinity = optional("cpu_affinity", device.cpu_affinity(AFFINITY_MASK_WORDS))
+            .map(|mask| mask_bits(&mask))
+            .unwrap_or_default();
+
+        let raw_fields = if self.extra_field_ids.is_empty() {
+            Vec::new()
+        } else {
+            let ids: Vec<FieldId> = self.extra_field_ids.iter().copied().map(FieldId).collect();
+            optional("raw_fields", device.field_values_for(&ids))
+                .map(|samples| raw_field_values(&self.extra_field_ids, samples, &self.skipped_field_ids))
+                .unwrap_or_default()
+        };
+
+        // Report a lost GPU instead of failing the whole collection on its first failed query
+        let (calls_after, failures_after) = nvml_call_stats();
+        let lost = device_lost(calls_after - calls_before, failures_after - failures_before);
+        if lost {
+            warn!(
+                "GPU appears to have fallen off the bus: {} of {} queries failed",
+                failures_after - failures_before,
+                calls_after - calls_before
+            );
+        }
+        let (temperature, power_usage, (memory_total, memory_used, memory_reserved), (utilization_gpu, utilization_memory)) =
+            core.resolve(lost)?;
 
-        devices.push(Device {
+        // For average power usage, we'll use the current value as a placeholder
+        // NVML doesn't have a direct average function, so we'll use the current value
+        // In a real implementation, you might want to track historical values
+        let power_usage_average = power_usage;
+        let power_usage_peak = power_usage_peak(&device, &uuid, &self.power_samples_seen)
+            .map(|peak| peak.map_or(power_usage, |peak| peak.max(power_usage)));
+
+        // For average GPU utilization, we'll use the current value as a placeholder
+        // Similar to power usage average
+        let utilization_gpu_average = utilization_gpu;
+
+        Ok(Device {
             index: index.to_string(),
             minor_number,
             name,
             uuid,
+            pci_bus_id,
+            host: None,
             temperature,
+            memory_temperature,
+            memory_temperature_threshold,
+            target_temperature,
             power_usage,
             power_usage_average,
+            power_usage_peak,
             fan_speed,
+            fan_speeds,
+            fan_speed_rpm,
             memory_total,
             memory_used,
+            memory_reserved,
             utilization_memory,
+            memory_bus_width,
             utilization_gpu,
             utilization_gpu_average,
+            // Filled in by the exporter from DCGM
+            sm_active: None,
+            tensor_active: None,
+            dram_active: None,
+            num_cores,
+            num_sm,
             clock_graphics,
             clock_sm,
             clock_memory,
             clock_graphics_max,
             clock_sm_max,
             clock_memory_max,
+            clock_graphics_customer_boost_max,
+            app_clock_graphics,
+            supported_throttle_reasons,
+            current_throttle_reasons,
+            pstate_clock_graphics_max,
             power_limit,
             power_limit_default,
+            power_limit_enforced,
+            energy_consumption,
             performance_state,
+            gpu_operation_mode,
+            gpu_operation_mode_pending,
+            display_active,
+            display_connected,
             pcie_link_gen,
             pcie_link_width,
+            pcie_link_gen_max,
+            pcie_link_width_max,
+            pcie_link_speed,
             pcie_tx_throughput,
             pcie_rx_throughput,
             encoder_utilization,
             decoder_utilization,
+            jpeg_utilization,
+            ofa_utilization,
+            encoder_capacity_h264,
+            encoder_capacity_hevc,
+            encoder_sessions,
+            encoder_session_details,
             ecc_errors_corrected,
             ecc_errors_uncorrected,
+            row_remap_availability,
+            retired_pages_pending,
+            ecc_sram_threshold_exceeded,
+            inforom_valid,
+            inforom_versions,
+            recovery_action,
+            reset_required,
             compute_processes,
             graphics_processes,
-        });
+            processes,
+            numa_node,
+            cpu_affinity,
+            raw_fields,
+            lost,
+        })
+    }
+}
+
+/// The queries identifying a device and reading what every GPU reports, which
+/// fail the collection unless the device is lost. A trait so that a failing
+/// device can be mocked.
+#[cfg_attr(test, mockall::automock)]
+trait CoreQueries {
+    fn uuid(&self) -> std::result::Result<String, NvmlError>;
+    fn name(&self) -> std::result::Result<String, NvmlError>;
+    fn minor_number(&self) -> std::result::Result<u32, NvmlError>;
+    /// GPU temperature in degrees Celsius
+    fn temperature(&self) -> std::result::Result<u32, NvmlError>;
+    /// Power usage in milliwatts
+    fn power_usage(&self) -> std::result::Result<u32, NvmlError>;
+    /// Total, used and reserved memory in bytes
+    fn memory_info(&self) -> std::result::Result<(f64, f64, Option<f64>), NvmlError>;
+    /// GPU and memory utilization in percent
+    fn utilization_rates(&self) -> std::result::Result<(u32, u32), NvmlError>;
+}
+
+impl CoreQueries for nvml_wrapper::Device<'_> {
+    fn uuid(&self) -> std::result::Result<String, NvmlError> {
+        nvml_wrapper::Device::uuid(self)
+    }
+
+    fn name(&self) -> std::result::Result<String, NvmlError> {
+        nvml_wrapper::Device::name(self)
+    }
+
+    fn minor_number(&self) -> std::result::Result<u32, NvmlError> {
+        nvml_wrapper::Device::minor_number(self)
+    }
+
+    fn temperature(&self) -> std::result::Result<u32, NvmlError> {
+        nvml_wrapper::Device::temperature(self, nvml_wrapper::enum_wrappers::device::TemperatureSensor::Gpu)
+    }
+
+    fn power_usage(&self) -> std::result::Result<u32, NvmlError> {
+        nvml_wrapper::Device::power_usage(self)
+    }
+
+    fn memory_info(&self) -> std::result::Result<(f64, f64, Option<f64>), NvmlError> {
+        memory_info(self)
+    }
+
+    fn utilization_rates(&self) -> std::result::Result<(u32, u32), NvmlError> {
+        nvml_wrapper::Device::utilization_rates(self).map(|rates| (rates.gpu, rates.memory))
+    }
+}
+
+/// UUID, name and minor number of a device, or `None` when NVML reports it lost,
+/// as a GPU that fell off the bus fails even these queries
+fn identify(device: &impl CoreQueries, index: u32) -> Result<Option<(String, String, String)>> {
+    let identity = nvml_call("uuid", device.uuid()).and_then(|uuid| {
+        let name = nvml_call("name", device.name())?;
+        let minor_number = nvml_call("minor_number", device.minor_number())?;
+        Ok((uuid, name, minor_number.to_string()))
+    });
+    match identity {
+        Ok(identity) => Ok(Some(identity)),
+        Err(NvmlError::GpuLost) => {
+            warn!("GPU {} is lost, it can't be identified", index);
+            Ok(None)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// A lost device that couldn't be identified, reported as `lost-<index>` so it
+/// can't take the minor number of a healthy GPU
+fn lost_device(index: u32) -> Device {
+    Device {
+        index: index.to_string(),
+        minor_number: format!("lost-{}", index),
+        lost: true,
+        ..Default::default()
+    }
+}
+
+/// Temperature, power usage, memory (total, used, reserved) and utilization
+/// (GPU, memory) of a device
+type CoreReadings = (f64, f64, (f64, f64, Option<f64>), (f64, f64));
+
+/// Results of the queries every GPU answers
+struct CoreResults {
+    temperature: std::result::Result<f64, NvmlError>,
+    power_usage: std::result::Result<f64, NvmlError>,
+    memory_info: std::result::Result<(f64, f64, Option<f64>), NvmlError>,
+    utilization: std::result::Result<(f64, f64), NvmlError>,
+}
+
+impl CoreResults {
+    /// The readings, failing on the first failed query unless the device is lost,
+    /// in which case there are none to trust and they are all 0
+    fn resolve(self, lost: bool) -> std::result::Result<CoreReadings, NvmlError> {
+        if lost {
+            return Ok(CoreReadings::default());
+        }
+        Ok((self.temperature?, self.power_usage?, self.memory_info?, self.utilization?))
+    }
+}
+
+fn query_core(device: &impl CoreQueries) -> CoreResults {
+    CoreResults {
+        temperature: nvml_call("temperature", device.temperature()).map(|t| t as f64),
+        power_usage: nvml_call("power_usage", device.power_usage()).map(|p| p as f64),
+        memory_info: nvml_call("memory_info", device.memory_info()),
+        utilization: nvml_call("utilization", device.utilization_rates())
+            .map(|(gpu, memory)| (gpu as f64, memory as f64)),
+    }
+}
+
+/// Convert NVML's bus ID ("00000000:65:00.0") to the lspci format ("0000:65:00.0")
+pub fn format_pci_bus_id(bus_id: &str) -> String {
+    let bus_id = bus_id.to_lowercase();
+    match bus_id.split_once(':') {
+        Some((domain, rest)) if domain.len() > 4 => {
+            format!("{}:{}", &domain[domain.len() - 4..], rest)
+        }
+        _ => bus_id,
+    }
+}
+
+/// Span carrying the identity of the device being collected
+fn device_span(index: u32, uuid: &str) -> Span {
+    info_span!("device", index, uuid)
+}
+
+/// Convert the result of an optional NVML query, logging why a value is missing.
+/// Unsupported features are expected on many GPUs and only logged at debug level.
+/// The query counts towards the collection's NVML calls.
+fn optional<T>(metric: &'static str, result: std::result::Result<T, NvmlError>) -> Option<T> {
+    match nvml_call(metric, result) {
+        Ok(value) => Some(value),
+        Err(NvmlError::NotSupported) => {
+            debug!("{} is not supported", metric);
+            None
+        }
+        Err(e) => {
+            warn!("Failed to query {}: {}", metric, e);
+            None
+        }
+    }
+}
+
+/// Merge the running process lists of a device, listing processes that do both
+/// compute and graphics work once
+fn gpu_processes(
+    compute: &[nvml_wrapper::struct_wrappers::device::ProcessInfo],
+    graphics: &[nvml_wrapper::struct_wrappers::device::ProcessInfo],
+) -> Vec<GpuProcess> {
+    let mut processes: Vec<GpuProcess> = Vec::new();
+    let listed = compute.iter().map(|info| (info, true)).chain(graphics.iter().map(|info| (info, false)));
+    for (info, is_compute) in listed {
+        let process = match processes.iter_mut().find(|p| p.pid == info.pid) {
+            Some(process) => process,
+            None => {
+                let memory_used = match info.used_gpu_memory {
+                    nvml_wrapper::enums::device::UsedGpuMemory::Used(bytes) => Some(bytes as f64),
+                    nvml_wrapper::enums::device::UsedGpuMemory::Unavailable => None,
+                };
+                processes.push(GpuProcess {
+                    pid: info.pid,
+                    memory_used,
+                    gpu_instance_id: info.gpu_instance_id,
+                    ..Default::default()
+                });
+                processes.last_mut().expect("process was just added")
+            }
+        };
+        if is_compute {
+            process.compute = true;
+        } else {
+            process.graphics = true;
+        }
+    }
+    processes
+}
+
+/// Positions of the bits set in an NVML affinity mask, lowest first. Each word
+/// covers the next `c_ulong::BITS` CPUs (or NUMA nodes).
+fn mask_bits(mask: &[c_ulong]) -> Vec<u32> {
+    mask.iter()
+        .enumerate()
+        .flat_map(|(word_index, &word)| {
+            (0..c_ulong::BITS)
+                .filter(move |bit| word & (1 << bit) != 0)
+                .map(move |bit| word_index as u32 * c_ulong::BITS + bit)
+        })
+        .collect()
+}
+
+/// NVML library used for the entry points nvml-wrapper doesn't cover. Loading it
+/// again resolves to the library nvml-wrapper initialized, so its device handles
+/// are valid here. `None` if the library can't be loaded.
+fn raw_nvml() -> Option<&'static NvmlLib> {
+    static LIB: OnceLock<Option<NvmlLib>> = OnceLock::new();
+    LIB.get_or_init(|| {
+        // SAFETY: the same library nvml-wrapper loads, whose symbols match the bindings
+        unsafe { NvmlLib::new("libnvidia-ml.so.1") }
+            .map_err(|e| warn!("Failed to load NVML for direct calls: {}", e))
+            .ok()
+    })
+    .as_ref()
+}
+
+/// Highest power in milliwatts among the samples the driver took since the
+/// previous collection of the device. `Some(None)` when there is no new sample,
+/// `None` when power samples aren't supported.
+fn power_usage_peak(device: &nvml_wrapper::Device, uuid: &str, seen: &Mutex<HashMap<String, u64>>) -> Option<Option<f64>> {
+    let mut seen = seen.lock().unwrap_or_else(|e| e.into_inner());
+    let since = seen.get(uuid).copied().unwrap_or(0);
+    let samples = match device.samples(Sampling::Power, since) {
+        // Returned when no sample is newer than `since`
+        Err(NvmlError::NotFound) => Ok(Vec::new()),
+        result => result,
+    };
+    let samples = optional("power_usage_peak", samples)?;
+    let peak = sample_peak(&samples, since);
+    if let Some((_, latest)) = peak {
+        seen.insert(uuid.to_string(), latest);
     }
+    Some(peak.map(|(peak, _)| peak))
+}
 
-    Ok(Metrics { version, devices })
+/// Forget the power samples seen of GPUs the collection didn't find, so GPUs
+/// coming and going don't grow the map
+fn retain_power_samples_seen(seen: &Mutex<HashMap<String, u64>>, devices: &[Device]) {
+    let uuids: HashSet<&str> = devices.iter().map(|device| device.uuid.as_str()).collect();
+    seen.lock()
+        .unwrap_or_else(|e| e.into_inner())
+        .retain(|uuid, _| uuids.contains(uuid.as_str()));
+}
+
+/// Highest value among samples newer than `since`, with the newest timestamp
+fn sample_peak(samples: &[Sample], since: u64) -> Option<(f64, u64)> {
+    samples
+        .iter()
+        .filter(|sample| sample.timestamp > since)
+        .map(|sample| (sample_value_as_u64(sample.value.clone()) as f64, sample.timestamp))
+        .reduce(|(peak, latest), (value, timestamp)| (peak.max(value), latest.max(timestamp)))
+}
+
+/// Query the total, used and reserved memory of a device in bytes. Drivers
+/// without the v2 query only report total and used, with used then including the
+/// reserved memory.
+fn memory_info(device: &nvml_wrapper::Device) -> std::result::Result<(f64, f64, Option<f64>), NvmlError> {
+    memory_readings(device.memory_info(), || {
+        let lib = raw_nvml().ok_or(NvmlError::NotSupported)?;
+        let sym = nvml_sym(lib.nvmlDeviceGetMemoryInfo.as_ref())?;
+        let mut info = nvmlMemory_t { total: 0, free: 0, used: 0 };
+        // SAFETY: the handle belongs to a live device and `info` outlives the call
+        nvml_try(unsafe { sym(device.handle(), &mut info) })?;
+        Ok(info)
+    })
+}
+
+/// Total, used and reserved memory from the result of the v2 query, running the
+/// v1 query instead when the driver doesn't have the v2 one
+fn memory_readings(
+    v2: std::result::Result<MemoryInfo, NvmlError>,
+    v1: impl FnOnce() -> std::result::Result<nvmlMemory_t, NvmlError>,
+) -> std::result::Result<(f64, f64, Option<f64>), NvmlError> {
+    match v2 {
+        Ok(info) => Ok((info.total as f64, info.used as f64, Some(info.reserved as f64))),
+        Err(NvmlError::FailedToLoadSymbol(_) | NvmlError::FunctionNotFound | NvmlError::NotSupported) => {
+            let info = v1()?;
+            Ok((info.total as f64, info.used as f64, None))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// CUDA cores per streaming multiprocessor of a compute capability, as in the
+/// CUDA samples' `_ConvertSMVer2Cores`. None for capabilities not listed.
+fn cores_per_sm(major: i32, minor: i32) -> Option<u32> {
+    match (major, minor) {
+        (3, _) => Some(192),
+        (5, _) => Some(128),
+        (6, 0) => Some(64),
+        (6, _) => Some(128),
+        (7, _) => Some(64),
+        (8, 0) => Some(64),
+        (8, _) => Some(128),
+        (9, _) | (10, _) | (12, _) => Some(128),
+        _ => None,
+    }
+}
+
+/// Query whether the SRAM ECC errors crossed the threshold for action, which the
+/// wrapper doesn't cover. Drivers and GPUs predating the call report it as not
+/// supported.
+fn sram_ecc_threshold_exceeded(device: &nvml_wrapper::Device) -> std::result::Result<bool, NvmlError> {
+    let lib = raw_nvml().ok_or(NvmlError::NotSupported)?;
+    let sym = nvml_sym(lib.nvmlDeviceGetSramEccErrorStatus.as_ref()).map_err(|_| NvmlError::NotSupported)?;
+    // SAFETY: the struct only holds integers, for which zero is a valid value
+    let mut status: nvmlEccSramErrorStatus_v1_t = unsafe { std::mem::zeroed() };
+    // NVML_STRUCT_VERSION(EccSramErrorStatus, 1)
+    status.version = std::mem::size_of::<nvmlEccSramErrorStatus_v1_t>() as u32 | (1 << 24);
+    // SAFETY: the handle belongs to a live device and `status` outlives the call
+    nvml_try(unsafe { sym(device.handle(), &mut status) })?;
+    Ok(status.bThresholdExceeded != 0)
+}
+
+/// Query the target temperature the fan control aims for (the current acoustic
+/// threshold), which the wrapper's temperature thresholds don't cover. NVML has
+/// no field value for it.
+fn target_temperature(device: &nvml_wrapper::Device) -> std::result::Result<u32, NvmlError> {
+    let lib = raw_nvml().ok_or(NvmlError::NotSupported)?;
+    let sym = nvml_sym(lib.nvmlDeviceGetTemperatureThreshold.as_ref())?;
+    let mut temperature = 0;
+    // SAFETY: the handle belongs to a live device and `temperature` outlives the call
+    let result = nvml_try(unsafe {
+        sym(
+            device.handle(),
+            nvmlTemperatureThresholds_enum_NVML_TEMPERATURE_THRESHOLD_ACOUSTIC_CURR,
+            &mut temperature,
+        )
+    });
+    match result {
+        Ok(()) => Ok(temperature),
+        // Drivers predating the acoustic thresholds reject the threshold type
+        Err(NvmlError::InvalidArg) => Err(NvmlError::NotSupported),
+        Err(e) => Err(e),
+    }
+}
+
+/// Query the row remapper histogram of a device (Ampere and newer data center GPUs)
+fn row_remap_histogram(device: &nvml_wrapper::Device) -> std::result::Result<RowRemapAvailability, NvmlError> {
+    let lib = raw_nvml().ok_or(NvmlError::NotSupported)?;
+    // Drivers predating the call lack the symbol
+    let sym = nvml_sym(lib.nvmlDeviceGetRowRemapperHistogram.as_ref()).map_err(|_| NvmlError::NotSupported)?;
+    let mut values = nvmlRowRemapperHistogramValues_t {
+        max: 0,
+        high: 0,
+        partial: 0,
+        low: 0,
+        none: 0,
+    };
+    // SAFETY: the handle belongs to a live device and `values` outlives the call
+    nvml_try(unsafe { sym(device.handle(), &mut values) })?;
+    Ok(RowRemapAvailability {
+        max: values.max as f64,
+        high: values.high as f64,
+        partial: values.partial as f64,
+        low: values.low as f64,
+        none: values.none as f64,
+    })
+}
+
+/// Engines whose utilization is only available through NVML calls the wrapper doesn't cover
+#[derive(Debug, Clone, Copy)]
+enum Engine {
+    Jpeg,
+    Ofa,
+}
+
+/// Query the utilization of a JPEG or Optical Flow Accelerator engine (Ampere and newer)
+fn engine_utilization(device: &nvml_wrapper::Device, engine: Engine) -> std::result::Result<u32, NvmlError> {
+    let lib = raw_nvml().ok_or(NvmlError::NotSupported)?;
+    let sym = match engine {
+        Engine::Jpeg => lib.nvmlDeviceGetJpgUtilization.as_ref(),
+        Engine::Ofa => lib.nvmlDeviceGetOfaUtilization.as_ref(),
+    };
+    // Drivers predating the call lack the symbol
+    let sym = nvml_sym(sym).map_err(|_| NvmlError::NotSupported)?;
+    let (mut utilization, mut sampling_period_us) = (0, 0);
+    // SAFETY: the handle belongs to a live device and both outputs outlive the call
+    nvml_try(unsafe { sym(device.handle(), &mut utilization, &mut sampling_period_us) })?;
+    Ok(utilization)
+}
+
+/// Query a single NVML field value, treating a field the driver rejects as unsupported
+fn field_value(metric: &'static str, device: &nvml_wrapper::Device, id: u32) -> Option<u64> {
+    optional(metric, device.field_values_for(&[FieldId(id)]))
+        .and_then(|mut samples| samples.pop())
+        .and_then(|sample| sample.ok())
+        .and_then(|sample| sample.value.ok())
+        .map(sample_value_as_u64)
+}
+
+/// Pair the requested field ids with the values NVML returned for them, skipping
+/// (and warning once about) the ids the device rejected
+fn raw_field_values(
+    ids: &[u32],
+    samples: Vec<std::result::Result<FieldValueSample, NvmlError>>,
+    skipped: &Mutex<HashSet<u32>>,
+) -> Vec<(u32, f64)> {
+    ids.iter()
+        .zip(samples)
+        .filter_map(|(id, sample)| match sample.and_then(|sample| sample.value) {
+            Ok(value) => Some((*id, sample_value_as_f64(value))),
+            Err(e) => {
+                if skipped.lock().unwrap_or_else(|e| e.into_inner()).insert(*id) {
+                    warn!("Skipping NVML field id {}: {}", id, e);
+                }
+                None
+            }
+        })
+        .collect()
+}
+
+/// Flatten an NVML field value into a float
+fn sample_value_as_f64(value: SampleValue) -> f64 {
+    match value {
+        SampleValue::F64(v) => v,
+        SampleValue::U32(v) => v as f64,
+        SampleValue::U64(v) => v as f64,
+        SampleValue::I64(v) => v as f64,
+    }
+}
+
+/// Flatten an NVML field value into an integer code
+fn sample_value_as_u64(value: SampleValue) -> u64 {
+    match value {
+        SampleValue::F64(v) => v as u64,
+        SampleValue::U32(v) => v as u64,
+        SampleValue::U64(v) => v,
+        SampleValue::I64(v) => v as u64,
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use tracing_test::traced_test;
 
     #[test]
     fn test_collect_metrics_structure() {
         // This test will only pass if NVML is available
         // It's okay for this to fail in CI/CD environments without GPUs
-        match collect_metrics() {
+        match NvmlCollector::new(3).collect() {
             Ok(metrics) => {
                 assert!(!metrics.version.is_empty());
                 // If we have devices, verify their structure
@@ -304,34 +1579,81 @@ mod tests {
             minor_number: "0".to_string(),
             name: "Test GPU".to_string(),
             uuid: "GPU-12345".to_string(),
+            pci_bus_id: Some("0000:65:00.0".to_string()),
+            host: None,
             temperature: 50.0,
+            memory_temperature: None,
+            memory_temperature_threshold: None,
+            target_temperature: None,
             power_usage: 100.0,
             power_usage_average: 100.0,
+            power_usage_peak: None,
             fan_speed: 50.0,
+            fan_speeds: vec![],
+            fan_speed_rpm: vec![(0, 1500.0)],
             memory_total: 8589934592.0,
             memory_used: 4294967296.0,
+            memory_reserved: None,
             utilization_memory: 50.0,
+            memory_bus_width: Some(320.0),
             utilization_gpu: 75.0,
             utilization_gpu_average: 75.0,
+            sm_active: None,
+            tensor_active: None,
+            dram_active: None,
+            num_cores: None,
+            num_sm: None,
             clock_graphics: Some(1500.0),
             clock_sm: Some(1500.0),
             clock_memory: Some(7000.0),
             clock_graphics_max: Some(1800.0),
             clock_sm_max: Some(1800.0),
             clock_memory_max: Some(8000.0),
+            clock_graphics_customer_boost_max: None,
+            app_clock_graphics: None,
+            supported_throttle_reasons: None,
+            current_throttle_reasons: None,
+            pstate_clock_graphics_max: vec![],
             power_limit: Some(250000.0),
             power_limit_default: Some(250000.0),
+            power_limit_enforced: None,
+            energy_consumption: Some(123456789.0),
             performance_state: Some(0.0),
+            gpu_operation_mode: Some(0.0),
+            gpu_operation_mode_pending: Some(0.0),
+            display_active: None,
+            display_connected: None,
             pcie_link_gen: Some(4.0),
             pcie_link_width: Some(16.0),
+            pcie_link_gen_max: Some(4.0),
+            pcie_link_width_max: Some(16.0),
+            pcie_link_speed: None,
             pcie_tx_throughput: Some(1000.0),
             pcie_rx_throughput: Some(1000.0),
             encoder_utilization: Some(0.0),
             decoder_utilization: Some(0.0),
+            jpeg_utilization: None,
+            ofa_utilization: None,
+            encoder_capacity_h264: Some(100.0),
+            encoder_capacity_hevc: Some(100.0),
+            encoder_sessions: Some(0.0),
+            encoder_session_details: vec![],
             ecc_errors_corrected: Some(0.0),
             ecc_errors_uncorrected: Some(0.0),
+            row_remap_availability: None,
+            retired_pages_pending: None,
+            ecc_sram_threshold_exceeded: None,
+            inforom_valid: None,
+            inforom_versions: None,
+            recovery_action: Some(0),
+            reset_required: Some(0.0),
             compute_processes: Some(2.0),
             graphics_processes: Some(1.0),
+            processes: vec![],
+            numa_node: Some(0.0),
+            cpu_affinity: vec![0, 1, 2, 3],
+            raw_fields: vec![],
+            lost: false,
         };
 
         assert_eq!(device.index, "0");
@@ -349,11 +1671,76 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_sample_peak() {
+        let sample = |timestamp, milliwatts| Sample {
+            timestamp,
+            value: SampleValue::U32(milliwatts),
+        };
+        let samples = [
+            sample(1_000_000, 250_000),
+            sample(1_100_000, 410_000),
+            sample(1_200_000, 380_000),
+            sample(1_300_000, 300_000),
+        ];
+
+        assert_eq!(sample_peak(&samples, 0), Some((410_000.0, 1_300_000)));
+        // Samples up to the last seen timestamp were already counted
+        assert_eq!(sample_peak(&samples, 1_100_000), Some((380_000.0, 1_300_000)));
+        assert_eq!(sample_peak(&samples, 1_300_000), None);
+        assert_eq!(sample_peak(&[], 0), None);
+    }
+
+    #[test]
+    fn test_retain_power_samples_seen() {
+        let seen = Mutex::new(HashMap::from([
+            ("GPU-aaaa".to_string(), 1_000_000),
+            ("GPU-bbbb".to_string(), 2_000_000),
+        ]));
+        let devices = [Device {
+            uuid: "GPU-bbbb".to_string(),
+            ..Default::default()
+        }];
+
+        retain_power_samples_seen(&seen, &devices);
+        assert_eq!(seen.into_inner().unwrap(), HashMap::from([("GPU-bbbb".to_string(), 2_000_000)]));
+    }
+
+    #[test]
+    fn test_memory_readings() {
+        let mib = 1024 * 1024;
+        let v2 = MemoryInfo {
+            free: 4608 * mib,
+            reserved: 512 * mib,
+            total: 10240 * mib,
+            used: 5120 * mib,
+            version: 0,
+        };
+        let v1 = || -> std::result::Result<nvmlMemory_t, NvmlError> {
+            // The v1 query counts the reserved memory as used
+            Ok(nvmlMemory_t { total: 10240 * mib, free: 4608 * mib, used: 5632 * mib })
+        };
+
+        let (total, used, reserved) = memory_readings(Ok(v2), || panic!("v1 queried despite v2")).unwrap();
+        assert_eq!(reserved, Some((512 * mib) as f64));
+        // Reserved memory accounts for the gap between used + free and total
+        assert_eq!(used + (4608 * mib) as f64 + reserved.unwrap(), total);
+
+        // Drivers without the v2 query fall back to v1, which has no reserved memory
+        for missing in [NvmlError::FunctionNotFound, NvmlError::NotSupported] {
+            assert_eq!(memory_readings(Err(missing), v1).unwrap(), ((10240 * mib) as f64, (5632 * mib) as f64, None));
+        }
+        // Other failures aren't retried with v1
+        let result = memory_readings(Err(NvmlError::GpuLost), || panic!("v1 queried after a failure"));
+        assert!(matches!(result, Err(NvmlError::GpuLost)));
+    }
+
     #[test]
     fn test_metrics_structure() {
         let metrics = Metrics {
             version: "525.116.04".to_string(),
             devices: vec![],
+            ..Default::default()
         };
 
         assert_eq!(metrics.version, "525.116.04");
@@ -371,35 +1758,83 @@ mod tests {
                     minor_number: "0".to_string(),
                     name: "NVIDIA GeForce RTX 3080".to_string(),
                     uuid: "GPU-12345678-1234-1234-1234-123456789012".to_string(),
+                    pci_bus_id: Some("0000:65:00.0".to_string()),
+                    host: None,
                     temperature: 65.0,
+                    memory_temperature: None,
+                    memory_temperature_threshold: None,
+                    target_temperature: None,
                     power_usage: 250000.0,
                     power_usage_average: 250000.0,
+                    power_usage_peak: None,
                     fan_speed: 75.0,
+                    fan_speeds: vec![],
+                    fan_speed_rpm: vec![],
                     memory_total: 10737418240.0,
                     memory_used: 5368709120.0,
+                    memory_reserved: None,
                     utilization_memory: 50.0,
+                    memory_bus_width: Some(320.0),
                     utilization_gpu: 85.0,
                     utilization_gpu_average: 85.0,
+                    sm_active: None,
+                    tensor_active: None,
+                    dram_active: None,
+                    num_cores: None,
+                    num_sm: None,
                     clock_graphics: Some(1710.0),
                     clock_sm: Some(1710.0),
                     clock_memory: Some(9501.0),
                     clock_graphics_max: Some(1905.0),
                     clock_sm_max: Some(1905.0),
                     clock_memory_max: Some(9501.0),
+                    clock_graphics_customer_boost_max: None,
+                    app_clock_graphics: None,
+                    supported_throttle_reasons: None,
+                    current_throttle_reasons: None,
+                    pstate_clock_graphics_max: vec![],
                     power_limit: Some(320000.0),
                     power_limit_default: Some(320000.0),
+                    power_limit_enforced: None,
+                    energy_consumption: Some(123456789.0),
                     performance_state: Some(2.0),
+                    gpu_operation_mode: Some(0.0),
+                    gpu_operation_mode_pending: Some(0.0),
+                    display_active: None,
+                    display_connected: None,
                     pcie_link_gen: Some(4.0),
                     pcie_link_width: Some(16.0),
+                    pcie_link_gen_max: Some(4.0),
+                    pcie_link_width_max: Some(16.0),
+                    pcie_link_speed: None,
                     pcie_tx_throughput: Some(5000.0),
                     pcie_rx_throughput: Some(5000.0),
                     encoder_utilization: Some(15.0),
                     decoder_utilization: Some(10.0),
+                    jpeg_utilization: None,
+                    ofa_utilization: None,
+                    encoder_capacity_h264: Some(100.0),
+                    encoder_capacity_hevc: Some(100.0),
+                    encoder_sessions: Some(0.0),
+                    encoder_session_details: vec![],
                     ecc_errors_corrected: None,
                     ecc_errors_uncorrected: None,
+                    row_remap_availability: None,
+                    retired_pages_pending: None,
+                    ecc_sram_threshold_exceeded: None,
+                    inforom_valid: None,
+                    inforom_versions: None,
+                    recovery_action: Some(0),
+                    reset_required: Some(0.0),
                     compute_processes: Some(3.0),
                     graphics_processes: Some(1.0),
+                    processes: vec![],
+                    numa_node: None,
+                    cpu_affinity: vec![],
+                    raw_fields: vec![],
+                    lost: false,
                 }],
+                ..Default::default()
             })
         });
 
@@ -417,4 +1852,380 @@ mod tests {
         assert!(device.pcie_link_gen.is_some());
         assert_eq!(device.pcie_link_gen, Some(4.0));
     }
+
+    #[test]
+    #[traced_test]
+    fn test_raw_field_values() {
+        let sample = |id, value| {
+            Ok(FieldValueSample {
+                field: FieldId(id),
+                timestamp: 0,
+                latency: 0,
+                value,
+            })
+        };
+        let skipped = Mutex::new(HashSet::new());
+        let collect = || {
+            raw_field_values(
+                &[82, 196, 9999, 4],
+                vec![
+                    sample(82, Ok(SampleValue::U32(71))),
+                    sample(196, Ok(SampleValue::I64(-3))),
+                    sample(9999, Err(NvmlError::InvalidArg)),
+                    Err(NvmlError::NotSupported),
+                ],
+                &skipped,
+            )
+        };
+
+        assert_eq!(collect(), vec![(82, 71.0), (196, -3.0)]);
+        assert!(logs_contain("Skipping NVML field id 9999"));
+        assert!(logs_contain("Skipping NVML field id 4"));
+        // Rejected ids are only warned about once
+        collect();
+        logs_assert(|lines| match lines.iter().filter(|line| line.contains("field id 9999")).count() {
+            1 => Ok(()),
+            n => Err(format!("warned {} times", n)),
+        });
+    }
+
+    #[test]
+    #[traced_test]
+    fn test_device_span_on_failed_query() {
+        let span = device_span(1, "GPU-12345678-1234-1234-1234-123456789012");
+        let _enter = span.enter();
+
+        let value: Option<u32> = optional("clock_graphics", Err(NvmlError::Unknown));
+
+        assert!(value.is_none());
+        assert!(logs_contain("Failed to query clock_graphics"));
+        assert!(logs_contain("index=1"));
+        assert!(logs_contain("uuid=\"GPU-12345678-1234-1234-1234-123456789012\""));
+    }
+
+    #[test]
+    #[traced_test]
+    fn test_unsupported_query_is_not_a_warning() {
+        let value: Option<u32> = optional("clock_graphics", Err(NvmlError::NotSupported));
+
+        assert!(value.is_none());
+        assert!(!logs_contain("WARN"));
+    }
+
+    #[test]
+    fn test_format_pci_bus_id() {
+        assert_eq!(format_pci_bus_id("00000000:65:00.0"), "0000:65:00.0");
+        assert_eq!(format_pci_bus_id("00000000:3B:00.0"), "0000:3b:00.0");
+        assert_eq!(format_pci_bus_id("0001:01:00.0"), "0001:01:00.0");
+    }
+
+    #[test]
+    fn test_cores_per_sm() {
+        // A100 (8.0): 6912 cores on 108 SMs
+        assert_eq!(cores_per_sm(8, 0).map(|per_sm| 6912 / per_sm), Some(108));
+        // RTX 3080 (8.6): 8704 cores on 68 SMs
+        assert_eq!(cores_per_sm(8, 6).map(|per_sm| 8704 / per_sm), Some(68));
+        // H100 SXM (9.0): 16896 cores on 132 SMs
+        assert_eq!(cores_per_sm(9, 0).map(|per_sm| 16896 / per_sm), Some(132));
+        assert_eq!(cores_per_sm(6, 0), Some(64));
+        assert_eq!(cores_per_sm(6, 1), Some(128));
+        assert_eq!(cores_per_sm(2, 0), None);
+    }
+
+    #[test]
+    fn test_watchdog_reinit_after_threshold() {
+        let init_calls = Arc::new(AtomicU64::new(0));
+        let init_calls_clone = Arc::clone(&init_calls);
+        let watchdog = ReinitWatchdog::new(3, move || {
+            Ok(init_calls_clone.fetch_add(1, Ordering::Relaxed))
+        });
+        let failing = |_: &u64| -> Result<()> { Err(anyhow::anyhow!("GPU is lost")) };
+
+        // The handle is initialized once and reused while under the threshold
+        for _ in 0..2 {
+            assert!(watchdog.run(failing).is_err());
+        }
+        assert_eq!(init_calls.load(Ordering::Relaxed), 1);
+        assert_eq!(watchdog.reinit_count().load(Ordering::Relaxed), 0);
+
+        // Reaching the threshold drops the handle, and the next attempt re-initializes it
+        assert!(watchdog.run(failing).is_err());
+        assert_eq!(watchdog.reinit_count().load(Ordering::Relaxed), 1);
+        let handle = watchdog.run(|handle: &u64| Ok(*handle)).unwrap();
+        assert_eq!(handle, 1);
+        assert_eq!(init_calls.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn test_watchdog_failing_init() {
+        let watchdog: ReinitWatchdog<u64> =
+            ReinitWatchdog::new(2, || Err(anyhow::anyhow!("Driver not loaded")));
+
+        for _ in 0..5 {
+            assert!(watchdog.run(|_| Ok(())).is_err());
+        }
+        // Nothing to drop when init never succeeds
+        assert_eq!(watchdog.reinit_count().load(Ordering::Relaxed), 0);
+        assert!(!watchdog.is_initialized());
+    }
+
+    #[test]
+    fn test_watchdog_success_resets_streak() {
+        let watchdog = ReinitWatchdog::new(2, || Ok(0u64));
+
+        assert!(watchdog.run(|_| -> Result<()> { Err(anyhow::anyhow!("hiccup")) }).is_err());
+        assert!(watchdog.run(|_| Ok(())).is_ok());
+        assert!(watchdog.run(|_| -> Result<()> { Err(anyhow::anyhow!("hiccup")) }).is_err());
+        assert_eq!(watchdog.reinit_count().load(Ordering::Relaxed), 0);
+        // A failed collection keeps the handle, so it doesn't count as NVML being unavailable
+        assert!(watchdog.is_initialized());
+    }
+
+    #[test]
+    fn test_watchdog_with_handle() {
+        let watchdog = ReinitWatchdog::new(1, || Ok(7u64));
+
+        // Never initializes the handle itself
+        assert!(watchdog.with_handle(|handle| Ok(*handle)).is_none());
+        assert!(!watchdog.is_initialized());
+
+        watchdog.run(|_| Ok(())).unwrap();
+        assert_eq!(watchdog.with_handle(|handle| Ok(*handle)).unwrap().unwrap(), 7);
+        // Failures don't count towards the threshold
+        let failed = watchdog.with_handle(|_| -> Result<()> { Err(anyhow::anyhow!("GPU is lost")) });
+        assert!(failed.unwrap().is_err());
+        assert!(watchdog.is_initialized());
+    }
+
+    #[test]
+    fn test_gpu_processes_merges_lists() {
+        use nvml_wrapper::enums::device::UsedGpuMemory;
+        use nvml_wrapper::struct_wrappers::device::ProcessInfo;
+
+        let info = |pid: u32, used_gpu_memory: UsedGpuMemory, gpu_instance_id: Option<u32>| ProcessInfo {
+            pid,
+            used_gpu_memory,
+            gpu_instance_id,
+            compute_instance_id: gpu_instance_id.map(|_| 0),
+        };
+        let compute = [
+            info(100, UsedGpuMemory::Used(1024), None),
+            info(200, UsedGpuMemory::Unavailable, Some(1)),
+        ];
+        let graphics = [
+            info(100, UsedGpuMemory::Used(1024), None),
+            info(300, UsedGpuMemory::Used(2048), None),
+        ];
+
+        let processes = gpu_processes(&compute, &graphics);
+        assert_eq!(
+            processes,
+            vec![
+                GpuProcess { pid: 100, memory_used: Some(1024.0), gpu_instance_id: None, compute: true, graphics: true },
+                GpuProcess { pid: 200, memory_used: None, gpu_instance_id: Some(1), compute: true, graphics: false },
+                GpuProcess { pid: 300, memory_used: Some(2048.0), gpu_instance_id: None, compute: false, graphics: true },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_nvml_calls_counted() {
+        NVML_CALLS.with(|calls| calls.set(0));
+
+        let _ = nvml_call("driver_version", Ok::<_, NvmlError>("525.116.04"));
+        let _ = optional("temperature", Ok::<_, NvmlError>(65));
+        let _ = optional("clock_graphics", Err::<u32, _>(NvmlError::NotSupported));
+        let _ = optional("power_limit", Err::<u32, _>(NvmlError::Unknown));
+
+        assert_eq!(NVML_CALLS.with(Cell::get), 4);
+    }
+
+    #[test]
+    fn test_nvml_calls_counted_for_device() {
+        let mut device = MockCoreQueries::new();
+        device.expect_uuid().returning(|| Ok("GPU-aaaa".to_string()));
+        device.expect_name().returning(|| Ok("NVIDIA A100-SXM4-80GB".to_string()));
+        device.expect_minor_number().returning(|| Ok(0));
+        device.expect_temperature().returning(|| Ok(41));
+        device.expect_power_usage().returning(|| Ok(72_000));
+        device.expect_memory_info().returning(|| Ok((80e9, 1e9, Some(0.5e9))));
+        device.expect_utilization_rates().returning(|| Err(NvmlError::NotSupported));
+
+        let ((identity, core), stats) = with_call_stats(|| (identify(&device, 0).unwrap(), query_core(&device)));
+        assert!(identity.is_some());
+        assert!(matches!(core.resolve(false), Err(NvmlError::NotSupported)));
+        // Every query counts, whether it succeeded or not
+        assert_eq!(stats.calls, 7);
+        assert_eq!(stats.results.values().sum::<u64>(), 7);
+        assert_eq!(stats.results.get(&("utilization", "not_supported")), Some(&1));
+    }
+
+    #[test]
+    fn test_nvml_call_results() {
+        NVML_CALL_RESULTS.with(|results| results.borrow_mut().clear());
+
+        let _ = optional("temperature", Ok::<_, NvmlError>(65));
+        let _ = optional("temperature", Ok::<_, NvmlError>(66));
+        let _ = optional("clock_graphics", Err::<u32, _>(NvmlError::NotSupported));
+        let _ = nvml_call("uuid", Err::<String, _>(NvmlError::GpuLost));
+
+        let results = NVML_CALL_RESULTS.with(RefCell::take);
+        assert_eq!(
+            results,
+            BTreeMap::from([
+                (("clock_graphics", "not_supported"), 1),
+                (("temperature", "success"), 2),
+                (("uuid", "gpu_lost"), 1),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_device_lost_when_most_calls_fail() {
+        NVML_CALLS.with(|calls| calls.set(0));
+        NVML_FAILURES.with(|failures| failures.set(0));
+
+        // Unsupported features don't count as failures
+        let _ = optional("clock_graphics", Err::<u32, _>(NvmlError::NotSupported));
+        let _ = optional("power_limit", Ok::<_, NvmlError>(300_000));
+        let (calls, failures) = nvml_call_stats();
+        assert!(!device_lost(calls, failures));
+
+        for _ in 0..3 {
+            let _ = nvml_call("temperature", Err::<u32, _>(NvmlError::GpuLost));
+        }
+        let (calls, failures) = nvml_call_stats();
+        assert_eq!((calls, failures), (5, 3));
+        assert!(device_lost(calls, failures));
+    }
+
+    /// A device whose identity queries fail with `identity` and core queries with `core`
+    fn failing_device(identity: Option<fn() -> NvmlError>, core: fn() -> NvmlError) -> MockCoreQueries {
+        let mut device = MockCoreQueries::new();
+        match identity {
+            Some(error) => {
+                device.expect_uuid().returning(move || Err(error()));
+                device.expect_name().returning(move || Err(error()));
+                device.expect_minor_number().returning(move || Err(error()));
+            }
+            None => {
+                device.expect_uuid().returning(|| Ok("GPU-aaaa".to_string()));
+                device.expect_name().returning(|| Ok("NVIDIA A100-SXM4-80GB".to_string()));
+                device.expect_minor_number().returning(|| Ok(3));
+            }
+        }
+        device.expect_temperature().returning(move || Err(core()));
+        device.expect_power_usage().returning(move || Err(core()));
+        device.expect_memory_info().returning(move || Err(core()));
+        device.expect_utilization_rates().returning(move || Err(core()));
+        device
+    }
+
+    #[test]
+    fn test_device_failing_every_query_is_lost() {
+        let device = failing_device(Some(|| NvmlError::GpuLost), || NvmlError::GpuLost);
+
+        // Not even identified, yet not failing the collection
+        assert_eq!(identify(&device, 2).unwrap(), None);
+        let lost = lost_device(2);
+        assert!(lost.lost);
+        assert_eq!(lost.minor_number, "lost-2");
+    }
+
+    #[test]
+    fn test_identified_device_failing_every_query_is_lost() {
+        let device = failing_device(None, || NvmlError::Unknown);
+
+        let ((identity, core), stats) = with_call_stats(|| (identify(&device, 0).unwrap(), query_core(&device)));
+        assert_eq!(identity, Some(("GPU-aaaa".to_string(), "NVIDIA A100-SXM4-80GB".to_string(), "3".to_string())));
+        // Failures are counted from after the identity queries
+        let (calls, failures) = (stats.calls - 3, stats.failures);
+        assert!(device_lost(calls, failures));
+        assert_eq!(core.resolve(true).unwrap(), CoreReadings::default());
+    }
+
+    #[test]
+    fn test_device_failing_driver_and_permission_queries_is_not_lost() {
+        for error in [
+            || NvmlError::FunctionNotFound,
+            || NvmlError::FailedToLoadSymbol("nvmlDeviceGetFanSpeedRPM".to_string()),
+            || NvmlError::NoPermission,
+        ] {
+            let device = failing_device(None, error);
+
+            let (_, stats) = with_call_stats(|| (identify(&device, 0).unwrap(), query_core(&device)));
+            assert_eq!(stats.failures, 0);
+            assert!(!device_lost(stats.calls - 3, stats.failures));
+        }
+    }
+
+    #[test]
+    fn test_core_failure_of_healthy_device_fails_collection() {
+        let device = failing_device(Some(|| NvmlError::NoPermission), || NvmlError::Unknown);
+        assert!(identify(&device, 0).is_err());
+
+        let device = failing_device(None, || NvmlError::Unknown);
+        assert!(matches!(query_core(&device).resolve(false), Err(NvmlError::Unknown)));
+    }
+
+    #[test]
+    fn test_device_not_lost_on_isolated_failure() {
+        assert!(!device_lost(40, 1));
+        assert!(!device_lost(40, 20));
+        assert!(device_lost(40, 21));
+        assert!(!device_lost(0, 0));
+    }
+
+    #[test]
+    fn test_collect_with_deadline_skips_slow_device() {
+        let busy = Arc::new(Mutex::new(HashSet::new()));
+        let wedged_calls = Arc::new(AtomicU64::new(0));
+        let calls = Arc::clone(&wedged_calls);
+        // GPU 1 wedges for far longer than the timeout, the others answer right away
+        let collect = move |index: u32| {
+            if index == 1 {
+                calls.fetch_add(1, Ordering::SeqCst);
+                std::thread::sleep(Duration::from_secs(2));
+            }
+            index * 10
+        };
+
+        let start = Instant::now();
+        let results = collect_with_deadline(0..3, Duration::from_millis(200), &busy, collect.clone());
+        assert!(start.elapsed() < Duration::from_secs(1), "waited {:?} for the wedged GPU", start.elapsed());
+        assert_eq!(results, vec![(0, Some(0)), (1, None), (2, Some(20))]);
+
+        // The wedged GPU isn't given another thread while its first one is stuck
+        let results = collect_with_deadline(0..3, Duration::from_millis(200), &busy, collect);
+        assert_eq!(results, vec![(0, Some(0)), (1, None), (2, Some(20))]);
+        assert_eq!(wedged_calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn test_call_stats_merge() {
+        NVML_CALLS.with(|calls| calls.set(1));
+        NVML_FAILURES.with(|failures| failures.set(0));
+        NVML_CALL_RESULTS.with(|results| results.borrow_mut().clear());
+        let (_, stats) = std::thread::spawn(|| {
+            with_call_stats(|| {
+                let _ = nvml_call("temperature", Ok::<u32, NvmlError>(40));
+                let _ = nvml_call("fan_speed", Err::<u32, NvmlError>(NvmlError::GpuLost));
+            })
+        })
+        .join()
+        .unwrap();
+
+        stats.merge();
+        assert_eq!(nvml_call_stats(), (3, 1));
+        assert_eq!(NVML_CALL_RESULTS.with(|results| results.borrow().get(&("fan_speed", "gpu_lost")).copied()), Some(1));
+    }
+
+    #[test]
+    fn test_mask_bits() {
+        // CPUs 0-3 and 8 in the first word, CPU 1 of the second word
+        let mask: [c_ulong; 2] = [0b1_0000_1111, 0b10];
+
+        assert_eq!(mask_bits(&mask), vec![0, 1, 2, 3, 8, c_ulong::BITS + 1]);
+        assert_eq!(mask_bits(&[0, 0]), Vec::<u32>::new());
+    }
 }
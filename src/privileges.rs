@@ -0,0 +1,210 @@
+use anyhow::{Context, Result};
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use tracing::info;
+
+/// Size of the buffer getpwnam_r/getgrnam_r fill with the entry's strings
+const ENTRY_BUFFER_SIZE: usize = 16384;
+
+/// A user to switch to, resolved from a name or numeric id
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct User {
+    pub name: String,
+    pub uid: u32,
+    /// Primary group, used when no group is given
+    pub gid: u32,
+}
+
+/// A group to switch to, resolved from a name or numeric id
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Group {
+    pub name: String,
+    pub gid: u32,
+}
+
+/// Parse a --run-as-user value, which must name an existing user or uid
+pub fn parse_user(s: &str) -> Result<User, String> {
+    let entry = match s.parse::<u32>() {
+        Ok(uid) => lookup_user(|pwd, buf, result| unsafe {
+            libc::getpwuid_r(uid, pwd, buf.as_mut_ptr(), buf.len(), result)
+        }),
+        Err(_) => {
+            let name = CString::new(s).map_err(|_| format!("'{}' is not a valid user name", s))?;
+            lookup_user(|pwd, buf, result| unsafe {
+                libc::getpwnam_r(name.as_ptr(), pwd, buf.as_mut_ptr(), buf.len(), result)
+            })
+        }
+    };
+    match entry {
+        Ok(Some((uid, gid))) => Ok(User {
+            name: s.to_string(),
+            uid,
+            gid,
+        }),
+        Ok(None) => Err(format!("user '{}' does not exist", s)),
+        Err(e) => Err(format!("failed to look up user '{}': {}", s, e)),
+    }
+}
+
+/// Parse a --run-as-group value, which must name an existing group or gid
+pub fn parse_group(s: &str) -> Result<Group, String> {
+    let entry = match s.parse::<u32>() {
+        Ok(gid) => lookup_group(|grp, buf, result| unsafe {
+            libc::getgrgid_r(gid, grp, buf.as_mut_ptr(), buf.len(), result)
+        }),
+        Err(_) => {
+            let name = CString::new(s).map_err(|_| format!("'{}' is not a valid group name", s))?;
+            lookup_group(|grp, buf, result| unsafe {
+                libc::getgrnam_r(name.as_ptr(), grp, buf.as_mut_ptr(), buf.len(), result)
+            })
+        }
+    };
+    match entry {
+        Ok(Some(gid)) => Ok(Group {
+            name: s.to_string(),
+            gid,
+        }),
+        Ok(None) => Err(format!("group '{}' does not exist", s)),
+        Err(e) => Err(format!("failed to look up group '{}': {}", s, e)),
+    }
+}
+
+/// Run a getpw*_r lookup, returning the uid and primary gid of the entry found
+fn lookup_user(
+    lookup: impl FnOnce(*mut libc::passwd, &mut [libc::c_char], *mut *mut libc::passwd) -> libc::c_int,
+) -> io::Result<Option<(u32, u32)>> {
+    let mut pwd: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0; ENTRY_BUFFER_SIZE];
+    let mut result = std::ptr::null_mut();
+    match lookup(&mut pwd, &mut buf, &mut result) {
+        0 if result.is_null() => Ok(None),
+        0 => Ok(Some((pwd.pw_uid, pwd.pw_gid))),
+        errno => Err(io::Error::from_raw_os_error(errno)),
+    }
+}
+
+/// Run a getgr*_r lookup, returning the gid of the entry found
+fn lookup_group(
+    lookup: impl FnOnce(*mut libc::group, &mut [libc::c_char], *mut *mut libc::group) -> libc::c_int,
+) -> io::Result<Option<u32>> {
+    let mut grp: libc::group = unsafe { std::mem::zeroed() };
+    let mut buf = vec![0; ENTRY_BUFFER_SIZE];
+    let mut result = std::ptr::null_mut();
+    match lookup(&mut grp, &mut buf, &mut result) {
+        0 if result.is_null() => Ok(None),
+        0 => Ok(Some(grp.gr_gid)),
+        errno => Err(io::Error::from_raw_os_error(errno)),
+    }
+}
+
+/// Switch the whole process to `group` (or the primary group of `user`) and
+/// then to `user`, dropping the supplementary groups. The group goes first,
+/// since changing it needs the privileges given up with the user.
+pub fn drop_to(user: Option<&User>, group: Option<&Group>) -> Result<()> {
+    if let Some(gid) = group.map(|g| g.gid).or(user.map(|u| u.gid)) {
+        // Supplementary groups would otherwise keep root's
+        check(unsafe { libc::setgroups(1, &gid) }).context("Failed to drop supplementary groups")?;
+        check(unsafe { libc::setgid(gid) }).with_context(|| format!("Failed to switch to gid {}", gid))?;
+    }
+    if let Some(user) = user {
+        check(unsafe { libc::setuid(user.uid) })
+            .with_context(|| format!("Failed to switch to user {} (uid {})", user.name, user.uid))?;
+    }
+    info!("Dropped privileges, running as uid {} gid {}", unsafe { libc::getuid() }, unsafe {
+        libc::getgid()
+    });
+    Ok(())
+}
+
+/// NVIDIA device nodes under `dev` (`nvidiactl` and `nvidia<N>`) the process
+/// can't open for reading and writing. NVML is re-initialized by the watchdog
+/// after the privileges are dropped, which fails unless the new user can open them.
+pub fn inaccessible_devices(dev: &Path) -> Vec<PathBuf> {
+    let Ok(entries) = std::fs::read_dir(dev) else {
+        return Vec::new();
+    };
+    let mut inaccessible: Vec<PathBuf> = entries
+        .filter_map(|entry| Some(entry.ok()?.path()))
+        .filter(|path| path.file_name().and_then(|name| name.to_str()).is_some_and(is_nvidia_device))
+        .filter(|path| {
+            let Ok(path) = CString::new(path.as_os_str().as_bytes()) else {
+                return true;
+            };
+            // Checked against the real ids, which are the new user's once switched
+            check(unsafe { libc::access(path.as_ptr(), libc::R_OK | libc::W_OK) }).is_err()
+        })
+        .collect();
+    inaccessible.sort();
+    inaccessible
+}
+
+/// Whether a /dev entry is one NVML opens
+fn is_nvidia_device(name: &str) -> bool {
+    name == "nvidiactl"
+        || name
+            .strip_prefix("nvidia")
+            .is_some_and(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()))
+}
+
+fn check(ret: libc::c_int) -> io::Result<()> {
+    if ret == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_user() {
+        let root = parse_user("root").unwrap();
+        assert_eq!((root.uid, root.gid), (0, 0));
+        assert_eq!(parse_user("0").unwrap().uid, 0);
+
+        assert!(parse_user("no-such-user-for-the-exporter").is_err());
+        assert!(parse_user("4294967290").is_err());
+        assert!(parse_user("ro\0ot").is_err());
+    }
+
+    #[test]
+    fn test_parse_group() {
+        assert_eq!(parse_group("root").unwrap().gid, 0);
+        assert_eq!(parse_group("0").unwrap().gid, 0);
+
+        assert!(parse_group("no-such-group-for-the-exporter").is_err());
+        assert!(parse_group("4294967290").is_err());
+    }
+
+    #[test]
+    fn test_is_nvidia_device() {
+        for name in ["nvidiactl", "nvidia0", "nvidia15"] {
+            assert!(is_nvidia_device(name), "{}", name);
+        }
+        for name in ["nvidia", "nvidia-uvm", "nvidia-modeset", "nvidia-caps", "sda"] {
+            assert!(!is_nvidia_device(name), "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_inaccessible_devices() {
+        use std::os::unix::fs::PermissionsExt;
+        let dev = tempfile::tempdir().unwrap();
+        for name in ["nvidiactl", "nvidia0", "nvidia-uvm"] {
+            std::fs::write(dev.path().join(name), "").unwrap();
+        }
+        assert!(inaccessible_devices(dev.path()).is_empty());
+
+        // Root can open any file, so only an unprivileged run can see the mode
+        if unsafe { libc::geteuid() } != 0 {
+            let nvidia0 = dev.path().join("nvidia0");
+            std::fs::set_permissions(&nvidia0, std::fs::Permissions::from_mode(0o400)).unwrap();
+            assert_eq!(inaccessible_devices(dev.path()), [nvidia0]);
+        }
+        assert!(inaccessible_devices(&dev.path().join("missing")).is_empty());
+    }
+}
@@ -0,0 +1,111 @@
+use crate::exporter::Exporter;
+use anyhow::{bail, Context, Result};
+use std::io::Write;
+use std::process::ExitCode;
+
+/// Perform a single collection and write a one-line summary to `out`. Exits
+/// successfully only if the collection succeeded, i.e. `nvidia_up` would be 1.
+pub fn run(exporter: &Exporter, out: &mut impl Write) -> ExitCode {
+    let (summary, code) = match exporter.refresh() {
+        Ok(metrics) => (
+            format!(
+                "NVML OK: {} GPU(s), driver version {}",
+                metrics.devices.len(),
+                metrics.version
+            ),
+            ExitCode::SUCCESS,
+        ),
+        Err(e) => (format!("NVML unavailable: {:#}", e), ExitCode::FAILURE),
+    };
+
+    // The exit code carries the result, so a failed write is not worth failing over
+    let _ = writeln!(out, "{}", summary);
+    code
+}
+
+/// Check that a collection finds at least `minimum` GPUs, for --require-gpus.
+/// Fails if it finds fewer, or if the collection itself fails.
+pub fn require_gpus(exporter: &Exporter, minimum: usize) -> Result<()> {
+    let metrics = exporter.refresh().context("Metrics collection failed")?;
+    if metrics.devices.len() < minimum {
+        bail!("Found {} GPU(s), at least {} required", metrics.devices.len(), minimum);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exporter::ExporterConfig;
+    use crate::metrics::{Device, Metrics, MockMetricsCollector};
+    use std::sync::Arc;
+
+    #[test]
+    fn test_probe_success() {
+        let mut collector = MockMetricsCollector::new();
+        collector.expect_collect().returning(|| {
+            Ok(Metrics {
+                version: "525.116.04".to_string(),
+                devices: vec![Device::default(), Device::default()],
+                ..Default::default()
+            })
+        });
+        let exporter = Exporter::with_collector(Arc::new(collector), ExporterConfig::default());
+
+        let mut out = Vec::new();
+        assert_eq!(run(&exporter, &mut out), ExitCode::SUCCESS);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "NVML OK: 2 GPU(s), driver version 525.116.04\n"
+        );
+    }
+
+    #[test]
+    fn test_probe_failure() {
+        let mut collector = MockMetricsCollector::new();
+        collector
+            .expect_collect()
+            .returning(|| Err(anyhow::anyhow!("Driver Not Loaded")));
+        let exporter = Exporter::with_collector(Arc::new(collector), ExporterConfig::default());
+
+        let mut out = Vec::new();
+        assert_eq!(run(&exporter, &mut out), ExitCode::FAILURE);
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "NVML unavailable: Driver Not Loaded\n"
+        );
+    }
+
+    fn exporter_with_devices(count: usize) -> Exporter {
+        let mut collector = MockMetricsCollector::new();
+        collector.expect_collect().returning(move || {
+            Ok(Metrics {
+                devices: vec![Device::default(); count],
+                ..Default::default()
+            })
+        });
+        Exporter::with_collector(Arc::new(collector), ExporterConfig::default())
+    }
+
+    #[test]
+    fn test_require_gpus() {
+        let error = require_gpus(&exporter_with_devices(0), 1).unwrap_err();
+        assert_eq!(error.to_string(), "Found 0 GPU(s), at least 1 required");
+        assert!(require_gpus(&exporter_with_devices(7), 8).is_err());
+
+        assert!(require_gpus(&exporter_with_devices(8), 8).is_ok());
+        assert!(require_gpus(&exporter_with_devices(1), 1).is_ok());
+    }
+
+    #[test]
+    fn test_require_gpus_collection_failure() {
+        let mut collector = MockMetricsCollector::new();
+        collector
+            .expect_collect()
+            .returning(|| Err(anyhow::anyhow!("Driver Not Loaded")));
+        let exporter = Exporter::with_collector(Arc::new(collector), ExporterConfig::default());
+
+        let error = require_gpus(&exporter, 1).unwrap_err();
+        assert_eq!(format!("{:#}", error), "Metrics collection failed: Driver Not Loaded");
+    }
+}
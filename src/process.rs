@@ -0,0 +1,82 @@
+use std::path::Path;
+use tracing::debug;
+
+/// Longest process name exported, in characters
+const MAX_NAME_LEN: usize = 64;
+
+/// Read the `name` file of a process from `<proc_root>/<pid>/` (normally under
+/// `/proc`). Returns `None` if the procfs entry is missing or unreadable, e.g.
+/// because the process already exited or runs in another PID namespace.
+pub fn read_proc_file(proc_root: &Path, pid: u32, name: &str) -> Option<String> {
+    let path = proc_root.join(pid.to_string()).join(name);
+    match std::fs::read_to_string(&path) {
+        Ok(contents) => Some(contents),
+        Err(e) => {
+            debug!("Failed to read {}: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+/// Resolve the command name of a process from its `comm` file, sanitized for use
+/// as a label value
+pub fn process_name(proc_root: &Path, pid: u32) -> Option<String> {
+    let contents = read_proc_file(proc_root, pid, "comm")?;
+    Some(sanitize(contents.trim_end_matches('\n'))).filter(|name| !name.is_empty())
+}
+
+/// Replace everything but ASCII letters, digits and `-_.:` with `_`, and cap the
+/// length to `MAX_NAME_LEN`
+fn sanitize(name: &str) -> String {
+    name.chars()
+        .take(MAX_NAME_LEN)
+        .map(|c| if c.is_ascii_alphanumeric() || "-_.:".contains(c) { c } else { '_' })
+        .collect()
+}
+
+/// A procfs in a temporary directory, with the `name` file of each process
+/// holding the given contents
+#[cfg(test)]
+pub fn fixture_proc(name: &str, entries: &[(u32, &str)]) -> tempfile::TempDir {
+    let root = tempfile::tempdir().unwrap();
+    for (pid, contents) in entries {
+        let dir = root.path().join(pid.to_string());
+        std::fs::create_dir(&dir).unwrap();
+        std::fs::write(dir.join(name), contents).unwrap();
+    }
+    root
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_process_name() {
+        let proc_root = fixture_proc("comm", &[(4242, "python3\n"), (4343, "pt_main_thread\n")]);
+
+        assert_eq!(process_name(proc_root.path(), 4242), Some("python3".to_string()));
+        assert_eq!(process_name(proc_root.path(), 4343), Some("pt_main_thread".to_string()));
+    }
+
+    #[test]
+    fn test_process_name_sanitized() {
+        let long = "x".repeat(100);
+        let proc_root = fixture_proc("comm", &[(4242, "kworker/0:1 \"é\"\n"), (4343, &long), (4444, "\n")]);
+
+        assert_eq!(process_name(proc_root.path(), 4242), Some("kworker_0:1____".to_string()));
+        assert_eq!(process_name(proc_root.path(), 4343), Some("x".repeat(MAX_NAME_LEN)));
+        assert_eq!(process_name(proc_root.path(), 4444), None);
+    }
+
+    #[test]
+    fn test_process_name_unreadable() {
+        let proc_root = fixture_proc("comm", &[]);
+        assert_eq!(process_name(proc_root.path(), 4242), None);
+
+        // A directory where the file should be can't be read either
+        fs::create_dir_all(proc_root.path().join("4343/comm")).unwrap();
+        assert_eq!(process_name(proc_root.path(), 4343), None);
+    }
+}
@@ -0,0 +1,255 @@
+use crate::exporter::{Exporter, NAMESPACE};
+use prometheus::core::Collector;
+use prometheus::{Counter, Opts};
+use std::collections::HashMap;
+use std::time::Duration;
+use tokio::time::MissedTickBehavior;
+use tracing::{debug, warn};
+use url::Url;
+
+/// Label the per-process series carry their job under when pushed, as the
+/// Pushgateway reserves `job` for the grouping key
+const EXPORTED_JOB_LABEL: &str = "exported_job";
+
+/// Parse a --pushgateway-url value the way the push client does, so a URL it
+/// can't use fails at startup instead of panicking on the first push
+pub fn parse_pushgateway_url(s: &str) -> Result<String, String> {
+    // Like the client, default to http:// for a bare host:port
+    let full = if s.contains("://") { s.to_string() } else { format!("http://{}", s) };
+    let url = Url::parse(&full).map_err(|e| format!("invalid Pushgateway URL {:?}: {}", s, e))?;
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(format!("Pushgateway URL {:?} must use http or https", s));
+    }
+    Ok(s.to_string())
+}
+
+/// Parse a --job value, which becomes a path segment of the push URL
+pub fn parse_job(s: &str) -> Result<String, String> {
+    if s.is_empty() {
+        return Err("job name must not be empty".to_string());
+    }
+    if s.contains('/') {
+        return Err(format!("job name {:?} must not contain '/'", s));
+    }
+    Ok(s.to_string())
+}
+
+/// Pushes collected metrics to a Pushgateway, for jobs too short-lived to be scraped
+#[derive(Clone)]
+pub struct Pusher {
+    exporter: Exporter,
+    url: String,
+    job: String,
+    failures: Counter,
+}
+
+impl Pusher {
+    pub fn new(exporter: Exporter, url: String, job: String) -> Self {
+        let failures = Counter::with_opts(
+            Opts::new("push_failures_total", "Number of failed pushes to the Pushgateway")
+                .namespace(NAMESPACE)
+                .const_labels(exporter.const_labels().clone()),
+        )
+        .expect("Failed to create push_failures metric");
+        Self {
+            exporter,
+            url,
+            job,
+            failures,
+        }
+    }
+
+    /// Collect and push the metrics once, replacing those previously pushed for the job.
+    /// A failed push is counted in `nvidia_push_failures_total`, which goes out with the
+    /// next one.
+    pub fn push(&self) -> prometheus::Result<()> {
+        let mut mfs = self.exporter.gather();
+        mfs.extend(self.failures.collect());
+        // The Pushgateway keeps no metadata for families without samples, which
+        // the protobuf encoder rejects
+        mfs.retain(|mf| !mf.get_metric().is_empty());
+        // The push client rejects series with a `job` label of their own, which
+        // the per-process series have with --enable-cgroup-labels
+        for mf in &mut mfs {
+            for metric in mf.mut_metric().iter_mut() {
+                let labels = metric.mut_label();
+                for label in labels.iter_mut().filter(|label| label.get_name() == "job") {
+                    label.set_name(EXPORTED_JOB_LABEL.to_string());
+                }
+                labels.sort_by(|a, b| a.get_name().cmp(b.get_name()));
+            }
+        }
+
+        let result = prometheus::push_metrics(&self.job, HashMap::new(), &self.url, mfs, None);
+        if result.is_err() {
+            self.failures.inc();
+        }
+        result
+    }
+}
+
+/// Push on a fixed interval until the task is dropped
+pub async fn run(pusher: Pusher, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        let attempt = pusher.clone();
+        // Both NVML and the push client block, so keep them off the async workers
+        match tokio::task::spawn_blocking(move || attempt.push()).await {
+            Ok(Ok(())) => debug!("Pushed metrics to {}", pusher.url),
+            Ok(Err(e)) => warn!("Failed to push metrics to {}: {}", pusher.url, e),
+            Err(e) => warn!("Push task failed: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exporter::ExporterConfig;
+    use crate::metrics::{Device, GpuProcess, Metrics, MockMetricsCollector};
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::sync::Arc;
+
+    /// A request received by the mock Pushgateway
+    struct PushRequest {
+        request_line: String,
+        content_type: String,
+        body: Vec<u8>,
+    }
+
+    /// Serve `responses` to consecutive requests on a local port, passing the requests back
+    fn mock_pushgateway(responses: &'static [&'static str]) -> (String, mpsc::Receiver<PushRequest>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let (tx, rx) = mpsc::channel();
+
+        std::thread::spawn(move || {
+            for status in responses {
+                let (stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream);
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+
+                let (mut content_type, mut content_length) = (String::new(), 0);
+                loop {
+                    let mut header = String::new();
+                    reader.read_line(&mut header).unwrap();
+                    let header = header.trim_end();
+                    if header.is_empty() {
+                        break;
+                    }
+                    let (name, value) = header.split_once(": ").unwrap();
+                    match name.to_ascii_lowercase().as_str() {
+                        "content-type" => content_type = value.to_string(),
+                        "content-length" => content_length = value.parse().unwrap(),
+                        _ => {}
+                    }
+                }
+                let mut body = vec![0; content_length];
+                reader.read_exact(&mut body).unwrap();
+
+                write!(reader.get_mut(), "HTTP/1.1 {}\r\ncontent-length: 0\r\n\r\n", status).unwrap();
+                tx.send(PushRequest {
+                    request_line: request_line.trim_end().to_string(),
+                    content_type,
+                    body,
+                })
+                .unwrap();
+            }
+        });
+        (url, rx)
+    }
+
+    fn mock_exporter(config: ExporterConfig) -> Exporter {
+        let mut collector = MockMetricsCollector::new();
+        collector.expect_collect().returning(|| {
+            Ok(Metrics {
+                version: "525.116.04".to_string(),
+                devices: vec![Device {
+                    index: "0".to_string(),
+                    minor_number: "0".to_string(),
+                    temperature: 65.0,
+                    processes: vec![GpuProcess {
+                        pid: 4242,
+                        memory_used: Some(1048576.0),
+                        gpu_instance_id: None,
+                        compute: true,
+                        graphics: false,
+                    }],
+                    ..Default::default()
+                }],
+                ..Default::default()
+            })
+        });
+        Exporter::with_collector(Arc::new(collector), config)
+    }
+
+    fn contains(haystack: &[u8], needle: &str) -> bool {
+        haystack.windows(needle.len()).any(|w| w == needle.as_bytes())
+    }
+
+    #[test]
+    fn test_push_payload() {
+        let (url, requests) = mock_pushgateway(&["202 Accepted"]);
+        let pusher = Pusher::new(mock_exporter(ExporterConfig::default()), url, "batch-job".to_string());
+
+        pusher.push().unwrap();
+
+        let request = requests.recv().unwrap();
+        assert_eq!(request.request_line, "PUT /metrics/job/batch-job HTTP/1.1");
+        assert!(request.content_type.starts_with("application/vnd.google.protobuf"));
+        for name in ["nvidia_up", "nvidia_temperatures", "nvidia_driver_info", "525.116.04", "nvidia_push_failures_total"] {
+            assert!(contains(&request.body, name), "{} missing from the pushed payload", name);
+        }
+    }
+
+    #[test]
+    fn test_push_failure_is_counted() {
+        let (url, requests) = mock_pushgateway(&["500 Internal Server Error", "202 Accepted"]);
+        let pusher = Pusher::new(mock_exporter(ExporterConfig::default()), url, "batch-job".to_string());
+
+        assert!(pusher.push().is_err());
+        assert_eq!(pusher.failures.get(), 1.0);
+        requests.recv().unwrap();
+
+        pusher.push().unwrap();
+        assert_eq!(pusher.failures.get(), 1.0);
+        requests.recv().unwrap();
+    }
+
+    #[test]
+    fn test_push_with_cgroup_labels() {
+        let (url, requests) = mock_pushgateway(&["202 Accepted"]);
+        let config = ExporterConfig {
+            enable_cgroup_labels: true,
+            ..Default::default()
+        };
+        let pusher = Pusher::new(mock_exporter(config), url, "batch-job".to_string());
+
+        pusher.push().unwrap();
+
+        let request = requests.recv().unwrap();
+        assert!(contains(&request.body, "nvidia_process_memory_used_bytes"));
+        assert!(contains(&request.body, EXPORTED_JOB_LABEL));
+    }
+
+    #[test]
+    fn test_parse_pushgateway_url() {
+        assert_eq!(parse_pushgateway_url("pushgateway:9091").unwrap(), "pushgateway:9091");
+        assert!(parse_pushgateway_url("https://pushgateway:9091/").is_ok());
+        for invalid in ["", "http://", "http://push gateway", "ftp://pushgateway:9091"] {
+            assert!(parse_pushgateway_url(invalid).is_err(), "{:?} should be rejected", invalid);
+        }
+    }
+
+    #[test]
+    fn test_parse_job() {
+        assert_eq!(parse_job("training-run").unwrap(), "training-run");
+        assert!(parse_job("").is_err());
+        assert!(parse_job("team/training-run").is_err());
+    }
+}
@@ -0,0 +1,81 @@
+use std::time::{Duration, Instant};
+
+/// Token bucket refilled at a constant rate, holding up to a second's worth of
+/// tokens (at least one), so short bursts pass while the average rate is capped
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    /// A full bucket allowing `rate` requests per second on average
+    pub fn new(rate: f64, now: Instant) -> Self {
+        let capacity = rate.max(1.0);
+        Self {
+            rate,
+            capacity,
+            tokens: capacity,
+            refilled: now,
+        }
+    }
+
+    /// Take a token at `now`, or return how long until one is available
+    pub fn try_acquire(&mut self, now: Instant) -> Result<(), Duration> {
+        let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.refilled = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - self.tokens) / self.rate))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_token_bucket_burst_then_rate() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(2.0, start);
+
+        // A second's worth of requests passes at once
+        assert!(bucket.try_acquire(start).is_ok());
+        assert!(bucket.try_acquire(start).is_ok());
+        assert_eq!(bucket.try_acquire(start), Err(Duration::from_millis(500)));
+
+        // Then one every 500ms
+        assert!(bucket.try_acquire(start + Duration::from_millis(250)).is_err());
+        assert!(bucket.try_acquire(start + Duration::from_millis(500)).is_ok());
+        assert!(bucket.try_acquire(start + Duration::from_millis(600)).is_err());
+        assert!(bucket.try_acquire(start + Duration::from_millis(1000)).is_ok());
+    }
+
+    #[test]
+    fn test_token_bucket_slow_rate() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(0.1, start);
+
+        assert!(bucket.try_acquire(start).is_ok());
+        assert_eq!(bucket.try_acquire(start), Err(Duration::from_secs(10)));
+        assert!(bucket.try_acquire(start + Duration::from_secs(10)).is_ok());
+    }
+
+    #[test]
+    fn test_token_bucket_does_not_save_up() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(1.0, start);
+
+        // An idle hour doesn't allow an hour's worth of requests
+        let later = start + Duration::from_secs(3600);
+        assert!(bucket.try_acquire(later).is_ok());
+        assert!(bucket.try_acquire(later).is_err());
+    }
+}
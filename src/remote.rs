@@ -0,0 +1,338 @@
+use crate::metrics::{Metrics, MetricsCollector};
+use anyhow::{bail, Context, Result};
+use std::collections::BTreeSet;
+use std::io::{Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+use tracing::warn;
+
+/// Port of a remote exporter when the endpoint doesn't give one, the exporter's default
+const DEFAULT_PORT: u16 = 9445;
+
+/// Telemetry path of a remote exporter when the endpoint doesn't give one
+const DEFAULT_PATH: &str = "/metrics";
+
+/// How long connecting to, and each read from or write to, a remote exporter may take
+const PEER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Largest response read from a remote exporter, headers included, so a
+/// misbehaving peer can't make the exporter buffer without bound
+const MAX_RESPONSE_BYTES: u64 = 16 * 1024 * 1024;
+
+/// A remote exporter to collect from, as given to --remote-endpoint
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RemoteEndpoint {
+    /// "host:port" of the exporter, also used as the `host` label of its devices
+    pub host: String,
+    /// Telemetry path of the exporter
+    pub path: String,
+}
+
+/// Parse a --remote-endpoint value: "host[:port][/path]", optionally prefixed with "http://"
+pub fn parse_remote_endpoint(s: &str) -> Result<RemoteEndpoint, String> {
+    parse_endpoint(s, DEFAULT_PORT)
+}
+
+/// Parse an HTTP endpoint given as "host[:port][/path]", optionally prefixed with
+/// "http://", with `default_port` and `/metrics` filled in when left out. IPv6
+/// addresses are given in brackets, as in "[::1]:9445".
+pub fn parse_endpoint(s: &str, default_port: u16) -> Result<RemoteEndpoint, String> {
+    if s.starts_with("https://") {
+        return Err(format!("'{}' uses https, but remote exporters are only reached over http", s));
+    }
+    let rest = s.strip_prefix("http://").unwrap_or(s);
+    let (authority, path) = match rest.find('/') {
+        Some(pos) => rest.split_at(pos),
+        None => (rest, DEFAULT_PATH),
+    };
+    if authority.is_empty() {
+        return Err(format!("'{}' has no host", s));
+    }
+    // The colons of an IPv6 address are inside the brackets, the port's after them
+    let port = match authority.strip_prefix('[') {
+        Some(bracketed) => match bracketed.split_once(']') {
+            Some((_, "")) => None,
+            Some((_, rest)) => match rest.strip_prefix(':') {
+                Some(port) => Some(port),
+                None => return Err(format!("'{}' has an invalid port", s)),
+            },
+            None => return Err(format!("'{}' has an unclosed IPv6 address", s)),
+        },
+        None => authority.rsplit_once(':').map(|(_, port)| port),
+    };
+    let host = match port {
+        Some(port) if port.parse::<u16>().is_err() => return Err(format!("'{}' has an invalid port", s)),
+        Some(_) => authority.to_string(),
+        None => format!("{}:{}", authority, default_port),
+    };
+    Ok(RemoteEndpoint {
+        host,
+        path: path.to_string(),
+    })
+}
+
+/// Collects from other exporters over HTTP, merging their devices so one exporter
+/// can serve the GPUs of several nodes. Each device is keyed by a "host/minor"
+/// minor number, which the exporter splits into `host` and `minor` labels.
+pub struct RemoteCollector {
+    endpoints: Vec<RemoteEndpoint>,
+}
+
+impl MetricsCollector for RemoteCollector {
+    fn collect(&self) -> Result<Metrics> {
+        // Peers are fetched in parallel, so one that is slow to answer doesn't delay the others
+        let results: Vec<Result<Metrics>> = std::thread::scope(|scope| {
+            let fetches: Vec<_> = self
+                .endpoints
+                .iter()
+                .map(|endpoint| scope.spawn(move || fetch(endpoint)))
+                .collect();
+            fetches
+                .into_iter()
+                .map(|fetch| fetch.join().unwrap_or_else(|_| Err(anyhow::anyhow!("Fetch panicked"))))
+                .collect()
+        });
+
+        let mut metrics = Metrics::default();
+        let mut versions = BTreeSet::new();
+        for (endpoint, result) in self.endpoints.iter().zip(results) {
+            match result {
+                Ok(peer) => {
+                    versions.insert(peer.version);
+                    metrics.nvml_calls += peer.nvml_calls;
+                    for mut device in peer.devices {
+                        device.minor_number = format!("{}/{}", endpoint.host, device.minor_number);
+                        device.host = Some(endpoint.host.clone());
+                        // Indices are renumbered so they stay unique across peers
+                        device.index = metrics.devices.len().to_string();
+                        metrics.devices.push(device);
+                    }
+                    metrics.remote_up.insert(endpoint.host.clone(), true);
+                }
+                Err(e) => {
+                    warn!("Failed to collect from remote exporter {}: {:#}", endpoint.host, e);
+                    metrics.remote_up.insert(endpoint.host.clone(), false);
+                }
+            }
+        }
+        if !metrics.remote_up.values().any(|up| *up) {
+            bail!("None of the {} remote exporters could be collected from", self.endpoints.len());
+        }
+        metrics.version = versions.into_iter().collect::<Vec<_>>().join(",");
+        Ok(metrics)
+    }
+}
+
+impl RemoteCollector {
+    pub fn new(endpoints: Vec<RemoteEndpoint>) -> Self {
+        Self { endpoints }
+    }
+}
+
+/// Fetch the collected data of a remote exporter, which it serves as JSON with
+/// `?format=json`
+fn fetch(endpoint: &RemoteEndpoint) -> Result<Metrics> {
+    let body = http_get(endpoint, "format=json", "application/json", PEER_TIMEOUT)?;
+    serde_json::from_slice(&body).context("Failed to decode the collected data")
+}
+
+/// GET the path of `endpoint` with `query` added to its query string (empty for
+/// none), returning the body of a 200 response. `timeout` applies to connecting
+/// and to each read and write, and responses over `MAX_RESPONSE_BYTES` fail.
+pub fn http_get(endpoint: &RemoteEndpoint, query: &str, accept: &str, timeout: Duration) -> Result<Vec<u8>> {
+    let addr = endpoint
+        .host
+        .to_socket_addrs()
+        .with_context(|| format!("Failed to resolve {}", endpoint.host))?
+        .next()
+        .with_context(|| format!("{} resolved to no address", endpoint.host))?;
+    let mut stream = TcpStream::connect_timeout(&addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+
+    // HTTP/1.0 gets a response delimited by the end of the connection, so there
+    // is no chunked encoding to undo. The request goes out in one write: a peer
+    // that answers on its first read may close before a later write lands.
+    let target = match (query.is_empty(), endpoint.path.contains('?')) {
+        (true, _) => endpoint.path.clone(),
+        (false, true) => format!("{}&{}", endpoint.path, query),
+        (false, false) => format!("{}?{}", endpoint.path, query),
+    };
+    let request = format!(
+        "GET {} HTTP/1.0\r\nHost: {}\r\nAccept: {}\r\n\r\n",
+        target, endpoint.host, accept
+    );
+    stream.write_all(request.as_bytes())?;
+    let mut response = Vec::new();
+    stream.take(MAX_RESPONSE_BYTES + 1).read_to_end(&mut response)?;
+    if response.len() as u64 > MAX_RESPONSE_BYTES {
+        bail!("Response larger than {} bytes", MAX_RESPONSE_BYTES);
+    }
+
+    let Some(split) = response.windows(4).position(|w| w == b"\r\n\r\n") else {
+        bail!("Malformed HTTP response");
+    };
+    let head = String::from_utf8_lossy(&response[..split]);
+    let status = head.lines().next().unwrap_or_default();
+    if status.split_whitespace().nth(1) != Some("200") {
+        bail!("Unexpected response: {}", status);
+    }
+    response.drain(..split + 4);
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::Device;
+    use std::net::TcpListener;
+
+    /// Serve `response` to every connection on a local port, returning the endpoint
+    fn mock_peer(response: String) -> RemoteEndpoint {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let host = listener.local_addr().unwrap().to_string();
+        std::thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let mut request = [0; 1024];
+                let _ = stream.read(&mut request);
+                let _ = stream.write_all(response.as_bytes());
+            }
+        });
+        RemoteEndpoint {
+            host,
+            path: DEFAULT_PATH.to_string(),
+        }
+    }
+
+    fn json_response(metrics: &Metrics) -> String {
+        let body = serde_json::to_string(metrics).unwrap();
+        format!("HTTP/1.0 200 OK\r\nContent-Type: application/json\r\n\r\n{}", body)
+    }
+
+    fn device(minor: &str, temperature: f64) -> Device {
+        Device {
+            index: minor.to_string(),
+            minor_number: minor.to_string(),
+            uuid: format!("GPU-{}", minor),
+            temperature,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_parse_remote_endpoint() {
+        let parse = |s| parse_remote_endpoint(s).map(|e| (e.host, e.path));
+        assert_eq!(parse("gpu-1:9445"), Ok(("gpu-1:9445".to_string(), "/metrics".to_string())));
+        assert_eq!(parse("gpu-1"), Ok(("gpu-1:9445".to_string(), "/metrics".to_string())));
+        assert_eq!(parse("http://10.0.0.2:9100/gpu"), Ok(("10.0.0.2:9100".to_string(), "/gpu".to_string())));
+        assert_eq!(parse("[::1]"), Ok(("[::1]:9445".to_string(), "/metrics".to_string())));
+        assert_eq!(parse("http://[fd00::2]:9100/gpu"), Ok(("[fd00::2]:9100".to_string(), "/gpu".to_string())));
+
+        for invalid in ["", "/metrics", "gpu-1:port", "gpu-1:99999", "https://gpu-1:9445", "[::1", "[::1]9445", "[::1]:port"] {
+            assert!(parse_remote_endpoint(invalid).is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn test_remote_collector_merges_peers() {
+        let a = mock_peer(json_response(&Metrics {
+            version: "535.129.03".to_string(),
+            devices: vec![device("0", 40.0), device("1", 41.0)],
+            nvml_calls: 10,
+            ..Default::default()
+        }));
+        let b = mock_peer(json_response(&Metrics {
+            version: "550.54.15".to_string(),
+            devices: vec![device("0", 50.0)],
+            nvml_calls: 5,
+            ..Default::default()
+        }));
+        let collector = RemoteCollector::new(vec![a.clone(), b.clone()]);
+
+        let metrics = collector.collect().unwrap();
+        assert_eq!(metrics.version, "535.129.03,550.54.15");
+        assert_eq!(metrics.nvml_calls, 15);
+        let devices: Vec<(&str, &str, Option<&str>, f64)> = metrics
+            .devices
+            .iter()
+            .map(|d| (d.index.as_str(), d.minor_number.as_str(), d.host.as_deref(), d.temperature))
+            .collect();
+        let (a_0, a_1, b_0) = (format!("{}/0", a.host), format!("{}/1", a.host), format!("{}/0", b.host));
+        assert_eq!(
+            devices,
+            vec![
+                ("0", a_0.as_str(), Some(a.host.as_str()), 40.0),
+                ("1", a_1.as_str(), Some(a.host.as_str()), 41.0),
+                ("2", b_0.as_str(), Some(b.host.as_str()), 50.0),
+            ]
+        );
+        assert_eq!(metrics.remote_up.values().collect::<Vec<_>>(), vec![&true, &true]);
+    }
+
+    #[test]
+    fn test_remote_collector_marks_failed_peers_down() {
+        let up = mock_peer(json_response(&Metrics {
+            devices: vec![device("0", 40.0)],
+            ..Default::default()
+        }));
+        let failing = mock_peer("HTTP/1.0 503 Service Unavailable\r\n\r\nMetrics collection timed out".to_string());
+        let garbled = mock_peer("HTTP/1.0 200 OK\r\n\r\n# HELP nvidia_up".to_string());
+        // Nothing listens on a port that was bound and released
+        let unreachable = {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            RemoteEndpoint {
+                host: listener.local_addr().unwrap().to_string(),
+                path: DEFAULT_PATH.to_string(),
+            }
+        };
+        let collector = RemoteCollector::new(vec![up.clone(), failing.clone(), garbled.clone(), unreachable.clone()]);
+
+        let metrics = collector.collect().unwrap();
+        assert_eq!(metrics.devices.len(), 1);
+        assert!(metrics.remote_up[&up.host]);
+        assert!(!metrics.remote_up[&failing.host]);
+        assert!(!metrics.remote_up[&garbled.host]);
+        assert!(!metrics.remote_up[&unreachable.host]);
+
+        // With every peer down there is nothing to serve
+        let collector = RemoteCollector::new(vec![failing, garbled, unreachable]);
+        assert!(collector.collect().is_err());
+    }
+
+    #[test]
+    fn test_http_get_response_size_limit() {
+        let body = "x".repeat(MAX_RESPONSE_BYTES as usize);
+        let endpoint = mock_peer(format!("HTTP/1.0 200 OK\r\n\r\n{}", body));
+
+        let err = http_get(&endpoint, "", "text/plain", PEER_TIMEOUT).unwrap_err();
+        assert!(err.to_string().contains("larger than"), "{}", err);
+    }
+
+    #[test]
+    fn test_http_get_query() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let host = listener.local_addr().unwrap().to_string();
+        std::thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                let mut request = [0; 1024];
+                let read = stream.read(&mut request).unwrap();
+                let request_line = String::from_utf8_lossy(&request[..read]).lines().next().unwrap().to_string();
+                tx.send(request_line).unwrap();
+                let _ = stream.write_all(b"HTTP/1.0 200 OK\r\n\r\n");
+            }
+        });
+        let get = |path: &str, query: &str| {
+            let endpoint = RemoteEndpoint {
+                host: host.clone(),
+                path: path.to_string(),
+            };
+            http_get(&endpoint, query, "application/json", PEER_TIMEOUT).unwrap();
+            rx.recv().unwrap()
+        };
+
+        assert_eq!(get("/metrics", "format=json"), "GET /metrics?format=json HTTP/1.0");
+        assert_eq!(get("/metrics?collect[]=gpu", "format=json"), "GET /metrics?collect[]=gpu&format=json HTTP/1.0");
+        assert_eq!(get("/metrics?collect[]=gpu", ""), "GET /metrics?collect[]=gpu HTTP/1.0");
+    }
+}
@@ -0,0 +1,122 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Samples of a per-device value kept over a sliding time window, at most
+/// `capacity` of them
+#[derive(Debug, Clone)]
+pub struct SampleWindow {
+    window: Duration,
+    capacity: usize,
+    samples: VecDeque<(Instant, f64)>,
+}
+
+impl SampleWindow {
+    pub fn new(window: Duration, capacity: usize) -> Self {
+        Self {
+            window,
+            capacity,
+            samples: VecDeque::new(),
+        }
+    }
+
+    /// Record a sample taken at `at`, evicting the samples that fell out of the
+    /// window, then the oldest ones beyond the capacity. Returns the number of
+    /// samples evicted for the capacity though still within the window.
+    pub fn push(&mut self, at: Instant, value: f64) -> usize {
+        self.samples.push_back((at, value));
+        while let Some(&(taken, _)) = self.samples.front() {
+            if at.duration_since(taken) <= self.window {
+                break;
+            }
+            self.samples.pop_front();
+        }
+        let evicted = self.samples.len().saturating_sub(self.capacity);
+        self.samples.drain(..evicted);
+        evicted
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+
+    pub fn sum(&self) -> f64 {
+        self.samples.iter().map(|&(_, value)| value).sum()
+    }
+
+    /// Nearest-rank quantile of the samples in the window, `q` in [0, 1].
+    /// Returns `None` if the window is empty.
+    pub fn quantile(&self, q: f64) -> Option<f64> {
+        if self.samples.is_empty() {
+            return None;
+        }
+        let mut values: Vec<f64> = self.samples.iter().map(|&(_, value)| value).collect();
+        values.sort_by(f64::total_cmp);
+        let rank = (q * values.len() as f64).ceil() as usize;
+        Some(values[rank.clamp(1, values.len()) - 1])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_quantiles_of_known_distribution() {
+        let mut window = SampleWindow::new(Duration::from_secs(300), 1000);
+        let start = Instant::now();
+        // 1..=100 W in milliwatts, pushed out of order
+        for (i, watts) in (1..=100).rev().enumerate() {
+            window.push(start + Duration::from_secs(i as u64), watts as f64 * 1000.0);
+        }
+
+        assert_eq!(window.len(), 100);
+        assert_eq!(window.quantile(0.5), Some(50_000.0));
+        assert_eq!(window.quantile(0.95), Some(95_000.0));
+        assert_eq!(window.quantile(0.0), Some(1_000.0));
+        assert_eq!(window.quantile(1.0), Some(100_000.0));
+        assert_eq!(window.sum(), 5_050_000.0);
+    }
+
+    #[test]
+    fn test_samples_outside_window_are_evicted() {
+        let mut window = SampleWindow::new(Duration::from_secs(60), 1000);
+        let start = Instant::now();
+        window.push(start, 300_000.0);
+        window.push(start + Duration::from_secs(30), 100_000.0);
+        window.push(start + Duration::from_secs(61), 200_000.0);
+
+        assert_eq!(window.len(), 2);
+        assert_eq!(window.quantile(1.0), Some(200_000.0));
+    }
+
+    #[test]
+    fn test_samples_beyond_capacity_are_evicted() {
+        let mut window = SampleWindow::new(Duration::from_secs(300), 3);
+        let start = Instant::now();
+        let mut evicted = 0;
+        for i in 0..10 {
+            evicted += window.push(start + Duration::from_secs(i), i as f64);
+            assert!(window.len() <= 3);
+        }
+
+        assert_eq!(window.len(), 3);
+        assert_eq!(evicted, 7);
+        assert_eq!(window.quantile(0.0), Some(7.0));
+
+        // Samples leaving the window aren't counted as evicted for the capacity
+        assert_eq!(window.push(start + Duration::from_secs(400), 10.0), 0);
+        assert_eq!(window.len(), 1);
+    }
+
+    #[test]
+    fn test_empty_window() {
+        let window = SampleWindow::new(Duration::from_secs(60), 1000);
+
+        assert_eq!(window.len(), 0);
+        assert_eq!(window.quantile(0.5), None);
+    }
+}
@@ -0,0 +1,71 @@
+use axum::extract::Request;
+use axum::middleware::Next;
+use axum::Router;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// Tracks the requests being handled, so shutdown can let them finish for up
+/// to a grace period instead of cutting off their responses
+#[derive(Clone)]
+pub struct Drain {
+    grace: Duration,
+    in_flight: Arc<AtomicUsize>,
+}
+
+/// Counts a request as in flight until it is dropped, whether it completed or
+/// was cancelled
+struct InFlightGuard(Arc<AtomicUsize>);
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Drain {
+    pub fn new(grace: Duration) -> Self {
+        Self {
+            grace,
+            in_flight: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Number of requests currently being handled
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::SeqCst)
+    }
+
+    /// Count the requests handled by `app`
+    pub fn track(&self, app: Router) -> Router {
+        let in_flight = Arc::clone(&self.in_flight);
+        app.layer(axum::middleware::from_fn(move |request: Request, next: Next| {
+            in_flight.fetch_add(1, Ordering::SeqCst);
+            let guard = InFlightGuard(Arc::clone(&in_flight));
+            async move {
+                let response = next.run(request).await;
+                drop(guard);
+                response
+            }
+        }))
+    }
+
+    /// Report the start of the drain
+    pub fn begin(&self) {
+        info!(
+            "Shutting down, waiting up to {:?} for {} in-flight request(s)",
+            self.grace,
+            self.in_flight()
+        );
+    }
+
+    /// Complete once the grace period is over, reporting the requests it cuts off
+    pub async fn grace_elapsed(&self) {
+        tokio::time::sleep(self.grace).await;
+        warn!(
+            "Shutdown grace period elapsed, dropping {} in-flight request(s)",
+            self.in_flight()
+        );
+    }
+}
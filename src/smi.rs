@@ -0,0 +1,340 @@
+use crate::metrics::{format_pci_bus_id, Device, Metrics, MetricsCollector};
+use anyhow::{bail, Context, Result};
+use std::io::Read;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+/// Time `nvidia-smi` gets to answer before it is killed, so a wedged driver
+/// can't hang collections
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Interval at which a running `nvidia-smi` is checked for having exited
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Fields queried from `nvidia-smi --query-gpu`, in the order of the CSV columns
+const QUERY_FIELDS: [&str; 29] = [
+    "index",
+    "uuid",
+    "name",
+    "pci.bus_id",
+    "driver_version",
+    "temperature.gpu",
+    "temperature.memory",
+    "fan.speed",
+    "power.draw",
+    "power.limit",
+    "power.default_limit",
+    "memory.total",
+    "memory.used",
+    "utilization.gpu",
+    "utilization.memory",
+    "clocks.gr",
+    "clocks.sm",
+    "clocks.mem",
+    "clocks.max.gr",
+    "clocks.max.sm",
+    "clocks.max.mem",
+    "pstate",
+    "pcie.link.gen.current",
+    "pcie.link.width.current",
+    "pcie.link.gen.max",
+    "pcie.link.width.max",
+    "encoder.stats.sessionCount",
+    "ecc.errors.corrected.aggregate.total",
+    "ecc.errors.uncorrected.aggregate.total",
+];
+
+const BYTES_PER_MIB: f64 = 1024.0 * 1024.0;
+
+/// Collects a subset of the metrics by running `nvidia-smi`, for nodes where NVML
+/// can't be loaded by the exporter but the `nvidia-smi` binary still works
+pub struct SmiCollector {
+    program: PathBuf,
+    parse_errors: Arc<AtomicU64>,
+    timeout: Duration,
+}
+
+impl MetricsCollector for SmiCollector {
+    fn collect(&self) -> Result<Metrics> {
+        let mut child = Command::new(&self.program)
+            .arg(format!("--query-gpu={}", QUERY_FIELDS.join(",")))
+            .arg("--format=csv,noheader,nounits")
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .with_context(|| format!("Failed to run {}", self.program.display()))?;
+        // Drained on their own threads, so a full pipe can't stall nvidia-smi
+        let stdout = drain(child.stdout.take());
+        let stderr = drain(child.stderr.take());
+
+        let deadline = Instant::now() + self.timeout;
+        let status = loop {
+            if let Some(status) = child.try_wait()? {
+                break status;
+            }
+            if Instant::now() >= deadline {
+                let _ = child.kill();
+                let _ = child.wait();
+                bail!("{} timed out after {:?}", self.program.display(), self.timeout);
+            }
+            std::thread::sleep(POLL_INTERVAL);
+        };
+        let (stdout, stderr) = (stdout.join().unwrap_or_default(), stderr.join().unwrap_or_default());
+        if !status.success() {
+            bail!(
+                "{} failed with {}: {}",
+                self.program.display(),
+                status,
+                String::from_utf8_lossy(&stderr).trim()
+            );
+        }
+        parse_metrics(&String::from_utf8_lossy(&stdout), &self.parse_errors)
+    }
+}
+
+/// Read a pipe of a child process to the end on a separate thread
+fn drain(pipe: Option<impl Read + Send + 'static>) -> JoinHandle<Vec<u8>> {
+    std::thread::spawn(move || {
+        let mut buf = Vec::new();
+        if let Some(mut pipe) = pipe {
+            let _ = pipe.read_to_end(&mut buf);
+        }
+        buf
+    })
+}
+
+impl SmiCollector {
+    /// Create a collector running `program`, normally `nvidia-smi` from the `PATH`
+    pub fn new(program: impl Into<PathBuf>) -> Self {
+        Self {
+            program: program.into(),
+            parse_errors: Arc::new(AtomicU64::new(0)),
+            timeout: TIMEOUT,
+        }
+    }
+
+    /// Shared count of `nvidia-smi` rows skipped because they couldn't be parsed
+    pub fn parse_error_count(&self) -> Arc<AtomicU64> {
+        Arc::clone(&self.parse_errors)
+    }
+}
+
+/// Parse the CSV output of the query into metrics, skipping and counting the rows
+/// that can't be parsed. Fails when there were rows but none of them parsed.
+fn parse_metrics(csv: &str, parse_errors: &AtomicU64) -> Result<Metrics> {
+    let mut metrics = Metrics::default();
+    let rows: Vec<&str> = csv.lines().filter(|line| !line.trim().is_empty()).collect();
+    for line in &rows {
+        match parse_row(line) {
+            Ok((version, device)) => {
+                metrics.version = version;
+                metrics.devices.push(device);
+            }
+            Err(e) => {
+                warn!("Skipping nvidia-smi row {:?}: {:#}", line, e);
+                parse_errors.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+    if metrics.devices.is_empty() && !rows.is_empty() {
+        bail!("None of the {} nvidia-smi rows could be parsed", rows.len());
+    }
+    Ok(metrics)
+}
+
+/// Whether nvidia-smi printed a placeholder instead of a value: `N/A`, or any
+/// bracketed status like `[Not Supported]`, `[Unknown Error]` or `[GPU requires reset]`
+fn is_missing(raw: &str) -> bool {
+    raw == "N/A" || (raw.starts_with('[') && raw.ends_with(']'))
+}
+
+/// Parse one CSV row into the driver version and the device it describes
+fn parse_row(line: &str) -> Result<(String, Device)> {
+    let columns: Vec<&str> = line.split(',').map(str::trim).collect();
+    if columns.len() != QUERY_FIELDS.len() {
+        bail!("expected {} columns, found {}", QUERY_FIELDS.len(), columns.len());
+    }
+    let field = |name: &str| columns[QUERY_FIELDS.iter().position(|f| *f == name).expect("queried field")];
+    let value = |name: &str| -> Result<Option<f64>> {
+        match field(name) {
+            raw if is_missing(raw) => Ok(None),
+            raw => raw
+                .parse()
+                .map(Some)
+                .with_context(|| format!("invalid {} {:?}", name, raw)),
+        }
+    };
+
+    let index = field("index");
+    if index.parse::<u32>().is_err() {
+        bail!("invalid index {:?}", index);
+    }
+    let power_usage = value("power.draw")?.unwrap_or(0.0) * 1000.0;
+    let utilization_gpu = value("utilization.gpu")?.unwrap_or(0.0);
+    let performance_state = match field("pstate") {
+        pstate if is_missing(pstate) => None,
+        pstate => Some(
+            pstate
+                .strip_prefix('P')
+                .and_then(|n| n.parse::<f64>().ok())
+                .with_context(|| format!("invalid pstate {:?}", pstate))?,
+        ),
+    };
+
+    let device = Device {
+        index: index.to_string(),
+        // nvidia-smi can't be queried for the minor number, which matches the index
+        // unless devices are hidden from the exporter
+        minor_number: index.to_string(),
+        name: field("name").to_string(),
+        uuid: field("uuid").to_string(),
+        pci_bus_id: Some(format_pci_bus_id(field("pci.bus_id"))),
+        temperature: value("temperature.gpu")?.unwrap_or(0.0),
+        memory_temperature: value("temperature.memory")?,
+        fan_speed: value("fan.speed")?.unwrap_or(0.0),
+        // nvidia-smi reports no averages, so the current values stand in for them
+        power_usage,
+        power_usage_average: power_usage,
+        power_limit: value("power.limit")?.map(|w| w * 1000.0),
+        power_limit_default: value("power.default_limit")?.map(|w| w * 1000.0),
+        memory_total: value("memory.total")?.unwrap_or(0.0) * BYTES_PER_MIB,
+        memory_used: value("memory.used")?.unwrap_or(0.0) * BYTES_PER_MIB,
+        utilization_memory: value("utilization.memory")?.unwrap_or(0.0),
+        utilization_gpu,
+        utilization_gpu_average: utilization_gpu,
+        clock_graphics: value("clocks.gr")?,
+        clock_sm: value("clocks.sm")?,
+        clock_memory: value("clocks.mem")?,
+        clock_graphics_max: value("clocks.max.gr")?,
+        clock_sm_max: value("clocks.max.sm")?,
+        clock_memory_max: value("clocks.max.mem")?,
+        performance_state,
+        pcie_link_gen: value("pcie.link.gen.current")?,
+        pcie_link_width: value("pcie.link.width.current")?,
+        pcie_link_gen_max: value("pcie.link.gen.max")?,
+        pcie_link_width_max: value("pcie.link.width.max")?,
+        encoder_sessions: value("encoder.stats.sessionCount")?,
+        ecc_errors_corrected: value("ecc.errors.corrected.aggregate.total")?,
+        ecc_errors_uncorrected: value("ecc.errors.uncorrected.aggregate.total")?,
+        ..Default::default()
+    };
+    Ok((field("driver_version").to_string(), device))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIXTURE: &str = include_str!("../tests/fixtures/nvidia-smi-query-gpu.csv");
+
+    #[test]
+    fn test_parse_fixture() {
+        let parse_errors = AtomicU64::new(0);
+        let metrics = parse_metrics(FIXTURE, &parse_errors).unwrap();
+
+        assert_eq!(parse_errors.load(Ordering::Relaxed), 0);
+        assert_eq!(metrics.version, "535.129.03");
+        assert_eq!(metrics.devices.len(), 3);
+
+        let a100 = &metrics.devices[0];
+        assert_eq!(a100.index, "0");
+        assert_eq!(a100.minor_number, "0");
+        assert_eq!(a100.name, "NVIDIA A100-SXM4-80GB");
+        assert_eq!(a100.uuid, "GPU-5c1e4a6b-2f0d-8e3c-41a7-9b2d6f0e1c3a");
+        assert_eq!(a100.pci_bus_id.as_deref(), Some("0000:07:00.0"));
+        assert_eq!(a100.temperature, 34.0);
+        assert_eq!(a100.memory_temperature, Some(45.0));
+        // Passively cooled, so there is no fan to report
+        assert_eq!(a100.fan_speed, 0.0);
+        assert_eq!(a100.power_usage, 65_250.0);
+        assert_eq!(a100.power_limit, Some(400_000.0));
+        assert_eq!(a100.memory_total, 81920.0 * BYTES_PER_MIB);
+        assert_eq!(a100.memory_used, 2048.0 * BYTES_PER_MIB);
+        assert_eq!(a100.utilization_gpu, 87.0);
+        assert_eq!(a100.utilization_memory, 41.0);
+        assert_eq!(a100.clock_memory_max, Some(1593.0));
+        assert_eq!(a100.performance_state, Some(0.0));
+        assert_eq!(a100.pcie_link_gen, Some(4.0));
+        assert_eq!(a100.pcie_link_width, Some(16.0));
+        assert_eq!(a100.ecc_errors_corrected, Some(0.0));
+
+        let rtx = &metrics.devices[1];
+        assert_eq!(rtx.index, "1");
+        assert_eq!(rtx.name, "NVIDIA GeForce RTX 3080");
+        assert_eq!(rtx.memory_temperature, None);
+        assert_eq!(rtx.fan_speed, 30.0);
+        assert_eq!(rtx.power_usage, 112_500.0);
+        assert_eq!(rtx.performance_state, Some(2.0));
+        assert_eq!(rtx.pcie_link_gen, Some(3.0));
+        assert_eq!(rtx.encoder_sessions, Some(1.0));
+        assert_eq!(rtx.ecc_errors_corrected, None);
+        assert_eq!(rtx.ecc_errors_uncorrected, None);
+
+        // Columns nvidia-smi couldn't read are missing rather than failing the row
+        let t4 = &metrics.devices[2];
+        assert_eq!(t4.index, "2");
+        assert_eq!(t4.temperature, 0.0);
+        assert_eq!(t4.power_usage, 0.0);
+        assert_eq!(t4.power_limit, Some(70_000.0));
+        assert_eq!(t4.performance_state, None);
+        assert_eq!(t4.ecc_errors_corrected, None);
+    }
+
+    #[test]
+    fn test_parse_errors_skip_rows() {
+        let mut rows: Vec<&str> = FIXTURE.lines().collect();
+        let truncated = rows[1].rsplit_once(',').unwrap().0.to_string();
+        let extra_column = rows[1].replacen("NVIDIA", "NVIDIA, GPU", 1);
+        let unparsable = rows[1].replace(", 52,", ", hot,");
+        rows.extend([truncated.as_str(), extra_column.as_str(), unparsable.as_str()]);
+
+        let parse_errors = AtomicU64::new(0);
+        let metrics = parse_metrics(&rows.join("\n"), &parse_errors).unwrap();
+
+        assert_eq!(parse_errors.load(Ordering::Relaxed), 3);
+        assert_eq!(metrics.devices.len(), 3);
+    }
+
+    #[test]
+    fn test_no_parsable_rows_fails() {
+        let parse_errors = AtomicU64::new(0);
+
+        assert!(parse_metrics("Unable to determine the device handle for GPU0000:07:00.0: Unknown Error\n", &parse_errors).is_err());
+        assert_eq!(parse_errors.load(Ordering::Relaxed), 1);
+    }
+
+    /// Collector running a shell script in place of nvidia-smi
+    fn script_collector(dir: &tempfile::TempDir, script: &str) -> SmiCollector {
+        use std::os::unix::fs::PermissionsExt;
+        let program = dir.path().join("nvidia-smi");
+        std::fs::write(&program, format!("#!/bin/sh\n{}\n", script)).unwrap();
+        std::fs::set_permissions(&program, std::fs::Permissions::from_mode(0o755)).unwrap();
+        SmiCollector::new(program)
+    }
+
+    #[test]
+    fn test_collect_runs_program() {
+        let dir = tempfile::tempdir().unwrap();
+        let fixture = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/nvidia-smi-query-gpu.csv");
+        let collector = script_collector(&dir, &format!("cat {}", fixture.display()));
+
+        assert_eq!(collector.collect().unwrap().devices.len(), 3);
+    }
+
+    #[test]
+    fn test_collect_times_out() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut collector = script_collector(&dir, "exec sleep 10");
+        collector.timeout = Duration::from_millis(100);
+
+        let started = Instant::now();
+        let err = collector.collect().unwrap_err();
+        assert!(err.to_string().contains("timed out"), "{}", err);
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+}
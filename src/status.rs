@@ -0,0 +1,103 @@
+use crate::metrics::Device;
+use std::fmt::Write;
+
+/// Render the status page: a table of the GPUs from the last collection, or a
+/// notice when there is nothing to show because NVML is unavailable or nothing
+/// was `collected` yet
+pub fn render(devices: Option<&[Device]>, collected: bool) -> String {
+    let mut body = String::new();
+    match devices {
+        Some(devices) => {
+            body.push_str(
+                "<table border=\"1\" cellpadding=\"4\">\n\
+                 <tr><th>Index</th><th>Minor</th><th>Name</th><th>Temperature (&deg;C)</th>\
+                 <th>Power (W)</th><th>GPU Utilization (%)</th><th>Memory Used / Total (MiB)</th></tr>\n",
+            );
+            for device in devices {
+                let _ = writeln!(
+                    body,
+                    "<tr><td>{}</td><td>{}</td><td>{}</td><td>{:.0}</td><td>{:.1}</td><td>{:.0}</td><td>{:.0} / {:.0}</td></tr>",
+                    escape(&device.index),
+                    escape(&device.minor_number),
+                    escape(&device.name),
+                    device.temperature,
+                    device.power_usage / 1000.0,
+                    device.utilization_gpu,
+                    device.memory_used / (1024.0 * 1024.0),
+                    device.memory_total / (1024.0 * 1024.0),
+                );
+            }
+            body.push_str("</table>\n");
+        }
+        None if !collected => body.push_str("<p><strong>Not collected yet</strong></p>\n"),
+        None => body.push_str("<p><strong>NVML unavailable</strong></p>\n"),
+    }
+
+    format!(
+        "<html>\n\
+         <head><title>NVIDIA GPU Exporter</title></head>\n\
+         <body>\n\
+         <h1>NVIDIA GPU Exporter</h1>\n\
+         {}\
+         <p><a href='/metrics'>Metrics</a></p>\n\
+         </body>\n\
+         </html>\n",
+        body
+    )
+}
+
+/// Escape text for use in HTML element content
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_devices() {
+        let devices = [Device {
+            index: "0".to_string(),
+            minor_number: "0".to_string(),
+            name: "NVIDIA A100-SXM4-80GB".to_string(),
+            temperature: 41.0,
+            power_usage: 65_432.0,
+            utilization_gpu: 87.0,
+            memory_used: 2048.0 * 1024.0 * 1024.0,
+            memory_total: 81920.0 * 1024.0 * 1024.0,
+            ..Default::default()
+        }];
+        let html = render(Some(&devices), true);
+
+        assert!(html.contains(
+            "<tr><td>0</td><td>0</td><td>NVIDIA A100-SXM4-80GB</td><td>41</td><td>65.4</td><td>87</td><td>2048 / 81920</td></tr>"
+        ));
+        assert!(!html.contains("NVML unavailable"));
+    }
+
+    #[test]
+    fn test_render_unavailable() {
+        let html = render(None, true);
+
+        assert!(html.contains("NVML unavailable"));
+        assert!(!html.contains("<table"));
+    }
+
+    #[test]
+    fn test_render_not_collected_yet() {
+        let html = render(None, false);
+
+        assert!(html.contains("Not collected yet"));
+        assert!(!html.contains("NVML unavailable"));
+        assert!(!html.contains("<table"));
+    }
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(escape("<b>\"GPU\" & co</b>"), "&lt;b&gt;&quot;GPU&quot; &amp; co&lt;/b&gt;");
+    }
+}
@@ -0,0 +1,145 @@
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+/// `nvmlClocksThrottleReasonGpuIdle`, reported whenever nothing runs on the GPU
+pub const GPU_IDLE: u64 = 0x1;
+/// `nvmlClocksThrottleReasonApplicationsClocksSetting`
+pub const APPLICATIONS_CLOCKS_SETTING: u64 = 0x2;
+/// `nvmlClocksThrottleReasonSwPowerCap`
+pub const SW_POWER_CAP: u64 = 0x4;
+/// `nvmlClocksThrottleReasonHwSlowdown`
+pub const HW_SLOWDOWN: u64 = 0x8;
+/// `nvmlClocksThrottleReasonSyncBoost`
+pub const SYNC_BOOST: u64 = 0x10;
+/// `nvmlClocksThrottleReasonSwThermalSlowdown`, set when either the GPU or the
+/// memory is above its maximum operating temperature. NVML has no reason bit for
+/// the memory alone.
+pub const SW_THERMAL_SLOWDOWN: u64 = 0x20;
+/// `nvmlClocksThrottleReasonHwThermalSlowdown`
+pub const HW_THERMAL_SLOWDOWN: u64 = 0x40;
+/// `nvmlClocksThrottleReasonHwPowerBrakeSlowdown`
+pub const HW_POWER_BRAKE_SLOWDOWN: u64 = 0x80;
+/// `nvmlClocksThrottleReasonDisplayClockSetting`
+pub const DISPLAY_CLOCK_SETTING: u64 = 0x100;
+
+/// Clock throttle reasons tracked by `ThrottleTimer`, with their NVML bit
+pub const THROTTLE_REASONS: [(&str, u64); 9] = [
+    ("gpu_idle", GPU_IDLE),
+    ("applications_clocks_setting", APPLICATIONS_CLOCKS_SETTING),
+    ("sw_power_cap", SW_POWER_CAP),
+    ("hw_slowdown", HW_SLOWDOWN),
+    ("sync_boost", SYNC_BOOST),
+    ("sw_thermal_slowdown", SW_THERMAL_SLOWDOWN),
+    ("hw_thermal_slowdown", HW_THERMAL_SLOWDOWN),
+    ("hw_power_brake_slowdown", HW_POWER_BRAKE_SLOWDOWN),
+    ("display_clock_setting", DISPLAY_CLOCK_SETTING),
+];
+
+/// Time spent in each throttle reason per device, from throttle reason masks
+/// sampled over time. The time between two samples goes to the reasons of the
+/// first one, since that is the state the device was last seen in.
+#[derive(Debug, Default)]
+pub struct ThrottleTimer {
+    /// Last sample of each device, keyed by minor number
+    last: BTreeMap<String, (Instant, u64)>,
+}
+
+impl ThrottleTimer {
+    /// Record the mask of a device sampled at `at`, returning the time to add to
+    /// each reason active since the previous sample of the device
+    pub fn record(&mut self, minor: &str, mask: u64, at: Instant) -> Vec<(&'static str, Duration)> {
+        let Some((previous_at, previous_mask)) = self.last.insert(minor.to_string(), (at, mask)) else {
+            return Vec::new();
+        };
+        let elapsed = at.saturating_duration_since(previous_at);
+        THROTTLE_REASONS
+            .iter()
+            .filter(|(_, bit)| previous_mask & bit != 0)
+            .map(|(reason, _)| (*reason, elapsed))
+            .collect()
+    }
+
+    /// Reasons in `mask` that weren't active in the previous sample of the device,
+    /// none if there is no previous sample to compare with
+    pub fn rising_edges(&self, minor: &str, mask: u64) -> u64 {
+        self.last.get(minor).map_or(0, |(_, previous_mask)| mask & !previous_mask)
+    }
+
+    /// Forget the previous samples, so the time until the next one isn't
+    /// attributed to anything, e.g. after a sampling gap
+    pub fn reset(&mut self) {
+        self.last.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_throttle_timer_accumulates_reasons() {
+        let start = Instant::now();
+        let at = |ms| start + Duration::from_millis(ms);
+        let mut timer = ThrottleTimer::default();
+        let mut totals: BTreeMap<&str, Duration> = BTreeMap::new();
+        let mut record = |timer: &mut ThrottleTimer, mask, ms| {
+            for (reason, elapsed) in timer.record("0", mask, at(ms)) {
+                *totals.entry(reason).or_default() += elapsed;
+            }
+        };
+
+        // Idle, a 300ms power cap with thermal slowdown for the last 100ms, then idle again
+        record(&mut timer, 0x1, 0);
+        record(&mut timer, 0x1, 250);
+        record(&mut timer, 0x4, 500);
+        record(&mut timer, 0x4 | 0x20, 700);
+        record(&mut timer, 0x1, 800);
+        record(&mut timer, 0x1, 1000);
+
+        assert_eq!(
+            totals,
+            BTreeMap::from([
+                ("gpu_idle", Duration::from_millis(700)),
+                ("sw_power_cap", Duration::from_millis(300)),
+                ("sw_thermal_slowdown", Duration::from_millis(100)),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_throttle_timer_tracks_devices_separately() {
+        let start = Instant::now();
+        let mut timer = ThrottleTimer::default();
+
+        assert!(timer.record("0", 0x4, start).is_empty());
+        assert!(timer.record("1", 0x40, start).is_empty());
+        let later = start + Duration::from_secs(1);
+        assert_eq!(timer.record("0", 0x0, later), vec![("sw_power_cap", Duration::from_secs(1))]);
+        assert_eq!(timer.record("1", 0x0, later), vec![("hw_thermal_slowdown", Duration::from_secs(1))]);
+        // Nothing was active
+        assert!(timer.record("0", 0x0, later + Duration::from_secs(1)).is_empty());
+    }
+
+    #[test]
+    fn test_throttle_timer_rising_edges() {
+        let start = Instant::now();
+        let mut timer = ThrottleTimer::default();
+
+        // Nothing to compare the first sample with
+        assert_eq!(timer.rising_edges("0", 0x24), 0);
+        timer.record("0", 0x24, start);
+        assert_eq!(timer.rising_edges("0", 0x24), 0);
+        assert_eq!(timer.rising_edges("0", 0x64), 0x40);
+        assert_eq!(timer.rising_edges("0", 0x0), 0);
+    }
+
+    #[test]
+    fn test_throttle_timer_reset() {
+        let start = Instant::now();
+        let mut timer = ThrottleTimer::default();
+
+        timer.record("0", 0x4, start);
+        timer.reset();
+        assert!(timer.record("0", 0x4, start + Duration::from_secs(60)).is_empty());
+    }
+}
@@ -0,0 +1,125 @@
+use std::path::Path;
+use tracing::debug;
+
+/// Environment variable the NVIDIA container runtime reads to pick the GPUs of a container
+pub const VISIBLE_DEVICES_ENV: &str = "NVIDIA_VISIBLE_DEVICES";
+
+/// Character device major number of the NVIDIA GPU device nodes
+const NVIDIA_MAJOR: &str = "195";
+
+/// Minor numbers from here on are the control devices (nvidia-modeset, nvidiactl), not GPUs
+const NVIDIA_CONTROL_MINORS: u32 = 254;
+
+/// Whether `NVIDIA_VISIBLE_DEVICES` selects a subset of the GPUs. Unset or "all"
+/// exposes every GPU; a list of indices or UUIDs, "none" or "void" does not.
+pub fn restricted_by_env(visible_devices: Option<&str>) -> bool {
+    visible_devices.is_some_and(|value| !value.trim().eq_ignore_ascii_case("all"))
+}
+
+/// Whether a cgroup v1 `devices.list` only allows access to specific GPUs, as
+/// container runtimes set it up, rather than to every device or every GPU
+pub fn restricted_by_cgroup(devices_list: &str) -> bool {
+    let wildcard = devices_list.lines().any(|line| {
+        let mut fields = line.split_whitespace();
+        match (fields.next(), fields.next()) {
+            (Some("a"), _) => true,
+            (Some("c"), Some(device)) => device == "*:*" || device == format!("{}:*", NVIDIA_MAJOR),
+            _ => false,
+        }
+    });
+    !wildcard
+}
+
+/// Number of GPUs in the devices cgroup allow list, for reporting
+fn allowed_gpus(devices_list: &str) -> usize {
+    devices_list
+        .lines()
+        .filter_map(|line| line.split_whitespace().nth(1)?.strip_prefix(NVIDIA_MAJOR)?.strip_prefix(':'))
+        .filter(|minor| minor.parse::<u32>().is_ok_and(|minor| minor < NVIDIA_CONTROL_MINORS))
+        .count()
+}
+
+/// Read the devices cgroup of the exporter under `sys_root` (normally `/sys`),
+/// returning whether it restricts the GPUs. Not derivable with cgroup v2, whose
+/// device controller has no allow list to read.
+pub fn cgroup_restricts_gpus(sys_root: &Path) -> bool {
+    let path = sys_root.join("fs/cgroup/devices/devices.list");
+    match std::fs::read_to_string(&path) {
+        Ok(contents) => {
+            let restricted = restricted_by_cgroup(&contents);
+            if restricted {
+                debug!("Devices cgroup allows {} GPU(s)", allowed_gpus(&contents));
+            }
+            restricted
+        }
+        Err(e) => {
+            debug!("Failed to read {}: {}", path.display(), e);
+            false
+        }
+    }
+}
+
+/// Number of GPUs the driver has on the host, from the per-GPU entries under
+/// `<proc_root>/driver/nvidia/gpus`. `None` when the directory can't be read, as
+/// in containers that don't mount it.
+pub fn host_device_count(proc_root: &Path) -> Option<usize> {
+    let path = proc_root.join("driver/nvidia/gpus");
+    match std::fs::read_dir(&path) {
+        Ok(entries) => Some(entries.count()),
+        Err(e) => {
+            debug!("Failed to read {}: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_restricted_by_env() {
+        assert!(!restricted_by_env(None));
+        assert!(!restricted_by_env(Some("all")));
+        assert!(!restricted_by_env(Some("ALL")));
+        assert!(restricted_by_env(Some("0,1")));
+        assert!(restricted_by_env(Some("GPU-5c1e4a6b-2f0d-8e3c-41a7-9b2d6f0e1c3a")));
+        assert!(restricted_by_env(Some("none")));
+        assert!(restricted_by_env(Some("")));
+    }
+
+    #[test]
+    fn test_restricted_by_cgroup() {
+        assert!(!restricted_by_cgroup("a *:* rwm\n"));
+        assert!(!restricted_by_cgroup("c 1:3 rwm\nc 195:* rwm\n"));
+
+        let container = "c 1:3 rwm\nc 195:255 rw\nc 195:254 rw\nc 195:2 rw\nc 195:3 rw\n";
+        assert!(restricted_by_cgroup(container));
+        assert_eq!(allowed_gpus(container), 2);
+    }
+
+    #[test]
+    fn test_cgroup_restricts_gpus() {
+        let sys_root = tempfile::tempdir().unwrap();
+        // Nothing to read with cgroup v2
+        assert!(!cgroup_restricts_gpus(sys_root.path()));
+
+        let devices = sys_root.path().join("fs/cgroup/devices");
+        fs::create_dir_all(&devices).unwrap();
+        fs::write(devices.join("devices.list"), "c 195:255 rw\nc 195:0 rw\n").unwrap();
+        assert!(cgroup_restricts_gpus(sys_root.path()));
+    }
+
+    #[test]
+    fn test_host_device_count() {
+        let proc_root = tempfile::tempdir().unwrap();
+        assert_eq!(host_device_count(proc_root.path()), None);
+
+        let gpus = proc_root.path().join("driver/nvidia/gpus");
+        for bus_id in ["0000:07:00.0", "0000:0f:00.0", "0000:47:00.0"] {
+            fs::create_dir_all(gpus.join(bus_id)).unwrap();
+        }
+        assert_eq!(host_device_count(proc_root.path()), Some(3));
+    }
+}